@@ -0,0 +1,343 @@
+//! Canadian adjusted cost base
+//!
+//! All BTC a taxpayer holds is one class of identical property with a single
+//! adjusted cost base (ACB): acquisitions add their cost, and a disposal takes
+//! its share of the ACB at the time.
+//!
+//! A loss is superficial when BTC is also acquired in the 30 days before or
+//! after the sale and some is still held at the end of that window. The
+//! denied part of the loss (sold, acquired in the window and held at its end,
+//! whichever is least, over the sats sold) is added to the ACB of what's still
+//! held, or of the next acquisition if nothing is. Values must be in CAD,
+//! matching runs per entity like the UK report, and totals are per calendar
+//! year. Fees are added to the ACB of acquisitions and taken off proceeds as
+//! outlays.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+use crate::audit::money;
+use crate::cost_basis::{self, classify, Entry, Kind};
+use crate::dates::{self, SECONDS_PER_DAY};
+
+/// Days before and after a loss sale in which a reacquisition makes it superficial
+const SUPERFICIAL_LOSS_DAYS: i64 = 30;
+
+/// Share of a net capital gain that is taxable
+const INCLUSION_RATE: f64 = 0.5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AcbDisposal {
+    pub id: i64,
+    pub date: String,
+    pub sats: i64,
+    pub proceeds: f64,
+    pub acb: f64,
+    /// Before any superficial loss is denied
+    pub gain: f64,
+    pub superficial: bool,
+    /// Part of the loss denied, as a positive amount
+    pub denied_loss: f64,
+    pub allowed_gain: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AcbYear {
+    pub year: i64,
+    pub disposals: usize,
+    pub proceeds: f64,
+    pub acb: f64,
+    pub gains: f64,
+    /// Allowed losses, as a positive amount
+    pub losses: f64,
+    pub denied_losses: f64,
+    pub net_gain: f64,
+    pub taxable_gain: f64,
+    /// Holdings on 31 December
+    pub holdings_sats: i64,
+    pub holdings_acb: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AcbReport {
+    pub entity_id: Option<i64>,
+    pub disposals: Vec<AcbDisposal>,
+    pub years: Vec<AcbYear>,
+    pub holdings_sats: i64,
+    pub holdings_acb: f64,
+    pub warnings: Vec<String>,
+}
+
+/// Sats a disposal's loss is denied for: sold, acquired within 30 days either
+/// side and still held at the end of the window, whichever is least
+fn superficial_sats(events: &[(i64, i64)], disposed_at: i64, sats: i64) -> i64 {
+    let day = disposed_at.div_euclid(SECONDS_PER_DAY);
+    let window_start = (day - SUPERFICIAL_LOSS_DAYS) * SECONDS_PER_DAY;
+    let window_end = (day + SUPERFICIAL_LOSS_DAYS + 1) * SECONDS_PER_DAY;
+
+    let acquired: i64 = events
+        .iter()
+        .filter(|(timestamp, amount)| *amount > 0 && *timestamp >= window_start && *timestamp < window_end)
+        .map(|(_, amount)| amount)
+        .sum();
+    let held: i64 = events
+        .iter()
+        .filter(|(timestamp, _)| *timestamp < window_end)
+        .map(|(_, amount)| amount)
+        .sum();
+
+    sats.min(acquired).min(held.max(0))
+}
+
+/// ACB and gains for one taxpayer's transactions
+pub fn compute(entries: &[Entry], entity_id: Option<i64>) -> AcbReport {
+    let mut entries: Vec<(&Entry, Kind)> = entries
+        .iter()
+        .filter_map(|entry| classify(&entry.category, entry.amount_sats).map(|kind| (entry, kind)))
+        .collect();
+    entries.sort_by_key(|(entry, _)| (entry.timestamp, entry.id));
+    let events: Vec<(i64, i64)> = entries
+        .iter()
+        .map(|(entry, _)| (entry.timestamp, entry.amount_sats))
+        .collect();
+
+    let mut holdings_sats = 0i64;
+    let mut holdings_acb = 0.0;
+    // Denied losses waiting for something to be held again
+    let mut pending = 0.0;
+    let mut disposals = Vec::new();
+    let mut years: BTreeMap<i64, AcbYear> = BTreeMap::new();
+    let mut warnings = Vec::new();
+
+    for (entry, kind) in entries {
+        let year = dates::civil_date(entry.timestamp).0;
+
+        if kind == Kind::Acquisition {
+            holdings_sats += entry.amount_sats;
+            holdings_acb += entry.cost() + pending;
+            pending = 0.0;
+        } else {
+            let sats = entry.amount_sats.abs();
+            let held = sats.min(holdings_sats);
+            if held < sats {
+                warnings.push(format!(
+                    "Transaction {} disposes of {} sats more than were held",
+                    entry.id,
+                    sats - held
+                ));
+            }
+
+            let acb = if held == holdings_sats {
+                holdings_acb
+            } else {
+                holdings_acb * held as f64 / holdings_sats as f64
+            };
+            holdings_sats -= held;
+            holdings_acb -= acb;
+
+            let gain = entry.proceeds() - acb;
+            let denied_loss = if gain < 0.0 {
+                -gain * superficial_sats(&events, entry.timestamp, sats) as f64 / sats as f64
+            } else {
+                0.0
+            };
+            if denied_loss > 0.0 {
+                if holdings_sats > 0 {
+                    holdings_acb += denied_loss;
+                } else {
+                    pending += denied_loss;
+                }
+            }
+
+            let disposal = AcbDisposal {
+                id: entry.id,
+                date: dates::iso_date(entry.timestamp),
+                sats,
+                proceeds: entry.proceeds(),
+                acb,
+                gain,
+                superficial: denied_loss > 0.0,
+                denied_loss,
+                allowed_gain: gain + denied_loss,
+            };
+
+            let totals = years.entry(year).or_insert_with(|| AcbYear {
+                year,
+                ..Default::default()
+            });
+            totals.disposals += 1;
+            totals.proceeds += disposal.proceeds;
+            totals.acb += disposal.acb;
+            if disposal.allowed_gain >= 0.0 {
+                totals.gains += disposal.allowed_gain;
+            } else {
+                totals.losses -= disposal.allowed_gain;
+            }
+            totals.denied_losses += disposal.denied_loss;
+            totals.net_gain += disposal.allowed_gain;
+            totals.taxable_gain = totals.net_gain * INCLUSION_RATE;
+            disposals.push(disposal);
+        }
+
+        if let Some(totals) = years.get_mut(&year) {
+            totals.holdings_sats = holdings_sats;
+            totals.holdings_acb = holdings_acb;
+        }
+    }
+
+    if pending > 0.0 {
+        warnings.push(format!(
+            "{:.2} of denied superficial losses couldn't be added to any holdings",
+            pending
+        ));
+    }
+
+    AcbReport {
+        entity_id,
+        disposals,
+        years: years.into_values().collect(),
+        holdings_sats,
+        holdings_acb,
+        warnings,
+    }
+}
+
+/// Disposals and the yearly summary as CSV, optionally for one year
+pub fn to_csv(report: &AcbReport, year: Option<i64>) -> String {
+    let shown = |date: &str| year.is_none_or(|year| date.starts_with(&format!("{}-", year)));
+
+    let mut csv = String::from(
+        "Date,Transaction,Sats,Proceeds (CAD),ACB (CAD),Gain or loss (CAD),Superficial loss denied (CAD),Allowed gain or loss (CAD)\n",
+    );
+    for disposal in report.disposals.iter().filter(|d| shown(&d.date)) {
+        let row = [
+            disposal.date.clone(),
+            disposal.id.to_string(),
+            disposal.sats.to_string(),
+            money(disposal.proceeds),
+            money(disposal.acb),
+            money(disposal.gain),
+            money(disposal.denied_loss),
+            money(disposal.allowed_gain),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv.push_str(
+        "\nYear,Disposals,Proceeds (CAD),ACB (CAD),Gains (CAD),Losses (CAD),Superficial losses denied (CAD),\
+         Net gain (CAD),Taxable gain (CAD),Sats held on 31 Dec,ACB on 31 Dec (CAD)\n",
+    );
+    for totals in report.years.iter().filter(|y| year.is_none_or(|year| year == y.year)) {
+        let row = [
+            totals.year.to_string(),
+            totals.disposals.to_string(),
+            money(totals.proceeds),
+            money(totals.acb),
+            money(totals.gains),
+            money(totals.losses),
+            money(totals.denied_losses),
+            money(totals.net_gain),
+            money(totals.taxable_gain),
+            totals.holdings_sats.to_string(),
+            money(totals.holdings_acb),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// ACB report from the stored transactions, for one entity or all of them
+pub async fn generate(pool: &SqlitePool, entity_id: Option<i64>) -> Result<AcbReport, String> {
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let currency = cost_basis::default_currency(&mut conn).await?;
+    if !currency.eq_ignore_ascii_case("CAD") {
+        return Err(format!("The ACB report needs values in CAD, not {}", currency));
+    }
+
+    let (mut entries, mut warnings) = cost_basis::load_entries(&mut conn, &currency).await?;
+    entries.retain(|entry| entity_id.is_none_or(|id| id == entry.entity_id));
+
+    let mut report = compute(&entries, entity_id);
+    warnings.append(&mut report.warnings);
+    report.warnings = warnings;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::entry;
+
+    #[test]
+    fn test_average_cost() {
+        let report = compute(
+            &[
+                entry(1, "2023-01-01", 1_000, 1_000.0, "Buy"),
+                entry(2, "2023-02-01", 1_000, 2_000.0, "Buy"),
+                entry(3, "2023-06-01", -500, 1_000.0, "Sell"),
+            ],
+            Some(1),
+        );
+
+        assert_eq!(report.disposals[0].acb, 750.0);
+        assert_eq!(report.disposals[0].allowed_gain, 250.0);
+        assert!(!report.disposals[0].superficial);
+        assert_eq!(report.years[0].taxable_gain, 125.0);
+        assert_eq!(report.years[0].holdings_acb, 2_250.0);
+    }
+
+    #[test]
+    fn test_superficial_loss() {
+        let report = compute(
+            &[
+                entry(1, "2023-01-01", 1_000, 2_000.0, "Buy"),
+                entry(2, "2023-06-01", -1_000, 1_000.0, "Sell"),
+                entry(3, "2023-06-20", 400, 500.0, "Buy"),
+            ],
+            Some(1),
+        );
+
+        // 400 of the 1,000 sats sold were bought back within 30 days
+        let disposal = &report.disposals[0];
+        assert!(disposal.superficial);
+        assert_eq!(disposal.gain, -1_000.0);
+        assert_eq!(disposal.denied_loss, 400.0);
+        assert_eq!(disposal.allowed_gain, -600.0);
+        assert_eq!(report.years[0].losses, 600.0);
+        assert_eq!(report.years[0].denied_losses, 400.0);
+
+        // Nothing was held after the sale, so the denied loss goes to the rebuy
+        assert_eq!(report.holdings_acb, 900.0);
+        assert_eq!(report.years[0].holdings_acb, 900.0);
+    }
+
+    #[test]
+    fn test_loss_not_superficial() {
+        let entries = [
+            entry(1, "2023-01-01", 1_000, 2_000.0, "Buy"),
+            entry(2, "2023-06-01", -1_000, 1_000.0, "Sell"),
+            entry(3, "2023-07-02", 400, 500.0, "Buy"),
+        ];
+        assert!(!compute(&entries, None).disposals[0].superficial);
+
+        // Bought back in time but sold again before the window closed
+        let entries = [
+            entry(1, "2023-01-01", 1_000, 2_000.0, "Buy"),
+            entry(2, "2023-06-01", -1_000, 1_000.0, "Sell"),
+            entry(3, "2023-06-10", 400, 500.0, "Buy"),
+            entry(4, "2023-06-15", -400, 600.0, "Sell"),
+        ];
+        let report = compute(&entries, None);
+        assert!(!report.disposals[0].superficial);
+        assert_eq!(report.years[0].net_gain, -900.0);
+
+        let csv = to_csv(&report, Some(2023));
+        assert!(csv.contains("2023-06-01,2,1000,1000.00,2000.00,-1000.00,0.00,-1000.00\n"));
+        assert!(csv.contains("\n2023,2,1600.00,2500.00,100.00,1000.00,0.00,-900.00,-450.00,0,0.00\n"));
+    }
+}
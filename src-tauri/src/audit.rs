@@ -0,0 +1,420 @@
+//! Append-only audit log
+//!
+//! Data-changing commands record what they changed, with old and new values as
+//! JSON, in the same database transaction as the change itself. Triggers in
+//! `db.rs` reject any UPDATE or DELETE on `audit_log`, so entries can only be
+//! added.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sqlx::{SqliteConnection, SqlitePool};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub command: String,
+    pub table_name: String,
+    pub row_id: Option<i64>,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub actor: String,
+    pub created_at: String,
+}
+
+/// Filters for querying the log; all optional
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct AuditFilter {
+    pub table_name: Option<String>,
+    pub row_id: Option<i64>,
+    pub command: Option<String>,
+    /// Inclusive, `YYYY-MM-DD` or a full timestamp
+    pub from: Option<String>,
+    /// Inclusive, `YYYY-MM-DD` or a full timestamp
+    pub to: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Editable fields of a transaction, as recorded in the log
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct TransactionValues {
+    wallet_id: i64,
+    txid: String,
+    amount_sats: i64,
+    fee_sats: i64,
+    fee_fiat: Option<f64>,
+    fee_fiat_currency: Option<String>,
+    timestamp: Option<i64>,
+    category: Option<String>,
+    note: Option<String>,
+    fiat_value: Option<f64>,
+    fiat_currency: Option<String>,
+    currency: Option<String>,
+}
+
+/// OS user making the change; the app itself has no accounts
+fn actor() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Append an entry to the log
+pub async fn record(
+    conn: &mut SqliteConnection,
+    command: &str,
+    table_name: &str,
+    row_id: Option<i64>,
+    old_value: Option<Value>,
+    new_value: Option<Value>,
+) -> Result<(), String> {
+    sqlx::query(
+        "INSERT INTO audit_log (command, table_name, row_id, old_value, new_value, actor) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(command)
+    .bind(table_name)
+    .bind(row_id)
+    .bind(old_value.map(|v| v.to_string()))
+    .bind(new_value.map(|v| v.to_string()))
+    .bind(actor())
+    .execute(conn)
+    .await
+    .map_err(|e| format!("Failed to write audit log: {}", e))?;
+
+    Ok(())
+}
+
+/// Record an edit, keeping only the fields that changed; no-op edits aren't logged
+pub async fn record_change(
+    conn: &mut SqliteConnection,
+    command: &str,
+    table_name: &str,
+    row_id: i64,
+    old_value: Value,
+    new_value: Value,
+) -> Result<(), String> {
+    let (Value::Object(old), Value::Object(new)) = (old_value, new_value) else {
+        return Err("Audit values must be objects".to_string());
+    };
+
+    let mut old_changed = Map::new();
+    let mut new_changed = Map::new();
+    for (key, new) in new {
+        let old = old.get(&key).cloned().unwrap_or(Value::Null);
+        if old != new {
+            old_changed.insert(key.clone(), old);
+            new_changed.insert(key, new);
+        }
+    }
+
+    if new_changed.is_empty() {
+        return Ok(());
+    }
+
+    record(
+        conn,
+        command,
+        table_name,
+        Some(row_id),
+        Some(Value::Object(old_changed)),
+        Some(Value::Object(new_changed)),
+    )
+    .await
+}
+
+/// Current values of a transaction, for before/after snapshots
+pub async fn transaction_values(conn: &mut SqliteConnection, id: i64) -> Result<Value, String> {
+    let values = sqlx::query_as::<_, TransactionValues>(
+        r#"
+        SELECT t.wallet_id, t.txid, t.amount_sats, t.fee_sats, t.fee_fiat, t.fee_fiat_currency, t.timestamp,
+               c.name as category, t.note, t.fiat_value, t.fiat_currency, t.currency
+        FROM transactions t
+        LEFT JOIN categories c ON t.category_id = c.id
+        WHERE t.id = ?
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("Transaction not found")?;
+
+    serde_json::to_value(values).map_err(|e| e.to_string())
+}
+
+/// Query the log, newest first
+pub async fn query(pool: &SqlitePool, filter: &AuditFilter) -> Result<Vec<AuditEntry>, String> {
+    // A bare date as the upper bound means the whole day
+    let to = filter.to.as_ref().map(|to| {
+        if to.len() == 10 {
+            format!("{}T23:59:59.999Z", to)
+        } else {
+            to.clone()
+        }
+    });
+
+    sqlx::query_as::<_, AuditEntry>(
+        r#"
+        SELECT id, command, table_name, row_id, old_value, new_value, actor, created_at
+        FROM audit_log
+        WHERE (?1 IS NULL OR table_name = ?1)
+          AND (?2 IS NULL OR row_id = ?2)
+          AND (?3 IS NULL OR command = ?3)
+          AND (?4 IS NULL OR created_at >= ?4)
+          AND (?5 IS NULL OR created_at <= ?5)
+        ORDER BY id DESC
+        LIMIT ?6
+        "#,
+    )
+    .bind(&filter.table_name)
+    .bind(filter.row_id)
+    .bind(&filter.command)
+    .bind(&filter.from)
+    .bind(to)
+    .bind(filter.limit.unwrap_or(-1))
+    .fetch_all(pool)
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Quote a CSV field if it needs it
+pub fn csv_field(value: &str) -> String {
+    if value.contains(['"', ',', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// A fiat amount for CSV, to the cent
+pub fn money(value: f64) -> String {
+    format!("{:.2}", value)
+}
+
+/// The log as CSV, oldest first
+pub fn to_csv(entries: &[AuditEntry]) -> String {
+    let mut csv = String::from("id,created_at,actor,command,table,row_id,old_value,new_value\n");
+
+    for entry in entries.iter().rev() {
+        let row = [
+            entry.id.to_string(),
+            entry.created_at.clone(),
+            entry.actor.clone(),
+            entry.command.clone(),
+            entry.table_name.clone(),
+            entry.row_id.map(|id| id.to_string()).unwrap_or_default(),
+            entry.old_value.clone().unwrap_or_default(),
+            entry.new_value.clone().unwrap_or_default(),
+        ];
+        let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::memory_pool;
+    use serde_json::json;
+
+    async fn entries(pool: &SqlitePool) -> Vec<AuditEntry> {
+        query(pool, &AuditFilter::default()).await.unwrap()
+    }
+
+    async fn ids(pool: &SqlitePool, filter: AuditFilter) -> Vec<i64> {
+        query(pool, &filter)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_log_is_append_only() {
+        let pool = memory_pool().await;
+        let mut conn = pool.acquire().await.unwrap();
+        record(
+            &mut conn,
+            "add_wallet",
+            "wallets",
+            Some(1),
+            None,
+            Some(json!({ "name": "Savings" })),
+        )
+        .await
+        .unwrap();
+
+        let update = sqlx::query("UPDATE audit_log SET actor = 'someone else'")
+            .execute(&mut *conn)
+            .await
+            .unwrap_err();
+        assert!(
+            update.to_string().contains("The audit log is append-only"),
+            "{}",
+            update
+        );
+
+        let delete = sqlx::query("DELETE FROM audit_log")
+            .execute(&mut *conn)
+            .await
+            .unwrap_err();
+        assert!(
+            delete.to_string().contains("The audit log is append-only"),
+            "{}",
+            delete
+        );
+
+        drop(conn);
+        let entries = entries(&pool).await;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].actor, actor());
+        assert_eq!(entries[0].new_value.as_deref(), Some(r#"{"name":"Savings"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_record_change_keeps_changed_fields() {
+        let pool = memory_pool().await;
+        let mut conn = pool.acquire().await.unwrap();
+
+        let old = json!({ "name": "Savings", "xpub": "xpub1", "entity_id": 1 });
+        record_change(&mut conn, "update_wallet", "wallets", 1, old.clone(), old.clone())
+            .await
+            .unwrap();
+
+        let new = json!({ "name": "Cold storage", "xpub": "xpub1", "entity_id": 2 });
+        record_change(&mut conn, "update_wallet", "wallets", 1, old.clone(), new)
+            .await
+            .unwrap();
+
+        // A field the old value didn't have counts as changed from null
+        record_change(
+            &mut conn,
+            "update_wallet",
+            "wallets",
+            1,
+            json!({}),
+            json!({ "note": "x" }),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            record_change(&mut conn, "update_wallet", "wallets", 1, json!(1), json!(2))
+                .await
+                .unwrap_err(),
+            "Audit values must be objects"
+        );
+
+        drop(conn);
+        let entries = entries(&pool).await;
+        assert_eq!(entries.len(), 2, "the no-op edit isn't logged");
+        assert_eq!(
+            entries[1].old_value.as_deref(),
+            Some(r#"{"entity_id":1,"name":"Savings"}"#)
+        );
+        assert_eq!(
+            entries[1].new_value.as_deref(),
+            Some(r#"{"entity_id":2,"name":"Cold storage"}"#)
+        );
+        assert_eq!(entries[0].old_value.as_deref(), Some(r#"{"note":null}"#));
+    }
+
+    #[tokio::test]
+    async fn test_query_filters() {
+        let pool = memory_pool().await;
+        sqlx::query(
+            r#"
+            INSERT INTO audit_log (command, table_name, row_id, actor, created_at) VALUES
+                ('add_wallet', 'wallets', 1, 'me', '2024-03-01T09:00:00.000Z'),
+                ('update_wallet', 'wallets', 1, 'me', '2024-03-02T23:30:00.000Z'),
+                ('add_wallet', 'wallets', 2, 'me', '2024-03-03T08:00:00.000Z'),
+                ('delete_transactions', 'transactions', 5, 'me', '2024-03-04T10:00:00.000Z')
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(ids(&pool, AuditFilter::default()).await, vec![4, 3, 2, 1]);
+        assert_eq!(
+            ids(
+                &pool,
+                AuditFilter {
+                    table_name: Some("wallets".to_string()),
+                    ..Default::default()
+                }
+            )
+            .await,
+            vec![3, 2, 1]
+        );
+        assert_eq!(
+            ids(
+                &pool,
+                AuditFilter {
+                    table_name: Some("wallets".to_string()),
+                    row_id: Some(1),
+                    ..Default::default()
+                }
+            )
+            .await,
+            vec![2, 1]
+        );
+        assert_eq!(
+            ids(
+                &pool,
+                AuditFilter {
+                    command: Some("add_wallet".to_string()),
+                    ..Default::default()
+                }
+            )
+            .await,
+            vec![3, 1]
+        );
+
+        // A bare `to` date includes the whole day
+        assert_eq!(
+            ids(
+                &pool,
+                AuditFilter {
+                    from: Some("2024-03-02".to_string()),
+                    to: Some("2024-03-03".to_string()),
+                    ..Default::default()
+                }
+            )
+            .await,
+            vec![3, 2]
+        );
+        assert_eq!(
+            ids(
+                &pool,
+                AuditFilter {
+                    to: Some("2024-03-02T12:00:00.000Z".to_string()),
+                    ..Default::default()
+                }
+            )
+            .await,
+            vec![1]
+        );
+        assert_eq!(
+            ids(
+                &pool,
+                AuditFilter {
+                    limit: Some(2),
+                    ..Default::default()
+                }
+            )
+            .await,
+            vec![4, 3]
+        );
+    }
+
+    #[test]
+    fn test_csv_field_quoting() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field(r#"{"note":"x"}"#), r#""{""note"":""x""}""#);
+    }
+}
@@ -0,0 +1,494 @@
+//! Encrypted backup archives
+//!
+//! An archive is a single SQLCipher database keyed with the backup password.
+//! It holds a snapshot of each vault database (taken with the SQLite backup
+//! API, so it's consistent while the app is running) and the encryption
+//! header, each stored with its SHA-256 checksum, plus an overall checksum
+//! over the file list.
+
+use rusqlite::{params, Connection, DatabaseName, OpenFlags};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+use crate::db;
+use crate::encrypted_db::{self, EncryptedDb};
+use crate::encryption_header::HEADER_FILE_NAME;
+
+/// Newest archive format this build understands
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+/// Shortest accepted backup password
+pub const MIN_BACKUP_PASSWORD_LEN: usize = 8;
+
+/// Files taken from (and restored to) a vault directory
+const VAULT_FILES: [&str; 3] = [db::DB_FILE_NAME, encrypted_db::DB_FILE_NAME, HEADER_FILE_NAME];
+
+/// SQLite side files that must not outlive a restored database
+const SIDE_FILE_SUFFIXES: [&str; 2] = ["-wal", "-shm"];
+
+/// Summary of an archive, returned on export and restore
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ArchiveInfo {
+    pub format_version: u32,
+    pub created_at: u64,
+    pub app_version: String,
+    pub vault_name: String,
+    pub files: Vec<String>,
+}
+
+/// A validated file read back from an archive
+pub struct ArchiveFile {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Checksum over every (name, checksum) pair, so files can't be dropped or swapped
+fn manifest_checksum(entries: &[(String, String)]) -> String {
+    let mut sorted = entries.to_vec();
+    sorted.sort();
+
+    let mut hasher = Sha256::new();
+    for (name, checksum) in sorted {
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update(checksum.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Open an archive keyed with the backup password
+fn open_archive(path: &Path, password: &str, create: bool) -> Result<Connection, String> {
+    let flags = if create {
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+    } else {
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+    };
+
+    let conn = Connection::open_with_flags(path, flags)
+        .map_err(|e| format!("Failed to open backup: {}", e))?;
+
+    // SQLCipher passphrase mode: PBKDF2 with a per-file salt, pages authenticated
+    conn.pragma_update(None, "key", password)
+        .map_err(|e| format!("Failed to set backup password: {}", e))?;
+    conn.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0))
+        .map_err(|_| "Wrong backup password, or not a backup file".to_string())?;
+
+    Ok(conn)
+}
+
+/// Take a consistent copy of a vault file
+///
+/// Plaintext databases go through the backup API; SQLCipher files are
+/// already encrypted and only written during conversions, so their bytes
+/// are copied as they are.
+fn snapshot_file(path: &Path) -> Result<Vec<u8>, String> {
+    let is_database = path.extension().is_some_and(|ext| ext == "db");
+    if !is_database || EncryptedDb::is_sqlcipher_file(path) {
+        return std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e));
+    }
+
+    // Next to the live database, which is itself plaintext
+    let snapshot_path = encrypted_db::with_suffix(path, "snapshot");
+    let result = (|| {
+        let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        source
+            .backup(DatabaseName::Main, &snapshot_path, None)
+            .map_err(|e| format!("Failed to snapshot {}: {}", path.display(), e))?;
+        std::fs::read(&snapshot_path).map_err(|e| format!("Failed to read snapshot: {}", e))
+    })();
+
+    let _ = encrypted_db::remove_if_exists(&snapshot_path);
+    result
+}
+
+/// Write an encrypted archive of a vault directory
+pub fn export_archive(
+    vault_dir: &Path,
+    vault_name: &str,
+    archive_path: &Path,
+    password: &str,
+) -> Result<ArchiveInfo, String> {
+    if password.chars().count() < MIN_BACKUP_PASSWORD_LEN {
+        return Err(format!(
+            "Backup password must be at least {} characters",
+            MIN_BACKUP_PASSWORD_LEN
+        ));
+    }
+
+    let info = ArchiveInfo {
+        format_version: ARCHIVE_FORMAT_VERSION,
+        created_at: encrypted_db::unix_now(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        vault_name: vault_name.to_string(),
+        files: VAULT_FILES
+            .iter()
+            .filter(|name| vault_dir.join(name).exists())
+            .map(|name| name.to_string())
+            .collect(),
+    };
+
+    // Build next to the destination and move into place once complete
+    let temp_path = encrypted_db::with_suffix(archive_path, "tmp");
+    encrypted_db::remove_if_exists(&temp_path)?;
+
+    let result = (|| {
+        let mut conn = open_archive(&temp_path, password, true)?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        tx.execute_batch(
+            "CREATE TABLE archive_info (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE archive_files (name TEXT PRIMARY KEY, data BLOB NOT NULL, sha256 TEXT NOT NULL);",
+        )
+        .map_err(|e| format!("Failed to create backup: {}", e))?;
+
+        let mut entries = Vec::new();
+        for name in &info.files {
+            let data = snapshot_file(&vault_dir.join(name))?;
+            let checksum = sha256_hex(&data);
+            tx.execute(
+                "INSERT INTO archive_files (name, data, sha256) VALUES (?1, ?2, ?3)",
+                params![name, data, checksum],
+            )
+            .map_err(|e| format!("Failed to write backup: {}", e))?;
+            entries.push((name.clone(), checksum));
+        }
+
+        let metadata = [
+            ("format_version", info.format_version.to_string()),
+            ("created_at", info.created_at.to_string()),
+            ("app_version", info.app_version.clone()),
+            ("vault_name", info.vault_name.clone()),
+            ("checksum", manifest_checksum(&entries)),
+        ];
+        for (key, value) in metadata {
+            tx.execute(
+                "INSERT INTO archive_info (key, value) VALUES (?1, ?2)",
+                params![key, value],
+            )
+            .map_err(|e| format!("Failed to write backup: {}", e))?;
+        }
+
+        tx.commit().map_err(|e| format!("Failed to write backup: {}", e))?;
+        drop(conn);
+
+        encrypted_db::sync_file(&temp_path)?;
+        std::fs::rename(&temp_path, archive_path)
+            .map_err(|e| format!("Failed to save backup: {}", e))
+    })();
+
+    if result.is_err() {
+        let _ = encrypted_db::remove_if_exists(&temp_path);
+    }
+    result?;
+
+    // Read it back so a bad archive is caught now, not when it's needed
+    read_archive(archive_path, password)?;
+
+    Ok(info)
+}
+
+/// Open an archive and verify every checksum
+pub fn read_archive(archive_path: &Path, password: &str) -> Result<(ArchiveInfo, Vec<ArchiveFile>), String> {
+    let conn = open_archive(archive_path, password, false)?;
+
+    let value = |key: &str| -> Result<String, String> {
+        conn.query_row("SELECT value FROM archive_info WHERE key = ?1", [key], |row| row.get(0))
+            .map_err(|_| format!("Backup is missing its {}", key))
+    };
+
+    let format_version: u32 = value("format_version")?
+        .parse()
+        .map_err(|_| "Backup has an invalid format version".to_string())?;
+    if format_version > ARCHIVE_FORMAT_VERSION {
+        return Err("Backup was created by a newer version of the app".to_string());
+    }
+
+    let mut stmt = conn
+        .prepare("SELECT name, data, sha256 FROM archive_files")
+        .map_err(|e| format!("Failed to read backup: {}", e))?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?, row.get::<_, String>(2)?)))
+        .map_err(|e| format!("Failed to read backup: {}", e))?;
+
+    let mut files = Vec::new();
+    let mut entries = Vec::new();
+    for row in rows {
+        let (name, data, checksum) = row.map_err(|e| format!("Failed to read backup: {}", e))?;
+
+        if !VAULT_FILES.contains(&name.as_str()) {
+            return Err(format!("Backup contains an unexpected file: {}", name));
+        }
+        if sha256_hex(&data) != checksum {
+            return Err(format!("Backup is corrupted: checksum mismatch for {}", name));
+        }
+
+        entries.push((name.clone(), checksum));
+        files.push(ArchiveFile { name, data });
+    }
+
+    if manifest_checksum(&entries) != value("checksum")? {
+        return Err("Backup is corrupted: file list does not match its checksum".to_string());
+    }
+    if !files.iter().any(|f| f.name == db::DB_FILE_NAME) {
+        return Err("Backup does not contain a database".to_string());
+    }
+
+    let info = ArchiveInfo {
+        format_version,
+        created_at: value("created_at")?.parse().unwrap_or(0),
+        app_version: value("app_version")?,
+        vault_name: value("vault_name")?,
+        files: files.iter().map(|f| f.name.clone()).collect(),
+    };
+
+    Ok((info, files))
+}
+
+/// Replace a vault directory's files with those from an archive
+///
+/// Everything is staged and checked first; the current files are kept
+/// aside until the swap has finished and put back if it fails. The caller
+/// must have closed any connections to the vault's databases.
+pub fn restore_files(vault_dir: &Path, files: &[ArchiveFile]) -> Result<(), String> {
+    std::fs::create_dir_all(vault_dir).map_err(|e| format!("Failed to create vault directory: {}", e))?;
+
+    // Stage
+    let mut staged = Vec::new();
+    let stage_result = (|| {
+        for file in files {
+            let target = vault_dir.join(&file.name);
+            let staged_path = encrypted_db::with_suffix(&target, "restore");
+            encrypted_db::write_synced(&staged_path, &file.data)?;
+            staged.push((staged_path.clone(), target));
+
+            if file.name == db::DB_FILE_NAME {
+                verify_plaintext_database(&staged_path)?;
+            }
+        }
+        Ok::<(), String>(())
+    })();
+    if let Err(e) = stage_result {
+        for (staged_path, _) in &staged {
+            let _ = encrypted_db::remove_if_exists(staged_path);
+        }
+        return Err(e);
+    }
+
+    // Move everything that's there now aside, including files the archive doesn't have
+    let mut current: Vec<String> = VAULT_FILES.iter().map(|name| name.to_string()).collect();
+    for name in [db::DB_FILE_NAME, encrypted_db::DB_FILE_NAME] {
+        for suffix in SIDE_FILE_SUFFIXES {
+            current.push(format!("{}{}", name, suffix));
+        }
+    }
+
+    let mut moved = Vec::new();
+    let mut installed = Vec::new();
+    let swap_result = (|| {
+        for name in &current {
+            let path = vault_dir.join(name);
+            if path.exists() {
+                let aside = encrypted_db::with_suffix(&path, "pre-restore");
+                std::fs::rename(&path, &aside)
+                    .map_err(|e| format!("Failed to move {} aside: {}", name, e))?;
+                moved.push((aside, path));
+            }
+        }
+
+        for (staged_path, target) in &staged {
+            std::fs::rename(staged_path, target)
+                .map_err(|e| format!("Failed to restore {}: {}", target.display(), e))?;
+            installed.push(target.clone());
+        }
+        Ok::<(), String>(())
+    })();
+
+    match swap_result {
+        Ok(()) => {
+            for (aside, _) in &moved {
+                let _ = encrypted_db::remove_if_exists(aside);
+            }
+            encrypted_db::sync_dir(vault_dir);
+            Ok(())
+        }
+        Err(e) => {
+            for target in &installed {
+                let _ = encrypted_db::remove_if_exists(target);
+            }
+            for (aside, path) in &moved {
+                let _ = std::fs::rename(aside, path);
+            }
+            for (staged_path, _) in &staged {
+                let _ = encrypted_db::remove_if_exists(staged_path);
+            }
+            encrypted_db::sync_dir(vault_dir);
+            Err(e)
+        }
+    }
+}
+
+fn verify_plaintext_database(path: &Path) -> Result<(), String> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("Backup database could not be opened: {}", e))?;
+    let check: String = conn
+        .query_row("PRAGMA quick_check", [], |row| row.get(0))
+        .map_err(|e| format!("Backup database could not be read: {}", e))?;
+
+    if check != "ok" {
+        return Err(format!("Backup database failed integrity check: {}", check));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::AppDir;
+
+    const PASSWORD: &str = "correct horse";
+
+    /// Fill a vault directory with a database holding `note` and a header
+    fn make_vault(dir: &Path, note: &str) {
+        std::fs::create_dir_all(dir).unwrap();
+        let conn = Connection::open(dir.join(db::DB_FILE_NAME)).unwrap();
+        conn.execute_batch("CREATE TABLE notes (body TEXT)").unwrap();
+        conn.execute("INSERT INTO notes (body) VALUES (?1)", [note]).unwrap();
+        std::fs::write(dir.join(HEADER_FILE_NAME), format!("header {}", note)).unwrap();
+    }
+
+    fn read_note(dir: &Path) -> String {
+        let conn = Connection::open(dir.join(db::DB_FILE_NAME)).unwrap();
+        conn.query_row("SELECT body FROM notes", [], |row| row.get(0)).unwrap()
+    }
+
+    fn vault_listing(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_export_and_read_roundtrip() {
+        let dir = AppDir::new();
+        let vault = dir.path().join("vault");
+        let archive = dir.path().join("backup.satstone");
+        make_vault(&vault, "kept");
+
+        let exported = export_archive(&vault, "Personal", &archive, PASSWORD).unwrap();
+        assert_eq!(exported.files, vec![db::DB_FILE_NAME, HEADER_FILE_NAME]);
+        assert!(!encrypted_db::with_suffix(&archive, "tmp").exists());
+
+        let (info, files) = read_archive(&archive, PASSWORD).unwrap();
+        assert_eq!(info.format_version, ARCHIVE_FORMAT_VERSION);
+        assert_eq!(info.vault_name, "Personal");
+        assert_eq!(info.created_at, exported.created_at);
+        assert_eq!(info.files, exported.files);
+
+        let restored = dir.path().join("restored");
+        restore_files(&restored, &files).unwrap();
+        assert_eq!(read_note(&restored), "kept");
+        assert_eq!(std::fs::read_to_string(restored.join(HEADER_FILE_NAME)).unwrap(), "header kept");
+        assert_eq!(vault_listing(&restored), vec![HEADER_FILE_NAME, db::DB_FILE_NAME]);
+    }
+
+    #[test]
+    fn test_password_is_checked() {
+        let dir = AppDir::new();
+        let vault = dir.path().join("vault");
+        let archive = dir.path().join("backup.satstone");
+        make_vault(&vault, "kept");
+
+        assert_eq!(
+            export_archive(&vault, "Personal", &archive, "short").unwrap_err(),
+            "Backup password must be at least 8 characters"
+        );
+        assert!(!archive.exists());
+
+        export_archive(&vault, "Personal", &archive, PASSWORD).unwrap();
+        assert_eq!(
+            read_archive(&archive, "wrong password").err().unwrap(),
+            "Wrong backup password, or not a backup file"
+        );
+    }
+
+    #[test]
+    fn test_tampered_archive_is_rejected() {
+        let dir = AppDir::new();
+        let vault = dir.path().join("vault");
+        let archive = dir.path().join("backup.satstone");
+        make_vault(&vault, "kept");
+        export_archive(&vault, "Personal", &archive, PASSWORD).unwrap();
+
+        // Someone with the password edits a file but not its checksum
+        let conn = open_archive(&archive, PASSWORD, true).unwrap();
+        conn.execute(
+            "UPDATE archive_files SET data = ?1 WHERE name = ?2",
+            params![b"header changed".to_vec(), HEADER_FILE_NAME],
+        )
+        .unwrap();
+        drop(conn);
+        assert_eq!(
+            read_archive(&archive, PASSWORD).err().unwrap(),
+            format!("Backup is corrupted: checksum mismatch for {}", HEADER_FILE_NAME)
+        );
+
+        // ...or drops a file along with its checksum
+        let conn = open_archive(&archive, PASSWORD, true).unwrap();
+        conn.execute("DELETE FROM archive_files WHERE name = ?1", [HEADER_FILE_NAME]).unwrap();
+        drop(conn);
+        assert_eq!(
+            read_archive(&archive, PASSWORD).err().unwrap(),
+            "Backup is corrupted: file list does not match its checksum"
+        );
+    }
+
+    #[test]
+    fn test_failed_restore_puts_files_back() {
+        let dir = AppDir::new();
+        let source = dir.path().join("source");
+        let archive = dir.path().join("backup.satstone");
+        make_vault(&source, "from backup");
+        export_archive(&source, "Personal", &archive, PASSWORD).unwrap();
+        let (_, mut files) = read_archive(&archive, PASSWORD).unwrap();
+
+        let vault = dir.path().join("vault");
+        make_vault(&vault, "current");
+        std::fs::write(vault.join(encrypted_db::DB_FILE_NAME), b"current sqlcipher").unwrap();
+        let before = vault_listing(&vault);
+
+        // The same file twice: its staged copy is gone by the second move,
+        // so the swap fails after the first files are already in place
+        let database = files.iter().find(|f| f.name == db::DB_FILE_NAME).unwrap();
+        let duplicate = ArchiveFile { name: database.name.clone(), data: database.data.clone() };
+        files.push(duplicate);
+
+        assert!(restore_files(&vault, &files).unwrap_err().starts_with("Failed to restore"));
+        assert_eq!(read_note(&vault), "current");
+        assert_eq!(std::fs::read_to_string(vault.join(HEADER_FILE_NAME)).unwrap(), "header current");
+        assert_eq!(std::fs::read(vault.join(encrypted_db::DB_FILE_NAME)).unwrap(), b"current sqlcipher");
+        assert_eq!(vault_listing(&vault), before);
+    }
+
+    #[test]
+    fn test_corrupt_database_is_refused_before_swapping() {
+        let dir = AppDir::new();
+        let vault = dir.path().join("vault");
+        make_vault(&vault, "current");
+        let before = vault_listing(&vault);
+
+        let files = vec![ArchiveFile { name: db::DB_FILE_NAME.to_string(), data: b"not a database".to_vec() }];
+
+        assert!(restore_files(&vault, &files).is_err());
+        assert_eq!(read_note(&vault), "current");
+        assert_eq!(vault_listing(&vault), before);
+    }
+}
@@ -0,0 +1,1869 @@
+//! Cost basis engine
+//!
+//! Turns BTC transactions into acquisition lots and disposals, matching each
+//! disposal against lots with the chosen method. All P&L figures and tax
+//! reports are built from this, so they agree with each other.
+//!
+//! Transactions are classified by category, the same way the P&L view always
+//! has: acquisitions are positive amounts in an acquisition category,
+//! disposals are negative amounts in a disposal category, and transfers,
+//! loans and uncategorised rows don't count. Values are in the fiat currency
+//! stored with each transaction (`fiat_value`, taken as a positive total);
+//! income without one is valued at the cached price on the day it arrived, and
+//! that value is its lot's cost basis.
+//!
+//! Network fees count too: a fee paid to acquire adds to the lot's cost, and
+//! a fee paid to dispose comes off the proceeds. The fee of a transfer between
+//! our own wallets either disposes of the fee sats at their value or writes
+//! them off with their cost basis and no gain or loss (`transfer_fee_rule`).
+//!
+//! A disposal can be assigned to specific lots (specific identification).
+//! Assignments are kept in `lot_matches` and applied first; whatever they
+//! don't cover is matched with the chosen method.
+//!
+//! Lots are pooled universally, per entity or per wallet. With separate
+//! pools, a transfer between two of our own wallets (the same txid as
+//! Transfer Out in one and Transfer In in the other) carries its lots over.
+//! A safe harbor allocation switches from one universal pool to per-wallet
+//! pools on a given date: the lots held then are assigned to wallets once
+//! and stored, so later edits to earlier history don't move them.
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::audit;
+use crate::dates;
+use crate::periods;
+
+const SATS_PER_BTC: f64 = 100_000_000.0;
+
+pub const ACQUISITION_CATEGORIES: &[&str] = &[
+    "Buy",
+    "Income",
+    "Mining",
+    "Gift",
+    "Trading Gain",
+    "Lending Interest (Income)",
+];
+
+pub const DISPOSAL_CATEGORIES: &[&str] = &[
+    "Sell",
+    "Food",
+    "Utilities",
+    "Shopping",
+    "Trading Loss",
+    "Liquidation",
+    "Gift",
+];
+
+/// Acquisitions that are income when received
+pub const INCOME_CATEGORIES: &[&str] = &["Income", "Mining", "Lending Interest (Income)"];
+
+pub const TRANSFER_IN: &str = "Transfer In";
+pub const TRANSFER_OUT: &str = "Transfer Out";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    Fifo,
+    Lifo,
+    /// Highest cost per sat first
+    Hifo,
+    /// Every open lot carries the pool's average cost
+    Average,
+}
+
+impl Method {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "fifo" => Ok(Self::Fifo),
+            "lifo" => Ok(Self::Lifo),
+            "hifo" => Ok(Self::Hifo),
+            "average" => Ok(Self::Average),
+            _ => Err("Invalid cost basis method".to_string()),
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Fifo => "FIFO",
+            Self::Lifo => "LIFO",
+            Self::Hifo => "HIFO",
+            Self::Average => "Average cost",
+        }
+    }
+}
+
+/// Which lots a disposal can draw from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Universal,
+    Entity,
+    Wallet,
+}
+
+impl Scope {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "universal" => Ok(Self::Universal),
+            "entity" => Ok(Self::Entity),
+            "wallet" => Ok(Self::Wallet),
+            _ => Err("Invalid pooling scope".to_string()),
+        }
+    }
+
+    fn pool(&self, wallet_id: i64, entity_id: i64) -> i64 {
+        match self {
+            Self::Universal => 0,
+            Self::Entity => entity_id,
+            Self::Wallet => wallet_id,
+        }
+    }
+}
+
+/// How the network fee of a transfer between our own wallets is treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferFeeRule {
+    /// The fee sats are spent: a disposal at the fee's value
+    Disposal,
+    /// The fee sats and their cost basis are written off with no gain or loss
+    NonDeductible,
+}
+
+impl TransferFeeRule {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "disposal" => Ok(Self::Disposal),
+            "non_deductible" => Ok(Self::NonDeductible),
+            _ => Err("Invalid transfer fee rule".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Acquisition,
+    Disposal,
+}
+
+/// Whether a transaction acquires or disposes of BTC, if either
+pub fn classify(category: &str, amount_sats: i64) -> Option<Kind> {
+    if amount_sats > 0 && ACQUISITION_CATEGORIES.contains(&category) {
+        Some(Kind::Acquisition)
+    } else if amount_sats < 0 && DISPOSAL_CATEGORIES.contains(&category) {
+        Some(Kind::Disposal)
+    } else {
+        None
+    }
+}
+
+fn is_transfer(category: &str) -> bool {
+    category == TRANSFER_IN || category == TRANSFER_OUT
+}
+
+/// One BTC transaction as the engine sees it
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub id: i64,
+    pub wallet_id: i64,
+    pub entity_id: i64,
+    pub txid: String,
+    pub timestamp: i64,
+    /// Signed, as stored
+    pub amount_sats: i64,
+    /// Total fiat value, always positive
+    pub fiat_value: f64,
+    pub category: String,
+    /// Network fee paid by this side of the transaction
+    pub fee_sats: i64,
+    /// Fiat value of the fee, always positive; 0 when unknown
+    pub fee_value: f64,
+}
+
+impl Entry {
+    /// Cost of an acquisition, fees included
+    pub fn cost(&self) -> f64 {
+        self.fiat_value + self.fee_value
+    }
+
+    /// Proceeds of a disposal, net of fees
+    pub fn proceeds(&self) -> f64 {
+        self.fiat_value - self.fee_value
+    }
+}
+
+/// Where a lot came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LotSource {
+    Acquisition,
+    /// Carried over from another pool by a transfer between our own wallets
+    Transfer,
+    /// Assigned to its wallet by the safe harbor allocation
+    SafeHarbor,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Lot {
+    /// The acquiring transaction
+    pub id: i64,
+    pub wallet_id: i64,
+    pub entity_id: i64,
+    pub category: String,
+    pub acquired_at: i64,
+    pub sats: i64,
+    pub cost: f64,
+    pub remaining_sats: i64,
+    pub remaining_cost: f64,
+    pub source: LotSource,
+    /// The Transfer In that carried this part of the lot over
+    pub transfer_id: Option<i64>,
+    #[serde(skip)]
+    pool: i64,
+}
+
+impl Lot {
+    fn cost_per_sat(&self) -> f64 {
+        if self.remaining_sats > 0 {
+            self.remaining_cost / self.remaining_sats as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The part of a disposal matched against one lot
+#[derive(Debug, Clone, Serialize)]
+pub struct LotMatch {
+    pub lot_id: i64,
+    pub acquired_at: i64,
+    pub sats: i64,
+    pub cost_basis: f64,
+    pub proceeds: f64,
+    pub gain: f64,
+    /// Chosen by the user rather than by the method
+    pub specific: bool,
+}
+
+/// Sats of a disposal assigned to a specific lot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct LotAssignment {
+    pub disposal_id: i64,
+    pub lot_id: i64,
+    pub sats: i64,
+}
+
+/// A lot chosen for a disposal, as passed in by the user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LotSelection {
+    pub lot_id: i64,
+    pub sats: i64,
+}
+
+/// An assignment the engine couldn't honour; its sats fall back to the method
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InvalidAssignment {
+    pub disposal_id: i64,
+    pub lot_id: i64,
+    pub reason: String,
+}
+
+/// Part of a lot assigned to a wallet by the safe harbor allocation
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct AllocatedLot {
+    pub lot_id: i64,
+    pub wallet_id: i64,
+    pub entity_id: i64,
+    pub category: String,
+    pub acquired_at: i64,
+    pub sats: i64,
+    pub cost: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SafeHarbor {
+    /// `YYYY-MM-DD`; the allocation applies from the start of this day (UTC)
+    pub date: String,
+    pub allocated_at: i64,
+    pub created_at: String,
+    pub lots: Vec<AllocatedLot>,
+    /// Differences found while making the allocation; not stored
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Disposal {
+    pub id: i64,
+    pub wallet_id: i64,
+    pub entity_id: i64,
+    pub category: String,
+    pub disposed_at: i64,
+    pub sats: i64,
+    pub proceeds: f64,
+    pub cost_basis: f64,
+    pub gain: f64,
+    pub matches: Vec<LotMatch>,
+    /// Sats disposed of beyond the lots held; matched at zero cost
+    pub unmatched_sats: i64,
+    /// The network fee of a transfer between our own wallets
+    pub transfer_fee: bool,
+}
+
+/// A transfer fee written off under [`TransferFeeRule::NonDeductible`]
+#[derive(Debug, Clone, Serialize)]
+pub struct NonDeductibleFee {
+    pub id: i64,
+    pub wallet_id: i64,
+    pub entity_id: i64,
+    pub paid_at: i64,
+    pub sats: i64,
+    pub cost_basis: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    pub acquired_sats: i64,
+    pub acquired_cost: f64,
+    pub disposed_sats: i64,
+    pub proceeds: f64,
+    pub cost_basis: f64,
+    pub realized_gain: f64,
+    pub remaining_sats: i64,
+    pub remaining_cost: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CostBasisReport {
+    pub method: Method,
+    pub scope: Scope,
+    pub currency: String,
+    pub lots: Vec<Lot>,
+    pub disposals: Vec<Disposal>,
+    pub transfer_fee_rule: TransferFeeRule,
+    pub non_deductible_fees: Vec<NonDeductibleFee>,
+    pub summary: Summary,
+    pub invalid_assignments: Vec<InvalidAssignment>,
+    pub warnings: Vec<String>,
+}
+
+/// How to run the engine
+#[derive(Debug, Clone, Copy)]
+pub struct Options<'a> {
+    pub method: Method,
+    pub scope: Scope,
+    pub assignments: &'a [LotAssignment],
+    /// Only used with per-entity or per-wallet pools
+    pub safe_harbor: Option<&'a SafeHarbor>,
+    pub transfer_fees: TransferFeeRule,
+}
+
+impl Options<'_> {
+    pub fn new(method: Method) -> Self {
+        Self {
+            method,
+            scope: Scope::Universal,
+            assignments: &[],
+            safe_harbor: None,
+            transfer_fees: TransferFeeRule::NonDeductible,
+        }
+    }
+}
+
+/// Open lots of a pool in the order a disposal should consume them
+fn consumption_order(lots: &[Lot], pool: i64, method: Method) -> Vec<usize> {
+    let mut open: Vec<usize> = (0..lots.len())
+        .filter(|&i| lots[i].pool == pool && lots[i].remaining_sats > 0)
+        .collect();
+    open.sort_by_key(|&i| (lots[i].acquired_at, lots[i].id));
+
+    match method {
+        Method::Fifo | Method::Average => {}
+        Method::Lifo => open.reverse(),
+        Method::Hifo => {
+            // Stable, so equal costs fall back to acquisition order
+            open.sort_by(|&a, &b| lots[b].cost_per_sat().total_cmp(&lots[a].cost_per_sat()));
+        }
+    }
+    open
+}
+
+/// Take `sats` out of a lot, returning the cost basis removed
+fn take_from_lot(lot: &mut Lot, sats: i64) -> f64 {
+    let cost = if sats == lot.remaining_sats {
+        lot.remaining_cost
+    } else {
+        lot.remaining_cost * sats as f64 / lot.remaining_sats as f64
+    };
+
+    lot.remaining_sats -= sats;
+    lot.remaining_cost -= cost;
+    cost
+}
+
+/// Spread a pool's cost evenly over its open lots
+fn average_lots(lots: &mut [Lot], pool: i64) {
+    let (sats, cost) = lots
+        .iter()
+        .filter(|lot| lot.pool == pool)
+        .fold((0i64, 0.0), |(sats, cost), lot| {
+            (sats + lot.remaining_sats, cost + lot.remaining_cost)
+        });
+    if sats == 0 {
+        return;
+    }
+
+    for lot in lots.iter_mut().filter(|lot| lot.pool == pool) {
+        lot.remaining_cost = cost * lot.remaining_sats as f64 / sats as f64;
+    }
+}
+
+/// Match a disposal against its pool's open lots: its assignments first, then the method
+fn dispose(
+    lots: &mut [Lot],
+    entry: &Entry,
+    pool: i64,
+    options: &Options,
+    invalid: &mut Vec<InvalidAssignment>,
+) -> Disposal {
+    let sats = entry.amount_sats.abs();
+    let proceeds = entry.proceeds();
+    let mut remaining = sats;
+    let mut matches = Vec::new();
+
+    let mut take = |lot: &mut Lot, taken: i64, specific: bool, remaining: &mut i64| {
+        let cost_basis = take_from_lot(lot, taken);
+        let share = proceeds * taken as f64 / sats as f64;
+
+        matches.push(LotMatch {
+            lot_id: lot.id,
+            acquired_at: lot.acquired_at,
+            sats: taken,
+            cost_basis,
+            proceeds: share,
+            gain: share - cost_basis,
+            specific,
+        });
+        *remaining -= taken;
+    };
+
+    for assignment in options.assignments.iter().filter(|a| a.disposal_id == entry.id) {
+        let wanted = assignment.sats.min(remaining);
+        if wanted == 0 {
+            break;
+        }
+        // A lot split up by transfers can have several parts in one pool
+        let parts: Vec<usize> = consumption_order(lots, pool, Method::Fifo)
+            .into_iter()
+            .filter(|&i| lots[i].id == assignment.lot_id)
+            .collect();
+        let held: i64 = parts.iter().map(|&i| lots[i].remaining_sats).sum();
+        let reason = if !lots.iter().any(|lot| lot.id == assignment.lot_id) {
+            "the lot wasn't acquired before the disposal"
+        } else if held == 0 && !lots.iter().any(|lot| lot.id == assignment.lot_id && lot.pool == pool) {
+            "the lot isn't held in the disposal's pool"
+        } else if held < wanted {
+            "the lot has fewer sats left than assigned"
+        } else {
+            let mut left = wanted;
+            for index in parts {
+                let taken = left.min(lots[index].remaining_sats);
+                if taken > 0 {
+                    take(&mut lots[index], taken, true, &mut remaining);
+                    left -= taken;
+                }
+            }
+            continue;
+        };
+
+        invalid.push(InvalidAssignment {
+            disposal_id: entry.id,
+            lot_id: assignment.lot_id,
+            reason: reason.to_string(),
+        });
+    }
+
+    for index in consumption_order(lots, pool, options.method) {
+        if remaining == 0 {
+            break;
+        }
+
+        let lot = &mut lots[index];
+        let taken = remaining.min(lot.remaining_sats);
+        take(lot, taken, false, &mut remaining);
+    }
+
+    let cost_basis: f64 = matches.iter().map(|m| m.cost_basis).sum();
+    Disposal {
+        id: entry.id,
+        wallet_id: entry.wallet_id,
+        entity_id: entry.entity_id,
+        category: entry.category.clone(),
+        disposed_at: entry.timestamp,
+        sats,
+        proceeds,
+        cost_basis,
+        gain: proceeds - cost_basis,
+        matches,
+        unmatched_sats: remaining,
+        transfer_fee: false,
+    }
+}
+
+/// Remove `sats` from a pool with no proceeds, returning the cost basis
+/// removed and the sats that weren't held
+fn write_off(lots: &mut [Lot], pool: i64, sats: i64, method: Method) -> (f64, i64) {
+    let mut remaining = sats;
+    let mut cost = 0.0;
+
+    for index in consumption_order(lots, pool, method) {
+        if remaining == 0 {
+            break;
+        }
+
+        let lot = &mut lots[index];
+        let taken = remaining.min(lot.remaining_sats);
+        cost += take_from_lot(lot, taken);
+        remaining -= taken;
+    }
+    (cost, remaining)
+}
+
+/// Move up to `sats` from one pool to another, keeping acquisition dates and
+/// cost; returns the sats that couldn't be moved
+fn carry_over(lots: &mut Vec<Lot>, from: i64, to: &Entry, to_pool: i64, sats: i64, method: Method) -> i64 {
+    let mut remaining = sats;
+    let mut moved = Vec::new();
+
+    for index in consumption_order(lots, from, method) {
+        if remaining == 0 {
+            break;
+        }
+
+        let lot = &mut lots[index];
+        let taken = remaining.min(lot.remaining_sats);
+        let cost = take_from_lot(lot, taken);
+        moved.push(Lot {
+            id: lot.id,
+            wallet_id: to.wallet_id,
+            entity_id: to.entity_id,
+            category: lot.category.clone(),
+            acquired_at: lot.acquired_at,
+            sats: taken,
+            cost,
+            remaining_sats: taken,
+            remaining_cost: cost,
+            source: LotSource::Transfer,
+            transfer_id: Some(to.id),
+            pool: to_pool,
+        });
+        remaining -= taken;
+    }
+
+    lots.append(&mut moved);
+    remaining
+}
+
+/// Replace the universal pool with the safe harbor allocation
+fn allocate(lots: &mut Vec<Lot>, safe_harbor: &SafeHarbor, scope: Scope, warnings: &mut Vec<String>) {
+    let pooled: i64 = lots.iter().map(|lot| lot.remaining_sats).sum();
+    let allocated: i64 = safe_harbor.lots.iter().map(|lot| lot.sats).sum();
+    if pooled != allocated {
+        warnings.push(format!(
+            "Holdings before the safe harbor date have changed since the allocation ({} sats then, {} now)",
+            allocated, pooled
+        ));
+    }
+
+    for lot in lots.iter_mut() {
+        lot.remaining_sats = 0;
+        lot.remaining_cost = 0.0;
+    }
+    for allocated in &safe_harbor.lots {
+        lots.push(Lot {
+            id: allocated.lot_id,
+            wallet_id: allocated.wallet_id,
+            entity_id: allocated.entity_id,
+            category: allocated.category.clone(),
+            acquired_at: allocated.acquired_at,
+            sats: allocated.sats,
+            cost: allocated.cost,
+            remaining_sats: allocated.sats,
+            remaining_cost: allocated.cost,
+            source: LotSource::SafeHarbor,
+            transfer_id: None,
+            pool: scope.pool(allocated.wallet_id, allocated.entity_id),
+        });
+    }
+}
+
+/// Run the engine over a set of transactions
+pub fn compute(entries: &[Entry], currency: &str, options: &Options) -> CostBasisReport {
+    let mut entries: Vec<&Entry> = entries.iter().collect();
+    entries.sort_by_key(|entry| (entry.timestamp, entry.id));
+
+    // The receiving side of each transfer between our own wallets
+    let transfers_in: HashMap<&str, &Entry> = entries
+        .iter()
+        .filter(|entry| entry.category == TRANSFER_IN && entry.amount_sats > 0)
+        .map(|entry| (entry.txid.as_str(), *entry))
+        .collect();
+
+    let mut safe_harbor = options.safe_harbor.filter(|_| options.scope != Scope::Universal);
+    let mut lots: Vec<Lot> = Vec::new();
+    let mut disposals = Vec::new();
+    let mut non_deductible_fees = Vec::new();
+    let mut invalid_assignments = Vec::new();
+    let mut warnings = Vec::new();
+
+    for entry in entries {
+        // Everything is one pool until the safe harbor date
+        let scope = match safe_harbor {
+            Some(allocation) if entry.timestamp >= allocation.allocated_at => {
+                allocate(&mut lots, allocation, options.scope, &mut warnings);
+                safe_harbor = None;
+                options.scope
+            }
+            Some(_) => Scope::Universal,
+            None => options.scope,
+        };
+        let pool = scope.pool(entry.wallet_id, entry.entity_id);
+
+        match classify(&entry.category, entry.amount_sats) {
+            Some(Kind::Acquisition) => {
+                lots.push(Lot {
+                    id: entry.id,
+                    wallet_id: entry.wallet_id,
+                    entity_id: entry.entity_id,
+                    category: entry.category.clone(),
+                    acquired_at: entry.timestamp,
+                    sats: entry.amount_sats,
+                    cost: entry.cost(),
+                    remaining_sats: entry.amount_sats,
+                    remaining_cost: entry.cost(),
+                    source: LotSource::Acquisition,
+                    transfer_id: None,
+                    pool,
+                });
+                if options.method == Method::Average {
+                    average_lots(&mut lots, pool);
+                }
+            }
+            Some(Kind::Disposal) => {
+                let disposal = dispose(&mut lots, entry, pool, options, &mut invalid_assignments);
+                if disposal.unmatched_sats > 0 {
+                    warnings.push(format!(
+                        "Transaction {} disposes of {} sats more than were held",
+                        entry.id, disposal.unmatched_sats
+                    ));
+                }
+                disposals.push(disposal);
+            }
+            None if entry.category == TRANSFER_OUT && entry.amount_sats < 0 => {
+                // The fee leaves our wallets whether or not the other side is known
+                let fee_sats = entry.fee_sats.clamp(0, entry.amount_sats.abs());
+                if fee_sats > 0 {
+                    match options.transfer_fees {
+                        TransferFeeRule::Disposal => {
+                            let fee = Entry {
+                                amount_sats: -fee_sats,
+                                fiat_value: entry.fee_value,
+                                fee_value: 0.0,
+                                ..entry.clone()
+                            };
+                            let mut disposal = dispose(&mut lots, &fee, pool, options, &mut invalid_assignments);
+                            disposal.transfer_fee = true;
+                            if disposal.unmatched_sats > 0 {
+                                warnings.push(format!(
+                                    "Transfer {} pays {} sats of fees more than were held",
+                                    entry.id, disposal.unmatched_sats
+                                ));
+                            }
+                            disposals.push(disposal);
+                        }
+                        TransferFeeRule::NonDeductible => {
+                            let (cost_basis, missing) = write_off(&mut lots, pool, fee_sats, options.method);
+                            if missing > 0 {
+                                warnings.push(format!(
+                                    "Transfer {} pays {} sats of fees more than were held",
+                                    entry.id, missing
+                                ));
+                            }
+                            non_deductible_fees.push(NonDeductibleFee {
+                                id: entry.id,
+                                wallet_id: entry.wallet_id,
+                                entity_id: entry.entity_id,
+                                paid_at: entry.timestamp,
+                                sats: fee_sats,
+                                cost_basis,
+                            });
+                        }
+                    }
+                }
+
+                let Some(received) = transfers_in.get(entry.txid.as_str()) else {
+                    continue;
+                };
+                let to_pool = scope.pool(received.wallet_id, received.entity_id);
+                if to_pool == pool {
+                    continue;
+                }
+
+                let sats = received.amount_sats.min(entry.amount_sats.abs() - fee_sats);
+                let missing = carry_over(&mut lots, pool, received, to_pool, sats, options.method);
+                if missing > 0 {
+                    warnings.push(format!(
+                        "Transfer {} moves {} sats more than its pool held",
+                        entry.id, missing
+                    ));
+                }
+                if options.method == Method::Average {
+                    average_lots(&mut lots, to_pool);
+                }
+            }
+            None => {}
+        }
+    }
+
+    // A safe harbor date after the last transaction still takes effect
+    if let Some(allocation) = safe_harbor {
+        allocate(&mut lots, allocation, options.scope, &mut warnings);
+    }
+
+    for invalid in &invalid_assignments {
+        warnings.push(format!(
+            "Lot {} can't be matched to transaction {}: {}",
+            invalid.lot_id, invalid.disposal_id, invalid.reason
+        ));
+    }
+
+    let summary = summarize(&lots, &disposals);
+    CostBasisReport {
+        method: options.method,
+        scope: options.scope,
+        currency: currency.to_string(),
+        lots,
+        disposals,
+        transfer_fee_rule: options.transfer_fees,
+        non_deductible_fees,
+        summary,
+        invalid_assignments,
+        warnings,
+    }
+}
+
+fn summarize(lots: &[Lot], disposals: &[Disposal]) -> Summary {
+    let mut summary = Summary::default();
+
+    for lot in lots {
+        if lot.source == LotSource::Acquisition {
+            summary.acquired_sats += lot.sats;
+            summary.acquired_cost += lot.cost;
+        }
+        summary.remaining_sats += lot.remaining_sats;
+        summary.remaining_cost += lot.remaining_cost;
+    }
+    for disposal in disposals {
+        summary.disposed_sats += disposal.sats;
+        summary.proceeds += disposal.proceeds;
+        summary.cost_basis += disposal.cost_basis;
+        summary.realized_gain += disposal.gain;
+    }
+    summary
+}
+
+/// Load live BTC transactions valued in `currency`
+///
+/// Rows the engine would use but can't value (no timestamp, no fiat value,
+/// or a value in another currency) are left out with a warning. Transfers
+/// only need a date.
+pub async fn load_entries(conn: &mut SqliteConnection, currency: &str) -> Result<(Vec<Entry>, Vec<String>), String> {
+    #[derive(sqlx::FromRow)]
+    struct Row {
+        id: i64,
+        wallet_id: i64,
+        entity_id: i64,
+        txid: String,
+        timestamp: Option<i64>,
+        amount_sats: i64,
+        fiat_value: Option<f64>,
+        fiat_currency: Option<String>,
+        fee_sats: i64,
+        fee_fiat: Option<f64>,
+        fee_fiat_currency: Option<String>,
+        category: String,
+    }
+
+    let rows = sqlx::query_as::<_, Row>(
+        r#"
+        SELECT t.id, t.wallet_id, w.entity_id, t.txid, t.timestamp, t.amount_sats, t.fiat_value, t.fiat_currency,
+               t.fee_sats, t.fee_fiat, t.fee_fiat_currency, COALESCE(c.name, 'Uncategorized') as category
+        FROM transactions t
+        JOIN wallets w ON t.wallet_id = w.id
+        LEFT JOIN categories c ON t.category_id = c.id
+        WHERE t.trash_id IS NULL AND COALESCE(t.currency, 'BTC') = 'BTC'
+        ORDER BY t.timestamp, t.id
+        "#,
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut entries = Vec::new();
+    let mut warnings = Vec::new();
+
+    for row in rows {
+        let transfer = is_transfer(&row.category);
+        if classify(&row.category, row.amount_sats).is_none() && !transfer {
+            continue;
+        }
+
+        let Some(timestamp) = row.timestamp else {
+            warnings.push(format!("Transaction {} has no date and was left out", row.id));
+            continue;
+        };
+        let cached = match row.fiat_value {
+            None if INCOME_CATEGORIES.contains(&row.category.as_str()) => {
+                cached_value(conn, currency, timestamp, row.amount_sats).await?
+            }
+            _ => None,
+        };
+        let fiat_value = match row.fiat_value.or(cached) {
+            Some(fiat_value) => fiat_value,
+            None if transfer => 0.0,
+            None => {
+                warnings.push(format!("Transaction {} has no fiat value and was left out", row.id));
+                continue;
+            }
+        };
+        if let Some(fiat_currency) = row.fiat_currency.as_ref().filter(|_| !transfer) {
+            if !fiat_currency.eq_ignore_ascii_case(currency) {
+                warnings.push(format!(
+                    "Transaction {} is valued in {}, not {}, and was left out",
+                    row.id, fiat_currency, currency
+                ));
+                continue;
+            }
+        }
+        let in_currency = |fiat_currency: &Option<String>| {
+            fiat_currency
+                .as_ref()
+                .is_none_or(|fiat_currency| fiat_currency.eq_ignore_ascii_case(currency))
+        };
+
+        // On-chain fees of sends are part of the amount; a receive's fee was
+        // paid by the sender, so acquisitions only count fees given in fiat
+        let fee_sats = if row.amount_sats < 0 { row.fee_sats.max(0) } else { 0 };
+        let fee_value = match row.fee_fiat {
+            Some(fee_fiat) if in_currency(&row.fee_fiat_currency) => fee_fiat.abs(),
+            _ if fee_sats == 0 => 0.0,
+            _ => match row.fiat_value.filter(|_| in_currency(&row.fiat_currency)) {
+                Some(value) => value.abs() * fee_sats.min(row.amount_sats.abs()) as f64 / row.amount_sats.abs() as f64,
+                None => cached_value(conn, currency, timestamp, fee_sats).await?.unwrap_or(0.0),
+            },
+        };
+
+        entries.push(Entry {
+            id: row.id,
+            wallet_id: row.wallet_id,
+            entity_id: row.entity_id,
+            txid: row.txid,
+            timestamp,
+            amount_sats: row.amount_sats,
+            fiat_value: fiat_value.abs(),
+            category: row.category,
+            fee_sats,
+            fee_value,
+        });
+    }
+
+    Ok((entries, warnings))
+}
+
+/// Value of `sats` at the cached daily price for the (UTC) date of a unix time
+///
+/// Income is valued when it's received, so receipts without a stored fiat
+/// value fall back to the price fetched for that day. Prices are cached under
+/// the UTC day as `DD-MM-YYYY`, the date the price history is fetched by.
+pub async fn cached_value(
+    conn: &mut SqliteConnection,
+    currency: &str,
+    timestamp: i64,
+    sats: i64,
+) -> Result<Option<f64>, String> {
+    let (year, month, day) = dates::civil_date(timestamp);
+    let price = sqlx::query_scalar::<_, f64>("SELECT price FROM price_cache WHERE currency = ? AND date = ?")
+        .bind(currency.to_uppercase())
+        .bind(format!("{:02}-{:02}-{}", day, month, year))
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(price.map(|price| price * sats.abs() as f64 / SATS_PER_BTC))
+}
+
+/// A setting's value, if set
+pub async fn setting(conn: &mut SqliteConnection, key: &str) -> Result<Option<String>, String> {
+    sqlx::query_scalar::<_, String>("SELECT value FROM settings WHERE key = ?")
+        .bind(key)
+        .fetch_optional(conn)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// The configured reporting currency
+pub async fn default_currency(conn: &mut SqliteConnection) -> Result<String, String> {
+    Ok(setting(conn, "fiat_currency")
+        .await?
+        .unwrap_or_else(|| "EUR".to_string()))
+}
+
+/// Everything the engine needs from the database besides the transactions
+pub struct Config {
+    pub method: Method,
+    pub scope: Scope,
+    pub currency: String,
+    pub assignments: Vec<LotAssignment>,
+    pub safe_harbor: Option<SafeHarbor>,
+    pub transfer_fees: TransferFeeRule,
+}
+
+impl Config {
+    /// The stored settings, lot matches and safe harbor allocation
+    pub async fn load(conn: &mut SqliteConnection) -> Result<Self, String> {
+        let method = setting(conn, "cost_basis_method").await?;
+        let scope = setting(conn, "cost_basis_scope").await?;
+        let transfer_fees = setting(conn, "transfer_fee_rule").await?;
+
+        Ok(Self {
+            method: Method::parse(method.as_deref().unwrap_or("average"))?,
+            scope: Scope::parse(scope.as_deref().unwrap_or("universal"))?,
+            currency: default_currency(conn).await?,
+            assignments: lot_matches(conn, None).await?,
+            safe_harbor: safe_harbor(conn).await?,
+            transfer_fees: TransferFeeRule::parse(transfer_fees.as_deref().unwrap_or("non_deductible"))?,
+        })
+    }
+
+    pub fn options(&self) -> Options<'_> {
+        Options {
+            method: self.method,
+            scope: self.scope,
+            assignments: &self.assignments,
+            safe_harbor: self.safe_harbor.as_ref(),
+            transfer_fees: self.transfer_fees,
+        }
+    }
+}
+
+/// Stored lot assignments, optionally for one disposal
+pub async fn lot_matches(conn: &mut SqliteConnection, disposal_id: Option<i64>) -> Result<Vec<LotAssignment>, String> {
+    sqlx::query_as::<_, LotAssignment>(
+        "SELECT disposal_id, lot_id, sats FROM lot_matches WHERE ?1 IS NULL OR disposal_id = ?1 ORDER BY id",
+    )
+    .bind(disposal_id)
+    .fetch_all(conn)
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Assignments the engine can't honour with everything as stored
+async fn invalid_assignments(conn: &mut SqliteConnection) -> Result<Vec<InvalidAssignment>, String> {
+    let config = Config::load(conn).await?;
+    let (entries, _) = load_entries(conn, &config.currency).await?;
+
+    Ok(compute(&entries, &config.currency, &config.options()).invalid_assignments)
+}
+
+/// Replace a disposal's lot assignments; an empty list clears them
+///
+/// Checked against the whole history with the configured method and pools,
+/// so a choice that would take more from a lot than is left after earlier
+/// disposals, or break another disposal's choice, is refused.
+pub async fn set_lot_matches(pool: &SqlitePool, disposal_id: i64, selections: &[LotSelection]) -> Result<(), String> {
+    let mut lot_ids = HashSet::new();
+    for selection in selections {
+        if selection.sats <= 0 {
+            return Err("Matched amounts must be positive".to_string());
+        }
+        if !lot_ids.insert(selection.lot_id) {
+            return Err("Each lot can only be chosen once".to_string());
+        }
+    }
+
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    periods::ensure_transaction_open(&mut tx, disposal_id).await?;
+
+    let (amount_sats, category) = transaction_kind(&mut tx, disposal_id).await?;
+    if classify(&category, amount_sats) != Some(Kind::Disposal) {
+        return Err("Only disposals can be matched to lots".to_string());
+    }
+    if selections.iter().map(|s| s.sats).sum::<i64>() > amount_sats.abs() {
+        return Err("Matched amounts exceed the disposal".to_string());
+    }
+    for selection in selections {
+        let (lot_sats, lot_category) = transaction_kind(&mut tx, selection.lot_id).await?;
+        if classify(&lot_category, lot_sats) != Some(Kind::Acquisition) {
+            return Err(format!("Transaction {} is not an acquisition", selection.lot_id));
+        }
+    }
+
+    let before = invalid_assignments(&mut tx).await?;
+    let old = lot_matches(&mut tx, Some(disposal_id)).await?;
+
+    sqlx::query("DELETE FROM lot_matches WHERE disposal_id = ?")
+        .bind(disposal_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    for selection in selections {
+        sqlx::query("INSERT INTO lot_matches (disposal_id, lot_id, sats) VALUES (?, ?, ?)")
+            .bind(disposal_id)
+            .bind(selection.lot_id)
+            .bind(selection.sats)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let after = invalid_assignments(&mut tx).await?;
+    if let Some(invalid) = after.iter().find(|invalid| !before.contains(invalid)) {
+        return Err(format!(
+            "Lot {} can't be matched to transaction {}: {}",
+            invalid.lot_id, invalid.disposal_id, invalid.reason
+        ));
+    }
+
+    audit::record(
+        &mut tx,
+        "set_lot_matches",
+        "lot_matches",
+        Some(disposal_id),
+        Some(json!(old)),
+        Some(json!(selections)),
+    )
+    .await?;
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+/// Amount and category of a live transaction
+async fn transaction_kind(conn: &mut SqliteConnection, id: i64) -> Result<(i64, String), String> {
+    sqlx::query_as::<_, (i64, String)>(
+        r#"
+        SELECT t.amount_sats, COALESCE(c.name, 'Uncategorized')
+        FROM transactions t
+        LEFT JOIN categories c ON t.category_id = c.id
+        WHERE t.id = ? AND t.trash_id IS NULL AND COALESCE(t.currency, 'BTC') = 'BTC'
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| format!("Transaction {} not found", id))
+}
+
+/// The stored safe harbor allocation, if one was made
+pub async fn safe_harbor(conn: &mut SqliteConnection) -> Result<Option<SafeHarbor>, String> {
+    let Some((date, allocated_at, created_at)) = sqlx::query_as::<_, (String, i64, String)>(
+        "SELECT date, allocated_at, CAST(created_at AS TEXT) FROM safe_harbor WHERE id = 1",
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?
+    else {
+        return Ok(None);
+    };
+
+    let lots = sqlx::query_as::<_, AllocatedLot>(
+        r#"
+        SELECT l.lot_id, l.wallet_id, w.entity_id, l.category, l.acquired_at, l.sats, l.cost
+        FROM safe_harbor_lots l
+        JOIN wallets w ON l.wallet_id = w.id
+        ORDER BY l.id
+        "#,
+    )
+    .fetch_all(conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(Some(SafeHarbor {
+        date,
+        allocated_at,
+        created_at,
+        lots,
+        warnings: Vec::new(),
+    }))
+}
+
+/// Allocate the universal pool to wallets as of the start of `date`
+///
+/// The lots held just before then (with the configured method and lot
+/// matches, pooled universally) are handed out oldest first to what each
+/// wallet held in lots at that moment, in wallet order. Holdings are those of
+/// per-wallet pools, so loans, uncategorised rows and sends to untracked
+/// wallets don't count. Lots left over stay with the wallet that acquired
+/// them, and any difference is returned as a warning. This is done once; the
+/// result is stored and only applies with per-entity or per-wallet pools.
+pub async fn create_safe_harbor(pool: &SqlitePool, date: &str) -> Result<SafeHarbor, String> {
+    let allocated_at = dates::date_to_unix(date)?;
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    if safe_harbor(&mut tx).await?.is_some() {
+        return Err("A safe harbor allocation has already been made".to_string());
+    }
+    periods::ensure_none_closed(&mut tx, "t.timestamp >= ?", Some(allocated_at)).await?;
+
+    let config = Config::load(&mut tx).await?;
+    let (mut entries, _) = load_entries(&mut tx, &config.currency).await?;
+    entries.retain(|entry| entry.timestamp < allocated_at);
+
+    let options = Options {
+        scope: Scope::Universal,
+        safe_harbor: None,
+        ..config.options()
+    };
+    let mut lots: Vec<Lot> = compute(&entries, &config.currency, &options)
+        .lots
+        .into_iter()
+        .filter(|lot| lot.remaining_sats > 0)
+        .collect();
+    lots.sort_by_key(|lot| (lot.acquired_at, lot.id));
+
+    // wallet -> (entity, sats held in lots)
+    let mut holdings: BTreeMap<i64, (i64, i64)> = BTreeMap::new();
+    let by_wallet = compute(
+        &entries,
+        &config.currency,
+        &Options {
+            scope: Scope::Wallet,
+            ..options
+        },
+    );
+    for lot in by_wallet.lots.iter().filter(|lot| lot.remaining_sats > 0) {
+        holdings.entry(lot.wallet_id).or_insert((lot.entity_id, 0)).1 += lot.remaining_sats;
+    }
+
+    let mut allocated = Vec::new();
+    let mut warnings = Vec::new();
+    let mut lots = lots.iter_mut().peekable();
+    for (wallet_id, (entity_id, held)) in holdings {
+        let mut needed = held;
+        while needed > 0 {
+            let Some(lot) = lots.peek_mut() else {
+                break;
+            };
+
+            let sats = needed.min(lot.remaining_sats);
+            let cost = take_from_lot(lot, sats);
+            allocated.push(AllocatedLot {
+                lot_id: lot.id,
+                wallet_id,
+                entity_id,
+                category: lot.category.clone(),
+                acquired_at: lot.acquired_at,
+                sats,
+                cost,
+            });
+            needed -= sats;
+
+            if lot.remaining_sats == 0 {
+                lots.next();
+            }
+        }
+
+        if needed > 0 {
+            warnings.push(format!(
+                "Wallet {} held {} sats more than the pooled lots had left for it",
+                wallet_id, needed
+            ));
+        }
+    }
+
+    let mut leftover = 0;
+    for lot in lots.filter(|lot| lot.remaining_sats > 0) {
+        leftover += lot.remaining_sats;
+        allocated.push(AllocatedLot {
+            lot_id: lot.id,
+            wallet_id: lot.wallet_id,
+            entity_id: lot.entity_id,
+            category: lot.category.clone(),
+            acquired_at: lot.acquired_at,
+            sats: lot.remaining_sats,
+            cost: lot.remaining_cost,
+        });
+    }
+    if leftover > 0 {
+        warnings.push(format!(
+            "{} sats of pooled lots weren't held by any wallet and stay with the wallets that acquired them",
+            leftover
+        ));
+    }
+
+    sqlx::query("INSERT INTO safe_harbor (id, date, allocated_at) VALUES (1, ?, ?)")
+        .bind(date)
+        .bind(allocated_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    for lot in &allocated {
+        sqlx::query(
+            "INSERT INTO safe_harbor_lots (lot_id, wallet_id, category, acquired_at, sats, cost) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(lot.lot_id)
+        .bind(lot.wallet_id)
+        .bind(&lot.category)
+        .bind(lot.acquired_at)
+        .bind(lot.sats)
+        .bind(lot.cost)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    }
+
+    audit::record(
+        &mut tx,
+        "create_safe_harbor",
+        "safe_harbor",
+        Some(1),
+        None,
+        Some(json!({
+            "date": date,
+            "lots": allocated.len(),
+            "sats": allocated.iter().map(|lot| lot.sats).sum::<i64>(),
+        })),
+    )
+    .await?;
+
+    let mut safe_harbor = safe_harbor(&mut tx)
+        .await?
+        .ok_or("Failed to save safe harbor allocation")?;
+    safe_harbor.warnings = warnings;
+    tx.commit().await.map_err(|e| e.to_string())?;
+
+    Ok(safe_harbor)
+}
+
+/// Remove the safe harbor allocation, unless periods after it are closed
+pub async fn clear_safe_harbor(pool: &SqlitePool) -> Result<(), String> {
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    let Some(existing) = safe_harbor(&mut tx).await? else {
+        return Err("No safe harbor allocation to remove".to_string());
+    };
+    periods::ensure_none_closed(&mut tx, "t.timestamp >= ?", Some(existing.allocated_at)).await?;
+
+    for statement in ["DELETE FROM safe_harbor_lots", "DELETE FROM safe_harbor"] {
+        sqlx::query(statement)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    audit::record(
+        &mut tx,
+        "clear_safe_harbor",
+        "safe_harbor",
+        Some(1),
+        Some(json!({ "date": existing.date, "lots": existing.lots.len() })),
+        None,
+    )
+    .await?;
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+/// Cost basis report with the stored configuration, unless another method or
+/// scope is given, optionally narrowed to one entity or wallet
+///
+/// Lots are always matched over the whole history; the entity and wallet
+/// only select which lots and disposals are shown.
+pub async fn report(
+    pool: &SqlitePool,
+    method: Option<&str>,
+    scope: Option<&str>,
+    entity_id: Option<i64>,
+    wallet_id: Option<i64>,
+) -> Result<CostBasisReport, String> {
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    load_report(&mut conn, method, scope, entity_id, wallet_id).await
+}
+
+/// [`report`] on a given connection, e.g. inside a read transaction
+pub async fn load_report(
+    conn: &mut SqliteConnection,
+    method: Option<&str>,
+    scope: Option<&str>,
+    entity_id: Option<i64>,
+    wallet_id: Option<i64>,
+) -> Result<CostBasisReport, String> {
+    let mut config = Config::load(conn).await?;
+    if let Some(method) = method {
+        config.method = Method::parse(method)?;
+    }
+    if let Some(scope) = scope {
+        config.scope = Scope::parse(scope)?;
+    }
+
+    let (entries, mut warnings) = load_entries(conn, &config.currency).await?;
+    let mut report = compute(&entries, &config.currency, &config.options());
+
+    if entity_id.is_some() || wallet_id.is_some() {
+        let shown = |wallet: i64, entity: i64| {
+            entity_id.is_none_or(|id| id == entity) && wallet_id.is_none_or(|id| id == wallet)
+        };
+        report.lots.retain(|lot| shown(lot.wallet_id, lot.entity_id));
+        report
+            .disposals
+            .retain(|disposal| shown(disposal.wallet_id, disposal.entity_id));
+        report
+            .non_deductible_fees
+            .retain(|fee| shown(fee.wallet_id, fee.entity_id));
+        report.summary = summarize(&report.lots, &report.disposals);
+    }
+
+    warnings.append(&mut report.warnings);
+    report.warnings = warnings;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i64, timestamp: i64, amount_sats: i64, fiat_value: f64, category: &str) -> Entry {
+        Entry {
+            id,
+            wallet_id: 1,
+            entity_id: 1,
+            txid: format!("tx{}", id),
+            timestamp,
+            amount_sats,
+            fiat_value,
+            category: category.to_string(),
+            fee_sats: 0,
+            fee_value: 0.0,
+        }
+    }
+
+    fn in_wallet(mut entry: Entry, wallet_id: i64, txid: &str) -> Entry {
+        entry.wallet_id = wallet_id;
+        entry.txid = txid.to_string();
+        entry
+    }
+
+    /// Buy 1 BTC at 10k, 1 BTC at 30k, 1 BTC at 20k, then sell 1.5 BTC for 60k
+    fn sample() -> Vec<Entry> {
+        vec![
+            entry(1, 100, 100_000_000, 10_000.0, "Buy"),
+            entry(2, 200, 100_000_000, 30_000.0, "Buy"),
+            entry(3, 300, 100_000_000, 20_000.0, "Buy"),
+            entry(4, 400, -150_000_000, 60_000.0, "Sell"),
+        ]
+    }
+
+    fn run(entries: &[Entry], method: Method) -> CostBasisReport {
+        compute(entries, "USD", &Options::new(method))
+    }
+
+    fn matched(report: &CostBasisReport) -> Vec<(i64, i64)> {
+        report.disposals[0].matches.iter().map(|m| (m.lot_id, m.sats)).collect()
+    }
+
+    #[test]
+    fn test_fifo() {
+        let report = run(&sample(), Method::Fifo);
+        assert_eq!(matched(&report), vec![(1, 100_000_000), (2, 50_000_000)]);
+        assert_eq!(report.summary.cost_basis, 25_000.0);
+        assert_eq!(report.summary.realized_gain, 35_000.0);
+        assert_eq!(report.summary.remaining_sats, 150_000_000);
+        assert_eq!(report.summary.remaining_cost, 35_000.0);
+    }
+
+    #[test]
+    fn test_lifo() {
+        let report = run(&sample(), Method::Lifo);
+        assert_eq!(matched(&report), vec![(3, 100_000_000), (2, 50_000_000)]);
+        assert_eq!(report.summary.cost_basis, 35_000.0);
+    }
+
+    #[test]
+    fn test_hifo() {
+        let report = run(&sample(), Method::Hifo);
+        assert_eq!(matched(&report), vec![(2, 100_000_000), (3, 50_000_000)]);
+        assert_eq!(report.summary.cost_basis, 40_000.0);
+        assert_eq!(report.summary.realized_gain, 20_000.0);
+    }
+
+    #[test]
+    fn test_average_uses_running_pool() {
+        let report = run(&sample(), Method::Average);
+        assert!((report.summary.cost_basis - 30_000.0).abs() < 1e-6);
+        assert!((report.summary.remaining_cost - 30_000.0).abs() < 1e-6);
+
+        // Later buys don't change the basis of an earlier sale
+        let mut entries = sample();
+        entries.push(entry(5, 500, 100_000_000, 90_000.0, "Buy"));
+        let later = run(&entries, Method::Average);
+        assert!((later.disposals[0].cost_basis - 30_000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_match_proceeds_add_up() {
+        let report = run(&sample(), Method::Fifo);
+        let disposal = &report.disposals[0];
+        let proceeds: f64 = disposal.matches.iter().map(|m| m.proceeds).sum();
+        assert!((proceeds - disposal.proceeds).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_disposal_beyond_holdings() {
+        let entries = vec![
+            entry(1, 100, 50_000_000, 5_000.0, "Buy"),
+            entry(2, 200, -80_000_000, 16_000.0, "Sell"),
+        ];
+        let report = run(&entries, Method::Fifo);
+        assert_eq!(report.disposals[0].unmatched_sats, 30_000_000);
+        assert_eq!(report.disposals[0].cost_basis, 5_000.0);
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_specific_identification() {
+        let assignments = [LotAssignment {
+            disposal_id: 4,
+            lot_id: 3,
+            sats: 100_000_000,
+        }];
+        let options = Options {
+            assignments: &assignments,
+            ..Options::new(Method::Fifo)
+        };
+        let report = compute(&sample(), "USD", &options);
+
+        // The chosen lot first, then FIFO for the rest
+        assert_eq!(matched(&report), vec![(3, 100_000_000), (1, 50_000_000)]);
+        assert!(report.disposals[0].matches[0].specific);
+        assert!(!report.disposals[0].matches[1].specific);
+        assert_eq!(report.summary.cost_basis, 25_000.0);
+        assert!(report.invalid_assignments.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_assignment_falls_back() {
+        let mut entries = sample();
+        entries.push(entry(5, 500, -100_000_000, 50_000.0, "Sell"));
+
+        // Lot 1 is used up by FIFO before disposal 5; lot 6 doesn't exist
+        let assignments = [
+            LotAssignment {
+                disposal_id: 5,
+                lot_id: 1,
+                sats: 10_000_000,
+            },
+            LotAssignment {
+                disposal_id: 5,
+                lot_id: 6,
+                sats: 10_000_000,
+            },
+        ];
+        let options = Options {
+            assignments: &assignments,
+            ..Options::new(Method::Fifo)
+        };
+        let report = compute(&entries, "USD", &options);
+
+        assert_eq!(report.invalid_assignments.len(), 2);
+        assert_eq!(report.disposals[1].matches[0].lot_id, 2);
+        assert_eq!(report.disposals[1].unmatched_sats, 0);
+    }
+
+    /// Wallet 1 buys at 10k, wallet 2 buys at 30k, wallet 2 sells
+    fn two_wallets() -> Vec<Entry> {
+        vec![
+            entry(1, 100, 100_000_000, 10_000.0, "Buy"),
+            in_wallet(entry(2, 200, 100_000_000, 30_000.0, "Buy"), 2, "tx2"),
+            in_wallet(entry(3, 300, -50_000_000, 25_000.0, "Sell"), 2, "tx3"),
+        ]
+    }
+
+    #[test]
+    fn test_pooling_scope() {
+        let universal = run(&two_wallets(), Method::Fifo);
+        assert_eq!(matched(&universal), vec![(1, 50_000_000)]);
+
+        let options = Options {
+            scope: Scope::Wallet,
+            ..Options::new(Method::Fifo)
+        };
+        let per_wallet = compute(&two_wallets(), "USD", &options);
+        assert_eq!(matched(&per_wallet), vec![(2, 50_000_000)]);
+        assert_eq!(per_wallet.summary.cost_basis, 15_000.0);
+
+        // Both wallets belong to the same entity
+        let options = Options {
+            scope: Scope::Entity,
+            ..Options::new(Method::Fifo)
+        };
+        assert_eq!(
+            matched(&compute(&two_wallets(), "USD", &options)),
+            vec![(1, 50_000_000)]
+        );
+    }
+
+    #[test]
+    fn test_transfer_carries_lots() {
+        let entries = vec![
+            entry(1, 100, 100_000_000, 10_000.0, "Buy"),
+            entry(2, 150, -40_000_000, 0.0, TRANSFER_OUT),
+            in_wallet(entry(3, 150, 40_000_000, 0.0, TRANSFER_IN), 2, "tx2"),
+            in_wallet(entry(4, 300, -40_000_000, 20_000.0, "Sell"), 2, "tx4"),
+        ];
+        let options = Options {
+            scope: Scope::Wallet,
+            ..Options::new(Method::Fifo)
+        };
+        let report = compute(&entries, "USD", &options);
+
+        assert_eq!(report.disposals[0].matches[0].lot_id, 1);
+        assert_eq!(report.disposals[0].matches[0].acquired_at, 100);
+        assert_eq!(report.disposals[0].cost_basis, 4_000.0);
+        assert_eq!(report.summary.acquired_sats, 100_000_000);
+        assert_eq!(report.summary.remaining_sats, 60_000_000);
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_fees() {
+        let mut buy = entry(1, 100, 100_000_000, 10_000.0, "Buy");
+        buy.fee_value = 100.0;
+        let mut sell = entry(2, 200, -50_000_000, 8_000.0, "Sell");
+        sell.fee_sats = 2_000;
+        sell.fee_value = 50.0;
+        let report = run(&[buy, sell], Method::Fifo);
+
+        assert_eq!(report.lots[0].cost, 10_100.0);
+        assert_eq!(report.disposals[0].proceeds, 7_950.0);
+        assert_eq!(report.disposals[0].cost_basis, 5_050.0);
+        assert_eq!(report.disposals[0].gain, 2_900.0);
+    }
+
+    #[test]
+    fn test_transfer_fee_rules() {
+        let mut transfer = entry(2, 150, -40_010_000, 0.0, TRANSFER_OUT);
+        transfer.fee_sats = 10_000;
+        transfer.fee_value = 5.0;
+        let entries = vec![
+            entry(1, 100, 100_000_000, 10_000.0, "Buy"),
+            transfer,
+            in_wallet(entry(3, 150, 40_000_000, 0.0, TRANSFER_IN), 2, "tx2"),
+            in_wallet(entry(4, 300, -40_000_000, 20_000.0, "Sell"), 2, "tx4"),
+        ];
+
+        // Written off: the fee's basis is gone, with no gain or loss
+        for scope in [Scope::Universal, Scope::Wallet] {
+            let options = Options {
+                scope,
+                ..Options::new(Method::Fifo)
+            };
+            let report = compute(&entries, "USD", &options);
+            assert_eq!(report.disposals.len(), 1);
+            assert_eq!(report.non_deductible_fees[0].sats, 10_000);
+            assert_eq!(report.non_deductible_fees[0].cost_basis, 1.0);
+            assert_eq!(report.summary.remaining_sats, 59_990_000);
+            assert!(report.warnings.is_empty());
+        }
+
+        // Disposed of at the fee's value
+        let options = Options {
+            scope: Scope::Wallet,
+            transfer_fees: TransferFeeRule::Disposal,
+            ..Options::new(Method::Fifo)
+        };
+        let report = compute(&entries, "USD", &options);
+        assert!(report.non_deductible_fees.is_empty());
+        assert_eq!(report.disposals.len(), 2);
+        let fee = &report.disposals[0];
+        assert!(fee.transfer_fee);
+        assert_eq!((fee.id, fee.sats, fee.proceeds, fee.cost_basis), (2, 10_000, 5.0, 1.0));
+        assert_eq!(report.disposals[1].cost_basis, 4_000.0);
+        assert_eq!(report.summary.remaining_sats, 59_990_000);
+    }
+
+    #[test]
+    fn test_safe_harbor_allocation() {
+        // Both lots were allocated to wallet 2 on the safe harbor date
+        let safe_harbor = SafeHarbor {
+            date: "1970-01-01".to_string(),
+            allocated_at: 250,
+            created_at: String::new(),
+            lots: vec![AllocatedLot {
+                lot_id: 1,
+                wallet_id: 2,
+                entity_id: 1,
+                category: "Buy".to_string(),
+                acquired_at: 100,
+                sats: 100_000_000,
+                cost: 10_000.0,
+            }],
+            warnings: Vec::new(),
+        };
+        let entries = vec![
+            entry(1, 100, 100_000_000, 10_000.0, "Buy"),
+            in_wallet(entry(2, 300, -50_000_000, 25_000.0, "Sell"), 2, "tx2"),
+        ];
+        let options = Options {
+            scope: Scope::Wallet,
+            safe_harbor: Some(&safe_harbor),
+            ..Options::new(Method::Fifo)
+        };
+        let report = compute(&entries, "USD", &options);
+
+        assert_eq!(report.disposals[0].cost_basis, 5_000.0);
+        assert_eq!(report.disposals[0].unmatched_sats, 0);
+        assert_eq!(report.summary.remaining_sats, 50_000_000);
+        assert!(report.warnings.is_empty());
+
+        // Without it, wallet 2 has nothing to sell from
+        let options = Options {
+            scope: Scope::Wallet,
+            ..Options::new(Method::Fifo)
+        };
+        assert_eq!(
+            compute(&entries, "USD", &options).disposals[0].unmatched_sats,
+            50_000_000
+        );
+    }
+
+    /// FIFO: two 1 BTC buys, a 1 BTC sale (3) that uses up lot 1, then a 0.5 BTC sale (4)
+    async fn sales() -> SqlitePool {
+        let pool = crate::test_support::memory_pool().await;
+        for statement in [
+            "UPDATE settings SET value = 'fifo' WHERE key = 'cost_basis_method'",
+            "INSERT INTO wallets (id, name, xpub) VALUES (1, 'Savings', 'xpub1')",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, timestamp, fiat_value, fiat_currency, category_id) VALUES
+                (1, 1, 'a', 100000000, 1672531200, 10000, 'EUR', (SELECT id FROM categories WHERE name = 'Buy')),
+                (2, 1, 'b', 100000000, 1680307200, 30000, 'EUR', (SELECT id FROM categories WHERE name = 'Buy')),
+                (3, 1, 'c', -100000000, 1688169600, 40000, 'EUR', (SELECT id FROM categories WHERE name = 'Sell')),
+                (4, 1, 'd', -50000000, 1695000000, 25000, 'EUR', (SELECT id FROM categories WHERE name = 'Sell'))",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    async fn stored_matches(pool: &SqlitePool, disposal_id: i64) -> Vec<(i64, i64)> {
+        let mut conn = pool.acquire().await.unwrap();
+        lot_matches(&mut conn, Some(disposal_id))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|assignment| (assignment.lot_id, assignment.sats))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_set_lot_matches_refuses_used_up_lot() {
+        let pool = sales().await;
+
+        let selection = [LotSelection {
+            lot_id: 1,
+            sats: 50_000_000,
+        }];
+        assert_eq!(
+            set_lot_matches(&pool, 4, &selection).await.unwrap_err(),
+            "Lot 1 can't be matched to transaction 4: the lot has fewer sats left than assigned"
+        );
+        assert!(stored_matches(&pool, 4).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_lot_matches_refuses_breaking_another_disposal() {
+        let pool = sales().await;
+
+        let half_of_lot_2 = [LotSelection {
+            lot_id: 2,
+            sats: 50_000_000,
+        }];
+        set_lot_matches(&pool, 4, &half_of_lot_2).await.unwrap();
+
+        // Sale 3 taking all of lot 2 would leave nothing for sale 4's choice
+        let all_of_lot_2 = [LotSelection {
+            lot_id: 2,
+            sats: 100_000_000,
+        }];
+        assert_eq!(
+            set_lot_matches(&pool, 3, &all_of_lot_2).await.unwrap_err(),
+            "Lot 2 can't be matched to transaction 4: the lot has fewer sats left than assigned"
+        );
+        assert!(stored_matches(&pool, 3).await.is_empty());
+        assert_eq!(stored_matches(&pool, 4).await, vec![(2, 50_000_000)]);
+    }
+
+    #[tokio::test]
+    async fn test_set_lot_matches_empty_selection_clears() {
+        let pool = sales().await;
+
+        let selection = [LotSelection {
+            lot_id: 2,
+            sats: 50_000_000,
+        }];
+        set_lot_matches(&pool, 4, &selection).await.unwrap();
+        set_lot_matches(&pool, 4, &[]).await.unwrap();
+        assert!(stored_matches(&pool, 4).await.is_empty());
+
+        let (old_value, new_value): (String, String) = sqlx::query_as(
+            "SELECT old_value, new_value FROM audit_log WHERE command = 'set_lot_matches' ORDER BY id DESC LIMIT 1",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(old_value, r#"[{"disposal_id":4,"lot_id":2,"sats":50000000}]"#);
+        assert_eq!(new_value, "[]");
+    }
+
+    /// 2023 in wallets 1 and 2, FIFO per wallet: two buys in wallet 1, a
+    /// transfer to wallet 2, a loan received there and a send to a wallet
+    /// that isn't tracked; then a sale in wallet 1 in 2024
+    async fn books() -> SqlitePool {
+        let pool = crate::test_support::memory_pool().await;
+        for statement in [
+            "UPDATE settings SET value = 'fifo' WHERE key = 'cost_basis_method'",
+            "UPDATE settings SET value = 'wallet' WHERE key = 'cost_basis_scope'",
+            "INSERT INTO wallets (id, name, xpub) VALUES (1, 'Savings', 'xpub1'), (2, 'Cold', 'xpub2')",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, timestamp, fiat_value, fiat_currency, category_id) VALUES
+                (1, 1, 'a', 100000000, 1672531200, 10000, 'EUR', (SELECT id FROM categories WHERE name = 'Buy')),
+                (2, 1, 'b', 50000000, 1680307200, 15000, 'EUR', (SELECT id FROM categories WHERE name = 'Buy')),
+                (3, 1, 'move', -30000000, 1688169600, NULL, NULL, (SELECT id FROM categories WHERE name = 'Transfer Out')),
+                (4, 2, 'move', 30000000, 1688169600, NULL, NULL, (SELECT id FROM categories WHERE name = 'Transfer In')),
+                (5, 2, 'loan', 20000000, 1690000000, NULL, NULL, NULL),
+                (6, 1, 'away', -10000000, 1695000000, NULL, NULL, (SELECT id FROM categories WHERE name = 'Transfer Out')),
+                (7, 1, 'c', -5000000, 1706745600, 2000, 'EUR', (SELECT id FROM categories WHERE name = 'Sell'))",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    fn allocation(safe_harbor: &SafeHarbor) -> Vec<(i64, i64, i64, f64)> {
+        safe_harbor
+            .lots
+            .iter()
+            .map(|lot| (lot.wallet_id, lot.lot_id, lot.sats, lot.cost))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_create_and_clear_safe_harbor() {
+        let pool = books().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let closed = periods::close(&mut conn, "2024-01-01", "2024-12-31").await.unwrap();
+        drop(conn);
+
+        let refused = "This includes transactions in the closed period 2024-01-01 to 2024-12-31. Reopen it first";
+        assert_eq!(create_safe_harbor(&pool, "2024-01-01").await.unwrap_err(), refused);
+
+        let mut conn = pool.acquire().await.unwrap();
+        periods::reopen(&mut conn, closed.id, "Safe harbor").await.unwrap();
+        drop(conn);
+
+        // The loan and the send to an untracked wallet don't move lots
+        let created = create_safe_harbor(&pool, "2024-01-01").await.unwrap();
+        assert_eq!(
+            allocation(&created),
+            vec![
+                (1, 1, 100_000_000, 10_000.0),
+                (1, 2, 20_000_000, 6_000.0),
+                (2, 2, 30_000_000, 9_000.0),
+            ]
+        );
+        assert!(created.warnings.is_empty(), "{:?}", created.warnings);
+        assert_eq!(
+            create_safe_harbor(&pool, "2024-01-01").await.unwrap_err(),
+            "A safe harbor allocation has already been made"
+        );
+
+        let report = report(&pool, None, None, None, None).await.unwrap();
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        assert_eq!(report.disposals[0].cost_basis, 500.0);
+
+        let mut conn = pool.acquire().await.unwrap();
+        periods::close(&mut conn, "2024-01-01", "2024-12-31").await.unwrap();
+        drop(conn);
+        assert_eq!(clear_safe_harbor(&pool).await.unwrap_err(), refused);
+
+        let mut conn = pool.acquire().await.unwrap();
+        let latest = periods::list(&mut conn).await.unwrap()[0].id;
+        periods::reopen(&mut conn, latest, "Redo the allocation").await.unwrap();
+        drop(conn);
+
+        clear_safe_harbor(&pool).await.unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        assert!(safe_harbor(&mut conn).await.unwrap().is_none());
+        let commands: Vec<(String,)> =
+            sqlx::query_as("SELECT command FROM audit_log WHERE table_name = 'safe_harbor' ORDER BY id")
+                .fetch_all(&mut *conn)
+                .await
+                .unwrap();
+        assert_eq!(
+            commands,
+            vec![("create_safe_harbor".to_string(),), ("clear_safe_harbor".to_string(),)]
+        );
+        drop(conn);
+
+        assert_eq!(
+            clear_safe_harbor(&pool).await.unwrap_err(),
+            "No safe harbor allocation to remove"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_safe_harbor_shortfall_is_reported() {
+        let pool = books().await;
+
+        // Wallet 2 sells more than it got by transfer; pooled, wallet 1's lots cover it
+        sqlx::query(
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, timestamp, fiat_value, fiat_currency, category_id)
+             VALUES (8, 2, 'd', -40000000, 1700000000, 12000, 'EUR', (SELECT id FROM categories WHERE name = 'Sell'))",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let created = create_safe_harbor(&pool, "2024-01-01").await.unwrap();
+        assert_eq!(
+            allocation(&created),
+            vec![(1, 1, 60_000_000, 6_000.0), (1, 2, 50_000_000, 15_000.0)]
+        );
+        assert_eq!(
+            created.warnings,
+            vec!["Wallet 1 held 10000000 sats more than the pooled lots had left for it"]
+        );
+
+        // Everything pooled was handed out, so reports don't flag a change
+        let report = report(&pool, None, None, None, None).await.unwrap();
+        assert!(
+            !report
+                .warnings
+                .iter()
+                .any(|warning| warning.starts_with("Holdings before")),
+            "{:?}",
+            report.warnings
+        );
+    }
+
+    #[test]
+    fn test_classification() {
+        assert_eq!(classify("Buy", 1), Some(Kind::Acquisition));
+        assert_eq!(classify("Buy", -1), None);
+        assert_eq!(classify("Gift", 1), Some(Kind::Acquisition));
+        assert_eq!(classify("Gift", -1), Some(Kind::Disposal));
+        assert_eq!(classify("Transfer Out", -1), None);
+        assert_eq!(classify("Uncategorized", 1), None);
+    }
+}
@@ -2,73 +2,143 @@
 //! 
 //! Uses:
 //! - BIP39 for recovery phrase generation
-//! - Argon2id for passphrase to key derivation
+//! - SLIP-39 for splitting the recovery phrase into Shamir shares
+//! - Argon2id for passphrase to key derivation (optionally keyed with a key file)
 //! - AES-256 via SQLCipher for database encryption
 
-use argon2::{Argon2, password_hash::SaltString, PasswordHasher};
+use argon2::{Algorithm, Argon2, Params, Version};
 use bip39::{Mnemonic, Language};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
 
-/// Generate a new 12-word BIP39 mnemonic recovery phrase
-pub fn generate_recovery_phrase() -> Result<String, String> {
-    // Generate 16 bytes of entropy for 12 words
-    let mut entropy = [0u8; 16];
-    getrandom::getrandom(&mut entropy)
+use crate::slip39;
+
+/// A secret string (passphrase, recovery phrase, share) wiped from memory on drop
+pub type SecretString = Zeroizing<String>;
+
+/// A 32-byte encryption key wiped from memory on drop
+pub type SecretKey = Zeroizing<[u8; 32]>;
+
+/// Normalize a recovery phrase: lowercase, single spaces between words
+pub fn normalize_recovery_phrase(phrase: &str) -> SecretString {
+    let lowered = Zeroizing::new(phrase.to_lowercase());
+    Zeroizing::new(lowered.split_whitespace().collect::<Vec<&str>>().join(" "))
+}
+
+/// Generate a new BIP39 mnemonic recovery phrase (12 or 24 words)
+pub fn generate_recovery_phrase(word_count: usize) -> Result<SecretString, String> {
+    // 16 bytes of entropy for 12 words, 32 bytes for 24 words
+    let entropy_len = match word_count {
+        12 => 16,
+        24 => 32,
+        _ => return Err(format!("Unsupported recovery phrase length: {} words", word_count)),
+    };
+    
+    let mut entropy = Zeroizing::new([0u8; 32]);
+    getrandom::getrandom(&mut entropy[..entropy_len])
         .map_err(|e| format!("Failed to generate random entropy: {}", e))?;
     
-    let mnemonic = Mnemonic::from_entropy_in(Language::English, &entropy)
+    let mnemonic = Mnemonic::from_entropy_in(Language::English, &entropy[..entropy_len])
         .map_err(|e| format!("Failed to generate mnemonic: {}", e))?;
     
-    Ok(mnemonic.to_string())
+    Ok(Zeroizing::new(mnemonic.to_string()))
+}
+
+/// Count the words of a recovery phrase, ignoring extra whitespace
+pub fn recovery_phrase_word_count(phrase: &str) -> usize {
+    phrase.split_whitespace().count()
 }
 
 /// Derive a deterministic encryption key from a recovery phrase
-/// This allows recovering the database with just the 12 words
-pub fn derive_key_from_recovery(recovery_phrase: &str) -> Result<String, String> {
+/// This allows recovering the database with just the recovery words
+/// (plus the optional BIP39 passphrase, empty if none was chosen)
+pub fn derive_key_from_recovery(recovery_phrase: &str, bip39_passphrase: &str) -> Result<SecretKey, String> {
     // Normalize the phrase
-    let normalized = recovery_phrase
-        .to_lowercase()
-        .trim()
-        .split_whitespace()
-        .collect::<Vec<&str>>()
-        .join(" ");
+    let normalized = normalize_recovery_phrase(recovery_phrase);
     
     // Parse and validate the mnemonic
     let mnemonic = Mnemonic::parse_in_normalized(Language::English, &normalized)
         .map_err(|e| format!("Invalid recovery phrase: {:?}", e))?;
     
     // Use the mnemonic's seed to derive a key
-    let seed = mnemonic.to_seed("");
+    let seed = Zeroizing::new(mnemonic.to_seed(bip39_passphrase));
     
     // Take first 32 bytes of seed as the encryption key
-    let key_bytes = &seed[0..32];
-    Ok(hex::encode(key_bytes))
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(&seed[0..32]);
+    Ok(key)
 }
 
 /// Derive an encryption key from a user passphrase using Argon2id
-/// Returns a hex-encoded 32-byte key suitable for SQLCipher
-pub fn derive_key_from_passphrase(passphrase: &str, salt: &str) -> Result<String, String> {
-    let argon2 = Argon2::default();
+/// Returns a 32-byte key suitable for SQLCipher
+///
+/// When a key file is given, its SHA-256 digest is used as the Argon2
+/// secret, so both the passphrase and the file are needed to derive the key.
+pub fn derive_key_from_passphrase(
+    passphrase: &str,
+    salt: &str,
+    key_file: Option<&[u8]>,
+    params: &KdfParams,
+) -> Result<SecretKey, String> {
+    let key_file_digest = key_file.map(|bytes| Zeroizing::new(<[u8; 32]>::from(Sha256::digest(bytes))));
+    let argon2_params = Params::new(params.memory_kib, params.iterations, params.parallelism, None)
+        .map_err(|e| format!("Invalid KDF parameters: {}", e))?;
     
-    // Use provided salt (stored alongside encrypted DB)
-    let salt_string = SaltString::encode_b64(salt.as_bytes())
-        .map_err(|e| format!("Invalid salt: {}", e))?;
+    let argon2 = match &key_file_digest {
+        Some(digest) => Argon2::new_with_secret(
+            digest.as_slice(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            argon2_params,
+        )
+        .map_err(|e| format!("Invalid key file: {}", e))?,
+        None => Argon2::new(Algorithm::Argon2id, Version::V0x13, argon2_params),
+    };
     
-    let password_hash = argon2
-        .hash_password(passphrase.as_bytes(), &salt_string)
+    // Use provided salt (stored alongside encrypted DB), hashing straight
+    // into the key buffer so no intermediate copy of the output is kept
+    let mut key = Zeroizing::new([0u8; 32]);
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), key.as_mut_slice())
         .map_err(|e| format!("Failed to hash passphrase: {}", e))?;
     
-    // Extract the hash output
-    let hash = password_hash.hash.ok_or("No hash output")?;
-    let hash_bytes = hash.as_bytes();
-    
-    // Take first 32 bytes for AES-256
-    let key_bytes = &hash_bytes[0..32.min(hash_bytes.len())];
-    
-    // Pad if necessary
-    let mut key = [0u8; 32];
-    key[..key_bytes.len()].copy_from_slice(key_bytes);
-    
-    Ok(hex::encode(key))
+    Ok(key)
+}
+
+/// Argon2id cost parameters, recorded in the encryption header so they can
+/// be raised later without breaking existing databases
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The argon2 crate defaults, used by every database so far
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Hex-encode a key, e.g. for SQLCipher's raw key syntax
+pub fn key_to_hex(key: &SecretKey) -> SecretString {
+    Zeroizing::new(hex::encode(key.as_slice()))
+}
+
+/// Minimum size accepted for a key file
+pub const MIN_KEY_FILE_LEN: usize = 32;
+
+/// Generate random contents for a new key file
+pub fn generate_key_file_bytes() -> Result<Zeroizing<Vec<u8>>, String> {
+    let mut bytes = Zeroizing::new(vec![0u8; 64]);
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| format!("Failed to generate random key file: {}", e))?;
+    Ok(bytes)
 }
 
 /// Generate a random salt for passphrase derivation
@@ -142,35 +212,132 @@ pub struct PasswordStrength {
 
 /// Validate a BIP39 mnemonic phrase
 pub fn validate_recovery_phrase(phrase: &str) -> bool {
-    // Normalize: lowercase, trim, collapse spaces
-    let normalized = phrase
-        .to_lowercase()
-        .trim()
-        .split_whitespace()
-        .collect::<Vec<&str>>()
-        .join(" ");
+    let normalized = normalize_recovery_phrase(phrase);
     
     Mnemonic::parse_in_normalized(Language::English, &normalized).is_ok()
 }
 
+/// Split a recovery phrase into SLIP-39 shares, any `threshold` of which
+/// rebuild it. The shares encode the phrase's BIP39 entropy, so the
+/// recombined phrase derives the same master key.
+pub fn split_recovery_phrase(recovery_phrase: &str, threshold: u8, share_count: u8) -> Result<Vec<SecretString>, String> {
+    let normalized = normalize_recovery_phrase(recovery_phrase);
+    
+    let mnemonic = Mnemonic::parse_in_normalized(Language::English, &normalized)
+        .map_err(|e| format!("Invalid recovery phrase: {:?}", e))?;
+    
+    let entropy = Zeroizing::new(mnemonic.to_entropy());
+    slip39::split_master_secret(&entropy, threshold, share_count, b"")
+}
+
+/// Recombine SLIP-39 shares into the original BIP39 recovery phrase
+pub fn combine_recovery_shares(shares: &[SecretString]) -> Result<SecretString, String> {
+    let entropy = slip39::combine_mnemonics(shares, b"")?;
+    
+    let mnemonic = Mnemonic::from_entropy_in(Language::English, &entropy)
+        .map_err(|e| format!("Shares do not encode a recovery phrase: {}", e))?;
+    
+    Ok(Zeroizing::new(mnemonic.to_string()))
+}
+
+/// Validate a single SLIP-39 share
+pub fn validate_recovery_share(share: &str) -> bool {
+    slip39::validate_mnemonic(share)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     
     #[test]
     fn test_generate_recovery_phrase() {
-        let phrase = generate_recovery_phrase().unwrap();
+        let phrase = generate_recovery_phrase(12).unwrap();
         let words: Vec<&str> = phrase.split_whitespace().collect();
         assert_eq!(words.len(), 12);
     }
     
+    #[test]
+    fn test_generate_24_word_recovery_phrase() {
+        let phrase = generate_recovery_phrase(24).unwrap();
+        assert_eq!(recovery_phrase_word_count(&phrase), 24);
+        assert!(validate_recovery_phrase(&phrase));
+        assert!(generate_recovery_phrase(18).is_err());
+    }
+    
     #[test]
     fn test_derive_key_deterministic() {
         let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
-        let key1 = derive_key_from_recovery(phrase).unwrap();
-        let key2 = derive_key_from_recovery(phrase).unwrap();
+        let key1 = derive_key_from_recovery(phrase, "").unwrap();
+        let key2 = derive_key_from_recovery(phrase, "").unwrap();
         assert_eq!(key1, key2);
-        assert_eq!(key1.len(), 64); // 32 bytes = 64 hex chars
+        // First 32 bytes of the BIP39 seed, unchanged for existing databases
+        assert_eq!(
+            key_to_hex(&key1).as_str(),
+            "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc1"
+        );
+    }
+    
+    #[test]
+    fn test_passphrase_key_compatible() {
+        // Keys derived before the switch to zeroizing buffers
+        let salt = "00112233445566778899aabbccddeeff";
+        let key = derive_key_from_passphrase("correct horse battery staple", salt, None, &KdfParams::default()).unwrap();
+        assert_eq!(
+            key_to_hex(&key).as_str(),
+            "b2492c002fe0029c9669ae753b68f994a84ac1add5c66caf614e8d0297555877"
+        );
+        let key = derive_key_from_passphrase("correct horse battery staple", salt, Some(&[7u8; 64]), &KdfParams::default()).unwrap();
+        assert_eq!(
+            key_to_hex(&key).as_str(),
+            "a69a71afd2a4be0121b5c12fcbdb13903f320a7c891a13b29a45cd87d24704b1"
+        );
+    }
+    
+    /// Compiles only for values wiped on drop
+    fn assert_zeroizing<T: zeroize::Zeroize>(_: &Zeroizing<T>) {}
+    
+    #[test]
+    fn test_secrets_are_zeroizing() {
+        let phrase = generate_recovery_phrase(12).unwrap();
+        assert_zeroizing(&phrase);
+        assert_zeroizing(&normalize_recovery_phrase(&phrase));
+        assert_zeroizing(&derive_key_from_recovery(&phrase, "").unwrap());
+        assert_zeroizing(&derive_key_from_passphrase("pass", &generate_salt(), None, &KdfParams::default()).unwrap());
+        assert_zeroizing(&generate_key_file_bytes().unwrap());
+        
+        let shares = split_recovery_phrase(&phrase, 2, 3).unwrap();
+        shares.iter().for_each(assert_zeroizing);
+        assert_zeroizing(&combine_recovery_shares(&shares).unwrap());
+    }
+    
+    #[test]
+    fn test_bip39_passphrase_changes_key() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let plain = derive_key_from_recovery(phrase, "").unwrap();
+        let extra = derive_key_from_recovery(phrase, "TREZOR").unwrap();
+        assert_ne!(plain, extra);
+    }
+    
+    #[test]
+    fn test_recovery_shares_derive_same_key() {
+        let phrase = generate_recovery_phrase(24).unwrap();
+        let shares = split_recovery_phrase(&phrase, 2, 3).unwrap();
+        let recombined = combine_recovery_shares(&shares[1..]).unwrap();
+        assert_eq!(recombined, phrase);
+        assert_eq!(
+            derive_key_from_recovery(&recombined, "").unwrap(),
+            derive_key_from_recovery(&phrase, "").unwrap()
+        );
+    }
+    
+    #[test]
+    fn test_key_file_changes_passphrase_key() {
+        let salt = generate_salt();
+        let key_file = generate_key_file_bytes().unwrap();
+        let without = derive_key_from_passphrase("correct horse", &salt, None, &KdfParams::default()).unwrap();
+        let with = derive_key_from_passphrase("correct horse", &salt, Some(&key_file), &KdfParams::default()).unwrap();
+        assert_ne!(without, with);
+        assert_eq!(with, derive_key_from_passphrase("correct horse", &salt, Some(&key_file), &KdfParams::default()).unwrap());
     }
     
     #[test]
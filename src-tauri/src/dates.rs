@@ -0,0 +1,108 @@
+//! Calendar dates in UTC
+//!
+//! Timestamps are unix seconds and dates are proleptic Gregorian, so reports
+//! and closed periods agree on which day a transaction falls on.
+
+pub const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Unix time of midnight UTC at the start of a `YYYY-MM-DD` date
+pub fn date_to_unix(date: &str) -> Result<i64, String> {
+    let invalid = || format!("Invalid date: {}", date);
+
+    let mut parts = date.split('-');
+    let (Some(year), Some(month), Some(day), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let year: i64 = year.parse().map_err(|_| invalid())?;
+    let month: i64 = month.parse().map_err(|_| invalid())?;
+    let day: i64 = day.parse().map_err(|_| invalid())?;
+
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return Err(invalid()),
+    };
+    if day < 1 || day > days_in_month {
+        return Err(invalid());
+    }
+
+    // Days from civil (Howard Hinnant)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    Ok((era * 146097 + doe - 719468) * SECONDS_PER_DAY)
+}
+
+/// Year, month and day (UTC) of a unix time
+pub fn civil_date(timestamp: i64) -> (i64, i64, i64) {
+    // Civil from days (Howard Hinnant)
+    let z = timestamp.div_euclid(SECONDS_PER_DAY) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// Whether a disposal comes after the end of a holding period of `months`
+/// from the acquisition date
+pub fn held_longer_than(acquired_at: i64, disposed_at: i64, months: i64) -> bool {
+    let (year, month, day) = civil_date(acquired_at);
+    let end = year * 12 + month - 1 + months;
+    civil_date(disposed_at) > (end.div_euclid(12), end.rem_euclid(12) + 1, day)
+}
+
+/// `YYYY-MM-DD` (UTC) of a unix time
+pub fn iso_date(timestamp: i64) -> String {
+    let (year, month, day) = civil_date(timestamp);
+    format!("{}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date_to_unix() {
+        assert_eq!(date_to_unix("1970-01-01"), Ok(0));
+        assert_eq!(date_to_unix("2024-01-01"), Ok(1704067200));
+        assert_eq!(date_to_unix("2024-02-29"), Ok(1709164800));
+        assert!(date_to_unix("2023-02-29").is_err());
+        assert!(date_to_unix("2024-13-01").is_err());
+        assert!(date_to_unix("2024-01").is_err());
+    }
+
+    #[test]
+    fn test_civil_date() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(1709164800 + 86399), (2024, 2, 29));
+        assert_eq!(civil_date(-1), (1969, 12, 31));
+        assert_eq!(civil_date(date_to_unix("2000-03-01").unwrap()), (2000, 3, 1));
+    }
+
+    #[test]
+    fn test_held_longer_than() {
+        let acquired = date_to_unix("2023-08-31").unwrap();
+        assert!(!held_longer_than(acquired, date_to_unix("2024-02-29").unwrap(), 6));
+        assert!(held_longer_than(acquired, date_to_unix("2024-03-01").unwrap(), 6));
+        assert!(held_longer_than(acquired, date_to_unix("2023-09-01").unwrap(), 0));
+    }
+
+    #[test]
+    fn test_iso_date() {
+        assert_eq!(iso_date(0), "1970-01-01");
+        assert_eq!(iso_date(date_to_unix("2024-02-29").unwrap() + 3600), "2024-02-29");
+    }
+}
@@ -1,25 +1,61 @@
-use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use serde::Serialize;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Connection, SqliteConnection, SqlitePool,
+};
 use std::path::PathBuf;
+use std::str::FromStr;
 
+/// Database file name inside a vault directory
+pub const DB_FILE_NAME: &str = "personal.db";
+
+/// Schema version stored in `PRAGMA user_version`
+/// Bump this whenever init_db gains a migration, so a snapshot is taken first
+pub const SCHEMA_VERSION: i64 = 7;
+
+/// Database of the active vault
 pub async fn get_db_path() -> PathBuf {
-    let app_dir = dirs::data_local_dir()
-        .expect("Failed to get local data directory")
-        .join("com.satstone.bitfinances");
+    crate::vaults::active_dir().join(DB_FILE_NAME)
+}
+
+/// Whether opening the active vault's database will run migrations it hasn't had yet
+pub async fn needs_migration() -> bool {
+    let db_path = get_db_path().await;
+    if !db_path.exists() {
+        return false;
+    }
     
-    std::fs::create_dir_all(&app_dir).expect("Failed to create app directory");
+    let db_url = format!("sqlite:{}?mode=ro", db_path.display());
+    let Ok(mut conn) = SqliteConnection::connect(&db_url).await else {
+        return false;
+    };
     
-    app_dir.join("personal.db")
+    sqlx::query_as::<_, (i64,)>("PRAGMA user_version")
+        .fetch_one(&mut conn)
+        .await
+        .map(|(version,)| version < SCHEMA_VERSION)
+        .unwrap_or(false)
 }
 
 pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
     let db_path = get_db_path().await;
     let db_url = format!("sqlite:{}?mode=rwc", db_path.display());
     
+    // SQLite only enforces FOREIGN KEY clauses when asked to, on every connection
+    let options = SqliteConnectOptions::from_str(&db_url)?.foreign_keys(true);
+    
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
-        .connect(&db_url)
+        .connect_with(options)
         .await?;
     
+    create_schema(&pool).await?;
+    
+    Ok(pool)
+}
+
+/// Create the tables and run the migrations a database hasn't had yet
+pub async fn create_schema(pool: &SqlitePool) -> Result<(), sqlx::Error> {
     // Create entities table
     sqlx::query(
         r#"
@@ -33,7 +69,7 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     // Insert default Personal entity if not exists
@@ -42,7 +78,7 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         INSERT OR IGNORE INTO entities (id, name, entity_type) VALUES (1, 'Personal', 'personal')
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     // Create wallets table
@@ -59,7 +95,7 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     // Create transactions table
@@ -76,35 +112,36 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
             confirmed INTEGER NOT NULL DEFAULT 0,
             block_height INTEGER,
             timestamp INTEGER,
-            category TEXT,
+            category_id INTEGER,
             note TEXT,
             fiat_value REAL,
             fiat_currency TEXT,
             currency TEXT DEFAULT 'BTC',
             created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
             FOREIGN KEY (wallet_id) REFERENCES wallets(id) ON DELETE CASCADE,
+            FOREIGN KEY (category_id) REFERENCES categories(id) ON DELETE SET NULL,
             UNIQUE(wallet_id, txid)
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     // Add columns if they don't exist (migrations for existing DBs)
     let _ = sqlx::query("ALTER TABLE transactions ADD COLUMN fiat_value REAL")
-        .execute(&pool)
+        .execute(pool)
         .await;
     let _ = sqlx::query("ALTER TABLE transactions ADD COLUMN fiat_currency TEXT")
-        .execute(&pool)
+        .execute(pool)
         .await;
     let _ = sqlx::query("ALTER TABLE transactions ADD COLUMN fee_fiat REAL")
-        .execute(&pool)
+        .execute(pool)
         .await;
     let _ = sqlx::query("ALTER TABLE transactions ADD COLUMN fee_fiat_currency TEXT")
-        .execute(&pool)
+        .execute(pool)
         .await;
     let _ = sqlx::query("ALTER TABLE transactions ADD COLUMN currency TEXT DEFAULT 'BTC'")
-        .execute(&pool)
+        .execute(pool)
         .await;
     
     // Create settings table
@@ -116,32 +153,44 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     // Insert default settings if not exist
     sqlx::query(
         r#"INSERT OR IGNORE INTO settings (key, value) VALUES ('currency_format', 'sats')"#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     sqlx::query(
         r#"INSERT OR IGNORE INTO settings (key, value) VALUES ('fiat_enabled', 'true')"#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     sqlx::query(
         r#"INSERT OR IGNORE INTO settings (key, value) VALUES ('fiat_currency', 'EUR')"#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     sqlx::query(
         r#"INSERT OR IGNORE INTO settings (key, value) VALUES ('cost_basis_method', 'average')"#,
     )
-    .execute(&pool)
+    .execute(pool)
+    .await?;
+    
+    sqlx::query(
+        r#"INSERT OR IGNORE INTO settings (key, value) VALUES ('cost_basis_scope', 'universal')"#,
+    )
+    .execute(pool)
+    .await?;
+    
+    sqlx::query(
+        r#"INSERT OR IGNORE INTO settings (key, value) VALUES ('transfer_fee_rule', 'non_deductible')"#,
+    )
+    .execute(pool)
     .await?;
     
     // Create price_cache table
@@ -157,7 +206,7 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     // Create categories table
@@ -171,7 +220,7 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         )
         "#,
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     // Insert default categories
@@ -202,9 +251,378 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
         )
         .bind(name)
         .bind(is_system)
-        .execute(&pool)
+        .execute(pool)
         .await?;
     }
     
-    Ok(pool)
-}
\ No newline at end of file
+    migrate_category_names(pool).await?;
+    
+    // Soft deletion: rows tagged with a trash entry are hidden until restored or purged
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS trash (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            operation TEXT NOT NULL,
+            description TEXT NOT NULL,
+            settings TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    for table in ["entities", "wallets", "transactions"] {
+        let _ = sqlx::query(&format!("ALTER TABLE {} ADD COLUMN trash_id INTEGER", table))
+            .execute(pool)
+            .await;
+        sqlx::query(&format!("CREATE INDEX IF NOT EXISTS idx_{}_trash_id ON {}(trash_id)", table, table))
+            .execute(pool)
+            .await?;
+    }
+    
+    // Append-only audit log
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            command TEXT NOT NULL,
+            table_name TEXT NOT NULL,
+            row_id INTEGER,
+            old_value TEXT,
+            new_value TEXT,
+            actor TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'))
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_audit_log_row ON audit_log(table_name, row_id)")
+        .execute(pool)
+        .await?;
+    
+    sqlx::query(
+        r#"
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'The audit log is append-only'); END
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    sqlx::query(
+        r#"
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'The audit log is append-only'); END
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    // Closed periods and the hash chain sealing their transactions
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS closed_periods (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            start_date TEXT NOT NULL,
+            end_date TEXT NOT NULL,
+            start_ts INTEGER NOT NULL,
+            end_ts INTEGER NOT NULL,
+            prev_hash TEXT NOT NULL,
+            head_hash TEXT NOT NULL,
+            entry_count INTEGER NOT NULL,
+            closed_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+            reopened_at TEXT,
+            reopen_reason TEXT
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS sealed_entries (
+            period_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            transaction_id INTEGER NOT NULL,
+            entry_hash TEXT NOT NULL,
+            PRIMARY KEY (period_id, position),
+            FOREIGN KEY (period_id) REFERENCES closed_periods(id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    // Disposals assigned to specific acquisition lots
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS lot_matches (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            disposal_id INTEGER NOT NULL,
+            lot_id INTEGER NOT NULL,
+            sats INTEGER NOT NULL CHECK(sats > 0),
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (disposal_id) REFERENCES transactions(id) ON DELETE CASCADE,
+            FOREIGN KEY (lot_id) REFERENCES transactions(id) ON DELETE CASCADE,
+            UNIQUE(disposal_id, lot_id)
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    // Safe harbor allocation: a one-time snapshot of which lots each wallet held
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS safe_harbor (
+            id INTEGER PRIMARY KEY CHECK(id = 1),
+            date TEXT NOT NULL,
+            allocated_at INTEGER NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS safe_harbor_lots (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            lot_id INTEGER NOT NULL,
+            wallet_id INTEGER NOT NULL,
+            category TEXT NOT NULL,
+            acquired_at INTEGER NOT NULL,
+            sats INTEGER NOT NULL CHECK(sats > 0),
+            cost REAL NOT NULL,
+            FOREIGN KEY (wallet_id) REFERENCES wallets(id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+    
+    sqlx::query(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+/// Move transactions from a free-text `category` to a `category_id` reference
+///
+/// Names that aren't in `categories` yet become custom categories, so no
+/// transaction loses its category.
+async fn migrate_category_names(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let (has_name_column,) = sqlx::query_as::<_, (i64,)>(
+        "SELECT COUNT(*) FROM pragma_table_info('transactions') WHERE name = 'category'"
+    )
+    .fetch_one(pool)
+    .await?;
+    
+    if has_name_column == 0 {
+        return Ok(());
+    }
+    
+    let mut tx = pool.begin().await?;
+    
+    let _ = sqlx::query("ALTER TABLE transactions ADD COLUMN category_id INTEGER REFERENCES categories(id) ON DELETE SET NULL")
+        .execute(&mut *tx)
+        .await;
+    
+    sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO categories (name, is_system)
+        SELECT DISTINCT TRIM(category), 0 FROM transactions
+        WHERE category IS NOT NULL AND TRIM(category) != ''
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    
+    sqlx::query(
+        r#"
+        UPDATE transactions
+        SET category_id = (SELECT id FROM categories WHERE name = TRIM(transactions.category))
+        WHERE category IS NOT NULL
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    
+    sqlx::query("ALTER TABLE transactions DROP COLUMN category")
+        .execute(&mut *tx)
+        .await?;
+    
+    tx.commit().await
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrphanedRows {
+    pub table: String,
+    pub parent: String,
+    pub row_ids: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IntegrityReport {
+    pub ok: bool,
+    /// Output of `PRAGMA integrity_check` ("ok" when the file is sound)
+    pub integrity: Vec<String>,
+    /// Rows whose foreign key points at a row that doesn't exist
+    pub orphaned: Vec<OrphanedRows>,
+}
+
+/// Check the database file and report rows that break a foreign key
+pub async fn check_integrity(pool: &SqlitePool) -> Result<IntegrityReport, sqlx::Error> {
+    let integrity: Vec<String> = sqlx::query_as::<_, (String,)>("PRAGMA integrity_check")
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|(line,)| line)
+        .collect();
+    
+    // table, rowid, parent table, foreign key index
+    let violations = sqlx::query_as::<_, (String, Option<i64>, String, i64)>("PRAGMA foreign_key_check")
+        .fetch_all(pool)
+        .await?;
+    
+    let mut orphaned: Vec<OrphanedRows> = Vec::new();
+    for (table, row_id, parent, _) in violations {
+        let Some(row_id) = row_id else { continue };
+        match orphaned.iter_mut().find(|o| o.table == table && o.parent == parent) {
+            Some(group) => group.row_ids.push(row_id),
+            None => orphaned.push(OrphanedRows { table, parent, row_ids: vec![row_id] }),
+        }
+    }
+    
+    Ok(IntegrityReport {
+        ok: integrity == ["ok"] && orphaned.is_empty(),
+        integrity,
+        orphaned,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{blank_pool, memory_pool};
+
+    #[tokio::test]
+    async fn test_category_names_migrate_to_ids() {
+        let pool = blank_pool().await;
+
+        // Transactions as they were before categories had ids
+        for statement in [
+            "CREATE TABLE wallets (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL, xpub TEXT NOT NULL UNIQUE)",
+            r#"CREATE TABLE transactions (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                wallet_id INTEGER NOT NULL,
+                txid TEXT NOT NULL,
+                amount_sats INTEGER NOT NULL,
+                timestamp INTEGER,
+                category TEXT,
+                FOREIGN KEY (wallet_id) REFERENCES wallets(id) ON DELETE CASCADE,
+                UNIQUE(wallet_id, txid)
+            )"#,
+            "INSERT INTO wallets (id, name, xpub) VALUES (1, 'Savings', 'xpub1')",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, category) VALUES
+                (1, 1, 'a', 100, 'Buy'), (2, 1, 'b', 200, ' Freelance '), (3, 1, 'c', 300, NULL), (4, 1, 'd', 400, '')",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        create_schema(&pool).await.unwrap();
+
+        let (has_name_column,) = sqlx::query_as::<_, (i64,)>(
+            "SELECT COUNT(*) FROM pragma_table_info('transactions') WHERE name = 'category'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(has_name_column, 0);
+
+        let categories: Vec<(i64, Option<String>)> = sqlx::query_as(
+            "SELECT t.id, c.name FROM transactions t LEFT JOIN categories c ON c.id = t.category_id ORDER BY t.id",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            categories,
+            vec![
+                (1, Some("Buy".to_string())),
+                (2, Some("Freelance".to_string())),
+                (3, None),
+                (4, None),
+            ]
+        );
+
+        let (is_system,): (i64,) = sqlx::query_as("SELECT is_system FROM categories WHERE name = 'Freelance'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(is_system, 0);
+
+        // Opening again finds nothing left to migrate
+        create_schema(&pool).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deleting_wallet_cascades() {
+        let pool = memory_pool().await;
+
+        for statement in [
+            "INSERT INTO wallets (id, name, xpub) VALUES (1, 'Savings', 'xpub1'), (2, 'Spending', 'xpub2')",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats) VALUES (1, 1, 'a', 100), (2, 2, 'b', 200)",
+            "DELETE FROM wallets WHERE id = 1",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        let remaining: Vec<(i64,)> = sqlx::query_as("SELECT id FROM transactions ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, vec![(2,)]);
+
+        // Nor can a transaction point at a wallet that doesn't exist
+        assert!(
+            sqlx::query("INSERT INTO transactions (wallet_id, txid, amount_sats) VALUES (9, 'c', 1)")
+                .execute(&pool)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_integrity_reports_orphans() {
+        let pool = memory_pool().await;
+
+        let report = check_integrity(&pool).await.unwrap();
+        assert!(report.ok);
+        assert_eq!(report.integrity, vec!["ok"]);
+
+        // Rows written while foreign keys weren't enforced
+        let mut conn = pool.acquire().await.unwrap();
+        for statement in [
+            "PRAGMA foreign_keys = OFF",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats) VALUES (7, 9, 'a', 100), (8, 9, 'b', 200)",
+            "PRAGMA foreign_keys = ON",
+        ] {
+            sqlx::query(statement).execute(&mut *conn).await.unwrap();
+        }
+        drop(conn);
+
+        let report = check_integrity(&pool).await.unwrap();
+        assert!(!report.ok);
+        assert_eq!(report.orphaned.len(), 1);
+        assert_eq!(report.orphaned[0].table, "transactions");
+        assert_eq!(report.orphaned[0].parent, "wallets");
+        assert_eq!(report.orphaned[0].row_ids, vec![7, 8]);
+    }
+}
@@ -1,8 +1,9 @@
 //! Encrypted database management using SQLCipher
 //!
 //! Architecture:
-//! - Recovery phrase (12 words) -> Master encryption key (via BIP39 seed)
-//! - Passphrase -> Used to encrypt/store the recovery phrase locally
+//! - Recovery phrase (12 or 24 words, optional BIP39 passphrase) -> Master encryption key (via BIP39 seed)
+//! - Passphrase (+ optional key file) -> Used to encrypt/store the recovery phrase locally
+//! - SLIP-39 shares -> Recombine into the recovery phrase (e.g. 2-of-3 among relatives)
 //! - Database is ALWAYS encrypted with the master key (derived from recovery phrase)
 //!
 //! This means:
@@ -10,11 +11,154 @@
 //! - Passphrase unlocks by decrypting the stored recovery phrase, then using that
 
 use rusqlite::{Connection, OpenFlags};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-use crate::crypto;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::crypto::{self, SecretKey, SecretString};
+use crate::encryption_header::{EncryptionHeader, ThrottleState, UnlockMethod, HEADER_FILE_NAME};
+use crate::vaults;
+
+/// Summary of the encryption header for the UI
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct EncryptionMeta {
+    pub encrypted: bool,
+    pub format_version: u32,
+    pub created_at: u64,
+    pub word_count: usize,
+    pub has_bip39_passphrase: bool,
+    pub requires_key_file: bool,
+    pub unlock_methods: Vec<UnlockMethod>,
+    /// Consecutive failed passphrase attempts
+    pub failed_attempts: u32,
+    /// Unix time of the last failed attempt
+    pub last_failed_at: u64,
+    /// Wipe the database after this many consecutive failures (opt-in)
+    pub wipe_after: Option<u32>,
+}
+
+impl EncryptionMeta {
+    /// Seconds to wait after the last failure before another passphrase attempt
+    pub fn unlock_delay_secs(&self) -> u64 {
+        unlock_delay_secs(self.failed_attempts)
+    }
+}
+
+/// Encrypted database file name inside a vault directory
+pub const DB_FILE_NAME: &str = "satstone.db";
+
+/// First 16 bytes of every plaintext SQLite database
+const SQLITE_FILE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// Files from before the single header: status text, salt, wrapped recovery
+const LEGACY_META_FILE: &str = "encryption.meta";
+const LEGACY_SALT_FILE: &str = "salt.key";
+const LEGACY_RECOVERY_FILE: &str = "recovery.enc";
+
+/// Failed passphrase attempts allowed before delays start
+const FREE_UNLOCK_ATTEMPTS: u32 = 3;
+
+/// Longest delay between passphrase attempts
+const MAX_UNLOCK_DELAY_SECS: u64 = 60 * 60;
+
+/// Exponential delay: 1s after the first throttled failure, doubling up to an hour
+fn unlock_delay_secs(failed_attempts: u32) -> u64 {
+    if failed_attempts < FREE_UNLOCK_ATTEMPTS {
+        return 0;
+    }
+    
+    let exponent = (failed_attempts - FREE_UNLOCK_ATTEMPTS).min(12);
+    (1u64 << exponent).min(MAX_UNLOCK_DELAY_SECS)
+}
+
+/// Where an interrupted plaintext <-> encrypted conversion stopped
+///
+/// Recorded in `conversion.journal` before each step that touches the real
+/// files, so startup can roll back (or finish cleaning up) after a crash.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConversionStage {
+    /// Writing and verifying the converted copy; the original is untouched
+    Exporting,
+    /// Moving files into place; the originals are kept as `.bak`
+    Swapping,
+    /// Converted database verified in place; only cleanup remains
+    Committed,
+}
+
+impl ConversionStage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Exporting => "exporting",
+            Self::Swapping => "swapping",
+            Self::Committed => "committed",
+        }
+    }
+}
+
+/// Flush a file's contents to disk
+pub(crate) fn sync_file(path: &Path) -> Result<(), String> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.sync_all())
+        .map_err(|e| format!("Failed to sync {}: {}", path.display(), e))
+}
+
+/// Flush renames in a directory to disk (not supported on every platform)
+pub(crate) fn sync_dir(dir: &Path) {
+    if let Ok(d) = std::fs::File::open(dir) {
+        let _ = d.sync_all();
+    }
+}
+
+/// Write a file and flush it before returning
+pub(crate) fn write_synced(path: &Path, content: impl AsRef<[u8]>) -> Result<(), String> {
+    use std::io::Write;
+    
+    let mut file = std::fs::File::create(path)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    file.write_all(content.as_ref())
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Replace a file in one step: write a synced sibling, then rename over it
+fn write_atomic(path: &Path, content: &str) -> Result<(), String> {
+    let temp = with_suffix(path, "new");
+    write_synced(&temp, content)?;
+    std::fs::rename(&temp, path)
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))?;
+    
+    if let Some(dir) = path.parent() {
+        sync_dir(dir);
+    }
+    Ok(())
+}
+
+/// `encryption.json` -> `encryption.json.<suffix>`
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+pub(crate) fn remove_if_exists(path: &Path) -> Result<(), String> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Failed to remove {}: {}", path.display(), e)),
+    }
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 
 /// Encrypted database connection wrapper
 pub struct EncryptedDb {
@@ -24,30 +168,19 @@ pub struct EncryptedDb {
 }
 
 impl EncryptedDb {
-    /// Get the database file path
+    /// Get the database file path (in the active vault)
     pub fn get_db_path() -> PathBuf {
-        let app_dir = dirs::data_local_dir()
-            .expect("Failed to get local data directory")
-            .join("com.satstone.bitfinances");
-        
-        std::fs::create_dir_all(&app_dir).expect("Failed to create app directory");
-        
-        app_dir.join("satstone.db")
+        Self::get_app_dir().join(DB_FILE_NAME)
     }
     
-    /// Get the app data directory
+    /// Get the active vault's data directory
     fn get_app_dir() -> PathBuf {
-        let app_dir = dirs::data_local_dir()
-            .expect("Failed to get local data directory")
-            .join("com.satstone.bitfinances");
-        
-        std::fs::create_dir_all(&app_dir).expect("Failed to create app directory");
-        app_dir
+        vaults::active_dir()
     }
     
-    /// Get the encryption metadata file path
-    pub fn get_meta_path() -> PathBuf {
-        Self::get_app_dir().join("encryption.meta")
+    /// Get the encryption header file path
+    pub fn get_header_path() -> PathBuf {
+        Self::get_app_dir().join(HEADER_FILE_NAME)
     }
     
     /// Check if database exists
@@ -56,47 +189,311 @@ impl EncryptedDb {
     }
     
     /// Check if database is encrypted
+    /// Looks at the file itself: plaintext SQLite starts with a fixed magic
+    /// string, SQLCipher files start with random salt
     pub fn is_encrypted() -> bool {
-        let meta_path = Self::get_meta_path();
-        if meta_path.exists() {
-            if let Ok(content) = std::fs::read_to_string(&meta_path) {
-                return content.trim() == "encrypted";
-            }
+        Self::is_sqlcipher_file(&Self::get_db_path())
+    }
+    
+    /// Check whether a database file is SQLCipher-encrypted
+    pub fn is_sqlcipher_file(path: &Path) -> bool {
+        use std::io::Read;
+        
+        let mut magic = [0u8; 16];
+        match std::fs::File::open(path).and_then(|mut f| f.read_exact(&mut magic)) {
+            Ok(()) => &magic != SQLITE_FILE_HEADER,
+            // Missing or too short to hold a page: nothing encrypted yet
+            Err(_) => false,
+        }
+    }
+    
+    /// Read the encryption metadata, if any
+    pub fn get_encryption_meta() -> Option<EncryptionMeta> {
+        let header = Self::load_header().ok().flatten()?;
+        
+        Some(EncryptionMeta {
+            encrypted: Self::is_encrypted(),
+            format_version: header.format_version,
+            created_at: header.created_at,
+            word_count: header.word_count,
+            has_bip39_passphrase: header.has_bip39_passphrase,
+            requires_key_file: header.requires_key_file(),
+            unlock_methods: header.unlock_methods.clone(),
+            failed_attempts: header.throttle.failed_attempts,
+            last_failed_at: header.throttle.last_failed_at,
+            wipe_after: header.wipe_after,
+        })
+    }
+    
+    /// Get the conversion journal path
+    fn get_journal_path() -> PathBuf {
+        Self::get_app_dir().join("conversion.journal")
+    }
+    
+    /// Read the encryption header
+    /// Falls back to the files written by older versions, which are replaced
+    /// by a header the next time it's saved
+    fn load_header() -> Result<Option<EncryptionHeader>, String> {
+        match std::fs::read_to_string(Self::get_header_path()) {
+            Ok(content) => return EncryptionHeader::from_json(&content).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to read encryption header: {}", e)),
+        }
+        
+        let app_dir = Self::get_app_dir();
+        let legacy_meta = app_dir.join(LEGACY_META_FILE);
+        let Ok(meta) = std::fs::read_to_string(&legacy_meta) else {
+            return Ok(None);
+        };
+        
+        let created_at = std::fs::metadata(&legacy_meta)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        
+        Ok(EncryptionHeader::from_legacy(
+            &meta,
+            std::fs::read_to_string(app_dir.join(LEGACY_SALT_FILE)).ok(),
+            std::fs::read_to_string(app_dir.join(LEGACY_RECOVERY_FILE)).ok(),
+            created_at,
+        ))
+    }
+    
+    /// Save the encryption header, retiring any older-format files
+    /// Unsealed headers are refused: read back, they would fail verification
+    fn save_header(header: &EncryptionHeader) -> Result<(), String> {
+        if header.mac.is_none() {
+            return Err("Unlock with your passphrase before changing encryption settings".to_string());
+        }
+        
+        write_atomic(&Self::get_header_path(), &header.to_json())?;
+        Self::remove_legacy_files()
+    }
+    
+    /// Count failed attempts in the older-format files, for a header that isn't sealed yet
+    fn save_legacy_throttle(throttle: &ThrottleState) -> Result<(), String> {
+        let path = Self::get_app_dir().join(LEGACY_META_FILE);
+        let meta = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read encryption metadata: {}", e))?;
+        
+        let mut lines: Vec<String> = meta
+            .lines()
+            .filter(|line| !line.trim().starts_with("failed_attempts=") && !line.trim().starts_with("last_failed_at="))
+            .map(|line| line.to_string())
+            .collect();
+        lines.push(format!("failed_attempts={}", throttle.failed_attempts));
+        lines.push(format!("last_failed_at={}", throttle.last_failed_at));
+        
+        write_atomic(&path, &format!("{}\n", lines.join("\n")))
+    }
+    
+    /// Remove the separate meta/salt/recovery files used by older versions
+    fn remove_legacy_files() -> Result<(), String> {
+        let app_dir = Self::get_app_dir();
+        remove_if_exists(&app_dir.join(LEGACY_META_FILE))?;
+        remove_if_exists(&app_dir.join(LEGACY_SALT_FILE))?;
+        remove_if_exists(&app_dir.join(LEGACY_RECOVERY_FILE))
+    }
+    
+    /// Read a key file from disk
+    pub fn read_key_file(path: &Path) -> Result<Zeroizing<Vec<u8>>, String> {
+        let bytes = Zeroizing::new(
+            std::fs::read(path).map_err(|e| format!("Failed to read key file: {}", e))?,
+        );
+        
+        if bytes.len() < crypto::MIN_KEY_FILE_LEN {
+            return Err("Key file is too small".to_string());
+        }
+        
+        Ok(bytes)
+    }
+    
+    /// Create a new random key file (e.g. on removable media)
+    pub fn create_key_file(path: &Path) -> Result<(), String> {
+        if path.exists() {
+            return Err("A file already exists at this location".to_string());
         }
-        false
+        
+        let bytes = crypto::generate_key_file_bytes()?;
+        std::fs::write(path, bytes.as_slice()).map_err(|e| format!("Failed to write key file: {}", e))
     }
     
-    /// Get the stored salt for passphrase operations
-    fn get_salt() -> Option<String> {
-        std::fs::read_to_string(Self::get_app_dir().join("salt.key")).ok()
+    /// Derive the passphrase key, requiring the key file if the header says so
+    fn derive_passphrase_key(
+        header: &EncryptionHeader,
+        passphrase: &str,
+        key_file: Option<&Path>,
+    ) -> Result<SecretKey, String> {
+        let key_file_bytes = if header.requires_key_file() {
+            let path = key_file.ok_or("This database requires its key file to unlock")?;
+            Some(Self::read_key_file(path)?)
+        } else {
+            None
+        };
+        
+        crypto::derive_key_from_passphrase(
+            passphrase,
+            &header.kdf.salt,
+            key_file_bytes.as_deref().map(|b| b.as_slice()),
+            &header.kdf.params,
+        )
     }
     
-    /// Save salt
-    fn save_salt(salt: &str) -> Result<(), String> {
-        std::fs::write(Self::get_app_dir().join("salt.key"), salt)
-            .map_err(|e| e.to_string())
+    /// Build the secret stored under the passphrase: the recovery phrase,
+    /// followed by the BIP39 passphrase on a second line if one is used
+    fn build_recovery_payload(recovery_phrase: &str, bip39_passphrase: &str) -> SecretString {
+        if bip39_passphrase.is_empty() {
+            Zeroizing::new(recovery_phrase.to_string())
+        } else {
+            Zeroizing::new(format!("{}\n{}", recovery_phrase, bip39_passphrase))
+        }
     }
     
-    /// Get the encrypted recovery phrase (encrypted with passphrase)
-    fn get_encrypted_recovery() -> Option<String> {
-        std::fs::read_to_string(Self::get_app_dir().join("recovery.enc")).ok()
+    /// Split a decrypted payload back into (recovery phrase, BIP39 passphrase)
+    fn split_recovery_payload(payload: &str) -> (SecretString, SecretString) {
+        match payload.split_once('\n') {
+            Some((phrase, extra)) => (Zeroizing::new(phrase.to_string()), Zeroizing::new(extra.to_string())),
+            None => (Zeroizing::new(payload.to_string()), Zeroizing::new(String::new())),
+        }
     }
     
-    /// Save encrypted recovery phrase
-    fn save_encrypted_recovery(encrypted: &str) -> Result<(), String> {
-        std::fs::write(Self::get_app_dir().join("recovery.enc"), encrypted)
-            .map_err(|e| e.to_string())
+    /// Decrypt the stored payload with a passphrase-derived key and validate it
+    fn unwrap_recovery_payload(
+        header: &EncryptionHeader,
+        passphrase_key: &SecretKey,
+    ) -> Result<(SecretString, SecretString), String> {
+        let payload = Self::xor_decrypt(&header.wrapped_key.data, passphrase_key)
+            .map_err(|_| "Invalid passphrase")?;
+        
+        let (recovery_phrase, bip39_passphrase) = Self::split_recovery_payload(&payload);
+        
+        // Validate it's a real recovery phrase
+        if !crypto::validate_recovery_phrase(&recovery_phrase) {
+            return Err("Invalid passphrase".to_string());
+        }
+        
+        Ok((recovery_phrase, bip39_passphrase))
     }
     
-    /// Set encryption status in metadata
-    fn set_encrypted_status(encrypted: bool) -> Result<(), String> {
-        let status = if encrypted { "encrypted" } else { "plaintext" };
-        std::fs::write(Self::get_meta_path(), status).map_err(|e| e.to_string())
+    /// Decrypt the recovery payload with the passphrase
+    fn unlock_recovery_with_passphrase(
+        passphrase: &str,
+        key_file: Option<&Path>,
+    ) -> Result<(SecretString, SecretString), String> {
+        let (header, passphrase_key) = Self::authenticate_passphrase(passphrase, key_file)?;
+        Self::unwrap_recovery_payload(&header, &passphrase_key)
+    }
+    
+    /// Check the passphrase against the stored payload, enforcing the attempt throttle
+    ///
+    /// Every passphrase path goes through here so the counter can't be
+    /// bypassed by calling a different command. A correct passphrase also
+    /// authenticates the header (and seals headers migrated from older files).
+    /// Returns the header and the passphrase key that seals it.
+    fn authenticate_passphrase(
+        passphrase: &str,
+        key_file: Option<&Path>,
+    ) -> Result<(EncryptionHeader, SecretKey), String> {
+        let mut header = Self::load_header()?.ok_or("No encryption data found")?;
+        
+        let now = unix_now();
+        let throttle = &header.throttle;
+        let ready_at = throttle.last_failed_at.saturating_add(unlock_delay_secs(throttle.failed_attempts));
+        if now < ready_at {
+            return Err(format!(
+                "Too many failed attempts. Try again in {} seconds",
+                ready_at - now
+            ));
+        }
+        
+        let passphrase_key = Self::derive_passphrase_key(&header, passphrase, key_file)?;
+        
+        match Self::unwrap_recovery_payload(&header, &passphrase_key) {
+            Ok(_) => {
+                header.verify(&passphrase_key)?;
+                
+                if header.throttle.failed_attempts > 0 || header.mac.is_none() {
+                    header.throttle.failed_attempts = 0;
+                    header.throttle.last_failed_at = 0;
+                    header.seal(&passphrase_key);
+                    Self::save_header(&header)?;
+                }
+                Ok((header, passphrase_key))
+            }
+            Err(e) => {
+                let throttle = &mut header.throttle;
+                throttle.failed_attempts = throttle.failed_attempts.saturating_add(1);
+                throttle.last_failed_at = now;
+                
+                if header.wipe_after.is_some_and(|limit| header.throttle.failed_attempts >= limit) {
+                    Self::wipe()?;
+                    return Err(
+                        "Too many failed attempts. The vault's databases have been wiped (snapshots and backups are kept)"
+                            .to_string(),
+                    );
+                }
+                
+                // A migrated header can only be saved once a correct passphrase seals it
+                if header.is_legacy() {
+                    Self::save_legacy_throttle(&header.throttle)?;
+                } else {
+                    Self::save_header(&header)?;
+                }
+                Err(e)
+            }
+        }
+    }
+    
+    /// Set how many consecutive failed attempts wipe the database (None disables)
+    ///
+    /// The limit is covered by the header's MAC, so changing it takes the passphrase.
+    pub fn set_wipe_after(passphrase: &str, key_file: Option<&Path>, wipe_after: Option<u32>) -> Result<(), String> {
+        if !Self::is_encrypted() {
+            return Err("Database is not encrypted".to_string());
+        }
+        if wipe_after == Some(0) {
+            return Err("Wipe limit must be at least 1".to_string());
+        }
+        
+        let (mut header, passphrase_key) = Self::authenticate_passphrase(passphrase, key_file)?;
+        header.wipe_after = wipe_after;
+        header.seal(&passphrase_key);
+        Self::save_header(&header)
+    }
+    
+    /// Irreversibly delete the vault's databases and everything needed to unlock them
+    /// Snapshots and exported backups are left alone
+    fn wipe() -> Result<(), String> {
+        let db_path = Self::get_db_path();
+        let plain_db_path = Self::get_app_dir().join(crate::db::DB_FILE_NAME);
+        let header_path = Self::get_header_path();
+        for path in [
+            plain_db_path.clone(),
+            plain_db_path.with_extension("db-wal"),
+            plain_db_path.with_extension("db-shm"),
+            db_path.clone(),
+            db_path.with_extension("db-wal"),
+            db_path.with_extension("db-shm"),
+            db_path.with_extension("db.tmp"),
+            db_path.with_extension("db.bak"),
+            with_suffix(&header_path, "new"),
+            with_suffix(&header_path, "bak"),
+            header_path,
+            Self::get_journal_path(),
+        ] {
+            remove_if_exists(&path).map_err(|e| format!("Failed to wipe database: {}", e))?;
+        }
+        
+        Self::remove_legacy_files()
     }
     
     /// Simple XOR encryption for storing recovery phrase (encrypted with passphrase-derived key)
-    fn xor_encrypt(data: &str, key: &str) -> String {
-        let key_bytes = key.as_bytes();
+    /// The key stream is the hex form of the key, as in databases encrypted before
+    fn xor_encrypt(data: &str, key: &SecretKey) -> String {
+        let key_hex = crypto::key_to_hex(key);
+        let key_bytes = key_hex.as_bytes();
         let encrypted: Vec<u8> = data.as_bytes()
             .iter()
             .enumerate()
@@ -106,21 +503,33 @@ impl EncryptedDb {
     }
     
     /// Simple XOR decryption
-    fn xor_decrypt(encrypted_hex: &str, key: &str) -> Result<String, String> {
+    fn xor_decrypt(encrypted_hex: &str, key: &SecretKey) -> Result<SecretString, String> {
         let encrypted = hex::decode(encrypted_hex)
             .map_err(|e| format!("Failed to decode: {}", e))?;
-        let key_bytes = key.as_bytes();
+        let key_hex = crypto::key_to_hex(key);
+        let key_bytes = key_hex.as_bytes();
         let decrypted: Vec<u8> = encrypted
             .iter()
             .enumerate()
             .map(|(i, b)| b ^ key_bytes[i % key_bytes.len()])
             .collect();
         String::from_utf8(decrypted)
-            .map_err(|e| format!("Failed to decrypt: {}", e))
+            .map(Zeroizing::new)
+            .map_err(|e| {
+                // Wipe the rejected bytes as well
+                let mut bytes = e.into_bytes();
+                bytes.zeroize();
+                "Failed to decrypt".to_string()
+            })
+    }
+    
+    /// SQLCipher raw key literal (`"x'<hex>'"`) for PRAGMA key / ATTACH ... KEY
+    fn key_literal(key: &SecretKey) -> SecretString {
+        Zeroizing::new(format!("\"x'{}'\"", crypto::key_to_hex(key).as_str()))
     }
     
     /// Open an encrypted database with the master key
-    fn open_with_key(hex_key: &str) -> Result<Self, String> {
+    fn open_with_key(master_key: &SecretKey) -> Result<Self, String> {
         let db_path = Self::get_db_path();
         
         let conn = Connection::open_with_flags(
@@ -130,13 +539,18 @@ impl EncryptedDb {
         .map_err(|e| format!("Failed to open database: {}", e))?;
         
         // Set the encryption key
-        conn.execute_batch(&format!("PRAGMA key = \"x'{}'\";", hex_key))
+        let pragma = Zeroizing::new(format!("PRAGMA key = {};", Self::key_literal(master_key).as_str()));
+        conn.execute_batch(&pragma)
             .map_err(|e| format!("Failed to set encryption key: {}", e))?;
         
         // Verify we can read the database
         conn.execute_batch("SELECT count(*) FROM sqlite_master;")
             .map_err(|_| "Invalid passphrase or corrupted database".to_string())?;
         
+        // SQLite only enforces FOREIGN KEY clauses when asked to, per connection
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .map_err(|e| format!("Failed to enable foreign keys: {}", e))?;
+        
         Ok(Self {
             conn: Arc::new(Mutex::new(conn)),
             _db_path: db_path,
@@ -144,42 +558,24 @@ impl EncryptedDb {
         })
     }
     
-    /// Open with passphrase
+    /// Open with passphrase (and key file, if one was set up)
     /// Decrypts the stored recovery phrase, then uses it to derive the master key
-    pub fn open_with_passphrase(passphrase: &str) -> Result<Self, String> {
-        // Get salt
-        let salt = Self::get_salt().ok_or("No encryption data found")?;
-        
-        // Derive key from passphrase
-        let passphrase_key = crypto::derive_key_from_passphrase(passphrase, &salt)?;
-        
-        // Get and decrypt the stored recovery phrase
-        let encrypted_recovery = Self::get_encrypted_recovery()
-            .ok_or("No encrypted recovery phrase found")?;
-        
-        let recovery_phrase = Self::xor_decrypt(&encrypted_recovery, &passphrase_key)
-            .map_err(|_| "Invalid passphrase")?;
-        
-        // Validate it's a real recovery phrase
-        if !crypto::validate_recovery_phrase(&recovery_phrase) {
-            return Err("Invalid passphrase".to_string());
-        }
+    pub fn open_with_passphrase(passphrase: &str, key_file: Option<&Path>) -> Result<Self, String> {
+        // Get and decrypt the stored recovery phrase (throttled)
+        let (recovery_phrase, bip39_passphrase) = Self::unlock_recovery_with_passphrase(passphrase, key_file)?;
         
         // Derive master key from recovery phrase
-        let master_key = crypto::derive_key_from_recovery(&recovery_phrase)?;
+        let master_key = crypto::derive_key_from_recovery(&recovery_phrase, &bip39_passphrase)?;
         
         // Open with master key
         Self::open_with_key(&master_key)
     }
     
     /// Open with recovery phrase directly
-    pub fn open_with_recovery(recovery_phrase: &str) -> Result<Self, String> {
+    /// `bip39_passphrase` must match the one chosen at setup (empty if none)
+    pub fn open_with_recovery(recovery_phrase: &str, bip39_passphrase: &str) -> Result<Self, String> {
         // Normalize
-        let normalized = recovery_phrase
-            .to_lowercase()
-            .split_whitespace()
-            .collect::<Vec<&str>>()
-            .join(" ");
+        let normalized = crypto::normalize_recovery_phrase(recovery_phrase);
         
         // Validate
         if !crypto::validate_recovery_phrase(&normalized) {
@@ -187,145 +583,324 @@ impl EncryptedDb {
         }
         
         // Derive master key
-        let master_key = crypto::derive_key_from_recovery(&normalized)?;
+        let master_key = crypto::derive_key_from_recovery(&normalized, bip39_passphrase)?;
         
         // Open with master key
         Self::open_with_key(&master_key)
     }
     
+    /// Open with SLIP-39 shares of the recovery phrase
+    /// Any threshold-sized subset of the shares rebuilds the phrase
+    pub fn open_with_shares(shares: &[SecretString], bip39_passphrase: &str) -> Result<Self, String> {
+        let recovery_phrase = crypto::combine_recovery_shares(shares)?;
+        
+        Self::open_with_recovery(&recovery_phrase, bip39_passphrase)
+    }
+    
     /// Encrypt an existing unencrypted database
-    pub fn encrypt_database(passphrase: &str, recovery_phrase: &str) -> Result<(), String> {
+    /// If `key_file` is given, it will be required alongside the passphrase
+    pub fn encrypt_database(
+        passphrase: &str,
+        recovery_phrase: &str,
+        bip39_passphrase: &str,
+        key_file: Option<&Path>,
+    ) -> Result<(), String> {
         let db_path = Self::get_db_path();
-        let temp_path = db_path.with_extension("db.tmp");
         
         // Normalize recovery phrase
-        let normalized_recovery = recovery_phrase
-            .to_lowercase()
-            .split_whitespace()
-            .collect::<Vec<&str>>()
-            .join(" ");
+        let normalized_recovery = crypto::normalize_recovery_phrase(recovery_phrase);
         
         // Derive MASTER KEY from recovery phrase
-        let master_key = crypto::derive_key_from_recovery(&normalized_recovery)?;
+        let master_key = crypto::derive_key_from_recovery(&normalized_recovery, bip39_passphrase)?;
         
         // Generate salt for passphrase
         let salt = crypto::generate_salt();
         
-        // Derive passphrase key
-        let passphrase_key = crypto::derive_key_from_passphrase(passphrase, &salt)?;
+        // Derive passphrase key, mixing in the key file if one is used
+        let key_file_bytes = key_file.map(Self::read_key_file).transpose()?;
+        let passphrase_key = crypto::derive_key_from_passphrase(
+            passphrase,
+            &salt,
+            key_file_bytes.as_deref().map(|b| b.as_slice()),
+            &crypto::KdfParams::default(),
+        )?;
         
-        // Encrypt the recovery phrase with the passphrase key
-        let encrypted_recovery = Self::xor_encrypt(&normalized_recovery, &passphrase_key);
+        // Encrypt the recovery phrase (and BIP39 passphrase) with the passphrase key
+        let payload = Self::build_recovery_payload(&normalized_recovery, bip39_passphrase);
+        let encrypted_recovery = Self::xor_encrypt(&payload, &passphrase_key);
         
-        // Open the existing unencrypted database
-        let conn = Connection::open(&db_path)
-            .map_err(|e| format!("Failed to open database: {}", e))?;
+        let mut header = EncryptionHeader::new(
+            salt,
+            encrypted_recovery,
+            crypto::recovery_phrase_word_count(&normalized_recovery),
+            !bip39_passphrase.is_empty(),
+            key_file.is_some(),
+            unix_now(),
+        );
+        header.seal(&passphrase_key);
         
-        // Export to a new encrypted database using MASTER KEY
-        conn.execute_batch(&format!(
-            "ATTACH DATABASE '{}' AS encrypted KEY \"x'{}'\";
-             SELECT sqlcipher_export('encrypted');
-             DETACH DATABASE encrypted;",
-            temp_path.display(),
-            master_key
-        ))
-        .map_err(|e| format!("Failed to encrypt database: {}", e))?;
+        Self::convert(Some(&header), Some(&master_key), |temp_path| {
+            // Open the existing unencrypted database
+            let conn = Connection::open(&db_path)
+                .map_err(|e| format!("Failed to open database: {}", e))?;
+            let tables = Self::count_tables(&conn)?;
+            
+            // Export to a new encrypted database using MASTER KEY
+            let export = Zeroizing::new(format!(
+                "ATTACH DATABASE '{}' AS encrypted KEY {};
+                 SELECT sqlcipher_export('encrypted');
+                 DETACH DATABASE encrypted;",
+                temp_path.display(),
+                Self::key_literal(&master_key).as_str()
+            ));
+            conn.execute_batch(&export)
+                .map_err(|e| format!("Failed to encrypt database: {}", e))?;
+            
+            Ok(tables)
+        })
+    }
+    
+    /// Change passphrase (re-encrypts the recovery phrase with new passphrase)
+    /// The key file requirement, if any, is kept with the same key file
+    pub fn change_passphrase(
+        old_passphrase: &str,
+        new_passphrase: &str,
+        key_file: Option<&Path>,
+    ) -> Result<(), String> {
+        // First, decrypt recovery phrase with old passphrase
+        let (recovery_phrase, bip39_passphrase) = Self::unlock_recovery_with_passphrase(old_passphrase, key_file)
+            .map_err(|e| if e == "Invalid passphrase" { "Invalid current passphrase".to_string() } else { e })?;
         
-        drop(conn);
+        // Generate new salt and encrypt recovery with new passphrase
+        let mut header = Self::load_header()?.ok_or("No encryption data found")?;
+        let key_file_bytes = match key_file {
+            Some(path) if header.requires_key_file() => Some(Self::read_key_file(path)?),
+            _ => None,
+        };
         
-        // Replace original with encrypted version
-        std::fs::rename(&temp_path, &db_path)
-            .map_err(|e| format!("Failed to replace database: {}", e))?;
+        // Re-wrapping is also when older KDF settings get upgraded
+        header.kdf.params = crypto::KdfParams::default();
+        header.kdf.salt = crypto::generate_salt();
+        let new_key = crypto::derive_key_from_passphrase(
+            new_passphrase,
+            &header.kdf.salt,
+            key_file_bytes.as_deref().map(|b| b.as_slice()),
+            &header.kdf.params,
+        )?;
+        let payload = Self::build_recovery_payload(&recovery_phrase, &bip39_passphrase);
+        header.wrapped_key.data = Self::xor_encrypt(&payload, &new_key);
+        header.seal(&new_key);
         
-        // Save metadata
-        Self::save_salt(&salt)?;
-        Self::save_encrypted_recovery(&encrypted_recovery)?;
-        Self::set_encrypted_status(true)?;
+        // Salt and wrapped phrase are replaced together in one write
+        Self::save_header(&header)
+    }
+    
+    /// Remove encryption from database
+    pub fn decrypt_database(passphrase: &str, key_file: Option<&Path>) -> Result<(), String> {
+        let db_path = Self::get_db_path();
         
-        Ok(())
+        // Get recovery phrase via passphrase
+        let (recovery_phrase, bip39_passphrase) = Self::unlock_recovery_with_passphrase(passphrase, key_file)?;
+        
+        // Derive master key
+        let master_key = crypto::derive_key_from_recovery(&recovery_phrase, &bip39_passphrase)?;
+        
+        Self::convert(None, None, |temp_path| {
+            // Open encrypted database
+            let conn = Connection::open(&db_path)
+                .map_err(|e| format!("Failed to open database: {}", e))?;
+            
+            let pragma = Zeroizing::new(format!("PRAGMA key = {};", Self::key_literal(&master_key).as_str()));
+            conn.execute_batch(&pragma)
+                .map_err(|e| format!("Failed to set key: {}", e))?;
+            
+            // Verify
+            let tables = Self::count_tables(&conn)
+                .map_err(|_| "Invalid passphrase".to_string())?;
+            
+            // Export to unencrypted
+            conn.execute_batch(&format!(
+                "ATTACH DATABASE '{}' AS plaintext KEY '';
+                 SELECT sqlcipher_export('plaintext');
+                 DETACH DATABASE plaintext;",
+                temp_path.display()
+            ))
+            .map_err(|e| format!("Failed to decrypt database: {}", e))?;
+            
+            Ok(tables)
+        })
     }
     
-    /// Change passphrase (re-encrypts the recovery phrase with new passphrase)
-    pub fn change_passphrase(old_passphrase: &str, new_passphrase: &str) -> Result<(), String> {
-        // First, decrypt recovery phrase with old passphrase
-        let salt = Self::get_salt().ok_or("No encryption data found")?;
-        let old_key = crypto::derive_key_from_passphrase(old_passphrase, &salt)?;
+    /// Convert the database through a journaled, verified temp copy
+    ///
+    /// `export` writes the converted copy to the path it's given and returns
+    /// the source's table count. The copy is opened with `key` (None for
+    /// plaintext) and checked before and after it replaces the original.
+    /// `new_header` replaces the encryption header (None removes it). Any
+    /// failure rolls back to the original files.
+    fn convert(
+        new_header: Option<&EncryptionHeader>,
+        key: Option<&SecretKey>,
+        export: impl FnOnce(&Path) -> Result<i64, String>,
+    ) -> Result<(), String> {
+        let db_path = Self::get_db_path();
+        let temp_path = db_path.with_extension("db.tmp");
+        let header_path = Self::get_header_path();
         
-        let encrypted_recovery = Self::get_encrypted_recovery()
-            .ok_or("No encrypted recovery phrase found")?;
+        // Clear out whatever an earlier attempt left behind
+        Self::recover_interrupted_conversion()?;
         
-        let recovery_phrase = Self::xor_decrypt(&encrypted_recovery, &old_key)
-            .map_err(|_| "Invalid current passphrase")?;
+        let had_header = header_path.exists();
+        Self::write_journal(ConversionStage::Exporting, had_header)?;
         
-        if !crypto::validate_recovery_phrase(&recovery_phrase) {
-            return Err("Invalid current passphrase".to_string());
+        let result = (|| {
+            let tables = export(&temp_path)?;
+            sync_file(&temp_path)?;
+            Self::verify_copy(&temp_path, key, tables)?;
+            
+            if let Some(header) = new_header {
+                write_synced(&with_suffix(&header_path, "new"), header.to_json())?;
+            }
+            
+            Self::write_journal(ConversionStage::Swapping, had_header)?;
+            Self::swap_in_converted()?;
+            Self::verify_copy(&db_path, key, tables)?;
+            
+            Self::write_journal(ConversionStage::Committed, had_header)
+        })();
+        
+        // Commits on success, rolls back on failure
+        let recovered = Self::recover_interrupted_conversion();
+        result?;
+        recovered
+    }
+    
+    /// Move the converted copy and header into place, keeping the originals as `.bak`
+    fn swap_in_converted() -> Result<(), String> {
+        let db_path = Self::get_db_path();
+        let header_path = Self::get_header_path();
+        let new_header_path = with_suffix(&header_path, "new");
+        
+        std::fs::rename(&db_path, db_path.with_extension("db.bak"))
+            .map_err(|e| format!("Failed to back up database: {}", e))?;
+        
+        if header_path.exists() {
+            std::fs::rename(&header_path, with_suffix(&header_path, "bak"))
+                .map_err(|e| format!("Failed to back up encryption header: {}", e))?;
         }
         
-        // Generate new salt and encrypt recovery with new passphrase
-        let new_salt = crypto::generate_salt();
-        let new_key = crypto::derive_key_from_passphrase(new_passphrase, &new_salt)?;
-        let new_encrypted_recovery = Self::xor_encrypt(&recovery_phrase, &new_key);
+        std::fs::rename(db_path.with_extension("db.tmp"), &db_path)
+            .map_err(|e| format!("Failed to replace database: {}", e))?;
         
-        // Save new data
-        Self::save_salt(&new_salt)?;
-        Self::save_encrypted_recovery(&new_encrypted_recovery)?;
+        if new_header_path.exists() {
+            std::fs::rename(&new_header_path, &header_path)
+                .map_err(|e| format!("Failed to save encryption header: {}", e))?;
+        }
         
+        sync_dir(&Self::get_app_dir());
         Ok(())
     }
     
-    /// Remove encryption from database
-    pub fn decrypt_database(passphrase: &str) -> Result<(), String> {
-        let db_path = Self::get_db_path();
-        let temp_path = db_path.with_extension("db.tmp");
+    /// Record the current conversion stage (and whether a header existed before)
+    fn write_journal(stage: ConversionStage, had_header: bool) -> Result<(), String> {
+        write_atomic(
+            &Self::get_journal_path(),
+            &format!("stage={}\nhad_header={}\n", stage.as_str(), if had_header { 1 } else { 0 }),
+        )
+    }
+    
+    /// Read the conversion journal, if a conversion was in progress
+    fn read_journal() -> Option<(ConversionStage, bool)> {
+        let content = std::fs::read_to_string(Self::get_journal_path()).ok()?;
         
-        // Get recovery phrase via passphrase
-        let salt = Self::get_salt().ok_or("No encryption data found")?;
-        let passphrase_key = crypto::derive_key_from_passphrase(passphrase, &salt)?;
+        // An unreadable journal is treated as an early stage: roll back, keep the header
+        let mut stage = ConversionStage::Exporting;
+        let mut had_header = true;
         
-        let encrypted_recovery = Self::get_encrypted_recovery()
-            .ok_or("No encrypted recovery phrase found")?;
+        for line in content.lines() {
+            match line.trim().split_once('=') {
+                Some(("stage", "swapping")) => stage = ConversionStage::Swapping,
+                Some(("stage", "committed")) => stage = ConversionStage::Committed,
+                Some(("had_header", value)) => had_header = value != "0",
+                _ => {}
+            }
+        }
         
-        let recovery_phrase = Self::xor_decrypt(&encrypted_recovery, &passphrase_key)
-            .map_err(|_| "Invalid passphrase")?;
+        Some((stage, had_header))
+    }
+    
+    /// Finish or roll back a conversion interrupted by a crash
+    ///
+    /// Called on startup and before every conversion. A committed conversion
+    /// only needs its backups removed; anything earlier is rolled back to the
+    /// original database and header. Leftover temp files are removed either way.
+    pub fn recover_interrupted_conversion() -> Result<(), String> {
+        let db_path = Self::get_db_path();
+        let backup_path = db_path.with_extension("db.bak");
+        let header_path = Self::get_header_path();
+        let header_backup_path = with_suffix(&header_path, "bak");
         
-        if !crypto::validate_recovery_phrase(&recovery_phrase) {
-            return Err("Invalid passphrase".to_string());
+        match Self::read_journal() {
+            Some((ConversionStage::Committed, _)) => {
+                remove_if_exists(&backup_path)?;
+                remove_if_exists(&header_backup_path)?;
+                Self::remove_legacy_files()?;
+            }
+            Some((_, had_header)) => {
+                if backup_path.exists() {
+                    std::fs::rename(&backup_path, &db_path)
+                        .map_err(|e| format!("Failed to restore database backup: {}", e))?;
+                }
+                
+                if header_backup_path.exists() {
+                    std::fs::rename(&header_backup_path, &header_path)
+                        .map_err(|e| format!("Failed to restore encryption header: {}", e))?;
+                } else if !had_header {
+                    remove_if_exists(&header_path)?;
+                }
+            }
+            None => {}
         }
         
-        // Derive master key
-        let master_key = crypto::derive_key_from_recovery(&recovery_phrase)?;
-        
-        // Open encrypted database
-        let conn = Connection::open(&db_path)
-            .map_err(|e| format!("Failed to open database: {}", e))?;
+        remove_if_exists(&db_path.with_extension("db.tmp"))?;
+        remove_if_exists(&with_suffix(&header_path, "new"))?;
+        sync_dir(&Self::get_app_dir());
         
-        conn.execute_batch(&format!("PRAGMA key = \"x'{}'\";", master_key))
-            .map_err(|e| format!("Failed to set key: {}", e))?;
+        // Only forget the journal once the files above are settled
+        remove_if_exists(&Self::get_journal_path())?;
+        sync_dir(&Self::get_app_dir());
         
-        // Verify
-        conn.execute_batch("SELECT count(*) FROM sqlite_master;")
-            .map_err(|_| "Invalid passphrase".to_string())?;
-        
-        // Export to unencrypted
-        conn.execute_batch(&format!(
-            "ATTACH DATABASE '{}' AS plaintext KEY '';
-             SELECT sqlcipher_export('plaintext');
-             DETACH DATABASE plaintext;",
-            temp_path.display()
-        ))
-        .map_err(|e| format!("Failed to decrypt database: {}", e))?;
+        Ok(())
+    }
+    
+    fn count_tables(conn: &Connection) -> Result<i64, String> {
+        conn.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get(0))
+            .map_err(|e| format!("Failed to read database: {}", e))
+    }
+    
+    /// Check a converted copy opens with its key and is complete
+    fn verify_copy(path: &Path, key: Option<&SecretKey>, expected_tables: i64) -> Result<(), String> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|e| format!("Failed to open converted database: {}", e))?;
         
-        drop(conn);
+        if let Some(key) = key {
+            let pragma = Zeroizing::new(format!("PRAGMA key = {};", Self::key_literal(key).as_str()));
+            conn.execute_batch(&pragma)
+                .map_err(|e| format!("Failed to set key: {}", e))?;
+        }
         
-        // Replace
-        std::fs::rename(&temp_path, &db_path)
-            .map_err(|e| format!("Failed to replace database: {}", e))?;
+        let tables = Self::count_tables(&conn)
+            .map_err(|_| "Converted database could not be read".to_string())?;
+        if tables != expected_tables {
+            return Err("Converted database is incomplete".to_string());
+        }
         
-        // Remove encryption files
-        let app_dir = Self::get_app_dir();
-        let _ = std::fs::remove_file(app_dir.join("salt.key"));
-        let _ = std::fs::remove_file(app_dir.join("recovery.enc"));
-        let _ = std::fs::remove_file(Self::get_meta_path());
+        let check: String = conn
+            .query_row("PRAGMA quick_check", [], |row| row.get(0))
+            .map_err(|e| format!("Failed to check converted database: {}", e))?;
+        if check != "ok" {
+            return Err(format!("Converted database failed integrity check: {}", check));
+        }
         
         Ok(())
     }
@@ -379,7 +954,7 @@ impl EncryptedDb {
                 confirmed INTEGER DEFAULT 0,
                 block_height INTEGER,
                 timestamp INTEGER,
-                category TEXT,
+                category_id INTEGER REFERENCES categories(id),
                 note TEXT,
                 fiat_value REAL,
                 fiat_currency TEXT,
@@ -422,4 +997,296 @@ impl EncryptedDb {
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, AppDir};
+
+    #[test]
+    fn test_unlock_delay_grows_exponentially() {
+        assert_eq!(unlock_delay_secs(0), 0);
+        assert_eq!(unlock_delay_secs(FREE_UNLOCK_ATTEMPTS - 1), 0);
+        assert_eq!(unlock_delay_secs(FREE_UNLOCK_ATTEMPTS), 1);
+        assert_eq!(unlock_delay_secs(FREE_UNLOCK_ATTEMPTS + 3), 8);
+        assert_eq!(unlock_delay_secs(u32::MAX), MAX_UNLOCK_DELAY_SECS);
+    }
+
+    fn throttle() -> crate::encryption_header::ThrottleState {
+        EncryptedDb::load_header().unwrap().unwrap().throttle
+    }
+
+    #[test]
+    fn test_failed_attempts_persist_and_reset() {
+        let _dir = AppDir::new();
+        test_support::encrypt_active_vault("passphrase");
+
+        assert!(EncryptedDb::open_with_passphrase("wrong", None).is_err());
+        assert_eq!(throttle().failed_attempts, 1);
+        assert!(throttle().last_failed_at > 0);
+
+        assert!(EncryptedDb::open_with_passphrase("also wrong", None).is_err());
+        assert_eq!(throttle().failed_attempts, 2);
+
+        assert!(EncryptedDb::open_with_passphrase("passphrase", None).is_ok());
+        assert_eq!(throttle().failed_attempts, 0);
+        assert_eq!(throttle().last_failed_at, 0);
+    }
+
+    #[test]
+    fn test_throttled_attempt_is_refused_before_checking() {
+        let _dir = AppDir::new();
+        test_support::encrypt_active_vault("passphrase");
+
+        let mut header = EncryptedDb::load_header().unwrap().unwrap();
+        header.throttle.failed_attempts = FREE_UNLOCK_ATTEMPTS + 3;
+        header.throttle.last_failed_at = unix_now();
+        EncryptedDb::save_header(&header).unwrap();
+
+        // Even the right passphrase has to wait, and waiting costs no attempt
+        let error = EncryptedDb::open_with_passphrase("passphrase", None).err().unwrap();
+        assert!(error.starts_with("Too many failed attempts. Try again in"), "{}", error);
+        assert_eq!(throttle().failed_attempts, FREE_UNLOCK_ATTEMPTS + 3);
+
+        header.throttle.last_failed_at = unix_now() - 8;
+        EncryptedDb::save_header(&header).unwrap();
+        assert!(EncryptedDb::open_with_passphrase("passphrase", None).is_ok());
+        assert_eq!(throttle().failed_attempts, 0);
+    }
+
+    #[test]
+    fn test_wipe_after_consecutive_failures() {
+        let _dir = AppDir::new();
+        test_support::encrypt_active_vault("passphrase");
+
+        let plain_db_path = EncryptedDb::get_app_dir().join(crate::db::DB_FILE_NAME);
+        std::fs::write(&plain_db_path, "plain").unwrap();
+
+        assert_eq!(
+            EncryptedDb::set_wipe_after("passphrase", None, Some(0)).unwrap_err(),
+            "Wipe limit must be at least 1"
+        );
+        assert_eq!(
+            EncryptedDb::set_wipe_after("wrong", None, Some(3)).unwrap_err(),
+            "Invalid passphrase"
+        );
+        EncryptedDb::set_wipe_after("passphrase", None, Some(3)).unwrap();
+
+        // A success in between starts the count again
+        assert!(EncryptedDb::open_with_passphrase("wrong", None).is_err());
+        assert!(EncryptedDb::open_with_passphrase("wrong", None).is_err());
+        assert!(EncryptedDb::open_with_passphrase("passphrase", None).is_ok());
+        assert!(EncryptedDb::open_with_passphrase("wrong", None).is_err());
+        assert!(EncryptedDb::open_with_passphrase("wrong", None).is_err());
+
+        assert_eq!(throttle().failed_attempts, 2);
+        assert_eq!(EncryptedDb::load_header().unwrap().unwrap().wipe_after, Some(3));
+        assert!(EncryptedDb::is_encrypted());
+        assert!(EncryptedDb::get_header_path().exists());
+
+        assert_eq!(
+            EncryptedDb::open_with_passphrase("wrong", None).err().unwrap(),
+            "Too many failed attempts. The vault's databases have been wiped (snapshots and backups are kept)"
+        );
+        assert!(!EncryptedDb::db_exists());
+        assert!(!EncryptedDb::get_header_path().exists());
+        assert!(!plain_db_path.exists());
+    }
+
+    #[test]
+    fn test_wipe_limit_changed_without_passphrase_fails_unlock() {
+        let _dir = AppDir::new();
+        test_support::encrypt_active_vault("passphrase");
+        EncryptedDb::set_wipe_after("passphrase", None, Some(10)).unwrap();
+
+        // Lifting the limit by editing the file (and fixing up the checksum)
+        let mut header = EncryptedDb::load_header().unwrap().unwrap();
+        header.wipe_after = None;
+        std::fs::write(EncryptedDb::get_header_path(), header.to_json()).unwrap();
+
+        assert_eq!(
+            EncryptedDb::open_with_passphrase("passphrase", None).err().unwrap(),
+            "Encryption header has been modified"
+        );
+    }
+
+    #[test]
+    fn test_legacy_header_is_sealed_on_first_unlock() {
+        let _dir = AppDir::new();
+        test_support::encrypt_active_vault("passphrase");
+
+        // Turn the header back into the files older versions wrote
+        let header = EncryptedDb::load_header().unwrap().unwrap();
+        let meta_path = EncryptedDb::get_app_dir().join(LEGACY_META_FILE);
+        std::fs::write(
+            &meta_path,
+            format!("encrypted\nwords=12\nsalt={}\nrecovery={}\n", header.kdf.salt, header.wrapped_key.data),
+        )
+        .unwrap();
+        std::fs::remove_file(EncryptedDb::get_header_path()).unwrap();
+
+        // Failures are counted without saving an unsealed header
+        assert!(EncryptedDb::open_with_passphrase("wrong", None).is_err());
+        assert!(!EncryptedDb::get_header_path().exists());
+        assert!(std::fs::read_to_string(&meta_path).unwrap().contains("failed_attempts=1"));
+
+        assert!(EncryptedDb::open_with_passphrase("passphrase", None).is_ok());
+        assert!(!meta_path.exists());
+        let sealed = EncryptedDb::load_header().unwrap().unwrap();
+        assert!(sealed.mac.is_some());
+        assert_eq!(sealed.throttle.failed_attempts, 0);
+    }
+
+    #[test]
+    fn test_stripped_mac_fails_unlock() {
+        let _dir = AppDir::new();
+        test_support::encrypt_active_vault("passphrase");
+
+        let mut header = EncryptedDb::load_header().unwrap().unwrap();
+        header.mac = None;
+        std::fs::write(EncryptedDb::get_header_path(), header.to_json()).unwrap();
+
+        assert_eq!(
+            EncryptedDb::open_with_passphrase("passphrase", None).err().unwrap(),
+            "Encryption header has been modified"
+        );
+    }
+
+    /// Files a conversion works with, in the active vault of an `AppDir`
+    struct ConversionFiles {
+        db: PathBuf,
+        db_tmp: PathBuf,
+        db_bak: PathBuf,
+        header: PathBuf,
+        header_new: PathBuf,
+        header_bak: PathBuf,
+    }
+
+    impl ConversionFiles {
+        fn new() -> Self {
+            let db = EncryptedDb::get_db_path();
+            let header = EncryptedDb::get_header_path();
+            Self {
+                db_tmp: db.with_extension("db.tmp"),
+                db_bak: db.with_extension("db.bak"),
+                db,
+                header_new: with_suffix(&header, "new"),
+                header_bak: with_suffix(&header, "bak"),
+                header,
+            }
+        }
+
+        fn read(path: &Path) -> String {
+            std::fs::read_to_string(path).unwrap()
+        }
+
+        /// Everything left in the vault directory
+        fn listing() -> Vec<String> {
+            let mut names: Vec<String> = std::fs::read_dir(EncryptedDb::get_app_dir())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    #[test]
+    fn test_recover_while_exporting() {
+        let _dir = AppDir::new();
+        let files = ConversionFiles::new();
+        std::fs::write(&files.db, "original").unwrap();
+        std::fs::write(&files.db_tmp, "converted").unwrap();
+        std::fs::write(&files.header_new, "new header").unwrap();
+        EncryptedDb::write_journal(ConversionStage::Exporting, false).unwrap();
+
+        EncryptedDb::recover_interrupted_conversion().unwrap();
+
+        assert_eq!(ConversionFiles::read(&files.db), "original");
+        assert_eq!(ConversionFiles::listing(), vec![DB_FILE_NAME]);
+    }
+
+    #[test]
+    fn test_recover_while_swapping() {
+        let _dir = AppDir::new();
+        let files = ConversionFiles::new();
+
+        // Originals moved aside, the converted copy not yet in place
+        std::fs::write(&files.db_bak, "original").unwrap();
+        std::fs::write(&files.header_bak, "old header").unwrap();
+        std::fs::write(&files.db_tmp, "converted").unwrap();
+        std::fs::write(&files.header_new, "new header").unwrap();
+        EncryptedDb::write_journal(ConversionStage::Swapping, true).unwrap();
+
+        EncryptedDb::recover_interrupted_conversion().unwrap();
+
+        assert_eq!(ConversionFiles::read(&files.db), "original");
+        assert_eq!(ConversionFiles::read(&files.header), "old header");
+        assert_eq!(ConversionFiles::listing(), vec![HEADER_FILE_NAME, DB_FILE_NAME]);
+    }
+
+    #[test]
+    fn test_recover_after_swapping_before_commit() {
+        let _dir = AppDir::new();
+        let files = ConversionFiles::new();
+
+        // Encrypting: everything in place, but the copy wasn't verified yet
+        std::fs::write(&files.db_bak, "original").unwrap();
+        std::fs::write(&files.db, "converted").unwrap();
+        std::fs::write(&files.header, "new header").unwrap();
+        EncryptedDb::write_journal(ConversionStage::Swapping, false).unwrap();
+
+        EncryptedDb::recover_interrupted_conversion().unwrap();
+
+        assert_eq!(ConversionFiles::read(&files.db), "original");
+        assert_eq!(ConversionFiles::listing(), vec![DB_FILE_NAME]);
+    }
+
+    #[test]
+    fn test_recover_committed() {
+        let _dir = AppDir::new();
+        let files = ConversionFiles::new();
+        std::fs::write(&files.db_bak, "original").unwrap();
+        std::fs::write(&files.header_bak, "old header").unwrap();
+        std::fs::write(&files.db, "converted").unwrap();
+        std::fs::write(&files.header, "new header").unwrap();
+        std::fs::write(EncryptedDb::get_app_dir().join(LEGACY_SALT_FILE), "salt").unwrap();
+        EncryptedDb::write_journal(ConversionStage::Committed, true).unwrap();
+
+        EncryptedDb::recover_interrupted_conversion().unwrap();
+
+        assert_eq!(ConversionFiles::read(&files.db), "converted");
+        assert_eq!(ConversionFiles::read(&files.header), "new header");
+        assert_eq!(ConversionFiles::listing(), vec![HEADER_FILE_NAME, DB_FILE_NAME]);
+    }
+
+    #[test]
+    fn test_recover_without_journal_leaves_files_alone() {
+        let _dir = AppDir::new();
+        let files = ConversionFiles::new();
+        std::fs::write(&files.db, "current").unwrap();
+        std::fs::write(&files.header, "header").unwrap();
+
+        EncryptedDb::recover_interrupted_conversion().unwrap();
+
+        assert_eq!(ConversionFiles::read(&files.db), "current");
+        assert_eq!(ConversionFiles::listing(), vec![HEADER_FILE_NAME, DB_FILE_NAME]);
+    }
+
+    #[test]
+    fn test_failed_conversion_rolls_back() {
+        let _dir = AppDir::new();
+        let files = ConversionFiles::new();
+        std::fs::write(&files.db, "original").unwrap();
+
+        let result = EncryptedDb::convert(None, None, |temp_path| {
+            std::fs::write(temp_path, "half written").unwrap();
+            Err("Export failed".to_string())
+        });
+
+        assert_eq!(result.unwrap_err(), "Export failed");
+        assert_eq!(ConversionFiles::read(&files.db), "original");
+        assert_eq!(ConversionFiles::listing(), vec![DB_FILE_NAME]);
+    }
+}
@@ -0,0 +1,356 @@
+//! Encryption header (`encryption.json`)
+//!
+//! One self-describing file holds everything needed to unlock an encrypted
+//! database apart from the secrets themselves: format version, KDF
+//! parameters and salt, the wrapped recovery payload, creation date and the
+//! unlock methods that are set up.
+//!
+//! Integrity:
+//! - `checksum` (SHA-256 of the header) catches corruption on every read
+//! - `mac` (HMAC-SHA256 keyed with the passphrase key) covers everything except
+//!   the attempt counters, and is checked whenever the passphrase unlocks
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::{KdfParams, SecretKey};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Newest header format this build understands
+pub const HEADER_FORMAT_VERSION: u32 = 1;
+
+/// File name of the header, next to the database
+pub const HEADER_FILE_NAME: &str = "encryption.json";
+
+/// Passphrase KDF used by every header so far
+pub const KDF_ARGON2ID: &str = "argon2id";
+
+/// Recovery payload XORed with the hex form of the passphrase key
+pub const WRAP_XOR_HEX_V1: &str = "xor-hex-v1";
+
+/// Ways the database can be unlocked
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlockMethod {
+    Passphrase,
+    /// The passphrase only works together with the key file
+    KeyFile,
+    RecoveryPhrase,
+    RecoveryShares,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdfHeader {
+    pub algorithm: String,
+    #[serde(flatten)]
+    pub params: KdfParams,
+    pub salt: String,
+}
+
+/// The recovery payload, wrapped with the passphrase key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub algorithm: String,
+    pub data: String,
+}
+
+/// Failed passphrase attempts, updated without the key so not covered by the MAC
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ThrottleState {
+    pub failed_attempts: u32,
+    pub last_failed_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionHeader {
+    pub format_version: u32,
+    /// Unix time the database was encrypted (0 if unknown)
+    pub created_at: u64,
+    pub kdf: KdfHeader,
+    pub wrapped_key: WrappedKey,
+    pub word_count: usize,
+    pub has_bip39_passphrase: bool,
+    pub unlock_methods: Vec<UnlockMethod>,
+    /// Consecutive failed attempts that wipe the database (None never does)
+    pub wipe_after: Option<u32>,
+    /// None only on a header just migrated from older versions, until it's sealed
+    pub mac: Option<String>,
+    pub throttle: ThrottleState,
+    /// Built from the files older versions wrote, which had no MAC
+    #[serde(skip)]
+    legacy: bool,
+}
+
+/// On-disk layout: the header plus a checksum over it
+#[derive(Serialize, Deserialize)]
+struct HeaderFile {
+    header: EncryptionHeader,
+    checksum: String,
+}
+
+impl EncryptionHeader {
+    pub fn new(
+        salt: String,
+        wrapped_recovery: String,
+        word_count: usize,
+        has_bip39_passphrase: bool,
+        requires_key_file: bool,
+        created_at: u64,
+    ) -> Self {
+        let mut unlock_methods = vec![UnlockMethod::Passphrase];
+        if requires_key_file {
+            unlock_methods.push(UnlockMethod::KeyFile);
+        }
+        unlock_methods.push(UnlockMethod::RecoveryPhrase);
+        unlock_methods.push(UnlockMethod::RecoveryShares);
+
+        Self {
+            format_version: HEADER_FORMAT_VERSION,
+            created_at,
+            kdf: KdfHeader {
+                algorithm: KDF_ARGON2ID.to_string(),
+                params: KdfParams::default(),
+                salt,
+            },
+            wrapped_key: WrappedKey {
+                algorithm: WRAP_XOR_HEX_V1.to_string(),
+                data: wrapped_recovery,
+            },
+            word_count,
+            has_bip39_passphrase,
+            unlock_methods,
+            wipe_after: None,
+            mac: None,
+            throttle: ThrottleState::default(),
+            legacy: false,
+        }
+    }
+
+    /// Build a header from the text `encryption.meta` and separate salt /
+    /// recovery files written by older versions
+    pub fn from_legacy(
+        meta: &str,
+        salt: Option<String>,
+        wrapped_recovery: Option<String>,
+        created_at: u64,
+    ) -> Option<Self> {
+        let mut lines = meta.lines();
+        if lines.next().map(|l| l.trim()) != Some("encrypted") {
+            return None;
+        }
+
+        let mut salt = salt;
+        let mut wrapped_recovery = wrapped_recovery;
+        let mut word_count = 12;
+        let mut has_bip39_passphrase = false;
+        let mut requires_key_file = false;
+        let mut throttle = ThrottleState::default();
+        let mut wipe_after = None;
+
+        for line in lines {
+            match line.trim().split_once('=') {
+                Some(("words", value)) => word_count = value.parse().unwrap_or(word_count),
+                Some(("bip39_passphrase", value)) => has_bip39_passphrase = value == "1",
+                Some(("key_file", value)) => requires_key_file = value == "1",
+                Some(("failed_attempts", value)) => throttle.failed_attempts = value.parse().unwrap_or(0),
+                Some(("last_failed_at", value)) => throttle.last_failed_at = value.parse().unwrap_or(0),
+                Some(("wipe_after", value)) => wipe_after = value.parse().ok().filter(|n| *n > 0),
+                Some(("salt", value)) => salt = Some(value.to_string()),
+                Some(("recovery", value)) => wrapped_recovery = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        let mut header = Self::new(
+            salt?.trim().to_string(),
+            wrapped_recovery?.trim().to_string(),
+            word_count,
+            has_bip39_passphrase,
+            requires_key_file,
+            created_at,
+        );
+        header.throttle = throttle;
+        header.wipe_after = wipe_after;
+        header.legacy = true;
+        Some(header)
+    }
+
+    /// Whether this header came from older-format files and hasn't been saved as a header yet
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    pub fn requires_key_file(&self) -> bool {
+        self.unlock_methods.contains(&UnlockMethod::KeyFile)
+    }
+
+    /// Everything the MAC covers, in a fixed order
+    fn authenticated_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            self.format_version,
+            self.created_at,
+            &self.kdf,
+            &self.wrapped_key,
+            self.word_count,
+            self.has_bip39_passphrase,
+            &self.unlock_methods,
+            self.wipe_after,
+        ))
+        .expect("header fields always serialize")
+    }
+
+    fn mac_for(&self, passphrase_key: &SecretKey) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(passphrase_key.as_slice())
+            .expect("HMAC accepts any key length");
+        mac.update(&self.authenticated_bytes());
+        mac
+    }
+
+    /// Authenticate the header with the passphrase key
+    pub fn seal(&mut self, passphrase_key: &SecretKey) {
+        self.mac = Some(hex::encode(self.mac_for(passphrase_key).finalize().into_bytes()));
+    }
+
+    /// Check the MAC
+    ///
+    /// Only a header migrated from older files may be unsealed; the caller
+    /// seals it once this passes. A saved header without a MAC was stripped.
+    pub fn verify(&self, passphrase_key: &SecretKey) -> Result<(), String> {
+        let Some(mac) = &self.mac else {
+            if self.legacy {
+                return Ok(());
+            }
+            return Err("Encryption header has been modified".to_string());
+        };
+
+        let expected = hex::decode(mac).map_err(|_| "Encryption header is corrupted".to_string())?;
+        self.mac_for(passphrase_key)
+            .verify_slice(&expected)
+            .map_err(|_| "Encryption header has been modified".to_string())
+    }
+
+    pub fn to_json(&self) -> String {
+        let file = HeaderFile {
+            header: self.clone(),
+            checksum: checksum(self),
+        };
+        serde_json::to_string_pretty(&file).expect("header always serializes")
+    }
+
+    pub fn from_json(content: &str) -> Result<Self, String> {
+        let file: HeaderFile = serde_json::from_str(content)
+            .map_err(|e| format!("Encryption header is corrupted: {}", e))?;
+
+        if file.header.format_version > HEADER_FORMAT_VERSION {
+            return Err("Encryption header was written by a newer version of the app".to_string());
+        }
+        if checksum(&file.header) != file.checksum {
+            return Err("Encryption header is corrupted: checksum mismatch".to_string());
+        }
+        if file.header.kdf.algorithm != KDF_ARGON2ID || file.header.wrapped_key.algorithm != WRAP_XOR_HEX_V1 {
+            return Err("Encryption header uses an unsupported algorithm".to_string());
+        }
+
+        Ok(file.header)
+    }
+}
+
+fn checksum(header: &EncryptionHeader) -> String {
+    let bytes = serde_json::to_vec(header).expect("header always serializes");
+    hex::encode(Sha256::digest(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zeroize::Zeroizing;
+
+    fn sample() -> EncryptionHeader {
+        EncryptionHeader::new("00112233".to_string(), "abcdef".to_string(), 24, true, true, 1_700_000_000)
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = sample();
+        let parsed = EncryptionHeader::from_json(&header.to_json()).unwrap();
+        assert_eq!(parsed.word_count, 24);
+        assert!(parsed.has_bip39_passphrase);
+        assert!(parsed.requires_key_file());
+        assert_eq!(parsed.kdf.params, KdfParams::default());
+    }
+
+    #[test]
+    fn test_checksum_detects_corruption() {
+        let json = sample().to_json().replace("\"word_count\": 24", "\"word_count\": 12");
+        assert!(EncryptionHeader::from_json(&json).is_err());
+    }
+
+    #[test]
+    fn test_mac_detects_changes() {
+        let key = Zeroizing::new([9u8; 32]);
+        let mut header = sample();
+        header.seal(&key);
+        assert!(header.verify(&key).is_ok());
+        assert!(header.verify(&Zeroizing::new([8u8; 32])).is_err());
+
+        // Attempt counters can change without the key
+        header.throttle.failed_attempts = 3;
+        assert!(header.verify(&key).is_ok());
+
+        // The wipe limit can't
+        header.wipe_after = Some(1);
+        assert!(header.verify(&key).is_err());
+        header.wipe_after = None;
+        assert!(header.verify(&key).is_ok());
+
+        header.kdf.salt = "ffffffff".to_string();
+        assert!(header.verify(&key).is_err());
+    }
+
+    #[test]
+    fn test_stripped_mac_is_rejected() {
+        let key = Zeroizing::new([9u8; 32]);
+        let mut header = sample();
+        header.seal(&key);
+
+        // Removing the MAC and fixing up the checksum still fails the unlock
+        header.mac = None;
+        let parsed = EncryptionHeader::from_json(&header.to_json()).unwrap();
+        assert!(!parsed.is_legacy());
+        assert_eq!(parsed.verify(&key).unwrap_err(), "Encryption header has been modified");
+    }
+
+    #[test]
+    fn test_from_legacy() {
+        let legacy = EncryptionHeader::from_legacy(
+            "encrypted\nwords=24\nkey_file=1\nfailed_attempts=4\nwipe_after=10\n",
+            Some("0011".to_string()),
+            Some("beef".to_string()),
+            0,
+        )
+        .unwrap();
+        assert_eq!(legacy.word_count, 24);
+        assert!(legacy.requires_key_file());
+        assert_eq!(legacy.throttle.failed_attempts, 4);
+        assert_eq!(legacy.wipe_after, Some(10));
+        assert!(legacy.mac.is_none());
+        assert!(legacy.is_legacy());
+        assert!(legacy.verify(&Zeroizing::new([9u8; 32])).is_ok());
+
+        // Once saved as a header it has to be sealed
+        let mut saved = EncryptionHeader::from_json(&legacy.to_json()).unwrap();
+        assert!(!saved.is_legacy());
+        assert!(saved.verify(&Zeroizing::new([9u8; 32])).is_err());
+        saved.seal(&Zeroizing::new([9u8; 32]));
+        assert!(saved.verify(&Zeroizing::new([9u8; 32])).is_ok());
+
+        // Salt and recovery written inline win over the old separate files
+        let inline = EncryptionHeader::from_legacy("encrypted\nsalt=aa\nrecovery=bb\n", Some("cc".to_string()), None, 0).unwrap();
+        assert_eq!(inline.kdf.salt, "aa");
+        assert_eq!(inline.wrapped_key.data, "bb");
+
+        assert!(EncryptionHeader::from_legacy("plaintext", None, None, 0).is_none());
+    }
+}
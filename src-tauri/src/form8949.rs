@@ -0,0 +1,319 @@
+//! US Form 8949 and Schedule D
+//!
+//! Lists every lot matched to a disposal in a tax year, split into short-term
+//! (held one year or less) and long-term, with the totals that carry over to
+//! Schedule D. Built from the cost basis engine with the stored method and
+//! pooling, so it agrees with the P&L view, and needs values in USD.
+//!
+//! Gifts given away aren't sales and are left out. Self-custodied BTC isn't
+//! reported to the IRS by a broker, so every sale goes in box C/F (box I/L
+//! from 2025, when the form got separate boxes for digital assets).
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::audit::{csv_field, money};
+use crate::cost_basis::{self, CostBasisReport, Method};
+use crate::dates;
+
+const SATS_PER_BTC: f64 = 100_000_000.0;
+
+/// One line of Form 8949: part of a disposal matched to one lot
+#[derive(Debug, Clone, Serialize)]
+pub struct Sale {
+    pub disposal_id: i64,
+    /// None for sats disposed of beyond the lots held
+    pub lot_id: Option<i64>,
+    /// Column (a)
+    pub description: String,
+    /// Column (b), `MM/DD/YYYY`
+    pub date_acquired: String,
+    /// Column (c), `MM/DD/YYYY`
+    pub date_sold: String,
+    pub sats: i64,
+    /// Column (d)
+    pub proceeds: f64,
+    /// Column (e)
+    pub cost_basis: f64,
+    /// Column (h)
+    pub gain: f64,
+    pub long_term: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Totals {
+    pub proceeds: f64,
+    pub cost_basis: f64,
+    pub gain: f64,
+}
+
+impl Totals {
+    fn of(sales: &[Sale]) -> Self {
+        sales.iter().fold(Self::default(), |totals, sale| Self {
+            proceeds: totals.proceeds + sale.proceeds,
+            cost_basis: totals.cost_basis + sale.cost_basis,
+            gain: totals.gain + sale.gain,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Form8949 {
+    pub year: i64,
+    pub method: Method,
+    /// Box checked in Part I
+    pub short_term_box: String,
+    /// Box checked in Part II
+    pub long_term_box: String,
+    pub short_term: Vec<Sale>,
+    pub long_term: Vec<Sale>,
+    pub short_term_totals: Totals,
+    pub long_term_totals: Totals,
+    /// Schedule D line 16
+    pub net_gain: f64,
+    pub warnings: Vec<String>,
+}
+
+fn us_date(timestamp: i64) -> String {
+    let (year, month, day) = dates::civil_date(timestamp);
+    format!("{:02}/{:02}/{}", month, day, year)
+}
+
+/// Held for more than one year: sold after the anniversary of the acquisition
+fn is_long_term(acquired_at: i64, disposed_at: i64) -> bool {
+    dates::held_longer_than(acquired_at, disposed_at, 12)
+}
+
+/// Form 8949 for one tax year from a cost basis report over the whole history
+pub fn build(report: &CostBasisReport, year: i64) -> Form8949 {
+    let mut sales = Vec::new();
+    let mut warnings = Vec::new();
+
+    for disposal in &report.disposals {
+        if dates::civil_date(disposal.disposed_at).0 != year {
+            continue;
+        }
+        if disposal.category == "Gift" {
+            warnings.push(format!(
+                "Transaction {} is a gift and isn't reported as a sale",
+                disposal.id
+            ));
+            continue;
+        }
+
+        let date_sold = us_date(disposal.disposed_at);
+        for lot in &disposal.matches {
+            sales.push(Sale {
+                disposal_id: disposal.id,
+                lot_id: Some(lot.lot_id),
+                description: format!("{:.8} BTC", lot.sats as f64 / SATS_PER_BTC),
+                date_acquired: us_date(lot.acquired_at),
+                date_sold: date_sold.clone(),
+                sats: lot.sats,
+                proceeds: lot.proceeds,
+                cost_basis: lot.cost_basis,
+                gain: lot.gain,
+                long_term: is_long_term(lot.acquired_at, disposal.disposed_at),
+            });
+        }
+
+        if disposal.unmatched_sats > 0 {
+            let proceeds = disposal.proceeds * disposal.unmatched_sats as f64 / disposal.sats as f64;
+            warnings.push(format!(
+                "Transaction {} sells {} sats with no known acquisition; they're reported as short-term with no basis",
+                disposal.id, disposal.unmatched_sats
+            ));
+            sales.push(Sale {
+                disposal_id: disposal.id,
+                lot_id: None,
+                description: format!("{:.8} BTC", disposal.unmatched_sats as f64 / SATS_PER_BTC),
+                date_acquired: "Unknown".to_string(),
+                date_sold,
+                sats: disposal.unmatched_sats,
+                proceeds,
+                cost_basis: 0.0,
+                gain: proceeds,
+                long_term: false,
+            });
+        }
+    }
+
+    let (long_term, short_term): (Vec<Sale>, Vec<Sale>) = sales.into_iter().partition(|sale| sale.long_term);
+    let short_term_totals = Totals::of(&short_term);
+    let long_term_totals = Totals::of(&long_term);
+    let (short_term_box, long_term_box) = if year >= 2025 { ("I", "L") } else { ("C", "F") };
+
+    Form8949 {
+        year,
+        method: report.method,
+        short_term_box: short_term_box.to_string(),
+        long_term_box: long_term_box.to_string(),
+        net_gain: short_term_totals.gain + long_term_totals.gain,
+        short_term,
+        long_term,
+        short_term_totals,
+        long_term_totals,
+        warnings,
+    }
+}
+
+fn push_part(csv: &mut String, title: &str, sales: &[Sale], totals: &Totals) {
+    csv.push_str(&csv_field(title));
+    csv.push('\n');
+    csv.push_str(
+        "(a) Description of property,(b) Date acquired,(c) Date sold or disposed of,(d) Proceeds,\
+         (e) Cost or other basis,(f) Code(s),(g) Amount of adjustment,(h) Gain or (loss)\n",
+    );
+    for sale in sales {
+        let row = [
+            csv_field(&sale.description),
+            sale.date_acquired.clone(),
+            sale.date_sold.clone(),
+            money(sale.proceeds),
+            money(sale.cost_basis),
+            String::new(),
+            String::new(),
+            money(sale.gain),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv.push_str(&format!(
+        "Totals,,,{},{},,,{}\n\n",
+        money(totals.proceeds),
+        money(totals.cost_basis),
+        money(totals.gain)
+    ));
+}
+
+/// The form as CSV in the 8949 column layout, followed by the Schedule D totals
+pub fn to_csv(form: &Form8949) -> String {
+    let mut csv = format!(
+        "Form 8949 - Sales and Other Dispositions of Capital Assets,Tax year {},Method: {}\n\n",
+        form.year,
+        form.method.label()
+    );
+
+    push_part(
+        &mut csv,
+        &format!("Part I - Short-term (box {})", form.short_term_box),
+        &form.short_term,
+        &form.short_term_totals,
+    );
+    push_part(
+        &mut csv,
+        &format!("Part II - Long-term (box {})", form.long_term_box),
+        &form.long_term,
+        &form.long_term_totals,
+    );
+
+    csv.push_str("Schedule D,(d) Proceeds,(e) Cost or other basis,(g) Adjustments,(h) Gain or (loss)\n");
+    for (line, totals) in [
+        (
+            format!("Line 3 - Short-term (box {})", form.short_term_box),
+            &form.short_term_totals,
+        ),
+        (
+            format!("Line 10 - Long-term (box {})", form.long_term_box),
+            &form.long_term_totals,
+        ),
+    ] {
+        csv.push_str(&format!(
+            "{},{},{},,{}\n",
+            csv_field(&line),
+            money(totals.proceeds),
+            money(totals.cost_basis),
+            money(totals.gain)
+        ));
+    }
+    csv.push_str(&format!("Line 16 - Net gain or (loss),,,,{}\n", money(form.net_gain)));
+
+    csv
+}
+
+/// Form 8949 for a tax year from the stored transactions and settings
+pub async fn generate(pool: &SqlitePool, year: i64) -> Result<Form8949, String> {
+    let report = cost_basis::report(pool, None, None, None, None).await?;
+    if !report.currency.eq_ignore_ascii_case("USD") {
+        return Err(format!("Form 8949 needs values in USD, not {}", report.currency));
+    }
+
+    let mut form = build(&report, year);
+    let mut warnings = report.warnings;
+    warnings.append(&mut form.warnings);
+    form.warnings = warnings;
+    Ok(form)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost_basis::{compute, Entry, Options};
+    use crate::test_support::entry;
+
+    fn report(entries: &[Entry]) -> CostBasisReport {
+        compute(entries, "USD", &Options::new(Method::Fifo))
+    }
+
+    #[test]
+    fn test_holding_period() {
+        let acquired = dates::date_to_unix("2023-03-15").unwrap();
+        assert!(!is_long_term(acquired, dates::date_to_unix("2024-03-15").unwrap()));
+        assert!(is_long_term(acquired, dates::date_to_unix("2024-03-16").unwrap()));
+
+        // Bought on a leap day: the anniversary is the end of February
+        let leap = dates::date_to_unix("2024-02-29").unwrap();
+        assert!(!is_long_term(leap, dates::date_to_unix("2025-02-28").unwrap()));
+        assert!(is_long_term(leap, dates::date_to_unix("2025-03-01").unwrap()));
+    }
+
+    #[test]
+    fn test_short_and_long_term() {
+        let entries = vec![
+            entry(1, "2022-06-01", 100_000_000, 20_000.0, "Buy"),
+            entry(2, "2023-09-01", 100_000_000, 26_000.0, "Buy"),
+            entry(3, "2023-12-01", -10_000_000, 4_000.0, "Sell"),
+            entry(4, "2024-03-01", -150_000_000, 90_000.0, "Sell"),
+            entry(5, "2024-05-01", -10_000_000, 6_000.0, "Gift"),
+        ];
+        let form = build(&report(&entries), 2024);
+
+        // Lot 1 was held over a year, lot 2 wasn't
+        assert_eq!(form.long_term.len(), 1);
+        assert_eq!(form.long_term[0].date_acquired, "06/01/2022");
+        assert_eq!(form.long_term[0].date_sold, "03/01/2024");
+        assert_eq!(form.long_term[0].description, "0.90000000 BTC");
+        assert_eq!(form.long_term_totals.cost_basis, 18_000.0);
+        assert_eq!(form.long_term_totals.proceeds, 54_000.0);
+
+        assert_eq!(form.short_term.len(), 1);
+        assert_eq!(form.short_term_totals.cost_basis, 15_600.0);
+        assert_eq!(form.net_gain, 90_000.0 - 33_600.0);
+        assert_eq!(form.short_term_box, "C");
+        assert_eq!(form.warnings.len(), 1);
+
+        // The 2023 sale is only in 2023
+        let earlier = build(&report(&entries), 2023);
+        assert_eq!(earlier.long_term.len(), 1);
+        assert_eq!(earlier.long_term_totals.gain, 2_000.0);
+        assert!(earlier.short_term.is_empty());
+    }
+
+    #[test]
+    fn test_csv_layout() {
+        let entries = vec![
+            entry(1, "2024-01-10", 50_000_000, 20_000.0, "Buy"),
+            entry(2, "2025-02-10", -80_000_000, 80_000.0, "Sell"),
+        ];
+        let form = build(&report(&entries), 2025);
+        assert_eq!(form.short_term.len(), 1);
+        assert_eq!(form.long_term.len(), 1);
+        assert_eq!(form.short_term[0].date_acquired, "Unknown");
+
+        let csv = to_csv(&form);
+        assert!(csv.contains("Part I - Short-term (box I)"));
+        assert!(csv.contains("0.50000000 BTC,01/10/2024,02/10/2025,50000.00,20000.00,,,30000.00\n"));
+        assert!(csv.contains("Line 10 - Long-term (box L),50000.00,20000.00,,30000.00\n"));
+        assert!(csv.ends_with("Line 16 - Net gain or (loss),,,,60000.00\n"));
+    }
+}
@@ -0,0 +1,456 @@
+//! UK capital gains with HMRC share matching
+//!
+//! HMRC doesn't accept FIFO or a running average for cryptoassets. Each day's
+//! disposals are matched, in order, with:
+//!
+//! 1. acquisitions on the same day,
+//! 2. acquisitions in the following 30 days (bed and breakfast), earliest first,
+//! 3. the Section 104 pool, which holds everything else at its average cost.
+//!
+//! All acquisitions and all disposals on one day count as one of each. Days
+//! are UTC, values must be in GBP, and gains are summed per tax year
+//! (6 April to 5 April). Each person is a separate taxpayer, so matching runs
+//! per entity; without one, every entity is treated as a single taxpayer.
+//! Fees are allowable costs: they add to acquisition costs and come off
+//! proceeds.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+use crate::audit::{csv_field, money};
+use crate::cost_basis::{self, classify, Entry, Kind};
+use crate::dates::{self, SECONDS_PER_DAY};
+
+/// Days after a disposal in which a new acquisition is matched to it
+const BED_AND_BREAKFAST_DAYS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    SameDay,
+    BedAndBreakfast,
+    Section104,
+    /// Sats disposed of beyond everything held, at zero cost
+    Unmatched,
+}
+
+impl Rule {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::SameDay => "Same day",
+            Self::BedAndBreakfast => "Bed and breakfast",
+            Self::Section104 => "Section 104 pool",
+            Self::Unmatched => "Unmatched",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleMatch {
+    pub rule: Rule,
+    pub sats: i64,
+    pub cost: f64,
+    /// For bed and breakfast matches, the day of the acquisition
+    pub acquired_on: Option<String>,
+}
+
+/// A day's disposals, matched as one
+#[derive(Debug, Clone, Serialize)]
+pub struct DayDisposal {
+    pub date: String,
+    pub tax_year: String,
+    pub transaction_ids: Vec<i64>,
+    pub sats: i64,
+    pub proceeds: f64,
+    pub cost: f64,
+    pub gain: f64,
+    pub matches: Vec<RuleMatch>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaxYear {
+    /// e.g. `2024/25`
+    pub tax_year: String,
+    pub disposals: usize,
+    pub proceeds: f64,
+    pub allowable_costs: f64,
+    /// Sum of the gains, before losses
+    pub gains: f64,
+    pub losses: f64,
+    pub net_gain: f64,
+    /// Section 104 pool on 5 April
+    pub pool_sats: i64,
+    pub pool_cost: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HmrcReport {
+    pub entity_id: Option<i64>,
+    pub disposals: Vec<DayDisposal>,
+    pub tax_years: Vec<TaxYear>,
+    pub pool_sats: i64,
+    pub pool_cost: f64,
+    pub warnings: Vec<String>,
+}
+
+/// Start year of the UK tax year a unix time falls in
+fn tax_year_of(timestamp: i64) -> i64 {
+    let (year, month, day) = dates::civil_date(timestamp);
+    if (month, day) >= (4, 6) {
+        year
+    } else {
+        year - 1
+    }
+}
+
+fn tax_year_label(start_year: i64) -> String {
+    format!("{}/{:02}", start_year, (start_year + 1) % 100)
+}
+
+fn day_date(day: i64) -> String {
+    dates::iso_date(day * SECONDS_PER_DAY)
+}
+
+/// One day's acquisitions and disposals, and what's still unmatched of each
+#[derive(Default)]
+struct Day {
+    day: i64,
+    acquired_sats: i64,
+    acquired_cost: f64,
+    disposed_sats: i64,
+    proceeds: f64,
+    transaction_ids: Vec<i64>,
+    matches: Vec<RuleMatch>,
+}
+
+impl Day {
+    fn matched_sats(&self) -> i64 {
+        self.matches.iter().map(|m| m.sats).sum()
+    }
+}
+
+/// Take `sats` of a day's remaining acquisitions, returning their cost
+fn take_acquired(day: &mut Day, sats: i64) -> f64 {
+    let cost = if sats == day.acquired_sats {
+        day.acquired_cost
+    } else {
+        day.acquired_cost * sats as f64 / day.acquired_sats as f64
+    };
+
+    day.acquired_sats -= sats;
+    day.acquired_cost -= cost;
+    cost
+}
+
+/// Match one taxpayer's acquisitions and disposals
+pub fn compute(entries: &[Entry], entity_id: Option<i64>) -> HmrcReport {
+    let mut by_day: BTreeMap<i64, Day> = BTreeMap::new();
+
+    for entry in entries {
+        let kind = classify(&entry.category, entry.amount_sats);
+        if kind.is_none() {
+            continue;
+        }
+
+        let day = entry.timestamp.div_euclid(SECONDS_PER_DAY);
+        let totals = by_day.entry(day).or_insert_with(|| Day {
+            day,
+            ..Default::default()
+        });
+        if kind == Some(Kind::Acquisition) {
+            totals.acquired_sats += entry.amount_sats;
+            totals.acquired_cost += entry.cost();
+        } else {
+            totals.disposed_sats += entry.amount_sats.abs();
+            totals.proceeds += entry.proceeds();
+            totals.transaction_ids.push(entry.id);
+        }
+    }
+    let mut days: Vec<Day> = by_day.into_values().collect();
+
+    // Same day
+    for day in days.iter_mut() {
+        let sats = day.acquired_sats.min(day.disposed_sats);
+        if sats > 0 {
+            let cost = take_acquired(day, sats);
+            day.matches.push(RuleMatch {
+                rule: Rule::SameDay,
+                sats,
+                cost,
+                acquired_on: None,
+            });
+        }
+    }
+
+    // Bed and breakfast: later acquisitions go to the earliest disposals first
+    for i in 0..days.len() {
+        for j in i + 1..days.len() {
+            let wanted = days[i].disposed_sats - days[i].matched_sats();
+            if wanted == 0 || days[j].day > days[i].day + BED_AND_BREAKFAST_DAYS {
+                break;
+            }
+
+            let sats = wanted.min(days[j].acquired_sats);
+            if sats > 0 {
+                let cost = take_acquired(&mut days[j], sats);
+                let acquired_on = Some(day_date(days[j].day));
+                days[i].matches.push(RuleMatch {
+                    rule: Rule::BedAndBreakfast,
+                    sats,
+                    cost,
+                    acquired_on,
+                });
+            }
+        }
+    }
+
+    // Section 104 pool
+    let mut pool_sats = 0i64;
+    let mut pool_cost = 0.0;
+    let mut disposals = Vec::new();
+    let mut tax_years: BTreeMap<i64, TaxYear> = BTreeMap::new();
+    let mut warnings = Vec::new();
+
+    for mut day in days {
+        pool_sats += day.acquired_sats;
+        pool_cost += day.acquired_cost;
+
+        let start_year = tax_year_of(day.day * SECONDS_PER_DAY);
+        let wanted = day.disposed_sats - day.matched_sats();
+        if wanted > 0 {
+            let sats = wanted.min(pool_sats);
+            if sats > 0 {
+                let cost = if sats == pool_sats {
+                    pool_cost
+                } else {
+                    pool_cost * sats as f64 / pool_sats as f64
+                };
+                pool_sats -= sats;
+                pool_cost -= cost;
+                day.matches.push(RuleMatch {
+                    rule: Rule::Section104,
+                    sats,
+                    cost,
+                    acquired_on: None,
+                });
+            }
+            if wanted > sats {
+                warnings.push(format!(
+                    "Disposals on {} are {} sats more than were held",
+                    day_date(day.day),
+                    wanted - sats
+                ));
+                day.matches.push(RuleMatch {
+                    rule: Rule::Unmatched,
+                    sats: wanted - sats,
+                    cost: 0.0,
+                    acquired_on: None,
+                });
+            }
+        }
+
+        let year = tax_years.entry(start_year).or_insert_with(|| TaxYear {
+            tax_year: tax_year_label(start_year),
+            ..Default::default()
+        });
+        year.pool_sats = pool_sats;
+        year.pool_cost = pool_cost;
+
+        if day.disposed_sats > 0 {
+            let cost: f64 = day.matches.iter().map(|m| m.cost).sum();
+            let gain = day.proceeds - cost;
+
+            year.disposals += 1;
+            year.proceeds += day.proceeds;
+            year.allowable_costs += cost;
+            if gain >= 0.0 {
+                year.gains += gain;
+            } else {
+                year.losses -= gain;
+            }
+            year.net_gain += gain;
+
+            disposals.push(DayDisposal {
+                date: day_date(day.day),
+                tax_year: tax_year_label(start_year),
+                transaction_ids: day.transaction_ids,
+                sats: day.disposed_sats,
+                proceeds: day.proceeds,
+                cost,
+                gain,
+                matches: day.matches,
+            });
+        }
+    }
+
+    HmrcReport {
+        entity_id,
+        disposals,
+        tax_years: tax_years.into_values().filter(|year| year.disposals > 0).collect(),
+        pool_sats,
+        pool_cost,
+        warnings,
+    }
+}
+
+/// Disposals and the per-tax-year summary as CSV, optionally for one tax year
+pub fn to_csv(report: &HmrcReport, tax_year: Option<&str>) -> String {
+    let shown = |year: &str| tax_year.is_none_or(|wanted| wanted == year);
+
+    let mut csv = String::from(
+        "Tax year,Date,Transactions,Sats,Proceeds (GBP),Rule,Matched sats,Allowable cost (GBP),Acquired on\n",
+    );
+    for disposal in report.disposals.iter().filter(|d| shown(&d.tax_year)) {
+        let ids: Vec<String> = disposal.transaction_ids.iter().map(|id| id.to_string()).collect();
+        for m in &disposal.matches {
+            let row = [
+                disposal.tax_year.clone(),
+                disposal.date.clone(),
+                csv_field(&ids.join(" ")),
+                disposal.sats.to_string(),
+                money(disposal.proceeds),
+                m.rule.label().to_string(),
+                m.sats.to_string(),
+                money(m.cost),
+                m.acquired_on.clone().unwrap_or_default(),
+            ];
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+    }
+
+    csv.push_str("\nTax year,Disposals,Proceeds (GBP),Allowable costs (GBP),Gains (GBP),Losses (GBP),Net gain (GBP),Pool sats,Pool cost (GBP)\n");
+    for year in report.tax_years.iter().filter(|y| shown(&y.tax_year)) {
+        let row = [
+            year.tax_year.clone(),
+            year.disposals.to_string(),
+            money(year.proceeds),
+            money(year.allowable_costs),
+            money(year.gains),
+            money(year.losses),
+            money(year.net_gain),
+            year.pool_sats.to_string(),
+            money(year.pool_cost),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+/// UK report from the stored transactions, for one entity or all of them
+pub async fn generate(pool: &SqlitePool, entity_id: Option<i64>) -> Result<HmrcReport, String> {
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+
+    let currency = cost_basis::default_currency(&mut conn).await?;
+    if !currency.eq_ignore_ascii_case("GBP") {
+        return Err(format!("HMRC matching needs values in GBP, not {}", currency));
+    }
+
+    let (mut entries, mut warnings) = cost_basis::load_entries(&mut conn, &currency).await?;
+    entries.retain(|entry| entity_id.is_none_or(|id| id == entry.entity_id));
+
+    let mut report = compute(&entries, entity_id);
+    warnings.append(&mut report.warnings);
+    report.warnings = warnings;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::entry;
+
+    fn rules(disposal: &DayDisposal) -> Vec<(Rule, i64, f64)> {
+        disposal.matches.iter().map(|m| (m.rule, m.sats, m.cost)).collect()
+    }
+
+    #[test]
+    fn test_tax_year() {
+        assert_eq!(tax_year_of(dates::date_to_unix("2024-04-05").unwrap()), 2023);
+        assert_eq!(tax_year_of(dates::date_to_unix("2024-04-06").unwrap()), 2024);
+        assert_eq!(tax_year_label(2024), "2024/25");
+        assert_eq!(tax_year_label(1999), "1999/00");
+    }
+
+    #[test]
+    fn test_section_104_pool() {
+        let report = compute(
+            &[
+                entry(1, "2023-01-01", 1_000, 1_000.0, "Buy"),
+                entry(2, "2023-02-01", 1_000, 3_000.0, "Buy"),
+                entry(3, "2023-06-01", -500, 2_500.0, "Sell"),
+            ],
+            Some(1),
+        );
+
+        assert_eq!(rules(&report.disposals[0]), vec![(Rule::Section104, 500, 1_000.0)]);
+        assert_eq!(report.pool_sats, 1_500);
+        assert_eq!(report.pool_cost, 3_000.0);
+        assert_eq!(report.tax_years[0].tax_year, "2023/24");
+        assert_eq!(report.tax_years[0].net_gain, 1_500.0);
+    }
+
+    #[test]
+    fn test_same_day_and_bed_and_breakfast() {
+        let report = compute(
+            &[
+                entry(1, "2023-01-01", 1_000, 1_000.0, "Buy"),
+                entry(2, "2023-06-01", -600, 3_000.0, "Sell"),
+                entry(3, "2023-06-01", 200, 1_200.0, "Buy"),
+                entry(4, "2023-06-20", 300, 1_500.0, "Buy"),
+                entry(5, "2023-07-15", 500, 9_999.0, "Buy"),
+            ],
+            Some(1),
+        );
+
+        // 200 same day, 300 bought back within 30 days, the rest from the pool;
+        // the buy on 15 July is 44 days later and goes to the pool
+        assert_eq!(
+            rules(&report.disposals[0]),
+            vec![
+                (Rule::SameDay, 200, 1_200.0),
+                (Rule::BedAndBreakfast, 300, 1_500.0),
+                (Rule::Section104, 100, 100.0)
+            ]
+        );
+        assert_eq!(
+            report.disposals[0].matches[1].acquired_on.as_deref(),
+            Some("2023-06-20")
+        );
+        assert_eq!(report.disposals[0].gain, 200.0);
+        assert_eq!(report.pool_sats, 1_400);
+        assert_eq!(report.pool_cost, 900.0 + 9_999.0);
+    }
+
+    #[test]
+    fn test_gains_and_losses_per_tax_year() {
+        let report = compute(
+            &[
+                entry(1, "2023-01-01", 1_000, 1_000.0, "Buy"),
+                entry(2, "2023-04-05", -100, 300.0, "Sell"),
+                entry(3, "2023-04-06", -100, 50.0, "Sell"),
+                entry(4, "2024-01-01", -100, 500.0, "Sell"),
+                entry(5, "2024-06-01", -700, 700.0, "Sell"),
+            ],
+            None,
+        );
+
+        let years: Vec<_> = report
+            .tax_years
+            .iter()
+            .map(|y| (y.tax_year.as_str(), y.gains, y.losses))
+            .collect();
+        assert_eq!(
+            years,
+            vec![("2022/23", 200.0, 0.0), ("2023/24", 400.0, 50.0), ("2024/25", 0.0, 0.0)]
+        );
+        assert_eq!(report.tax_years[1].net_gain, 350.0);
+
+        let csv = to_csv(&report, Some("2023/24"));
+        assert!(csv.contains("2023/24,2023-04-06,3,100,50.00,Section 104 pool,100,100.00,\n"));
+        assert!(!csv.contains("2022/23"));
+    }
+}
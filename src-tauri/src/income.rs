@@ -0,0 +1,323 @@
+//! Income report
+//!
+//! Totals BTC received as income (income, mining and lending interest) at its
+//! fiat value when received: the transaction's own fiat value, or else the
+//! cached price for that day. The cost basis engine values these lots the
+//! same way, so income reported here is the basis of what was received.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+
+use crate::audit::csv_field;
+use crate::cost_basis::{self, INCOME_CATEGORIES};
+use crate::dates;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueSource {
+    Transaction,
+    PriceCache,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IncomeItem {
+    pub id: i64,
+    pub entity_id: i64,
+    pub entity_name: String,
+    pub wallet_name: String,
+    pub category: String,
+    pub date: String,
+    pub year: i64,
+    pub sats: i64,
+    pub value: f64,
+    pub source: ValueSource,
+}
+
+/// Income of one category for one entity in one year
+#[derive(Debug, Clone, Serialize)]
+pub struct IncomeTotal {
+    pub year: i64,
+    pub entity_id: i64,
+    pub entity_name: String,
+    pub category: String,
+    pub count: usize,
+    pub sats: i64,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IncomeReport {
+    pub currency: String,
+    pub items: Vec<IncomeItem>,
+    /// By year, then entity, then category
+    pub totals: Vec<IncomeTotal>,
+    pub warnings: Vec<String>,
+}
+
+/// Sum items by year, entity and category
+pub fn summarize(items: &[IncomeItem]) -> Vec<IncomeTotal> {
+    let mut totals: BTreeMap<(i64, i64, &str), IncomeTotal> = BTreeMap::new();
+
+    for item in items {
+        let total = totals
+            .entry((item.year, item.entity_id, item.category.as_str()))
+            .or_insert_with(|| IncomeTotal {
+                year: item.year,
+                entity_id: item.entity_id,
+                entity_name: item.entity_name.clone(),
+                category: item.category.clone(),
+                count: 0,
+                sats: 0,
+                value: 0.0,
+            });
+        total.count += 1;
+        total.sats += item.sats;
+        total.value += item.value;
+    }
+
+    totals.into_values().collect()
+}
+
+/// Income received, optionally for one year and one entity
+pub async fn generate(pool: &SqlitePool, year: Option<i64>, entity_id: Option<i64>) -> Result<IncomeReport, String> {
+    #[derive(sqlx::FromRow)]
+    struct Row {
+        id: i64,
+        entity_id: i64,
+        entity_name: String,
+        wallet_name: String,
+        category: String,
+        timestamp: Option<i64>,
+        amount_sats: i64,
+        fiat_value: Option<f64>,
+        fiat_currency: Option<String>,
+    }
+
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    let currency = cost_basis::default_currency(&mut conn).await?;
+
+    let placeholders = vec!["?"; INCOME_CATEGORIES.len()].join(", ");
+    let sql = format!(
+        r#"
+        SELECT t.id, w.entity_id, e.name as entity_name, w.name as wallet_name, c.name as category,
+               t.timestamp, t.amount_sats, t.fiat_value, t.fiat_currency
+        FROM transactions t
+        JOIN wallets w ON t.wallet_id = w.id
+        JOIN entities e ON w.entity_id = e.id
+        JOIN categories c ON t.category_id = c.id
+        WHERE t.trash_id IS NULL AND COALESCE(t.currency, 'BTC') = 'BTC' AND t.amount_sats > 0
+          AND c.name IN ({}) AND (? IS NULL OR w.entity_id = ?)
+        ORDER BY t.timestamp, t.id
+        "#,
+        placeholders
+    );
+    let mut query = sqlx::query_as::<_, Row>(&sql);
+    for category in INCOME_CATEGORIES {
+        query = query.bind(*category);
+    }
+    let rows = query
+        .bind(entity_id)
+        .bind(entity_id)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut items = Vec::new();
+    let mut warnings = Vec::new();
+
+    for row in rows {
+        let Some(timestamp) = row.timestamp else {
+            warnings.push(format!("Transaction {} has no date and was left out", row.id));
+            continue;
+        };
+        let item_year = dates::civil_date(timestamp).0;
+        if year.is_some_and(|year| year != item_year) {
+            continue;
+        }
+
+        let (value, source) = match row.fiat_value {
+            Some(value) => {
+                if let Some(fiat_currency) = row.fiat_currency.as_ref() {
+                    if !fiat_currency.eq_ignore_ascii_case(&currency) {
+                        warnings.push(format!(
+                            "Transaction {} is valued in {}, not {}, and was left out",
+                            row.id, fiat_currency, currency
+                        ));
+                        continue;
+                    }
+                }
+                (value.abs(), ValueSource::Transaction)
+            }
+            None => match cost_basis::cached_value(&mut conn, &currency, timestamp, row.amount_sats).await? {
+                Some(value) => (value, ValueSource::PriceCache),
+                None => {
+                    warnings.push(format!(
+                        "Transaction {} has no fiat value or cached price and was left out",
+                        row.id
+                    ));
+                    continue;
+                }
+            },
+        };
+
+        items.push(IncomeItem {
+            id: row.id,
+            entity_id: row.entity_id,
+            entity_name: row.entity_name,
+            wallet_name: row.wallet_name,
+            category: row.category,
+            date: dates::iso_date(timestamp),
+            year: item_year,
+            sats: row.amount_sats,
+            value,
+            source,
+        });
+    }
+
+    Ok(IncomeReport {
+        currency,
+        totals: summarize(&items),
+        items,
+        warnings,
+    })
+}
+
+/// Receipts and totals as CSV
+pub fn to_csv(report: &IncomeReport) -> String {
+    let currency = &report.currency;
+    let mut csv = format!(
+        "Date,Transaction,Owner,Wallet,Category,Sats,Value ({}),Valued from\n",
+        currency
+    );
+
+    for item in &report.items {
+        let row = [
+            item.date.clone(),
+            item.id.to_string(),
+            csv_field(&item.entity_name),
+            csv_field(&item.wallet_name),
+            csv_field(&item.category),
+            item.sats.to_string(),
+            format!("{:.2}", item.value),
+            match item.source {
+                ValueSource::Transaction => "Transaction",
+                ValueSource::PriceCache => "Daily price",
+            }
+            .to_string(),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv.push_str(&format!("\nYear,Owner,Category,Receipts,Sats,Value ({})\n", currency));
+    for total in &report.totals {
+        let row = [
+            total.year.to_string(),
+            csv_field(&total.entity_name),
+            csv_field(&total.category),
+            total.count.to_string(),
+            total.sats.to_string(),
+            format!("{:.2}", total.value),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::memory_pool;
+
+    fn item(id: i64, year: i64, entity_id: i64, category: &str, value: f64) -> IncomeItem {
+        IncomeItem {
+            id,
+            entity_id,
+            entity_name: format!("Entity {}", entity_id),
+            wallet_name: "Wallet".to_string(),
+            category: category.to_string(),
+            date: format!("{}-01-01", year),
+            year,
+            sats: 1_000,
+            value,
+            source: ValueSource::Transaction,
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let totals = summarize(&[
+            item(1, 2024, 1, "Mining", 10.0),
+            item(2, 2023, 1, "Mining", 5.0),
+            item(3, 2024, 1, "Mining", 15.0),
+            item(4, 2024, 2, "Income", 7.0),
+            item(5, 2024, 1, "Income", 1.0),
+        ]);
+
+        let keys: Vec<_> = totals
+            .iter()
+            .map(|t| (t.year, t.entity_id, t.category.as_str(), t.count, t.value))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (2023, 1, "Mining", 1, 5.0),
+                (2024, 1, "Income", 1, 1.0),
+                (2024, 1, "Mining", 2, 25.0),
+                (2024, 2, "Income", 1, 7.0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generate() {
+        let pool = memory_pool().await;
+        for statement in [
+            "INSERT INTO wallets (id, name, xpub) VALUES (1, 'Savings', 'xpub1')",
+            // Prices are cached by UTC day
+            "INSERT INTO price_cache (currency, price, date) VALUES ('EUR', 60000, '01-03-2024'), ('EUR', 99999, '02-03-2024')",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, timestamp, fiat_value, fiat_currency, category_id) VALUES
+                (1, 1, 'a', 10000000, 1705276800, 500, 'EUR', (SELECT id FROM categories WHERE name = 'Income')),
+                (2, 1, 'b', 1000000, 1709335800, NULL, NULL, (SELECT id FROM categories WHERE name = 'Mining')),
+                (3, 1, 'c', 2000000, 1714521600, 100, 'USD', (SELECT id FROM categories WHERE name = 'Income')),
+                (4, 1, 'd', 3000000, 1714521600, NULL, NULL, (SELECT id FROM categories WHERE name = 'Mining')),
+                (5, 1, 'e', 4000000, 1714521600, 800, 'EUR', (SELECT id FROM categories WHERE name = 'Buy')),
+                (6, 1, 'f', 5000000, 1685577600, 900, 'EUR', (SELECT id FROM categories WHERE name = 'Income'))",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        let report = generate(&pool, Some(2024), None).await.unwrap();
+        assert_eq!(report.currency, "EUR");
+
+        let items: Vec<_> = report
+            .items
+            .iter()
+            .map(|item| (item.id, item.date.as_str(), item.value, item.source))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                (1, "2024-01-15", 500.0, ValueSource::Transaction),
+                // Received at 23:30 UTC, so valued at that day's price
+                (2, "2024-03-01", 600.0, ValueSource::PriceCache),
+            ]
+        );
+        assert_eq!(
+            report.warnings,
+            vec![
+                "Transaction 3 is valued in USD, not EUR, and was left out",
+                "Transaction 4 has no fiat value or cached price and was left out",
+            ]
+        );
+
+        let all_years = generate(&pool, None, None).await.unwrap();
+        assert_eq!(all_years.items.len(), 3);
+        assert_eq!(all_years.totals.len(), 3);
+
+        assert!(generate(&pool, None, Some(2)).await.unwrap().items.is_empty());
+    }
+}
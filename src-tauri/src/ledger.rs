@@ -0,0 +1,807 @@
+//! Double-entry journal
+//!
+//! Every live BTC transaction becomes one journal entry whose postings balance
+//! both in sats and in the reporting currency. Debits are positive, credits
+//! negative. The journal is generated from the transactions each time it's
+//! read, so it can't drift from them.
+//!
+//! Each wallet is an asset account; each entity has its own accounts for the
+//! other side: a category account per category, cash and BTC trading for buys
+//! and sells (the BTC moves against the trading account, the money against
+//! cash), realized gains, network fees, loans, and transfers between our own
+//! wallets. A transfer posts out of one wallet into the transfers account and
+//! back out of it into the other, so what's left there is in transit, or owed
+//! between entities when it crossed from one to another.
+//!
+//! Acquisitions are posted at their cost and disposals at their cost basis,
+//! proceeds and gain from the cost basis engine, with the stored method, pools
+//! and transfer fee rule, so the wallets of a pool together hold the basis of
+//! what's left in it. With per-wallet pools that's each wallet's own basis.
+//! Transfers within a wider pool (universal, or per entity) carry no basis, so
+//! one wallet there can keep the basis of sats it sent on while another's
+//! disposals take its fiat balance below zero. Everything else is posted at
+//! the transaction's own value, or in sats only when it has none.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::audit::csv_field;
+use crate::cost_basis::{
+    self, classify, CostBasisReport, Kind, LotSource, ACQUISITION_CATEGORIES, DISPOSAL_CATEGORIES, TRANSFER_IN,
+    TRANSFER_OUT,
+};
+use crate::dates::{self, SECONDS_PER_DAY};
+
+const LOAN_CATEGORIES: &[&str] = &["Receive Loan", "Repay Loan"];
+
+/// Fiat amounts smaller than this are rounding
+const EPSILON: f64 = 1e-6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountKind {
+    Asset,
+    Liability,
+    Equity,
+    Income,
+    Expense,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Account {
+    /// `wallet:<id>` or `entity:<id>:<name>`
+    pub id: String,
+    pub name: String,
+    pub kind: AccountKind,
+    pub entity_id: i64,
+    pub entity_name: String,
+    pub wallet_id: Option<i64>,
+}
+
+/// One BTC transaction as the journal sees it
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub id: i64,
+    pub wallet_id: i64,
+    pub wallet_name: String,
+    pub entity_id: i64,
+    pub entity_name: String,
+    pub txid: String,
+    pub timestamp: i64,
+    /// Signed, as stored
+    pub amount_sats: i64,
+    /// In the reporting currency, if known
+    pub fiat_value: Option<f64>,
+    pub category: String,
+}
+
+impl Transaction {
+    fn wallet(&self) -> Account {
+        Account {
+            id: format!("wallet:{}", self.wallet_id),
+            name: self.wallet_name.clone(),
+            kind: AccountKind::Asset,
+            entity_id: self.entity_id,
+            entity_name: self.entity_name.clone(),
+            wallet_id: Some(self.wallet_id),
+        }
+    }
+
+    /// One of the entity's accounts
+    fn entity_account(&self, key: &str, name: &str, kind: AccountKind) -> Account {
+        Account {
+            id: format!("entity:{}:{}", self.entity_id, key),
+            name: name.to_string(),
+            kind,
+            entity_id: self.entity_id,
+            entity_name: self.entity_name.clone(),
+            wallet_id: None,
+        }
+    }
+
+    fn category_account(&self) -> Account {
+        let category = self.category.as_str();
+        let acquisition = ACQUISITION_CATEGORIES.contains(&category);
+        let disposal = DISPOSAL_CATEGORIES.contains(&category) || category == "Lending Interest (Cost)";
+        let kind = match (acquisition, disposal) {
+            (true, false) => AccountKind::Income,
+            (false, true) => AccountKind::Expense,
+            // Gifts go both ways, and other categories aren't known to be either
+            _ => AccountKind::Equity,
+        };
+
+        self.entity_account(&format!("category:{}", category), category, kind)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Posting {
+    pub account_id: String,
+    pub sats: i64,
+    pub fiat: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalEntry {
+    pub transaction_id: i64,
+    pub timestamp: i64,
+    pub category: String,
+    pub postings: Vec<Posting>,
+}
+
+impl JournalEntry {
+    pub fn is_balanced(&self) -> bool {
+        let sats: i64 = self.postings.iter().map(|p| p.sats).sum();
+        let fiat: f64 = self.postings.iter().map(|p| p.fiat).sum();
+        sats == 0 && fiat.abs() < EPSILON
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Journal {
+    pub currency: String,
+    pub accounts: BTreeMap<String, Account>,
+    pub entries: Vec<JournalEntry>,
+    pub warnings: Vec<String>,
+}
+
+/// Collects the postings of one entry, registering their accounts
+struct Entry<'a> {
+    transaction: &'a Transaction,
+    accounts: &'a mut BTreeMap<String, Account>,
+    postings: Vec<Posting>,
+}
+
+impl Entry<'_> {
+    fn post(&mut self, account: Account, sats: i64, fiat: f64) {
+        if sats == 0 && fiat == 0.0 {
+            return;
+        }
+        self.postings.push(Posting {
+            account_id: account.id.clone(),
+            sats,
+            fiat,
+        });
+        self.accounts.entry(account.id.clone()).or_insert(account);
+    }
+
+    fn wallet(&mut self, sats: i64, fiat: f64) {
+        let account = self.transaction.wallet();
+        self.post(account, sats, fiat);
+    }
+
+    fn entity(&mut self, key: &str, name: &str, kind: AccountKind, sats: i64, fiat: f64) {
+        let account = self.transaction.entity_account(key, name, kind);
+        self.post(account, sats, fiat);
+    }
+
+    fn gains(&mut self, gain: f64) {
+        self.entity("gains", "Realized Gains", AccountKind::Income, 0, -gain);
+    }
+
+    fn fees(&mut self, sats: i64, fiat: f64) {
+        self.entity("fees", "Network Fees", AccountKind::Expense, sats, fiat);
+    }
+
+    fn transfers(&mut self, sats: i64, fiat: f64) {
+        self.entity("transfers", "Own Transfers", AccountKind::Asset, sats, fiat);
+    }
+
+    /// The other side of the wallet's posting
+    fn counter(&mut self, sats: i64, fiat: f64, currency: &str) {
+        let category = self.transaction.category.as_str();
+        if category == "Buy" || category == "Sell" {
+            self.entity("cash", &format!("Cash ({})", currency), AccountKind::Asset, 0, fiat);
+            self.entity("trading", "BTC Trading", AccountKind::Equity, sats, 0.0);
+        } else if LOAN_CATEGORIES.contains(&category) {
+            self.entity("loans", "Loans", AccountKind::Liability, sats, fiat);
+        } else {
+            let account = self.transaction.category_account();
+            self.post(account, sats, fiat);
+        }
+    }
+}
+
+/// Journal entries for a set of transactions, valued by a cost basis report
+/// computed over the same transactions
+pub fn build(transactions: &[Transaction], report: &CostBasisReport) -> Journal {
+    let currency = report.currency.as_str();
+    let acquisitions: HashMap<i64, f64> = report
+        .lots
+        .iter()
+        .filter(|lot| lot.source == LotSource::Acquisition)
+        .map(|lot| (lot.id, lot.cost))
+        .collect();
+    let disposals: HashMap<i64, _> = report
+        .disposals
+        .iter()
+        .filter(|disposal| !disposal.transfer_fee)
+        .map(|disposal| (disposal.id, disposal))
+        .collect();
+    let fee_disposals: HashMap<i64, _> = report
+        .disposals
+        .iter()
+        .filter(|disposal| disposal.transfer_fee)
+        .map(|disposal| (disposal.id, disposal))
+        .collect();
+    let written_off: HashMap<i64, _> = report.non_deductible_fees.iter().map(|fee| (fee.id, fee)).collect();
+
+    // Cost basis carried over by each Transfer In
+    let mut carried: HashMap<i64, f64> = HashMap::new();
+    for lot in &report.lots {
+        if let Some(transfer_id) = lot.transfer_id {
+            *carried.entry(transfer_id).or_default() += lot.cost;
+        }
+    }
+    let transfers_in: HashMap<&str, i64> = transactions
+        .iter()
+        .filter(|tx| tx.category == TRANSFER_IN && tx.amount_sats > 0)
+        .map(|tx| (tx.txid.as_str(), tx.id))
+        .collect();
+
+    let mut transactions: Vec<&Transaction> = transactions.iter().collect();
+    transactions.sort_by_key(|tx| (tx.timestamp, tx.id));
+
+    let mut accounts = BTreeMap::new();
+    let mut entries = Vec::new();
+    let mut warnings = Vec::new();
+
+    for tx in transactions {
+        let sats = tx.amount_sats;
+        let mut entry = Entry {
+            transaction: tx,
+            accounts: &mut accounts,
+            postings: Vec::new(),
+        };
+
+        match classify(&tx.category, sats) {
+            Some(Kind::Acquisition) if acquisitions.contains_key(&tx.id) => {
+                let cost = acquisitions[&tx.id];
+                entry.wallet(sats, cost);
+                entry.counter(-sats, -cost, currency);
+            }
+            Some(Kind::Disposal) if disposals.contains_key(&tx.id) => {
+                let disposal = disposals[&tx.id];
+                entry.wallet(sats, -disposal.cost_basis);
+                entry.counter(-sats, disposal.proceeds, currency);
+                entry.gains(disposal.gain);
+            }
+            None if tx.category == TRANSFER_OUT && sats < 0 => {
+                let mut fee_sats = 0;
+                if let Some(fee) = fee_disposals.get(&tx.id) {
+                    fee_sats = fee.sats;
+                    entry.wallet(-fee.sats, -fee.cost_basis);
+                    entry.fees(fee.sats, fee.proceeds);
+                    entry.gains(fee.gain);
+                } else if let Some(fee) = written_off.get(&tx.id) {
+                    fee_sats = fee.sats;
+                    entry.wallet(-fee.sats, -fee.cost_basis);
+                    entry.fees(fee.sats, fee.cost_basis);
+                }
+
+                let cost = transfers_in
+                    .get(tx.txid.as_str())
+                    .and_then(|id| carried.get(id))
+                    .copied()
+                    .unwrap_or(0.0);
+                let moved = sats.abs() - fee_sats;
+                entry.wallet(-moved, -cost);
+                entry.transfers(moved, cost);
+            }
+            None if tx.category == TRANSFER_IN && sats > 0 => {
+                let cost = carried.get(&tx.id).copied().unwrap_or(0.0);
+                entry.wallet(sats, cost);
+                entry.transfers(-sats, -cost);
+            }
+            _ => {
+                let value = match tx.fiat_value {
+                    Some(value) => value.abs(),
+                    None => {
+                        warnings.push(format!(
+                            "Transaction {} has no {} value and is posted in sats only",
+                            tx.id, currency
+                        ));
+                        0.0
+                    }
+                };
+                let fiat = if sats < 0 { -value } else { value };
+                entry.wallet(sats, fiat);
+                entry.counter(-sats, -fiat, currency);
+            }
+        }
+
+        let entry = JournalEntry {
+            transaction_id: tx.id,
+            timestamp: tx.timestamp,
+            category: tx.category.clone(),
+            postings: entry.postings,
+        };
+        if entry.postings.is_empty() {
+            continue;
+        }
+        if !entry.is_balanced() {
+            warnings.push(format!("The entry for transaction {} doesn't balance", tx.id));
+        }
+        entries.push(entry);
+    }
+
+    Journal {
+        currency: currency.to_string(),
+        accounts,
+        entries,
+        warnings,
+    }
+}
+
+/// The journal of all live BTC transactions with the stored settings
+pub async fn journal(pool: &SqlitePool) -> Result<Journal, String> {
+    #[derive(sqlx::FromRow)]
+    struct Row {
+        id: i64,
+        wallet_id: i64,
+        wallet_name: String,
+        entity_id: i64,
+        entity_name: String,
+        txid: String,
+        timestamp: Option<i64>,
+        amount_sats: i64,
+        fiat_value: Option<f64>,
+        fiat_currency: Option<String>,
+        category: String,
+    }
+
+    // One read transaction, so the lots and the postings see the same rows
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    let report = cost_basis::load_report(&mut tx, None, None, None, None).await?;
+
+    let rows = sqlx::query_as::<_, Row>(
+        r#"
+        SELECT t.id, t.wallet_id, w.name as wallet_name, w.entity_id, e.name as entity_name, t.txid, t.timestamp,
+               t.amount_sats, t.fiat_value, t.fiat_currency, COALESCE(c.name, 'Uncategorized') as category
+        FROM transactions t
+        JOIN wallets w ON t.wallet_id = w.id
+        JOIN entities e ON w.entity_id = e.id
+        LEFT JOIN categories c ON t.category_id = c.id
+        WHERE t.trash_id IS NULL AND COALESCE(t.currency, 'BTC') = 'BTC'
+        ORDER BY t.timestamp, t.id
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+    tx.commit().await.map_err(|e| e.to_string())?;
+
+    let mut transactions = Vec::new();
+    let mut warnings = Vec::new();
+
+    for row in rows {
+        let Some(timestamp) = row.timestamp else {
+            warnings.push(format!("Transaction {} has no date and was left out", row.id));
+            continue;
+        };
+        let in_currency = row
+            .fiat_currency
+            .as_ref()
+            .is_none_or(|fiat_currency| fiat_currency.eq_ignore_ascii_case(&report.currency));
+
+        transactions.push(Transaction {
+            id: row.id,
+            wallet_id: row.wallet_id,
+            wallet_name: row.wallet_name,
+            entity_id: row.entity_id,
+            entity_name: row.entity_name,
+            txid: row.txid,
+            timestamp,
+            amount_sats: row.amount_sats,
+            fiat_value: row.fiat_value.filter(|_| in_currency),
+            category: row.category,
+        });
+    }
+
+    let mut journal = build(&transactions, &report);
+    warnings.append(&mut journal.warnings);
+    journal.warnings = warnings;
+    Ok(journal)
+}
+
+/// Unix time at the end of an optional `YYYY-MM-DD` date
+fn end_of(date: Option<&str>) -> Result<Option<i64>, String> {
+    date.map(|date| Ok(dates::date_to_unix(date)? + SECONDS_PER_DAY))
+        .transpose()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Balance {
+    pub account: Account,
+    pub sats: i64,
+    pub fiat: f64,
+    pub debit: f64,
+    pub credit: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrialBalance {
+    pub currency: String,
+    pub as_of: Option<String>,
+    pub balances: Vec<Balance>,
+    pub total_debit: f64,
+    pub total_credit: f64,
+    /// Zero when every entry balances
+    pub total_sats: i64,
+    pub warnings: Vec<String>,
+}
+
+/// Every account's balance at the end of a date, optionally for one entity
+pub fn trial_balance(journal: &Journal, as_of: Option<&str>, entity_id: Option<i64>) -> Result<TrialBalance, String> {
+    let end = end_of(as_of)?;
+    let mut totals: HashMap<&str, (i64, f64)> = HashMap::new();
+
+    for entry in &journal.entries {
+        if end.is_some_and(|end| entry.timestamp >= end) {
+            continue;
+        }
+        for posting in &entry.postings {
+            let total = totals.entry(posting.account_id.as_str()).or_default();
+            total.0 += posting.sats;
+            total.1 += posting.fiat;
+        }
+    }
+
+    let mut balances: Vec<Balance> = totals
+        .into_iter()
+        .map(|(id, (sats, fiat))| Balance {
+            account: journal.accounts[id].clone(),
+            sats,
+            fiat,
+            debit: fiat.max(0.0),
+            credit: (-fiat).max(0.0),
+        })
+        .filter(|balance| entity_id.is_none_or(|id| id == balance.account.entity_id))
+        .collect();
+    balances.sort_by(|a, b| {
+        (a.account.entity_id, a.account.kind, &a.account.name, &a.account.id).cmp(&(
+            b.account.entity_id,
+            b.account.kind,
+            &b.account.name,
+            &b.account.id,
+        ))
+    });
+
+    Ok(TrialBalance {
+        currency: journal.currency.clone(),
+        as_of: as_of.map(str::to_string),
+        total_debit: balances.iter().map(|b| b.debit).sum(),
+        total_credit: balances.iter().map(|b| b.credit).sum(),
+        total_sats: balances.iter().map(|b| b.sats).sum(),
+        balances,
+        warnings: journal.warnings.clone(),
+    })
+}
+
+/// A posting to one account, with the running balance after it
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountPosting {
+    pub transaction_id: i64,
+    pub timestamp: i64,
+    pub category: String,
+    pub sats: i64,
+    pub fiat: f64,
+    pub balance_sats: i64,
+    pub balance_fiat: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountBalance {
+    pub account: Account,
+    pub currency: String,
+    pub sats: i64,
+    pub fiat: f64,
+    pub postings: Vec<AccountPosting>,
+}
+
+/// One account's postings and balance at the end of a date
+pub fn account_balance(journal: &Journal, account_id: &str, as_of: Option<&str>) -> Result<AccountBalance, String> {
+    let account = journal.accounts.get(account_id).ok_or("Unknown account")?;
+    let end = end_of(as_of)?;
+    let mut postings = Vec::new();
+    let (mut sats, mut fiat) = (0i64, 0.0);
+
+    for entry in &journal.entries {
+        if end.is_some_and(|end| entry.timestamp >= end) {
+            break;
+        }
+        for posting in entry.postings.iter().filter(|p| p.account_id == account_id) {
+            sats += posting.sats;
+            fiat += posting.fiat;
+            postings.push(AccountPosting {
+                transaction_id: entry.transaction_id,
+                timestamp: entry.timestamp,
+                category: entry.category.clone(),
+                sats: posting.sats,
+                fiat: posting.fiat,
+                balance_sats: sats,
+                balance_fiat: fiat,
+            });
+        }
+    }
+
+    Ok(AccountBalance {
+        account: account.clone(),
+        currency: journal.currency.clone(),
+        sats,
+        fiat,
+        postings,
+    })
+}
+
+/// The trial balance as CSV
+pub fn to_csv(trial_balance: &TrialBalance) -> String {
+    let currency = &trial_balance.currency;
+    let mut csv = format!("Owner,Account,Type,Sats,Debit ({}),Credit ({})\n", currency, currency);
+
+    for balance in &trial_balance.balances {
+        let kind = match balance.account.kind {
+            AccountKind::Asset => "Asset",
+            AccountKind::Liability => "Liability",
+            AccountKind::Equity => "Equity",
+            AccountKind::Income => "Income",
+            AccountKind::Expense => "Expense",
+        };
+        let row = [
+            csv_field(&balance.account.entity_name),
+            csv_field(&balance.account.name),
+            kind.to_string(),
+            balance.sats.to_string(),
+            format!("{:.2}", balance.debit),
+            format!("{:.2}", balance.credit),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv.push_str(&format!(
+        "Total,,,{},{:.2},{:.2}\n",
+        trial_balance.total_sats, trial_balance.total_debit, trial_balance.total_credit
+    ));
+
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost_basis::{compute, Entry as CostEntry, Method, Options, Scope, TransferFeeRule};
+
+    /// A transaction an hour into the given day after the epoch
+    fn tx(id: i64, wallet_id: i64, day: i64, amount_sats: i64, fiat_value: f64, category: &str) -> Transaction {
+        Transaction {
+            id,
+            wallet_id,
+            wallet_name: format!("Wallet {}", wallet_id),
+            entity_id: 1,
+            entity_name: "Personal".to_string(),
+            txid: format!("tx{}", id),
+            timestamp: day * SECONDS_PER_DAY + 3_600,
+            amount_sats,
+            fiat_value: Some(fiat_value),
+            category: category.to_string(),
+        }
+    }
+
+    fn journal(transactions: &[Transaction], scope: Scope) -> Journal {
+        let options = Options {
+            scope,
+            ..Options::new(Method::Fifo)
+        };
+        journal_with_fees(transactions, &options, &HashMap::new())
+    }
+
+    /// `fees` maps transaction ids to their fee in sats and its value
+    fn journal_with_fees(transactions: &[Transaction], options: &Options, fees: &HashMap<i64, (i64, f64)>) -> Journal {
+        let entries: Vec<CostEntry> = transactions
+            .iter()
+            .map(|tx| CostEntry {
+                id: tx.id,
+                wallet_id: tx.wallet_id,
+                entity_id: tx.entity_id,
+                txid: tx.txid.clone(),
+                timestamp: tx.timestamp,
+                amount_sats: tx.amount_sats,
+                fiat_value: tx.fiat_value.unwrap_or(0.0),
+                category: tx.category.clone(),
+                fee_sats: fees.get(&tx.id).map_or(0, |fee| fee.0),
+                fee_value: fees.get(&tx.id).map_or(0.0, |fee| fee.1),
+            })
+            .collect();
+        build(transactions, &compute(&entries, "EUR", options))
+    }
+
+    fn sample() -> Vec<Transaction> {
+        let mut transfer_in = tx(5, 2, 4, 40_000_000, 0.0, TRANSFER_IN);
+        transfer_in.txid = "tx4".to_string();
+        vec![
+            tx(1, 1, 1, 100_000_000, 10_000.0, "Buy"),
+            tx(2, 1, 2, 5_000_000, 1_500.0, "Mining"),
+            tx(3, 1, 3, -50_000_000, 20_000.0, "Sell"),
+            tx(4, 1, 4, -40_000_000, 0.0, TRANSFER_OUT),
+            transfer_in,
+            tx(6, 2, 5, 10_000_000, 3_000.0, "Receive Loan"),
+            tx(7, 2, 6, -1_000_000, 500.0, "Food"),
+        ]
+    }
+
+    #[test]
+    fn test_entries_balance() {
+        let journal = journal(&sample(), Scope::Wallet);
+        assert_eq!(journal.entries.len(), 7);
+        assert!(journal.entries.iter().all(JournalEntry::is_balanced));
+        assert!(journal.warnings.is_empty());
+
+        // The sale moves BTC out against trading and money into cash
+        let sale = &journal.entries[2];
+        let postings: Vec<_> = sale
+            .postings
+            .iter()
+            .map(|p| (p.account_id.as_str(), p.sats, p.fiat))
+            .collect();
+        assert_eq!(
+            postings,
+            vec![
+                ("wallet:1", -50_000_000, -5_000.0),
+                ("entity:1:cash", 0, 20_000.0),
+                ("entity:1:trading", 50_000_000, 0.0),
+                ("entity:1:gains", 0, -15_000.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trial_balance() {
+        let journal = journal(&sample(), Scope::Wallet);
+        let trial_balance = trial_balance(&journal, None, None).unwrap();
+        assert_eq!(trial_balance.total_sats, 0);
+        assert!((trial_balance.total_debit - trial_balance.total_credit).abs() < EPSILON);
+
+        let balance = |id: &str| {
+            let balance = trial_balance.balances.iter().find(|b| b.account.id == id).unwrap();
+            (balance.sats, balance.fiat)
+        };
+        // The transfer carried its lot's basis, and nothing is in transit
+        assert_eq!(balance("wallet:1"), (15_000_000, 2_500.0));
+        assert_eq!(balance("wallet:2"), (49_000_000, 6_900.0));
+        assert_eq!(balance("entity:1:transfers"), (0, 0.0));
+        assert_eq!(balance("entity:1:loans"), (-10_000_000, -3_000.0));
+        assert_eq!(balance("entity:1:category:Mining").0, -5_000_000);
+        assert_eq!(trial_balance.balances[0].account.kind, AccountKind::Asset);
+
+        // Before the sale on day 3: the buy and the mining income
+        let earlier = super::trial_balance(&journal, Some("1970-01-03"), None).unwrap();
+        assert_eq!(earlier.balances.len(), 4);
+        assert_eq!(earlier.total_debit, 11_500.0);
+        assert!(super::trial_balance(&journal, Some("1970-13-01"), None).is_err());
+    }
+
+    #[test]
+    fn test_account_balance() {
+        let journal = journal(&sample(), Scope::Universal);
+        let wallet = account_balance(&journal, "wallet:2", None).unwrap();
+        assert_eq!(wallet.postings.len(), 3);
+        assert_eq!(wallet.sats, 49_000_000);
+        // The transfer carried no basis: the loan's value less the food's share of lot 1
+        assert_eq!(wallet.fiat, 2_900.0);
+        assert_eq!(wallet.postings[1].balance_sats, 50_000_000);
+        assert_eq!(wallet.postings[2].transaction_id, 7);
+
+        let csv = to_csv(&trial_balance(&journal, None, None).unwrap());
+        assert!(csv.starts_with("Owner,Account,Type,Sats,Debit (EUR),Credit (EUR)\n"));
+        assert!(csv.contains("Personal,Loans,Liability,-10000000,0.00,3000.00\n"));
+        assert!(account_balance(&journal, "wallet:9", None).is_err());
+    }
+
+    #[test]
+    fn test_universal_pool_balances() {
+        let mut transfer_in = tx(3, 2, 2, 60_000_000, 0.0, TRANSFER_IN);
+        transfer_in.txid = "tx2".to_string();
+        let transactions = vec![
+            tx(1, 1, 1, 100_000_000, 10_000.0, "Buy"),
+            tx(2, 1, 2, -60_000_000, 0.0, TRANSFER_OUT),
+            transfer_in,
+            tx(4, 2, 3, -20_000_000, 8_000.0, "Sell"),
+        ];
+
+        // Wallet 1 keeps the basis it sent on; wallet 2's sale takes it below zero
+        let universal = balances(&journal(&transactions, Scope::Universal));
+        assert_eq!(universal["wallet:1"], (40_000_000, 10_000.0));
+        assert_eq!(universal["wallet:2"], (40_000_000, -2_000.0));
+        assert_eq!(universal["entity:1:transfers"], (0, 0.0));
+
+        // Together they hold what's left of the pool: 0.8 of lot 1
+        assert_eq!(universal["wallet:1"].1 + universal["wallet:2"].1, 8_000.0);
+
+        // Per-wallet pools carry the basis over with the sats
+        let per_wallet = balances(&journal(&transactions, Scope::Wallet));
+        assert_eq!(per_wallet["wallet:1"], (40_000_000, 4_000.0));
+        assert_eq!(per_wallet["wallet:2"], (40_000_000, 4_000.0));
+    }
+
+    /// A 1 BTC buy, then 0.4 BTC sent to wallet 2 with a 100,000 sat fee
+    fn transfer_with_fee(rule: TransferFeeRule) -> Journal {
+        let mut transfer_in = tx(3, 2, 2, 39_900_000, 0.0, TRANSFER_IN);
+        transfer_in.txid = "tx2".to_string();
+        let transactions = vec![
+            tx(1, 1, 1, 100_000_000, 10_000.0, "Buy"),
+            tx(2, 1, 2, -40_000_000, 0.0, TRANSFER_OUT),
+            transfer_in,
+        ];
+        let options = Options {
+            scope: Scope::Wallet,
+            transfer_fees: rule,
+            ..Options::new(Method::Fifo)
+        };
+        journal_with_fees(&transactions, &options, &HashMap::from([(2, (100_000, 60.0))]))
+    }
+
+    fn balances(journal: &Journal) -> HashMap<String, (i64, f64)> {
+        trial_balance(journal, None, None)
+            .unwrap()
+            .balances
+            .into_iter()
+            .map(|balance| (balance.account.id, (balance.sats, balance.fiat)))
+            .collect()
+    }
+
+    #[test]
+    fn test_transfer_fee_as_disposal() {
+        let journal = transfer_with_fee(TransferFeeRule::Disposal);
+        assert!(journal.entries.iter().all(JournalEntry::is_balanced));
+        assert!(journal.warnings.is_empty());
+
+        // The fee is sold at its value: 10 of basis, 60 of proceeds
+        let balances = balances(&journal);
+        assert_eq!(balances["wallet:1"], (60_000_000, 6_000.0));
+        assert_eq!(balances["wallet:2"], (39_900_000, 3_990.0));
+        assert_eq!(balances["entity:1:fees"], (100_000, 60.0));
+        assert_eq!(balances["entity:1:gains"], (0, -50.0));
+        assert_eq!(balances["entity:1:transfers"], (0, 0.0));
+    }
+
+    #[test]
+    fn test_transfer_fee_written_off() {
+        let journal = transfer_with_fee(TransferFeeRule::NonDeductible);
+        assert!(journal.entries.iter().all(JournalEntry::is_balanced));
+        assert!(journal.warnings.is_empty());
+
+        // The fee's basis goes to expenses with no gain
+        let balances = balances(&journal);
+        assert_eq!(balances["wallet:1"], (60_000_000, 6_000.0));
+        assert_eq!(balances["wallet:2"], (39_900_000, 3_990.0));
+        assert_eq!(balances["entity:1:fees"], (100_000, 10.0));
+        assert!(!balances.contains_key("entity:1:gains"));
+        assert_eq!(balances["entity:1:transfers"], (0, 0.0));
+    }
+
+    #[tokio::test]
+    async fn test_journal_from_database() {
+        let pool = crate::test_support::memory_pool().await;
+        for statement in [
+            "UPDATE settings SET value = 'disposal' WHERE key = 'transfer_fee_rule'",
+            "UPDATE settings SET value = 'wallet' WHERE key = 'cost_basis_scope'",
+            "INSERT INTO wallets (id, name, xpub) VALUES (1, 'Savings', 'xpub1'), (2, 'Cold', 'xpub2')",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, fee_sats, fee_fiat, timestamp, fiat_value, fiat_currency, category_id) VALUES
+                (1, 1, 'a', 100000000, 0, NULL, 90000, 10000, 'EUR', (SELECT id FROM categories WHERE name = 'Buy')),
+                (2, 1, 'b', -40000000, 100000, 60, 180000, NULL, NULL, (SELECT id FROM categories WHERE name = 'Transfer Out')),
+                (3, 2, 'b', 39900000, 0, NULL, 180000, NULL, NULL, (SELECT id FROM categories WHERE name = 'Transfer In'))",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        let journal = super::journal(&pool).await.unwrap();
+        assert_eq!(journal.currency, "EUR");
+        assert_eq!(journal.entries.len(), 3);
+        assert!(journal.warnings.is_empty(), "{:?}", journal.warnings);
+
+        let balances = balances(&journal);
+        assert_eq!(balances["wallet:2"], (39_900_000, 3_990.0));
+        assert_eq!(balances["entity:1:gains"], (0, -50.0));
+    }
+}
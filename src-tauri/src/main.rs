@@ -1,15 +1,33 @@
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
 
+mod acb;
+mod audit;
+mod backup;
 mod bitcoin_utils;
 mod blockchain;
+mod cost_basis;
+mod dates;
 mod db;
 mod crypto;
 mod encrypted_db;
+mod encryption_header;
+mod form8949;
+mod hmrc;
+mod income;
+mod ledger;
+mod periods;
+mod session;
+mod slip39;
+mod snapshots;
+mod tax_profile;
+#[cfg(test)]
+mod test_support;
+mod trash;
+mod vaults;
 
-use crate::crypto::PasswordStrength;
+use crate::crypto::{PasswordStrength, SecretString};
 
-use tauri::Manager;
+use tauri::{Emitter, Manager};
 
 #[derive(Debug, serde::Serialize, sqlx::FromRow)]
 struct Entity {
@@ -84,11 +102,13 @@ struct Category {
 // Entity commands
 
 #[tauri::command]
-async fn get_entities(pool: tauri::State<'_, SqlitePool>) -> Result<Vec<Entity>, String> {
+async fn get_entities(session: tauri::State<'_, session::Session>) -> Result<Vec<Entity>, String> {
+    let pool = session.pool().await?;
+    
     let entities = sqlx::query_as::<_, Entity>(
-        "SELECT id, name, entity_type, parent_id FROM entities ORDER BY entity_type, name"
+        "SELECT id, name, entity_type, parent_id FROM entities WHERE trash_id IS NULL ORDER BY entity_type, name"
     )
-    .fetch_all(pool.inner())
+    .fetch_all(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -97,14 +117,16 @@ async fn get_entities(pool: tauri::State<'_, SqlitePool>) -> Result<Vec<Entity>,
 
 #[tauri::command]
 async fn add_family_member(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     name: String,
 ) -> Result<Entity, String> {
+    let pool = session.pool().await?;
+    
     let result = sqlx::query(
         "INSERT INTO entities (name, entity_type) VALUES (?, 'family')"
     )
     .bind(&name)
-    .execute(pool.inner())
+    .execute(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -120,16 +142,18 @@ async fn add_family_member(
 
 #[tauri::command]
 async fn add_business(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     name: String,
     parent_id: Option<i64>,
 ) -> Result<Entity, String> {
+    let pool = session.pool().await?;
+    
     let result = sqlx::query(
         "INSERT INTO entities (name, entity_type, parent_id) VALUES (?, 'business', ?)"
     )
     .bind(&name)
     .bind(parent_id)
-    .execute(pool.inner())
+    .execute(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -145,35 +169,48 @@ async fn add_business(
 
 #[tauri::command]
 async fn delete_entity(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     id: i64,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     if id == 1 {
         return Err("Cannot delete Personal entity".to_string());
     }
     
-    sqlx::query("DELETE FROM entities WHERE id = ?")
-        .bind(id)
-        .execute(pool.inner())
-        .await
-        .map_err(|e| e.to_string())?;
+    trash::delete_entity(&pool, id).await?;
     
-    Ok("Entity deleted".to_string())
+    Ok("Entity moved to trash".to_string())
 }
 
 // Wallet commands
 
 #[tauri::command]
 async fn add_wallet(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     name: String,
     xpub: Option<String>,
     wallet_type: String,
     entity_id: i64,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     // For manual wallets, generate a unique identifier
     let xpub_value = xpub.unwrap_or_else(|| format!("manual-{}", uuid::Uuid::new_v4()));
     
+    // A trashed wallet still holds its xpub
+    let trashed = sqlx::query_as::<_, (i64,)>(
+        "SELECT id FROM wallets WHERE xpub = ? AND trash_id IS NOT NULL"
+    )
+    .bind(&xpub_value)
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| e.to_string())?;
+    
+    if trashed.is_some() {
+        return Err("This wallet is in the trash. Restore it instead".to_string());
+    }
+    
     sqlx::query(
         "INSERT INTO wallets (name, xpub, wallet_type, entity_id) VALUES (?, ?, ?, ?)"
     )
@@ -181,7 +218,7 @@ async fn add_wallet(
     .bind(&xpub_value)
     .bind(&wallet_type)
     .bind(entity_id)
-    .execute(pool.inner())
+    .execute(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -189,18 +226,20 @@ async fn add_wallet(
 }
 
 #[tauri::command]
-async fn get_wallets(pool: tauri::State<'_, SqlitePool>) -> Result<Vec<WalletWithOwner>, String> {
+async fn get_wallets(session: tauri::State<'_, session::Session>) -> Result<Vec<WalletWithOwner>, String> {
+    let pool = session.pool().await?;
+    
     let wallets = sqlx::query_as::<_, Wallet>(
-        "SELECT id, name, xpub, wallet_type, entity_id FROM wallets"
+        "SELECT id, name, xpub, wallet_type, entity_id FROM wallets WHERE trash_id IS NULL"
     )
-    .fetch_all(pool.inner())
+    .fetch_all(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
     let entities = sqlx::query_as::<_, Entity>(
         "SELECT id, name, entity_type, parent_id FROM entities"
     )
-    .fetch_all(pool.inner())
+    .fetch_all(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -224,14 +263,12 @@ async fn get_wallets(pool: tauri::State<'_, SqlitePool>) -> Result<Vec<WalletWit
 }
 
 #[tauri::command]
-async fn delete_wallet(pool: tauri::State<'_, SqlitePool>, id: i64) -> Result<String, String> {
-    sqlx::query("DELETE FROM wallets WHERE id = ?")
-        .bind(id)
-        .execute(pool.inner())
-        .await
-        .map_err(|e| e.to_string())?;
+async fn delete_wallet(session: tauri::State<'_, session::Session>, id: i64) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
+    trash::delete_wallet(&pool, id).await?;
     
-    Ok("Wallet deleted".to_string())
+    Ok("Wallet moved to trash".to_string())
 }
 
 #[tauri::command]
@@ -317,48 +354,114 @@ async fn fetch_wallet_transactions(xpub: String) -> Result<Vec<blockchain::Trans
 
 #[tauri::command]
 async fn save_transactions(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     wallet_id: i64,
     transactions: Vec<blockchain::Transaction>,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let mut db = pool.begin().await.map_err(|e| e.to_string())?;
+    let mut sealed = 0;
+    
     for tx in transactions {
-        sqlx::query(
-            r#"
-            INSERT INTO transactions (wallet_id, txid, amount_sats, fee_sats, confirmed, block_height, timestamp)
-            VALUES (?, ?, ?, ?, ?, ?, ?)
-            ON CONFLICT(wallet_id, txid) DO UPDATE SET
-                amount_sats = excluded.amount_sats,
-                fee_sats = excluded.fee_sats,
-                confirmed = excluded.confirmed,
-                block_height = excluded.block_height,
-                timestamp = excluded.timestamp
-            "#,
+        let amount_sats = tx.amount_sats;
+        let fee_sats = tx.fee_sats as i64;
+        let timestamp = tx.timestamp.map(|t| t as i64);
+        
+        let existing = sqlx::query_as::<_, (i64, i64, i64, Option<i64>)>(
+            "SELECT id, amount_sats, fee_sats, timestamp FROM transactions WHERE wallet_id = ? AND txid = ?"
         )
         .bind(wallet_id)
         .bind(&tx.txid)
-        .bind(tx.amount_sats)
-        .bind(tx.fee_sats as i64)
-        .bind(tx.confirmed)
-        .bind(tx.block_height.map(|h| h as i64))
-        .bind(tx.timestamp.map(|t| t as i64))
-        .execute(pool.inner())
+        .fetch_optional(&mut *db)
         .await
         .map_err(|e| e.to_string())?;
+        
+        match existing {
+            Some((id, old_amount, old_fee, old_timestamp)) => {
+                // Sealed rows are left as they are; reopening the period lets the next sync update them
+                if periods::is_closed(&mut db, old_timestamp).await? || periods::is_closed(&mut db, timestamp).await? {
+                    if (old_amount, old_fee, old_timestamp) != (amount_sats, fee_sats, timestamp) {
+                        sealed += 1;
+                    }
+                    continue;
+                }
+                
+                let old_values = audit::transaction_values(&mut db, id).await?;
+                
+                sqlx::query(
+                    "UPDATE transactions SET amount_sats = ?, fee_sats = ?, confirmed = ?, block_height = ?, timestamp = ? WHERE id = ?"
+                )
+                .bind(amount_sats)
+                .bind(fee_sats)
+                .bind(tx.confirmed)
+                .bind(tx.block_height.map(|h| h as i64))
+                .bind(timestamp)
+                .bind(id)
+                .execute(&mut *db)
+                .await
+                .map_err(|e| e.to_string())?;
+                
+                let new_values = audit::transaction_values(&mut db, id).await?;
+                audit::record_change(&mut db, "save_transactions", "transactions", id, old_values, new_values).await?;
+            }
+            None => {
+                // A new transaction dated in a closed period would change it
+                if periods::is_closed(&mut db, timestamp).await? {
+                    sealed += 1;
+                    continue;
+                }
+                
+                sqlx::query(
+                    r#"
+                    INSERT INTO transactions (wallet_id, txid, amount_sats, fee_sats, confirmed, block_height, timestamp)
+                    VALUES (?, ?, ?, ?, ?, ?, ?)
+                    "#,
+                )
+                .bind(wallet_id)
+                .bind(&tx.txid)
+                .bind(amount_sats)
+                .bind(fee_sats)
+                .bind(tx.confirmed)
+                .bind(tx.block_height.map(|h| h as i64))
+                .bind(timestamp)
+                .execute(&mut *db)
+                .await
+                .map_err(|e| e.to_string())?;
+            }
+        }
     }
     
-    Ok("Transactions saved".to_string())
+    db.commit().await.map_err(|e| e.to_string())?;
+    
+    if sealed > 0 {
+        Ok(format!(
+            "Transactions saved; {} in closed periods were left unchanged. Reopen the period to update them",
+            sealed
+        ))
+    } else {
+        Ok("Transactions saved".to_string())
+    }
 }
 
 #[tauri::command]
 async fn get_cached_transactions(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     wallet_id: i64,
 ) -> Result<Vec<StoredTransaction>, String> {
+    let pool = session.pool().await?;
+    
     let txs = sqlx::query_as::<_, StoredTransaction>(
-        "SELECT id, wallet_id, txid, amount_sats, fee_sats, confirmed, block_height, timestamp, category, note FROM transactions WHERE wallet_id = ? ORDER BY timestamp DESC"
+        r#"
+        SELECT t.id, t.wallet_id, t.txid, t.amount_sats, t.fee_sats, t.confirmed, t.block_height, t.timestamp,
+               c.name as category, t.note
+        FROM transactions t
+        LEFT JOIN categories c ON t.category_id = c.id
+        WHERE t.wallet_id = ? AND t.trash_id IS NULL
+        ORDER BY t.timestamp DESC
+        "#,
     )
     .bind(wallet_id)
-    .fetch_all(pool.inner())
+    .fetch_all(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -367,9 +470,11 @@ async fn get_cached_transactions(
 
 #[tauri::command]
 async fn get_all_transactions(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     entity_id: Option<i64>,
 ) -> Result<Vec<TransactionWithDetails>, String> {
+    let pool = session.pool().await?;
+    
     let query = match entity_id {
         Some(id) => {
             sqlx::query_as::<_, TransactionWithDetails>(
@@ -377,18 +482,19 @@ async fn get_all_transactions(
                 SELECT t.id, t.wallet_id, t.txid, t.amount_sats, t.fee_sats, 
                        t.fee_fiat, t.fee_fiat_currency,
                        t.confirmed, t.block_height, t.timestamp, 
-                       t.category, t.note, t.fiat_value, t.fiat_currency,
+                       c.name as category, t.note, t.fiat_value, t.fiat_currency,
                        COALESCE(t.currency, 'BTC') as currency,
                        w.name as wallet_name, e.name as owner_name
                 FROM transactions t
                 JOIN wallets w ON t.wallet_id = w.id
                 JOIN entities e ON w.entity_id = e.id
-                WHERE w.entity_id = ?
+                LEFT JOIN categories c ON t.category_id = c.id
+                WHERE w.entity_id = ? AND t.trash_id IS NULL
                 ORDER BY t.timestamp DESC
                 "#,
             )
             .bind(id)
-            .fetch_all(pool.inner())
+            .fetch_all(&pool)
             .await
         }
         None => {
@@ -397,16 +503,18 @@ async fn get_all_transactions(
                 SELECT t.id, t.wallet_id, t.txid, t.amount_sats, t.fee_sats,
                        t.fee_fiat, t.fee_fiat_currency,
                        t.confirmed, t.block_height, t.timestamp, 
-                       t.category, t.note, t.fiat_value, t.fiat_currency,
+                       c.name as category, t.note, t.fiat_value, t.fiat_currency,
                        COALESCE(t.currency, 'BTC') as currency,
                        w.name as wallet_name, e.name as owner_name
                 FROM transactions t
                 JOIN wallets w ON t.wallet_id = w.id
                 JOIN entities e ON w.entity_id = e.id
+                LEFT JOIN categories c ON t.category_id = c.id
+                WHERE t.trash_id IS NULL
                 ORDER BY t.timestamp DESC
                 "#,
             )
-            .fetch_all(pool.inner())
+            .fetch_all(&pool)
             .await
         }
     };
@@ -416,28 +524,42 @@ async fn get_all_transactions(
 
 #[tauri::command]
 async fn update_transaction(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     id: i64,
     category: Option<String>,
     note: Option<String>,
 ) -> Result<String, String> {
-    sqlx::query("UPDATE transactions SET category = ?, note = ? WHERE id = ?")
-        .bind(category)
+    let pool = session.pool().await?;
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    
+    periods::ensure_transaction_open(&mut tx, id).await?;
+    let old_values = audit::transaction_values(&mut tx, id).await?;
+    let category_id = resolve_category_id(&mut tx, category.as_deref()).await?;
+    
+    sqlx::query("UPDATE transactions SET category_id = ?, note = ? WHERE id = ?")
+        .bind(category_id)
         .bind(note)
         .bind(id)
-        .execute(pool.inner())
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
     
+    let new_values = audit::transaction_values(&mut tx, id).await?;
+    audit::record_change(&mut tx, "update_transaction", "transactions", id, old_values, new_values).await?;
+    
+    tx.commit().await.map_err(|e| e.to_string())?;
+    
     Ok("Transaction updated".to_string())
 }
 
 #[tauri::command]
-async fn get_wallet_balances(pool: tauri::State<'_, SqlitePool>) -> Result<std::collections::HashMap<i64, i64>, String> {
+async fn get_wallet_balances(session: tauri::State<'_, session::Session>) -> Result<std::collections::HashMap<i64, i64>, String> {
+    let pool = session.pool().await?;
+    
     let rows = sqlx::query_as::<_, (i64, i64)>(
-        "SELECT wallet_id, SUM(amount_sats) as balance FROM transactions GROUP BY wallet_id"
+        "SELECT wallet_id, SUM(amount_sats) as balance FROM transactions WHERE trash_id IS NULL GROUP BY wallet_id"
     )
-    .fetch_all(pool.inner())
+    .fetch_all(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -450,12 +572,14 @@ async fn get_wallet_balances(pool: tauri::State<'_, SqlitePool>) -> Result<std::
 }
 
 #[tauri::command]
-async fn get_setting(pool: tauri::State<'_, SqlitePool>, key: String) -> Result<Option<String>, String> {
+async fn get_setting(session: tauri::State<'_, session::Session>, key: String) -> Result<Option<String>, String> {
+    let pool = session.pool().await?;
+    
     let result = sqlx::query_as::<_, (String,)>(
         "SELECT value FROM settings WHERE key = ?"
     )
     .bind(&key)
-    .fetch_optional(pool.inner())
+    .fetch_optional(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -463,13 +587,15 @@ async fn get_setting(pool: tauri::State<'_, SqlitePool>, key: String) -> Result<
 }
 
 #[tauri::command]
-async fn set_setting(pool: tauri::State<'_, SqlitePool>, key: String, value: String) -> Result<String, String> {
+async fn set_setting(session: tauri::State<'_, session::Session>, key: String, value: String) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     sqlx::query(
         "INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value"
     )
     .bind(&key)
     .bind(&value)
-    .execute(pool.inner())
+    .execute(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -478,19 +604,46 @@ async fn set_setting(pool: tauri::State<'_, SqlitePool>, key: String, value: Str
 
 #[tauri::command]
 async fn update_wallet(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     id: i64,
     name: String,
     entity_id: i64,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    
+    let (old_name, old_entity_id) = sqlx::query_as::<_, (String, i64)>("SELECT name, entity_id FROM wallets WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("Wallet not found")?;
+    
+    // Moving a wallet to another owner moves its transactions, sealed ones included
+    if entity_id != old_entity_id {
+        periods::ensure_none_closed(&mut tx, "t.trash_id IS NULL AND t.wallet_id = ?", Some(id)).await?;
+    }
+    
     sqlx::query("UPDATE wallets SET name = ?, entity_id = ? WHERE id = ?")
         .bind(&name)
         .bind(entity_id)
         .bind(id)
-        .execute(pool.inner())
+        .execute(&mut *tx)
         .await
         .map_err(|e| e.to_string())?;
     
+    audit::record_change(
+        &mut tx,
+        "update_wallet",
+        "wallets",
+        id,
+        serde_json::json!({ "name": old_name, "entity_id": old_entity_id }),
+        serde_json::json!({ "name": name, "entity_id": entity_id }),
+    )
+    .await?;
+    
+    tx.commit().await.map_err(|e| e.to_string())?;
+    
     Ok("Wallet updated".to_string())
 }
 
@@ -562,16 +715,18 @@ async fn fetch_historical_price(currency: String, date: String) -> Result<f64, S
 
 #[tauri::command]
 async fn get_cached_price(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     currency: String,
     date: String,
 ) -> Result<Option<f64>, String> {
+    let pool = session.pool().await?;
+    
     let result = sqlx::query_as::<_, (f64,)>(
         "SELECT price FROM price_cache WHERE currency = ? AND date = ?"
     )
     .bind(&currency)
     .bind(&date)
-    .fetch_optional(pool.inner())
+    .fetch_optional(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -580,11 +735,13 @@ async fn get_cached_price(
 
 #[tauri::command]
 async fn cache_price(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     currency: String,
     price: f64,
     date: String,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     sqlx::query(
         "INSERT INTO price_cache (currency, price, date) VALUES (?, ?, ?) 
          ON CONFLICT(currency, date) DO UPDATE SET price = excluded.price, updated_at = CURRENT_TIMESTAMP"
@@ -592,7 +749,7 @@ async fn cache_price(
     .bind(&currency)
     .bind(price)
     .bind(&date)
-    .execute(pool.inner())
+    .execute(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -601,27 +758,38 @@ async fn cache_price(
 
 #[tauri::command]
 async fn update_transaction_fiat(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     id: i64,
     fiat_value: Option<f64>,
     fiat_currency: Option<String>,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    
+    periods::ensure_transaction_open(&mut tx, id).await?;
+    let old_values = audit::transaction_values(&mut tx, id).await?;
+    
     sqlx::query(
         "UPDATE transactions SET fiat_value = ?, fiat_currency = ? WHERE id = ?"
     )
     .bind(fiat_value)
     .bind(&fiat_currency)
     .bind(id)
-    .execute(pool.inner())
+    .execute(&mut *tx)
     .await
     .map_err(|e| e.to_string())?;
     
+    let new_values = audit::transaction_values(&mut tx, id).await?;
+    audit::record_change(&mut tx, "update_transaction_fiat", "transactions", id, old_values, new_values).await?;
+    
+    tx.commit().await.map_err(|e| e.to_string())?;
+    
     Ok("Transaction fiat updated".to_string())
 }
 
 #[tauri::command]
 async fn add_manual_transaction(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     wallet_id: i64,
     amount_sats: i64,
     timestamp: i64,
@@ -634,12 +802,19 @@ async fn add_manual_transaction(
     fee_fiat: Option<f64>,
     fee_fiat_currency: Option<String>,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     let txid = format!("manual-{}-{}", timestamp, rand::random::<u32>());
     let tx_currency = currency.unwrap_or_else(|| "BTC".to_string());
     
-    sqlx::query(
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    
+    periods::ensure_open(&mut tx, Some(timestamp)).await?;
+    let category_id = resolve_category_id(&mut tx, category.as_deref()).await?;
+    
+    let result = sqlx::query(
         r#"
-        INSERT INTO transactions (wallet_id, txid, amount_sats, fee_sats, fee_fiat, fee_fiat_currency, confirmed, timestamp, category, note, fiat_value, fiat_currency, currency)
+        INSERT INTO transactions (wallet_id, txid, amount_sats, fee_sats, fee_fiat, fee_fiat_currency, confirmed, timestamp, category_id, note, fiat_value, fiat_currency, currency)
         VALUES (?, ?, ?, ?, ?, ?, 1, ?, ?, ?, ?, ?, ?)
         "#,
     )
@@ -650,52 +825,58 @@ async fn add_manual_transaction(
     .bind(fee_fiat)
     .bind(&fee_fiat_currency)
     .bind(timestamp)
-    .bind(&category)
+    .bind(category_id)
     .bind(&note)
     .bind(fiat_value)
     .bind(&fiat_currency)
     .bind(&tx_currency)
-    .execute(pool.inner())
+    .execute(&mut *tx)
     .await
     .map_err(|e| e.to_string())?;
     
+    let id = result.last_insert_rowid();
+    let new_values = audit::transaction_values(&mut tx, id).await?;
+    audit::record(&mut tx, "add_manual_transaction", "transactions", Some(id), None, Some(new_values)).await?;
+    
+    tx.commit().await.map_err(|e| e.to_string())?;
+    
     Ok("Transaction added".to_string())
 }
 
 #[tauri::command]
 async fn delete_transaction(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     id: i64,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     // Only allow deleting manual transactions
-    let tx = sqlx::query_as::<_, (String,)>(
-        "SELECT txid FROM transactions WHERE id = ?"
+    let tx = sqlx::query_as::<_, (String, i64, Option<String>)>(
+        "SELECT txid, amount_sats, note FROM transactions WHERE id = ? AND trash_id IS NULL"
     )
     .bind(id)
-    .fetch_optional(pool.inner())
+    .fetch_optional(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
-    if let Some((txid,)) = tx {
-        if !txid.starts_with("manual-") {
+    let description = match tx {
+        Some((txid, _, _)) if !txid.starts_with("manual-") => {
             return Err("Cannot delete synced transactions".to_string());
         }
-    } else {
-        return Err("Transaction not found".to_string());
-    }
+        Some((_, amount_sats, note)) => note
+            .filter(|n| !n.trim().is_empty())
+            .unwrap_or_else(|| format!("{} sats", amount_sats)),
+        None => return Err("Transaction not found".to_string()),
+    };
     
-    sqlx::query("DELETE FROM transactions WHERE id = ?")
-        .bind(id)
-        .execute(pool.inner())
-        .await
-        .map_err(|e| e.to_string())?;
+    trash::delete_transaction(&pool, id, &description).await?;
     
-    Ok("Transaction deleted".to_string())
+    Ok("Transaction moved to trash".to_string())
 }
 
 #[tauri::command]
 async fn update_manual_transaction(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     id: i64,
     amount_sats: i64,
     timestamp: i64,
@@ -708,12 +889,14 @@ async fn update_manual_transaction(
     fee_fiat: Option<f64>,
     fee_fiat_currency: Option<String>,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     // Verify it's a manual transaction
     let existing = sqlx::query_as::<_, (String,)>(
         "SELECT txid FROM transactions WHERE id = ?"
     )
     .bind(id)
-    .fetch_optional(pool.inner())
+    .fetch_optional(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -721,11 +904,19 @@ async fn update_manual_transaction(
         Some((txid,)) if txid.starts_with("manual-") => {
             let tx_currency = currency.unwrap_or_else(|| "BTC".to_string());
             
+            let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+            
+            // Neither the old nor the new date may be in a closed period
+            periods::ensure_transaction_open(&mut tx, id).await?;
+            periods::ensure_open(&mut tx, Some(timestamp)).await?;
+            let old_values = audit::transaction_values(&mut tx, id).await?;
+            let category_id = resolve_category_id(&mut tx, category.as_deref()).await?;
+            
             sqlx::query(
                 r#"
                 UPDATE transactions 
                 SET amount_sats = ?, fee_sats = ?, fee_fiat = ?, fee_fiat_currency = ?,
-                    timestamp = ?, category = ?, note = ?, fiat_value = ?, fiat_currency = ?, currency = ?
+                    timestamp = ?, category_id = ?, note = ?, fiat_value = ?, fiat_currency = ?, currency = ?
                 WHERE id = ?
                 "#,
             )
@@ -734,16 +925,21 @@ async fn update_manual_transaction(
             .bind(fee_fiat)
             .bind(&fee_fiat_currency)
             .bind(timestamp)
-            .bind(&category)
+            .bind(category_id)
             .bind(&note)
             .bind(fiat_value)
             .bind(&fiat_currency)
             .bind(&tx_currency)
             .bind(id)
-            .execute(pool.inner())
+            .execute(&mut *tx)
             .await
             .map_err(|e| e.to_string())?;
             
+            let new_values = audit::transaction_values(&mut tx, id).await?;
+            audit::record_change(&mut tx, "update_manual_transaction", "transactions", id, old_values, new_values).await?;
+            
+            tx.commit().await.map_err(|e| e.to_string())?;
+            
             Ok("Transaction updated".to_string())
         }
         Some(_) => Err("Cannot edit synced transactions".to_string()),
@@ -768,13 +964,16 @@ struct ImportTransaction {
 
 #[tauri::command]
 async fn import_transactions(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     wallet_id: i64,
     transactions: Vec<ImportTransaction>,
 ) -> Result<ImportResult, String> {
+    let pool = session.pool().await?;
+    
     let mut imported = 0;
     let mut skipped = 0;
     let mut errors: Vec<String> = Vec::new();
+    let mut db = pool.begin().await.map_err(|e| e.to_string())?;
     
     for tx in transactions {
         // Check for duplicate by source_id
@@ -783,7 +982,7 @@ async fn import_transactions(
         )
         .bind(wallet_id)
         .bind(&tx.id)
-        .fetch_optional(pool.inner())
+        .fetch_optional(&mut *db)
         .await
         .map_err(|e| e.to_string())?;
         
@@ -792,12 +991,24 @@ async fn import_transactions(
             continue;
         }
         
+        if let Err(e) = periods::ensure_open(&mut db, Some(tx.timestamp)).await {
+            errors.push(format!("Failed to import tx {}: {}", tx.id, e));
+            continue;
+        }
+        
         let tx_currency = tx.currency.unwrap_or_else(|| "BTC".to_string());
+        let category_id = match resolve_category_id(&mut db, tx.category.as_deref()).await {
+            Ok(category_id) => category_id,
+            Err(e) => {
+                errors.push(format!("Failed to import tx {}: {}", tx.id, e));
+                continue;
+            }
+        };
         
         // Insert transaction
         let result = sqlx::query(
             r#"
-            INSERT INTO transactions (wallet_id, txid, amount_sats, fee_sats, fee_fiat, fee_fiat_currency, confirmed, block_height, timestamp, category, note, fiat_value, fiat_currency, currency)
+            INSERT INTO transactions (wallet_id, txid, amount_sats, fee_sats, fee_fiat, fee_fiat_currency, confirmed, block_height, timestamp, category_id, note, fiat_value, fiat_currency, currency)
             VALUES (?, ?, ?, ?, ?, ?, 1, NULL, ?, ?, ?, ?, ?, ?)
             "#,
         )
@@ -808,20 +1019,27 @@ async fn import_transactions(
         .bind(tx.fee_fiat)
         .bind(&tx.fee_fiat_currency)
         .bind(tx.timestamp)
-        .bind(&tx.category)
+        .bind(category_id)
         .bind(&tx.note)
         .bind(tx.fiat_value)
         .bind(&tx.fiat_currency)
         .bind(&tx_currency)
-        .execute(pool.inner())
+        .execute(&mut *db)
         .await;
         
         match result {
-            Ok(_) => imported += 1,
+            Ok(result) => {
+                imported += 1;
+                let id = result.last_insert_rowid();
+                let values = audit::transaction_values(&mut db, id).await?;
+                audit::record(&mut db, "import_transactions", "transactions", Some(id), None, Some(values)).await?;
+            }
             Err(e) => errors.push(format!("Failed to import tx {}: {}", tx.id, e)),
         }
     }
     
+    db.commit().await.map_err(|e| e.to_string())?;
+    
     Ok(ImportResult { imported, skipped, errors })
 }
 
@@ -833,46 +1051,91 @@ struct ImportResult {
 }
 
 #[tauri::command]
-async fn reset_all_data(pool: tauri::State<'_, SqlitePool>) -> Result<String, String> {
-    // Delete all transactions
-    sqlx::query("DELETE FROM transactions")
-        .execute(pool.inner())
-        .await
-        .map_err(|e| e.to_string())?;
+async fn reset_all_data(session: tauri::State<'_, session::Session>) -> Result<String, String> {
+    let pool = session.pool().await?;
     
-    // Delete all wallets
-    sqlx::query("DELETE FROM wallets")
-        .execute(pool.inner())
-        .await
-        .map_err(|e| e.to_string())?;
+    // Keep a way back
+    snapshots::create(&vaults::active_id(), snapshots::REASON_RESET)
+        .map_err(|e| format!("Reset cancelled: {}", e))?;
     
-    // Delete all entities except Personal
-    sqlx::query("DELETE FROM entities WHERE entity_type != 'personal'")
-        .execute(pool.inner())
-        .await
-        .map_err(|e| e.to_string())?;
+    // Transactions, wallets and entities go to the trash, so the reset can be undone
+    trash::reset_all(&pool).await?;
+    
+    Ok("All data reset successfully".to_string())
+}
+
+// Trash commands
+
+#[tauri::command]
+async fn list_trash(session: tauri::State<'_, session::Session>) -> Result<Vec<trash::TrashEntry>, String> {
+    let pool = session.pool().await?;
+    trash::list(&pool).await
+}
+
+#[tauri::command]
+async fn restore_from_trash(session: tauri::State<'_, session::Session>, id: i64) -> Result<String, String> {
+    let pool = session.pool().await?;
+    trash::restore(&pool, id).await?;
+    
+    Ok("Restored successfully".to_string())
+}
+
+#[tauri::command]
+async fn undo_delete(session: tauri::State<'_, session::Session>) -> Result<trash::TrashEntry, String> {
+    let pool = session.pool().await?;
+    trash::undo_last(&pool).await
+}
+
+#[tauri::command]
+async fn empty_trash(session: tauri::State<'_, session::Session>, id: Option<i64>) -> Result<String, String> {
+    let pool = session.pool().await?;
+    trash::purge(&pool, id).await?;
     
-    // Delete all settings
-    sqlx::query("DELETE FROM settings")
-        .execute(pool.inner())
+    Ok("Trash emptied".to_string())
+}
+
+/// Look up a category by name, creating a custom one for names not seen before
+async fn resolve_category_id(conn: &mut sqlx::SqliteConnection, name: Option<&str>) -> Result<Option<i64>, String> {
+    let Some(name) = name.map(str::trim).filter(|n| !n.is_empty()) else {
+        return Ok(None);
+    };
+    
+    let result = sqlx::query("INSERT OR IGNORE INTO categories (name, is_system) VALUES (?, 0)")
+        .bind(name)
+        .execute(&mut *conn)
         .await
         .map_err(|e| e.to_string())?;
     
-    // Delete price cache
-    sqlx::query("DELETE FROM price_cache")
-        .execute(pool.inner())
+    let (id,) = sqlx::query_as::<_, (i64,)>("SELECT id FROM categories WHERE name = ?")
+        .bind(name)
+        .fetch_one(&mut *conn)
         .await
         .map_err(|e| e.to_string())?;
     
-    Ok("All data reset successfully".to_string())
+    // A name that wasn't a category yet became one
+    if result.rows_affected() > 0 {
+        audit::record(
+            conn,
+            "add_category",
+            "categories",
+            Some(id),
+            None,
+            Some(serde_json::json!({ "name": name })),
+        )
+        .await?;
+    }
+    
+    Ok(Some(id))
 }
 
 #[tauri::command]
-async fn get_categories(pool: tauri::State<'_, SqlitePool>) -> Result<Vec<Category>, String> {
+async fn get_categories(session: tauri::State<'_, session::Session>) -> Result<Vec<Category>, String> {
+    let pool = session.pool().await?;
+    
     let categories = sqlx::query_as::<_, Category>(
         "SELECT id, name, is_system FROM categories ORDER BY is_system DESC, name ASC"
     )
-    .fetch_all(pool.inner())
+    .fetch_all(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -881,19 +1144,23 @@ async fn get_categories(pool: tauri::State<'_, SqlitePool>) -> Result<Vec<Catego
 
 #[tauri::command]
 async fn add_category(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     name: String,
 ) -> Result<Category, String> {
+    let pool = session.pool().await?;
+    
     let trimmed = name.trim();
     if trimmed.is_empty() {
         return Err("Category name cannot be empty".to_string());
     }
     
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    
     let result = sqlx::query(
         "INSERT INTO categories (name, is_system) VALUES (?, 0)"
     )
     .bind(trimmed)
-    .execute(pool.inner())
+    .execute(&mut *tx)
     .await
     .map_err(|e| {
         if e.to_string().contains("UNIQUE") {
@@ -903,6 +1170,18 @@ async fn add_category(
         }
     })?;
     
+    audit::record(
+        &mut tx,
+        "add_category",
+        "categories",
+        Some(result.last_insert_rowid()),
+        None,
+        Some(serde_json::json!({ "name": trimmed })),
+    )
+    .await?;
+    
+    tx.commit().await.map_err(|e| e.to_string())?;
+    
     Ok(Category {
         id: result.last_insert_rowid(),
         name: trimmed.to_string(),
@@ -912,10 +1191,12 @@ async fn add_category(
 
 #[tauri::command]
 async fn update_category(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     id: i64,
     name: String,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     let trimmed = name.trim();
     if trimmed.is_empty() {
         return Err("Category name cannot be empty".to_string());
@@ -926,7 +1207,7 @@ async fn update_category(
         "SELECT id, name, is_system FROM categories WHERE id = ?"
     )
     .bind(id)
-    .fetch_optional(pool.inner())
+    .fetch_optional(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -934,11 +1215,16 @@ async fn update_category(
         Some(cat) if cat.is_system => {
             Err("Cannot edit system categories".to_string())
         }
-        Some(_) => {
+        Some(cat) => {
+            let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+            
+            // Transactions show the category's current name, sealed ones included
+            periods::ensure_none_closed(&mut tx, "t.trash_id IS NULL AND t.category_id = ?", Some(id)).await?;
+            
             sqlx::query("UPDATE categories SET name = ? WHERE id = ?")
                 .bind(trimmed)
                 .bind(id)
-                .execute(pool.inner())
+                .execute(&mut *tx)
                 .await
                 .map_err(|e| {
                     if e.to_string().contains("UNIQUE") {
@@ -947,6 +1233,18 @@ async fn update_category(
                         e.to_string()
                     }
                 })?;
+            
+            audit::record_change(
+                &mut tx,
+                "update_category",
+                "categories",
+                id,
+                serde_json::json!({ "name": cat.name }),
+                serde_json::json!({ "name": trimmed }),
+            )
+            .await?;
+            
+            tx.commit().await.map_err(|e| e.to_string())?;
             Ok("Category updated".to_string())
         }
         None => Err("Category not found".to_string()),
@@ -955,15 +1253,17 @@ async fn update_category(
 
 #[tauri::command]
 async fn delete_category(
-    pool: tauri::State<'_, SqlitePool>,
+    session: tauri::State<'_, session::Session>,
     id: i64,
 ) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
     // Check if it's a system category
     let category = sqlx::query_as::<_, Category>(
         "SELECT id, name, is_system FROM categories WHERE id = ?"
     )
     .bind(id)
-    .fetch_optional(pool.inner())
+    .fetch_optional(&pool)
     .await
     .map_err(|e| e.to_string())?;
     
@@ -972,26 +1272,542 @@ async fn delete_category(
             Err("Cannot delete system categories".to_string())
         }
         Some(cat) => {
+            let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+            
+            periods::ensure_none_closed(&mut tx, "t.trash_id IS NULL AND t.category_id = ?", Some(id)).await?;
+            
             // Update transactions using this category to "Uncategorized"
-            sqlx::query("UPDATE transactions SET category = 'Uncategorized' WHERE category = ?")
-                .bind(&cat.name)
-                .execute(pool.inner())
-                .await
-                .map_err(|e| e.to_string())?;
+            let moved = sqlx::query(
+                "UPDATE transactions SET category_id = (SELECT id FROM categories WHERE name = 'Uncategorized') WHERE category_id = ?"
+            )
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
             
             // Delete the category
             sqlx::query("DELETE FROM categories WHERE id = ?")
                 .bind(id)
-                .execute(pool.inner())
+                .execute(&mut *tx)
                 .await
                 .map_err(|e| e.to_string())?;
             
+            audit::record(
+                &mut tx,
+                "delete_category",
+                "categories",
+                Some(id),
+                Some(serde_json::json!({ "name": cat.name })),
+                Some(serde_json::json!({ "transactions_moved_to_uncategorized": moved.rows_affected() })),
+            )
+            .await?;
+            
+            tx.commit().await.map_err(|e| e.to_string())?;
+            
             Ok("Category deleted".to_string())
         }
         None => Err("Category not found".to_string()),
     }
 }
 
+#[tauri::command]
+async fn check_integrity(session: tauri::State<'_, session::Session>) -> Result<db::IntegrityReport, String> {
+    let pool = session.pool().await?;
+    db::check_integrity(&pool).await.map_err(|e| format!("Integrity check failed: {}", e))
+}
+
+// Closed period commands
+
+#[tauri::command]
+async fn get_closed_periods(session: tauri::State<'_, session::Session>) -> Result<Vec<periods::ClosedPeriod>, String> {
+    let pool = session.pool().await?;
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    
+    periods::list(&mut conn).await
+}
+
+#[tauri::command]
+async fn close_period(
+    session: tauri::State<'_, session::Session>,
+    start_date: String,
+    end_date: String,
+) -> Result<periods::ClosedPeriod, String> {
+    let pool = session.pool().await?;
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    
+    let period = periods::close(&mut tx, &start_date, &end_date).await?;
+    tx.commit().await.map_err(|e| e.to_string())?;
+    
+    Ok(period)
+}
+
+#[tauri::command]
+async fn reopen_period(
+    session: tauri::State<'_, session::Session>,
+    id: i64,
+    reason: String,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+    
+    periods::reopen(&mut tx, id, &reason).await?;
+    tx.commit().await.map_err(|e| e.to_string())?;
+    
+    Ok("Period reopened".to_string())
+}
+
+#[tauri::command]
+async fn verify_closed_periods(session: tauri::State<'_, session::Session>) -> Result<Vec<periods::PeriodStatus>, String> {
+    let pool = session.pool().await?;
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    
+    periods::verify(&mut conn).await
+}
+
+// Cost basis commands
+
+#[tauri::command]
+async fn get_cost_basis(
+    session: tauri::State<'_, session::Session>,
+    method: Option<String>,
+    scope: Option<String>,
+    entity_id: Option<i64>,
+    wallet_id: Option<i64>,
+) -> Result<cost_basis::CostBasisReport, String> {
+    let pool = session.pool().await?;
+    cost_basis::report(&pool, method.as_deref(), scope.as_deref(), entity_id, wallet_id).await
+}
+
+#[tauri::command]
+async fn get_lot_matches(
+    session: tauri::State<'_, session::Session>,
+    disposal_id: Option<i64>,
+) -> Result<Vec<cost_basis::LotAssignment>, String> {
+    let pool = session.pool().await?;
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    
+    cost_basis::lot_matches(&mut conn, disposal_id).await
+}
+
+#[tauri::command]
+async fn set_lot_matches(
+    session: tauri::State<'_, session::Session>,
+    disposal_id: i64,
+    matches: Vec<cost_basis::LotSelection>,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    cost_basis::set_lot_matches(&pool, disposal_id, &matches).await?;
+    
+    Ok("Lot matches saved".to_string())
+}
+
+#[tauri::command]
+async fn get_safe_harbor(
+    session: tauri::State<'_, session::Session>,
+) -> Result<Option<cost_basis::SafeHarbor>, String> {
+    let pool = session.pool().await?;
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    
+    cost_basis::safe_harbor(&mut conn).await
+}
+
+#[tauri::command]
+async fn create_safe_harbor(
+    session: tauri::State<'_, session::Session>,
+    date: String,
+) -> Result<cost_basis::SafeHarbor, String> {
+    let pool = session.pool().await?;
+    cost_basis::create_safe_harbor(&pool, &date).await
+}
+
+#[tauri::command]
+async fn clear_safe_harbor(session: tauri::State<'_, session::Session>) -> Result<String, String> {
+    let pool = session.pool().await?;
+    cost_basis::clear_safe_harbor(&pool).await?;
+    
+    Ok("Safe harbor allocation removed".to_string())
+}
+
+// Tax report commands
+
+#[tauri::command]
+async fn get_form_8949(
+    session: tauri::State<'_, session::Session>,
+    year: i64,
+) -> Result<form8949::Form8949, String> {
+    let pool = session.pool().await?;
+    form8949::generate(&pool, year).await
+}
+
+#[tauri::command]
+async fn export_form_8949(
+    session: tauri::State<'_, session::Session>,
+    year: i64,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let form = form8949::generate(&pool, year).await?;
+    
+    Ok(form8949::to_csv(&form))
+}
+
+#[tauri::command]
+async fn get_hmrc_report(
+    session: tauri::State<'_, session::Session>,
+    entity_id: Option<i64>,
+) -> Result<hmrc::HmrcReport, String> {
+    let pool = session.pool().await?;
+    hmrc::generate(&pool, entity_id).await
+}
+
+#[tauri::command]
+async fn export_hmrc_report(
+    session: tauri::State<'_, session::Session>,
+    entity_id: Option<i64>,
+    tax_year: Option<String>,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let report = hmrc::generate(&pool, entity_id).await?;
+    
+    Ok(hmrc::to_csv(&report, tax_year.as_deref()))
+}
+
+#[tauri::command]
+async fn get_acb_report(
+    session: tauri::State<'_, session::Session>,
+    entity_id: Option<i64>,
+) -> Result<acb::AcbReport, String> {
+    let pool = session.pool().await?;
+    acb::generate(&pool, entity_id).await
+}
+
+#[tauri::command]
+async fn export_acb_report(
+    session: tauri::State<'_, session::Session>,
+    entity_id: Option<i64>,
+    year: Option<i64>,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let report = acb::generate(&pool, entity_id).await?;
+    
+    Ok(acb::to_csv(&report, year))
+}
+
+#[tauri::command]
+async fn get_tax_profile(
+    session: tauri::State<'_, session::Session>,
+) -> Result<Option<tax_profile::Profile>, String> {
+    let pool = session.pool().await?;
+    let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+    
+    tax_profile::profile(&mut conn).await
+}
+
+#[tauri::command]
+fn get_tax_profile_presets() -> Vec<tax_profile::Profile> {
+    tax_profile::Profile::presets()
+}
+
+#[tauri::command]
+async fn set_tax_profile(
+    session: tauri::State<'_, session::Session>,
+    profile: Option<tax_profile::Profile>,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    tax_profile::set_profile(&pool, profile.as_ref()).await?;
+    
+    Ok("Tax profile saved".to_string())
+}
+
+#[tauri::command]
+async fn get_tax_profile_report(
+    session: tauri::State<'_, session::Session>,
+    year: i64,
+) -> Result<tax_profile::ProfileReport, String> {
+    let pool = session.pool().await?;
+    tax_profile::generate(&pool, year).await
+}
+
+#[tauri::command]
+async fn export_tax_profile_report(
+    session: tauri::State<'_, session::Session>,
+    year: i64,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let report = tax_profile::generate(&pool, year).await?;
+    
+    Ok(tax_profile::to_csv(&report))
+}
+
+#[tauri::command]
+async fn get_income_report(
+    session: tauri::State<'_, session::Session>,
+    year: Option<i64>,
+    entity_id: Option<i64>,
+) -> Result<income::IncomeReport, String> {
+    let pool = session.pool().await?;
+    income::generate(&pool, year, entity_id).await
+}
+
+#[tauri::command]
+async fn export_income_report(
+    session: tauri::State<'_, session::Session>,
+    year: Option<i64>,
+    entity_id: Option<i64>,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let report = income::generate(&pool, year, entity_id).await?;
+    
+    Ok(income::to_csv(&report))
+}
+
+// Ledger commands
+
+#[tauri::command]
+async fn get_trial_balance(
+    session: tauri::State<'_, session::Session>,
+    as_of: Option<String>,
+    entity_id: Option<i64>,
+) -> Result<ledger::TrialBalance, String> {
+    let pool = session.pool().await?;
+    let journal = ledger::journal(&pool).await?;
+    ledger::trial_balance(&journal, as_of.as_deref(), entity_id)
+}
+
+#[tauri::command]
+async fn export_trial_balance(
+    session: tauri::State<'_, session::Session>,
+    as_of: Option<String>,
+    entity_id: Option<i64>,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let journal = ledger::journal(&pool).await?;
+    let trial_balance = ledger::trial_balance(&journal, as_of.as_deref(), entity_id)?;
+    
+    Ok(ledger::to_csv(&trial_balance))
+}
+
+#[tauri::command]
+async fn get_account_balance(
+    session: tauri::State<'_, session::Session>,
+    account_id: String,
+    as_of: Option<String>,
+) -> Result<ledger::AccountBalance, String> {
+    let pool = session.pool().await?;
+    let journal = ledger::journal(&pool).await?;
+    ledger::account_balance(&journal, &account_id, as_of.as_deref())
+}
+
+// Audit log commands
+
+#[tauri::command]
+async fn get_audit_log(
+    session: tauri::State<'_, session::Session>,
+    filter: Option<audit::AuditFilter>,
+) -> Result<Vec<audit::AuditEntry>, String> {
+    let pool = session.pool().await?;
+    audit::query(&pool, &filter.unwrap_or_default()).await
+}
+
+#[tauri::command]
+async fn export_audit_log(
+    session: tauri::State<'_, session::Session>,
+    filter: Option<audit::AuditFilter>,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    let entries = audit::query(&pool, &filter.unwrap_or_default()).await?;
+    
+    Ok(audit::to_csv(&entries))
+}
+
+// ============================================================================
+// VAULT COMMANDS
+// ============================================================================
+
+#[tauri::command]
+async fn list_vaults() -> Result<Vec<vaults::VaultInfo>, String> {
+    Ok(vaults::list())
+}
+
+#[tauri::command]
+async fn create_vault(name: String) -> Result<vaults::Vault, String> {
+    vaults::create(&name)
+}
+
+#[tauri::command]
+async fn rename_vault(
+    session: tauri::State<'_, session::Session>,
+    id: String,
+    name: String,
+) -> Result<String, String> {
+    if session.is_locked().await {
+        return Err(session::LOCKED_ERROR.to_string());
+    }
+    
+    vaults::rename(&id, &name)?;
+    Ok("Vault renamed".to_string())
+}
+
+/// `confirm_name` is the vault's name as typed by the user
+#[tauri::command]
+async fn delete_vault(
+    session: tauri::State<'_, session::Session>,
+    id: String,
+    confirm_name: String,
+) -> Result<String, String> {
+    if session.is_locked().await {
+        return Err(session::LOCKED_ERROR.to_string());
+    }
+    
+    vaults::delete(&id, &confirm_name)?;
+    Ok("Vault deleted".to_string())
+}
+
+#[tauri::command]
+async fn open_vault(session: tauri::State<'_, session::Session>, id: String) -> Result<String, String> {
+    let previous = vaults::active_id();
+    vaults::set_active(&id)?;
+    
+    let pool = match open_active_vault().await {
+        Ok(pool) => pool,
+        Err(e) => {
+            let _ = vaults::set_active(&previous);
+            return Err(e);
+        }
+    };
+    
+    // The new vault starts locked if it's encrypted
+    let idle_timeout = session::load_idle_timeout(&pool).await;
+    let old_pool = session.switch_vault(pool, idle_timeout).await;
+    old_pool.close().await;
+    
+    Ok("Vault opened".to_string())
+}
+
+/// Recover and open the active vault's database
+async fn open_active_vault() -> Result<sqlx::SqlitePool, String> {
+    // Roll back (or finish) an encrypt/decrypt that was cut short by a crash
+    encrypted_db::EncryptedDb::recover_interrupted_conversion()?;
+    
+    if db::needs_migration().await {
+        snapshots::create(&vaults::active_id(), snapshots::REASON_MIGRATION)?;
+    }
+    
+    db::init_db()
+        .await
+        .map_err(|e| format!("Failed to open vault: {}", e))
+}
+
+// ============================================================================
+// BACKUP COMMANDS
+// ============================================================================
+
+#[tauri::command]
+async fn export_backup(
+    session: tauri::State<'_, session::Session>,
+    path: String,
+    password: SecretString,
+) -> Result<backup::ArchiveInfo, String> {
+    // Only from an unlocked session
+    session.pool().await?;
+    
+    backup::export_archive(
+        &vaults::active_dir(),
+        &vaults::active_name(),
+        std::path::Path::new(&path),
+        &password,
+    )
+}
+
+#[tauri::command]
+async fn restore_backup(
+    session: tauri::State<'_, session::Session>,
+    path: String,
+    password: SecretString,
+    new_vault_name: Option<String>,
+) -> Result<backup::ArchiveInfo, String> {
+    // Validate the whole archive before touching any vault
+    let (info, files) = backup::read_archive(std::path::Path::new(&path), &password)?;
+    
+    if let Some(name) = new_vault_name {
+        let vault = vaults::create(&name)?;
+        if let Err(e) = backup::restore_files(&vaults::vault_dir(&vault.id), &files) {
+            let _ = vaults::delete(&vault.id, &vault.name);
+            return Err(e);
+        }
+        return Ok(info);
+    }
+    
+    // Replacing the active vault's data needs it unlocked, and its pool closed
+    let pool = session.pool().await?;
+    pool.close().await;
+    
+    let restored = backup::restore_files(&vaults::active_dir(), &files);
+    
+    // Reopen either way; the restored vault starts locked if it's encrypted
+    let pool = open_active_vault().await?;
+    let idle_timeout = session::load_idle_timeout(&pool).await;
+    session.switch_vault(pool, idle_timeout).await;
+    
+    restored.map(|_| info)
+}
+
+#[tauri::command]
+async fn get_auto_backup_settings() -> Result<snapshots::AutoBackupSettings, String> {
+    Ok(snapshots::load_settings(&vaults::active_id()))
+}
+
+#[tauri::command]
+async fn set_auto_backup_settings(
+    session: tauri::State<'_, session::Session>,
+    schedule: String,
+    directory: Option<String>,
+    keep: usize,
+) -> Result<String, String> {
+    session.pool().await?;
+    
+    let vault_id = vaults::active_id();
+    let mut settings = snapshots::load_settings(&vault_id);
+    settings.schedule = snapshots::Schedule::parse(&schedule)?;
+    settings.directory = directory.filter(|d| !d.trim().is_empty());
+    settings.keep = keep;
+    snapshots::save_settings(&vault_id, &settings)?;
+    
+    Ok("Backup settings saved".to_string())
+}
+
+#[tauri::command]
+async fn list_snapshots() -> Result<Vec<snapshots::SnapshotInfo>, String> {
+    Ok(snapshots::list(&vaults::active_id()))
+}
+
+#[tauri::command]
+async fn create_snapshot(session: tauri::State<'_, session::Session>) -> Result<snapshots::SnapshotInfo, String> {
+    session.pool().await?;
+    snapshots::create(&vaults::active_id(), snapshots::REASON_MANUAL)
+}
+
+#[tauri::command]
+async fn restore_snapshot(
+    session: tauri::State<'_, session::Session>,
+    file_name: String,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
+    let vault_id = vaults::active_id();
+    let files = snapshots::read(&vault_id, &file_name)?;
+    snapshots::create(&vault_id, snapshots::REASON_RESTORE)?;
+    
+    pool.close().await;
+    let restored = backup::restore_files(&vaults::active_dir(), &files);
+    
+    // Reopen either way; the restored vault starts locked if it's encrypted
+    let pool = open_active_vault().await?;
+    let idle_timeout = session::load_idle_timeout(&pool).await;
+    session.switch_vault(pool, idle_timeout).await;
+    
+    restored.map(|_| "Backup restored successfully".to_string())
+}
+
 // ============================================================================
 // ENCRYPTION COMMANDS
 // ============================================================================
@@ -1007,68 +1823,240 @@ async fn check_db_exists() -> Result<bool, String> {
 }
 
 #[tauri::command]
-async fn generate_recovery_phrase() -> Result<String, String> {
-    crypto::generate_recovery_phrase()
+async fn get_encryption_info() -> Result<Option<encrypted_db::EncryptionMeta>, String> {
+    Ok(encrypted_db::EncryptedDb::get_encryption_meta())
 }
 
 #[tauri::command]
-async fn check_password_strength(password: String) -> Result<PasswordStrength, String> {
+async fn generate_recovery_phrase(word_count: Option<usize>) -> Result<SecretString, String> {
+    crypto::generate_recovery_phrase(word_count.unwrap_or(12))
+}
+
+#[tauri::command]
+async fn check_password_strength(password: SecretString) -> Result<PasswordStrength, String> {
     Ok(crypto::calculate_password_strength(&password))
 }
 
 #[tauri::command]
-async fn validate_recovery_phrase(phrase: String) -> Result<bool, String> {
+async fn validate_recovery_phrase(phrase: SecretString) -> Result<bool, String> {
     Ok(crypto::validate_recovery_phrase(&phrase))
 }
 
 #[tauri::command]
-async fn encrypt_database(passphrase: String, recovery_phrase: String) -> Result<String, String> {
+async fn generate_key_file(path: String) -> Result<String, String> {
+    encrypted_db::EncryptedDb::create_key_file(std::path::Path::new(&path))?;
+    Ok("Key file created".to_string())
+}
+
+#[tauri::command]
+async fn encrypt_database(
+    session: tauri::State<'_, session::Session>,
+    passphrase: SecretString,
+    recovery_phrase: SecretString,
+    bip39_passphrase: Option<SecretString>,
+    key_file_path: Option<String>,
+) -> Result<String, String> {
     if !crypto::validate_recovery_phrase(&recovery_phrase) {
         return Err("Invalid recovery phrase".to_string());
     }
     
-    encrypted_db::EncryptedDb::encrypt_database(&passphrase, &recovery_phrase)?;
+    encrypted_db::EncryptedDb::encrypt_database(
+        &passphrase,
+        &recovery_phrase,
+        bip39_passphrase.as_ref().map_or("", |p| p.as_str()),
+        key_file_path.as_deref().map(std::path::Path::new),
+    )?;
+    
+    // The user just set this up, so start the session unlocked
+    let db = encrypted_db::EncryptedDb::open_with_recovery(
+        &recovery_phrase,
+        bip39_passphrase.as_ref().map_or("", |p| p.as_str()),
+    )?;
+    session.unlock(db).await;
     
     Ok("Database encrypted successfully".to_string())
 }
 
 #[tauri::command]
-async fn unlock_with_passphrase(passphrase: String) -> Result<bool, String> {
+async fn unlock_with_passphrase(
+    session: tauri::State<'_, session::Session>,
+    passphrase: SecretString,
+    key_file_path: Option<String>,
+) -> Result<bool, String> {
     // Try to open with passphrase - if it works, passphrase is correct
-    let _db = encrypted_db::EncryptedDb::open_with_passphrase(&passphrase)?;
+    let db = encrypted_db::EncryptedDb::open_with_passphrase(
+        &passphrase,
+        key_file_path.as_deref().map(std::path::Path::new),
+    )?;
+    session.unlock(db).await;
     Ok(true)
 }
 
 #[tauri::command]
-async fn unlock_with_recovery(recovery_phrase: String) -> Result<bool, String> {
+async fn unlock_with_recovery(
+    session: tauri::State<'_, session::Session>,
+    recovery_phrase: SecretString,
+    bip39_passphrase: Option<SecretString>,
+) -> Result<bool, String> {
     if !crypto::validate_recovery_phrase(&recovery_phrase) {
         return Err("Invalid recovery phrase".to_string());
     }
     
-    let _db = encrypted_db::EncryptedDb::open_with_recovery(&recovery_phrase)?;
+    let db = encrypted_db::EncryptedDb::open_with_recovery(
+        &recovery_phrase,
+        bip39_passphrase.as_ref().map_or("", |p| p.as_str()),
+    )?;
+    session.unlock(db).await;
     Ok(true)
 }
 
 #[tauri::command]
-async fn change_passphrase(old_passphrase: String, new_passphrase: String) -> Result<String, String> {
-    encrypted_db::EncryptedDb::change_passphrase(&old_passphrase, &new_passphrase)?;
+async fn generate_recovery_shares(
+    recovery_phrase: SecretString,
+    threshold: u8,
+    share_count: u8,
+) -> Result<Vec<SecretString>, String> {
+    crypto::split_recovery_phrase(&recovery_phrase, threshold, share_count)
+}
+
+#[tauri::command]
+async fn validate_recovery_share(share: SecretString) -> Result<bool, String> {
+    Ok(crypto::validate_recovery_share(&share))
+}
+
+#[tauri::command]
+async fn unlock_with_shares(
+    session: tauri::State<'_, session::Session>,
+    shares: Vec<SecretString>,
+    bip39_passphrase: Option<SecretString>,
+) -> Result<bool, String> {
+    let db = encrypted_db::EncryptedDb::open_with_shares(
+        &shares,
+        bip39_passphrase.as_ref().map_or("", |p| p.as_str()),
+    )?;
+    session.unlock(db).await;
+    Ok(true)
+}
+
+#[tauri::command]
+async fn lock_database(session: tauri::State<'_, session::Session>) -> Result<String, String> {
+    session.lock().await;
+    Ok("Database locked".to_string())
+}
+
+#[tauri::command]
+async fn is_database_locked(session: tauri::State<'_, session::Session>) -> Result<bool, String> {
+    Ok(session.is_locked().await)
+}
+
+#[tauri::command]
+async fn set_auto_lock_timeout(
+    session: tauri::State<'_, session::Session>,
+    value: String,
+) -> Result<String, String> {
+    let pool = session.pool().await?;
+    
+    // Minutes of inactivity, or "never"
+    if value != "never" && session::idle_timeout_from_setting(&value).is_none() {
+        return Err("Invalid auto-lock timeout".to_string());
+    }
+    
+    sqlx::query(
+        "INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value"
+    )
+    .bind(session::AUTO_LOCK_SETTING)
+    .bind(&value)
+    .execute(&pool)
+    .await
+    .map_err(|e| e.to_string())?;
+    
+    session.set_idle_timeout(session::idle_timeout_from_setting(&value)).await;
+    
+    Ok("Auto-lock timeout saved".to_string())
+}
+
+#[tauri::command]
+async fn set_unlock_wipe_after(
+    session: tauri::State<'_, session::Session>,
+    passphrase: SecretString,
+    key_file_path: Option<String>,
+    wipe_after: Option<u32>,
+) -> Result<String, String> {
+    // Only from an unlocked session, so a locked app can't be set up to self-destruct
+    session.pool().await?;
+    
+    encrypted_db::EncryptedDb::set_wipe_after(
+        &passphrase,
+        key_file_path.as_deref().map(std::path::Path::new),
+        wipe_after,
+    )?;
+    Ok("Wipe setting saved".to_string())
+}
+
+#[tauri::command]
+async fn change_passphrase(
+    old_passphrase: SecretString,
+    new_passphrase: SecretString,
+    key_file_path: Option<String>,
+) -> Result<String, String> {
+    encrypted_db::EncryptedDb::change_passphrase(
+        &old_passphrase,
+        &new_passphrase,
+        key_file_path.as_deref().map(std::path::Path::new),
+    )?;
     Ok("Passphrase changed successfully".to_string())
 }
 
 #[tauri::command]
-async fn remove_encryption(passphrase: String) -> Result<String, String> {
-    encrypted_db::EncryptedDb::decrypt_database(&passphrase)?;
+async fn remove_encryption(
+    session: tauri::State<'_, session::Session>,
+    passphrase: SecretString,
+    key_file_path: Option<String>,
+) -> Result<String, String> {
+    encrypted_db::EncryptedDb::decrypt_database(
+        &passphrase,
+        key_file_path.as_deref().map(std::path::Path::new),
+    )?;
+    
+    // The old connection points at the replaced encrypted file
+    session.lock().await;
+    
     Ok("Encryption removed successfully".to_string())
 }
 
 #[tokio::main]
 async fn main() {
-    let pool = db::init_db().await.expect("Failed to initialize database");
+    vaults::init_active();
+    let pool = open_active_vault().await.expect("Failed to initialize database");
+    let idle_timeout = session::load_idle_timeout(&pool).await;
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .setup(|app| {
-            app.manage(pool);
+        .setup(move |app| {
+            app.manage(session::Session::new(pool, idle_timeout));
+            
+            // Auto-lock after inactivity
+            let handle = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                let mut interval = tokio::time::interval(session::IDLE_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if handle.state::<session::Session>().lock_if_idle().await {
+                        let _ = handle.emit("database-locked", ());
+                    }
+                }
+            });
+            
+            // Scheduled local backups
+            tauri::async_runtime::spawn(async {
+                let mut interval = tokio::time::interval(snapshots::SCHEDULE_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let vault_id = vaults::active_id();
+                    let _ = tauri::async_runtime::spawn_blocking(move || snapshots::run_if_due(&vault_id)).await;
+                }
+            });
+            
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -1100,18 +2088,72 @@ async fn main() {
             update_manual_transaction,
             import_transactions,
             reset_all_data,
+            list_trash,
+            restore_from_trash,
+            undo_delete,
+            empty_trash,
+            get_audit_log,
+            check_integrity,
+            get_closed_periods,
+            close_period,
+            reopen_period,
+            verify_closed_periods,
+            get_cost_basis,
+            get_lot_matches,
+            set_lot_matches,
+            get_safe_harbor,
+            create_safe_harbor,
+            clear_safe_harbor,
+            get_form_8949,
+            export_form_8949,
+            get_hmrc_report,
+            export_hmrc_report,
+            get_acb_report,
+            export_acb_report,
+            get_tax_profile,
+            get_tax_profile_presets,
+            set_tax_profile,
+            get_tax_profile_report,
+            export_tax_profile_report,
+            get_income_report,
+            export_income_report,
+            get_trial_balance,
+            export_trial_balance,
+            get_account_balance,
+            export_audit_log,
             get_categories,
             add_category,
             update_category,
             delete_category,
             check_db_encrypted,
             check_db_exists,
+            get_encryption_info,
             generate_recovery_phrase,
+            generate_key_file,
             check_password_strength,
             validate_recovery_phrase,
             encrypt_database,
             unlock_with_passphrase,
             unlock_with_recovery,
+            generate_recovery_shares,
+            validate_recovery_share,
+            unlock_with_shares,
+            lock_database,
+            is_database_locked,
+            set_auto_lock_timeout,
+            set_unlock_wipe_after,
+            list_vaults,
+            create_vault,
+            rename_vault,
+            delete_vault,
+            open_vault,
+            export_backup,
+            restore_backup,
+            get_auto_backup_settings,
+            set_auto_backup_settings,
+            list_snapshots,
+            create_snapshot,
+            restore_snapshot,
             change_passphrase,
             remove_encryption,
         ])
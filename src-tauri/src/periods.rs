@@ -0,0 +1,524 @@
+//! Closed periods sealed with a hash chain
+//!
+//! Closing a period seals every live transaction dated inside it: each gets an
+//! entry hash of the previous hash, its id and the values the audit log
+//! records. The first entry links to the head of the previously closed period,
+//! so periods form one chain. Edits and deletions inside a closed period are
+//! refused until it's reopened (recorded in the audit log), and `verify`
+//! reports anything changed behind the app's back.
+
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{Sqlite, SqliteConnection};
+use std::collections::HashSet;
+
+use crate::audit;
+use crate::dates::{date_to_unix, SECONDS_PER_DAY};
+
+/// prev_hash of the first period ever closed
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ClosedPeriod {
+    pub id: i64,
+    pub start_date: String,
+    pub end_date: String,
+    pub start_ts: i64,
+    /// Exclusive
+    pub end_ts: i64,
+    pub prev_hash: String,
+    pub head_hash: String,
+    pub entry_count: i64,
+    pub closed_at: String,
+    pub reopened_at: Option<String>,
+    pub reopen_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeriodStatus {
+    #[serde(flatten)]
+    pub period: ClosedPeriod,
+    pub intact: bool,
+    pub problems: Vec<String>,
+}
+
+fn entry_hash(prev_hash: &str, transaction_id: i64, values: &serde_json::Value) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(transaction_id.to_string().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(values.to_string().as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Closed periods that haven't been reopened, oldest first
+async fn active_periods(conn: &mut SqliteConnection) -> Result<Vec<ClosedPeriod>, String> {
+    sqlx::query_as::<_, ClosedPeriod>("SELECT * FROM closed_periods WHERE reopened_at IS NULL ORDER BY id")
+        .fetch_all(conn)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// All periods, including reopened ones, newest first
+pub async fn list(conn: &mut SqliteConnection) -> Result<Vec<ClosedPeriod>, String> {
+    sqlx::query_as::<_, ClosedPeriod>("SELECT * FROM closed_periods ORDER BY id DESC")
+        .fetch_all(conn)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Start and end date of the closed period a timestamp falls in, if any
+async fn closed_period(
+    conn: &mut SqliteConnection,
+    timestamp: Option<i64>,
+) -> Result<Option<(String, String)>, String> {
+    let Some(timestamp) = timestamp else {
+        return Ok(None);
+    };
+
+    sqlx::query_as::<_, (String, String)>(
+        "SELECT start_date, end_date FROM closed_periods WHERE reopened_at IS NULL AND ? >= start_ts AND ? < end_ts",
+    )
+    .bind(timestamp)
+    .bind(timestamp)
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Whether a timestamp falls in a closed period
+pub async fn is_closed(conn: &mut SqliteConnection, timestamp: Option<i64>) -> Result<bool, String> {
+    Ok(closed_period(conn, timestamp).await?.is_some())
+}
+
+/// Refuse if a timestamp falls in a closed period
+pub async fn ensure_open(conn: &mut SqliteConnection, timestamp: Option<i64>) -> Result<(), String> {
+    match closed_period(conn, timestamp).await? {
+        Some((start, end)) => Err(format!(
+            "The period {} to {} is closed. Reopen it to make changes",
+            start, end
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Refuse if an existing transaction is dated in a closed period
+pub async fn ensure_transaction_open(conn: &mut SqliteConnection, id: i64) -> Result<(), String> {
+    let timestamp = sqlx::query_as::<_, (Option<i64>,)>("SELECT timestamp FROM transactions WHERE id = ?")
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?
+        .and_then(|(timestamp,)| timestamp);
+
+    ensure_open(conn, timestamp).await
+}
+
+/// Refuse if any transaction matching `condition` is dated in a closed period
+///
+/// `condition` is SQL over `transactions` with at most one `?` for `value`.
+pub async fn ensure_none_closed<T>(conn: &mut SqliteConnection, condition: &str, value: Option<T>) -> Result<(), String>
+where
+    T: 'static + Send + for<'q> sqlx::Encode<'q, Sqlite> + sqlx::Type<Sqlite>,
+{
+    let sql = format!(
+        r#"
+        SELECT p.start_date, p.end_date FROM transactions t
+        JOIN closed_periods p ON p.reopened_at IS NULL AND t.timestamp >= p.start_ts AND t.timestamp < p.end_ts
+        WHERE {}
+        LIMIT 1
+        "#,
+        condition
+    );
+
+    let mut query = sqlx::query_as::<_, (String, String)>(&sql);
+    if let Some(value) = value {
+        query = query.bind(value);
+    }
+
+    match query.fetch_optional(conn).await.map_err(|e| e.to_string())? {
+        Some((start, end)) => Err(format!(
+            "This includes transactions in the closed period {} to {}. Reopen it first",
+            start, end
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Close and seal a period, `YYYY-MM-DD` to `YYYY-MM-DD` inclusive (UTC)
+pub async fn close(conn: &mut SqliteConnection, start_date: &str, end_date: &str) -> Result<ClosedPeriod, String> {
+    let start_ts = date_to_unix(start_date)?;
+    let end_ts = date_to_unix(end_date)? + SECONDS_PER_DAY;
+    if end_ts <= start_ts {
+        return Err("The period must end after it starts".to_string());
+    }
+
+    let active = active_periods(conn).await?;
+    if let Some(overlap) = active.iter().find(|p| start_ts < p.end_ts && p.start_ts < end_ts) {
+        return Err(format!(
+            "Overlaps the closed period {} to {}",
+            overlap.start_date, overlap.end_date
+        ));
+    }
+
+    let prev_hash = active
+        .last()
+        .map(|p| p.head_hash.clone())
+        .unwrap_or_else(|| GENESIS_HASH.to_string());
+
+    let ids: Vec<(i64,)> = sqlx::query_as(
+        "SELECT id FROM transactions WHERE trash_id IS NULL AND timestamp >= ? AND timestamp < ? ORDER BY timestamp, id",
+    )
+    .bind(start_ts)
+    .bind(end_ts)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let result = sqlx::query(
+        r#"
+        INSERT INTO closed_periods (start_date, end_date, start_ts, end_ts, prev_hash, head_hash, entry_count)
+        VALUES (?, ?, ?, ?, ?, '', ?)
+        "#,
+    )
+    .bind(start_date)
+    .bind(end_date)
+    .bind(start_ts)
+    .bind(end_ts)
+    .bind(&prev_hash)
+    .bind(ids.len() as i64)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    let period_id = result.last_insert_rowid();
+
+    let mut head_hash = prev_hash;
+    for (position, (transaction_id,)) in ids.iter().enumerate() {
+        let values = audit::transaction_values(conn, *transaction_id).await?;
+        head_hash = entry_hash(&head_hash, *transaction_id, &values);
+
+        sqlx::query("INSERT INTO sealed_entries (period_id, position, transaction_id, entry_hash) VALUES (?, ?, ?, ?)")
+            .bind(period_id)
+            .bind(position as i64)
+            .bind(transaction_id)
+            .bind(&head_hash)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    sqlx::query("UPDATE closed_periods SET head_hash = ? WHERE id = ?")
+        .bind(&head_hash)
+        .bind(period_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    audit::record(
+        conn,
+        "close_period",
+        "closed_periods",
+        Some(period_id),
+        None,
+        Some(json!({
+            "start_date": start_date,
+            "end_date": end_date,
+            "entry_count": ids.len(),
+            "head_hash": head_hash,
+        })),
+    )
+    .await?;
+
+    sqlx::query_as::<_, ClosedPeriod>("SELECT * FROM closed_periods WHERE id = ?")
+        .bind(period_id)
+        .fetch_one(conn)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Reopen the most recently closed period
+///
+/// Only the latest can be reopened: later periods chain from its head hash.
+pub async fn reopen(conn: &mut SqliteConnection, id: i64, reason: &str) -> Result<(), String> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err("Give a reason for reopening the period".to_string());
+    }
+
+    let active = active_periods(conn).await?;
+    let period = active.iter().find(|p| p.id == id).ok_or("Closed period not found")?;
+    if active.last().map(|p| p.id) != Some(id) {
+        return Err("Reopen later periods first".to_string());
+    }
+
+    sqlx::query(
+        "UPDATE closed_periods SET reopened_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'), reopen_reason = ? WHERE id = ?",
+    )
+    .bind(reason)
+    .bind(id)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    audit::record(
+        conn,
+        "reopen_period",
+        "closed_periods",
+        Some(id),
+        Some(json!({
+            "start_date": period.start_date,
+            "end_date": period.end_date,
+            "head_hash": period.head_hash,
+        })),
+        Some(json!({ "reason": reason })),
+    )
+    .await
+}
+
+/// Recompute every closed period's chain and report what no longer matches
+pub async fn verify(conn: &mut SqliteConnection) -> Result<Vec<PeriodStatus>, String> {
+    let mut statuses = Vec::new();
+    let mut expected_prev = GENESIS_HASH.to_string();
+
+    for period in active_periods(conn).await? {
+        let mut problems = Vec::new();
+        if period.prev_hash != expected_prev {
+            problems.push("Doesn't link to the previous closed period".to_string());
+        }
+
+        let entries: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT transaction_id, entry_hash FROM sealed_entries WHERE period_id = ? ORDER BY position",
+        )
+        .bind(period.id)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        if entries.len() as i64 != period.entry_count {
+            problems.push("Sealed entries are missing".to_string());
+        }
+
+        let mut prev_hash = period.prev_hash.clone();
+        for (transaction_id, stored_hash) in &entries {
+            let live = sqlx::query_as::<_, (i64,)>("SELECT id FROM transactions WHERE id = ? AND trash_id IS NULL")
+                .bind(transaction_id)
+                .fetch_optional(&mut *conn)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            if live.is_none() {
+                problems.push(format!("Transaction {} was deleted", transaction_id));
+            } else {
+                let values = audit::transaction_values(conn, *transaction_id).await?;
+                if entry_hash(&prev_hash, *transaction_id, &values) != *stored_hash {
+                    problems.push(format!("Transaction {} was modified", transaction_id));
+                }
+            }
+
+            // Continue from the stored hash so one change is reported once
+            prev_hash = stored_hash.clone();
+        }
+
+        if prev_hash != period.head_hash {
+            problems.push("The chain doesn't end at the recorded head hash".to_string());
+        }
+
+        let sealed: HashSet<i64> = entries.iter().map(|(id, _)| *id).collect();
+        let current: Vec<(i64,)> =
+            sqlx::query_as("SELECT id FROM transactions WHERE trash_id IS NULL AND timestamp >= ? AND timestamp < ?")
+                .bind(period.start_ts)
+                .bind(period.end_ts)
+                .fetch_all(&mut *conn)
+                .await
+                .map_err(|e| e.to_string())?;
+        for (id,) in current {
+            if !sealed.contains(&id) {
+                problems.push(format!("Transaction {} was added", id));
+            }
+        }
+
+        expected_prev = period.head_hash.clone();
+        statuses.push(PeriodStatus {
+            intact: problems.is_empty(),
+            problems,
+            period,
+        });
+    }
+
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::memory_pool;
+    use sqlx::pool::PoolConnection;
+
+    /// Wallet 1 with transactions 1 and 2 in 2023, 3 and 4 in 2024; 4 is in the trash
+    async fn books() -> PoolConnection<Sqlite> {
+        let pool = memory_pool().await;
+        let mut conn = pool.acquire().await.unwrap();
+
+        for statement in [
+            "INSERT INTO wallets (id, name, xpub) VALUES (1, 'Savings', 'xpub1')",
+            "INSERT INTO trash (id, operation, description) VALUES (1, 'delete_transactions', '1 transaction')",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, timestamp, trash_id) VALUES
+                (1, 1, 'a', 100000, 1672531200, NULL), (2, 1, 'manual-b', -20000, 1688169600, NULL),
+                (3, 1, 'c', 50000, 1704067200, NULL), (4, 1, 'd', 7000, 1719792000, 1)",
+        ] {
+            sqlx::query(statement).execute(&mut *conn).await.unwrap();
+        }
+
+        conn
+    }
+
+    async fn problems(conn: &mut SqliteConnection) -> Vec<String> {
+        verify(conn)
+            .await
+            .unwrap()
+            .into_iter()
+            .flat_map(|status| status.problems)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_close_seals_live_rows_and_chains_periods() {
+        let mut conn = books().await;
+
+        let first = close(&mut conn, "2023-01-01", "2023-12-31").await.unwrap();
+        assert_eq!(first.prev_hash, GENESIS_HASH);
+        assert_eq!(first.entry_count, 2);
+
+        let second = close(&mut conn, "2024-01-01", "2024-12-31").await.unwrap();
+        assert_eq!(second.prev_hash, first.head_hash);
+        assert_eq!(second.entry_count, 1, "trashed rows aren't sealed");
+
+        let sealed: Vec<(i64,)> =
+            sqlx::query_as("SELECT transaction_id FROM sealed_entries ORDER BY period_id, position")
+                .fetch_all(&mut *conn)
+                .await
+                .unwrap();
+        assert_eq!(sealed, vec![(1,), (2,), (3,)]);
+
+        let statuses = verify(&mut conn).await.unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses.iter().all(|status| status.intact));
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_modified_row() {
+        let mut conn = books().await;
+        close(&mut conn, "2023-01-01", "2023-12-31").await.unwrap();
+
+        sqlx::query("UPDATE transactions SET amount_sats = 1 WHERE id = 1")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        assert_eq!(problems(&mut conn).await, vec!["Transaction 1 was modified"]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_deleted_row() {
+        let mut conn = books().await;
+        close(&mut conn, "2023-01-01", "2023-12-31").await.unwrap();
+
+        sqlx::query("DELETE FROM transactions WHERE id = 2")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        assert_eq!(problems(&mut conn).await, vec!["Transaction 2 was deleted"]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_reports_added_row() {
+        let mut conn = books().await;
+        close(&mut conn, "2023-01-01", "2023-12-31").await.unwrap();
+
+        sqlx::query(
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, timestamp) VALUES (5, 1, 'e', 1, 1680307200)",
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+
+        assert_eq!(problems(&mut conn).await, vec!["Transaction 5 was added"]);
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_close_refused() {
+        let mut conn = books().await;
+        close(&mut conn, "2023-01-01", "2023-12-31").await.unwrap();
+
+        assert_eq!(
+            close(&mut conn, "2023-07-01", "2024-06-30").await.unwrap_err(),
+            "Overlaps the closed period 2023-01-01 to 2023-12-31"
+        );
+        assert_eq!(list(&mut conn).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reopen_latest_with_reason() {
+        let mut conn = books().await;
+        let first = close(&mut conn, "2023-01-01", "2023-12-31").await.unwrap();
+        let second = close(&mut conn, "2024-01-01", "2024-12-31").await.unwrap();
+
+        assert_eq!(
+            reopen(&mut conn, second.id, "  ").await.unwrap_err(),
+            "Give a reason for reopening the period"
+        );
+        assert_eq!(
+            reopen(&mut conn, first.id, "Late invoice").await.unwrap_err(),
+            "Reopen later periods first"
+        );
+
+        reopen(&mut conn, second.id, "Late invoice").await.unwrap();
+        assert!(!is_closed(&mut conn, Some(1704067200)).await.unwrap());
+        assert!(is_closed(&mut conn, Some(1672531200)).await.unwrap());
+
+        let (old_value, new_value): (String, String) =
+            sqlx::query_as("SELECT old_value, new_value FROM audit_log WHERE command = 'reopen_period' AND row_id = ?")
+                .bind(second.id)
+                .fetch_one(&mut *conn)
+                .await
+                .unwrap();
+        assert!(old_value.contains(&second.head_hash));
+        assert_eq!(new_value, r#"{"reason":"Late invoice"}"#);
+
+        // Now the earlier one is the latest
+        reopen(&mut conn, first.id, "Audit").await.unwrap();
+        assert_eq!(
+            reopen(&mut conn, first.id, "Again").await.unwrap_err(),
+            "Closed period not found"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sealed_rows_refuse_changes() {
+        let mut conn = books().await;
+        close(&mut conn, "2023-01-01", "2023-12-31").await.unwrap();
+
+        // The checks update_manual_transaction makes before editing transaction 2
+        assert_eq!(
+            ensure_transaction_open(&mut conn, 2).await.unwrap_err(),
+            "The period 2023-01-01 to 2023-12-31 is closed. Reopen it to make changes"
+        );
+        ensure_transaction_open(&mut conn, 3).await.unwrap();
+
+        // Nor may an open row be moved into the period
+        assert!(ensure_open(&mut conn, Some(1680307200)).await.is_err());
+        ensure_open(&mut conn, Some(1704067200)).await.unwrap();
+        ensure_open(&mut conn, None).await.unwrap();
+
+        assert_eq!(
+            ensure_none_closed(&mut conn, "t.wallet_id = ?", Some(1))
+                .await
+                .unwrap_err(),
+            "This includes transactions in the closed period 2023-01-01 to 2023-12-31. Reopen it first"
+        );
+        ensure_none_closed(&mut conn, "t.id = ?", Some(3)).await.unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+//! Unlocked database session
+//!
+//! Data commands get their pool through the session, which refuses access
+//! while an encrypted database is locked. Unlocking stores the decrypted
+//! connection here; locking (explicitly or after the idle timeout) drops it
+//! so SQLCipher wipes the key from memory.
+//!
+//! The lock gates the app, not the files: data commands read `personal.db`
+//! through the pool below, and that file is not encrypted by locking. What
+//! the passphrase protects at rest is the SQLCipher database (`satstone.db`).
+
+use sqlx::SqlitePool;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::encrypted_db::EncryptedDb;
+
+/// Error returned by data commands while the database is locked
+pub const LOCKED_ERROR: &str = "Database is locked";
+
+/// Settings key holding the idle timeout in minutes ("never" to disable)
+pub const AUTO_LOCK_SETTING: &str = "auto_lock";
+
+/// How often the background task checks for inactivity
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+struct SessionState {
+    /// Pool of the active vault
+    pool: SqlitePool,
+    /// Decrypted connection from the last unlock, holding the key
+    unlocked: Option<EncryptedDb>,
+    last_activity: Instant,
+    idle_timeout: Option<Duration>,
+}
+
+pub struct Session {
+    state: Mutex<SessionState>,
+}
+
+impl Session {
+    pub fn new(pool: SqlitePool, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            state: Mutex::new(SessionState {
+                pool,
+                unlocked: None,
+                last_activity: Instant::now(),
+                idle_timeout,
+            }),
+        }
+    }
+
+    /// Get the pool for a data command, or a "locked" error
+    /// Counts as activity for the idle timeout
+    pub async fn pool(&self) -> Result<SqlitePool, String> {
+        let mut state = self.state.lock().await;
+
+        if state.unlocked.is_none() && EncryptedDb::is_encrypted() {
+            return Err(LOCKED_ERROR.to_string());
+        }
+
+        state.last_activity = Instant::now();
+        Ok(state.pool.clone())
+    }
+
+    /// Switch to another vault's pool, locking the session
+    /// Returns the previous pool so the caller can close it
+    pub async fn switch_vault(&self, pool: SqlitePool, idle_timeout: Option<Duration>) -> SqlitePool {
+        let mut state = self.state.lock().await;
+        state.unlocked = None;
+        state.idle_timeout = idle_timeout;
+        state.last_activity = Instant::now();
+        std::mem::replace(&mut state.pool, pool)
+    }
+
+    /// Keep the decrypted connection for this session
+    pub async fn unlock(&self, db: EncryptedDb) {
+        let mut state = self.state.lock().await;
+        state.unlocked = Some(db);
+        state.last_activity = Instant::now();
+    }
+
+    /// Drop the decrypted connection and its key
+    pub async fn lock(&self) {
+        let mut state = self.state.lock().await;
+        state.unlocked = None;
+    }
+
+    /// Whether data commands are currently refused
+    pub async fn is_locked(&self) -> bool {
+        let state = self.state.lock().await;
+        state.unlocked.is_none() && EncryptedDb::is_encrypted()
+    }
+
+    pub async fn set_idle_timeout(&self, idle_timeout: Option<Duration>) {
+        let mut state = self.state.lock().await;
+        state.idle_timeout = idle_timeout;
+        state.last_activity = Instant::now();
+    }
+
+    /// Lock if the idle timeout has elapsed; returns true if it locked
+    pub async fn lock_if_idle(&self) -> bool {
+        let mut state = self.state.lock().await;
+
+        let expired = match state.idle_timeout {
+            Some(timeout) => state.last_activity.elapsed() >= timeout,
+            None => false,
+        };
+
+        if expired && state.unlocked.is_some() {
+            state.unlocked = None;
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Convert the stored setting value into a timeout
+pub fn idle_timeout_from_setting(value: &str) -> Option<Duration> {
+    match value.trim().parse::<u64>() {
+        Ok(minutes) if minutes > 0 => Some(Duration::from_secs(minutes * 60)),
+        _ => None,
+    }
+}
+
+/// Read the configured idle timeout from settings
+pub async fn load_idle_timeout(pool: &SqlitePool) -> Option<Duration> {
+    let value = sqlx::query_as::<_, (String,)>("SELECT value FROM settings WHERE key = ?")
+        .bind(AUTO_LOCK_SETTING)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()?;
+
+    idle_timeout_from_setting(&value.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, AppDir};
+
+    async fn memory_pool() -> SqlitePool {
+        SqlitePool::connect("sqlite::memory:").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_unencrypted_vault_is_never_locked() {
+        let _dir = AppDir::new();
+        let session = Session::new(memory_pool().await, None);
+
+        session.lock().await;
+
+        assert!(!session.is_locked().await);
+        assert!(session.pool().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lock_refuses_data_commands() {
+        let _dir = AppDir::new();
+        let recovery_phrase = test_support::encrypt_active_vault("passphrase");
+        let session = Session::new(memory_pool().await, None);
+
+        assert!(session.is_locked().await);
+        assert_eq!(session.pool().await.unwrap_err(), LOCKED_ERROR);
+
+        session
+            .unlock(EncryptedDb::open_with_recovery(&recovery_phrase, "").unwrap())
+            .await;
+        assert!(!session.is_locked().await);
+        assert!(session.pool().await.is_ok());
+
+        session.lock().await;
+        assert!(session.is_locked().await);
+        assert_eq!(session.pool().await.unwrap_err(), LOCKED_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_lock_if_idle() {
+        let _dir = AppDir::new();
+        let recovery_phrase = test_support::encrypt_active_vault("passphrase");
+        let session = Session::new(memory_pool().await, Some(Duration::from_millis(200)));
+        session
+            .unlock(EncryptedDb::open_with_recovery(&recovery_phrase, "").unwrap())
+            .await;
+
+        // Using a data command keeps the session alive
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(session.pool().await.is_ok());
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(!session.lock_if_idle().await);
+        assert!(!session.is_locked().await);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(session.lock_if_idle().await);
+        assert!(session.is_locked().await);
+        assert_eq!(session.pool().await.unwrap_err(), LOCKED_ERROR);
+
+        // Already locked: nothing more to do
+        assert!(!session.lock_if_idle().await);
+    }
+
+    #[tokio::test]
+    async fn test_lock_if_idle_without_timeout() {
+        let _dir = AppDir::new();
+        let recovery_phrase = test_support::encrypt_active_vault("passphrase");
+        let session = Session::new(memory_pool().await, None);
+        session
+            .unlock(EncryptedDb::open_with_recovery(&recovery_phrase, "").unwrap())
+            .await;
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        assert!(!session.lock_if_idle().await);
+        assert!(!session.is_locked().await);
+    }
+
+    #[test]
+    fn test_idle_timeout_from_setting() {
+        assert_eq!(idle_timeout_from_setting("5"), Some(Duration::from_secs(300)));
+        assert_eq!(idle_timeout_from_setting(" 1 "), Some(Duration::from_secs(60)));
+        assert_eq!(idle_timeout_from_setting("0"), None);
+        assert_eq!(idle_timeout_from_setting("never"), None);
+    }
+}
@@ -0,0 +1,599 @@
+//! SLIP-39 Shamir secret sharing
+//!
+//! Splits a master secret into mnemonic shares so that any `threshold` of
+//! them can rebuild it. Follows https://github.com/satoshilabs/slips/blob/master/slip-0039.md:
+//! - Shamir sharing over GF(256) with a digest share for verification
+//! - 4-round Feistel encryption of the master secret (PBKDF2-HMAC-SHA256)
+//! - RS1024 checksum over the 1024-word SLIP-39 wordlist
+//!
+//! Shares are generated as a single group; recombination accepts any
+//! valid group layout.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shamir shares as (x coordinate, share value) pairs
+type IndexedShares = Vec<(u8, Vec<u8>)>;
+
+const WORDLIST_TEXT: &str = include_str!("slip39_wordlist.txt");
+
+const RADIX_BITS: usize = 10;
+const ID_LENGTH_BITS: usize = 15;
+const ITERATION_EXP_LENGTH_BITS: usize = 4;
+const CHECKSUM_LENGTH_WORDS: usize = 3;
+const DIGEST_LENGTH_BYTES: usize = 4;
+/// Words used by identifier, flags and group/member parameters
+const METADATA_LENGTH_WORDS: usize = 4;
+const MIN_MNEMONIC_LENGTH_WORDS: usize = METADATA_LENGTH_WORDS + CHECKSUM_LENGTH_WORDS + 13;
+const MIN_STRENGTH_BYTES: usize = 16;
+const MAX_SHARE_COUNT: u8 = 16;
+const BASE_ITERATION_COUNT: u32 = 10000;
+const ROUND_COUNT: u8 = 4;
+const SECRET_INDEX: u8 = 255;
+const DIGEST_INDEX: u8 = 254;
+
+/// Iteration exponent for newly generated shares (5000 PBKDF2 iterations per round)
+const DEFAULT_ITERATION_EXPONENT: u8 = 1;
+
+/// A decoded SLIP-39 share
+#[derive(Debug, Clone)]
+struct Share {
+    identifier: u16,
+    extendable: bool,
+    iteration_exponent: u8,
+    group_index: u8,
+    group_threshold: u8,
+    group_count: u8,
+    member_index: u8,
+    member_threshold: u8,
+    value: Vec<u8>,
+}
+
+fn wordlist() -> &'static [&'static str] {
+    static WORDLIST: OnceLock<Vec<&'static str>> = OnceLock::new();
+    WORDLIST.get_or_init(|| WORDLIST_TEXT.lines().collect())
+}
+
+fn word_index(word: &str) -> Result<u16, String> {
+    wordlist()
+        .binary_search(&word)
+        .map(|i| i as u16)
+        .map_err(|_| format!("Invalid share word: {}", word))
+}
+
+/// Customization string mixed into the checksum
+fn customization_string(extendable: bool) -> &'static [u8] {
+    if extendable {
+        b"shamir_extendable"
+    } else {
+        b"shamir"
+    }
+}
+
+fn rs1024_polymod(values: impl IntoIterator<Item = u32>) -> u32 {
+    const GEN: [u32; 10] = [
+        0xE0E040, 0x1C1C080, 0x3838100, 0x7070200, 0xE0E0009,
+        0x1C0C2412, 0x38086C24, 0x3090FC48, 0x21B1F890, 0x3F3F120,
+    ];
+    let mut chk: u32 = 1;
+    for v in values {
+        let b = chk >> 20;
+        chk = ((chk & 0xFFFFF) << 10) ^ v;
+        for (i, g) in GEN.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn rs1024_create_checksum(data: &[u16], extendable: bool) -> [u16; CHECKSUM_LENGTH_WORDS] {
+    let values = customization_string(extendable)
+        .iter()
+        .map(|&b| b as u32)
+        .chain(data.iter().map(|&w| w as u32))
+        .chain([0; CHECKSUM_LENGTH_WORDS]);
+    let polymod = rs1024_polymod(values) ^ 1;
+    [
+        ((polymod >> 20) & 1023) as u16,
+        ((polymod >> 10) & 1023) as u16,
+        (polymod & 1023) as u16,
+    ]
+}
+
+fn rs1024_verify_checksum(data: &[u16], extendable: bool) -> bool {
+    let values = customization_string(extendable)
+        .iter()
+        .map(|&b| b as u32)
+        .chain(data.iter().map(|&w| w as u32));
+    rs1024_polymod(values) == 1
+}
+
+/// GF(256) exp/log tables for the Rijndael polynomial x^8 + x^4 + x^3 + x + 1
+fn gf_tables() -> &'static ([u8; 255], [u8; 256]) {
+    static TABLES: OnceLock<([u8; 255], [u8; 256])> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut exp = [0u8; 255];
+        let mut log = [0u8; 256];
+        let mut poly: u16 = 1;
+        for (i, e) in exp.iter_mut().enumerate() {
+            *e = poly as u8;
+            log[poly as usize] = i as u8;
+            // Multiply by the generator x + 1
+            poly = (poly << 1) ^ poly;
+            if poly & 0x100 != 0 {
+                poly ^= 0x11B;
+            }
+        }
+        (exp, log)
+    })
+}
+
+/// Lagrange interpolation of the shares' polynomial at `x`
+fn interpolate(shares: &[(u8, Vec<u8>)], x: u8) -> Result<Vec<u8>, String> {
+    if let Some((_, value)) = shares.iter().find(|(index, _)| *index == x) {
+        return Ok(value.clone());
+    }
+
+    let value_len = shares.first().map(|(_, v)| v.len()).ok_or("No shares to interpolate")?;
+    if shares.iter().any(|(_, v)| v.len() != value_len) {
+        return Err("All shares must have the same length".to_string());
+    }
+
+    let (exp, log) = gf_tables();
+
+    // log of the product of (x - x_j) over all shares
+    let log_prod: u32 = shares
+        .iter()
+        .map(|(index, _)| log[(index ^ x) as usize] as u32)
+        .sum();
+
+    let mut result = vec![0u8; value_len];
+    for (i, (index_i, value_i)) in shares.iter().enumerate() {
+        // log of the basis polynomial's scaling factor for share i
+        let denominator: u32 = shares
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .map(|(_, (index_j, _))| log[(index_i ^ index_j) as usize] as u32)
+            .sum::<u32>()
+            + log[(index_i ^ x) as usize] as u32;
+        let log_basis = (log_prod + 255 * shares.len() as u32 - denominator) % 255;
+
+        for (r, &v) in result.iter_mut().zip(value_i) {
+            if v != 0 {
+                *r ^= exp[((log[v as usize] as u32 + log_basis) % 255) as usize];
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| format!("Failed to generate random entropy: {}", e))?;
+    Ok(bytes)
+}
+
+fn create_digest(random_data: &[u8], shared_secret: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(random_data).expect("HMAC accepts any key length");
+    mac.update(shared_secret);
+    mac.finalize().into_bytes()[..DIGEST_LENGTH_BYTES].to_vec()
+}
+
+fn split_secret(threshold: u8, share_count: u8, secret: &[u8]) -> Result<IndexedShares, String> {
+    if threshold == 0 || threshold > share_count || share_count > MAX_SHARE_COUNT {
+        return Err(format!(
+            "Invalid sharing scheme {}-of-{} (at most {} shares)",
+            threshold, share_count, MAX_SHARE_COUNT
+        ));
+    }
+
+    if threshold == 1 {
+        return Ok((0..share_count).map(|i| (i, secret.to_vec())).collect());
+    }
+
+    let random_share_count = threshold - 2;
+    let mut shares: IndexedShares = (0..random_share_count)
+        .map(|i| random_bytes(secret.len()).map(|value| (i, value)))
+        .collect::<Result<_, _>>()?;
+
+    let random_part = random_bytes(secret.len() - DIGEST_LENGTH_BYTES)?;
+    let mut digest = create_digest(&random_part, secret);
+    digest.extend_from_slice(&random_part);
+
+    let mut base_shares = shares.clone();
+    base_shares.push((DIGEST_INDEX, digest));
+    base_shares.push((SECRET_INDEX, secret.to_vec()));
+
+    for i in random_share_count..share_count {
+        shares.push((i, interpolate(&base_shares, i)?));
+    }
+
+    Ok(shares)
+}
+
+fn recover_secret(threshold: u8, shares: &[(u8, Vec<u8>)]) -> Result<Vec<u8>, String> {
+    if threshold == 1 {
+        return shares
+            .first()
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| "No shares provided".to_string());
+    }
+
+    let shared_secret = interpolate(shares, SECRET_INDEX)?;
+    let digest_share = interpolate(shares, DIGEST_INDEX)?;
+    let (digest, random_part) = digest_share.split_at(DIGEST_LENGTH_BYTES);
+
+    if digest != create_digest(random_part, &shared_secret).as_slice() {
+        return Err("Invalid digest of the shared secret".to_string());
+    }
+
+    Ok(shared_secret)
+}
+
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    for (block_index, chunk) in out.chunks_mut(32).enumerate() {
+        let mut mac = HmacSha256::new_from_slice(password).expect("HMAC accepts any key length");
+        mac.update(salt);
+        mac.update(&(block_index as u32 + 1).to_be_bytes());
+        let mut u = mac.finalize().into_bytes();
+        let mut t = u;
+
+        for _ in 1..iterations {
+            let mut mac = HmacSha256::new_from_slice(password).expect("HMAC accepts any key length");
+            mac.update(&u);
+            u = mac.finalize().into_bytes();
+            t.iter_mut().zip(u.iter()).for_each(|(a, b)| *a ^= b);
+        }
+
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
+
+fn feistel_salt(identifier: u16, extendable: bool) -> Vec<u8> {
+    if extendable {
+        Vec::new()
+    } else {
+        let mut salt = b"shamir".to_vec();
+        salt.extend_from_slice(&identifier.to_be_bytes());
+        salt
+    }
+}
+
+fn feistel_round(
+    round: u8,
+    passphrase: &[u8],
+    iteration_exponent: u8,
+    salt: &[u8],
+    right: &[u8],
+) -> Vec<u8> {
+    let mut password = vec![round];
+    password.extend_from_slice(passphrase);
+    let mut round_salt = salt.to_vec();
+    round_salt.extend_from_slice(right);
+
+    let iterations = (BASE_ITERATION_COUNT << iteration_exponent) / ROUND_COUNT as u32;
+    let mut out = vec![0u8; right.len()];
+    pbkdf2_sha256(&password, &round_salt, iterations, &mut out);
+    out
+}
+
+fn feistel(
+    secret: &[u8],
+    passphrase: &[u8],
+    iteration_exponent: u8,
+    identifier: u16,
+    extendable: bool,
+    decrypt: bool,
+) -> Vec<u8> {
+    let half = secret.len() / 2;
+    let mut left = secret[..half].to_vec();
+    let mut right = secret[half..].to_vec();
+    let salt = feistel_salt(identifier, extendable);
+
+    let rounds: Vec<u8> = if decrypt {
+        (0..ROUND_COUNT).rev().collect()
+    } else {
+        (0..ROUND_COUNT).collect()
+    };
+
+    for round in rounds {
+        let f = feistel_round(round, passphrase, iteration_exponent, &salt, &right);
+        let new_right: Vec<u8> = left.iter().zip(&f).map(|(a, b)| a ^ b).collect();
+        left = std::mem::replace(&mut right, new_right);
+    }
+
+    right.extend_from_slice(&left);
+    right
+}
+
+/// Pack bit fields into 10-bit words
+fn int_to_words(fields: &[(u32, usize)]) -> Vec<u16> {
+    let mut acc: u64 = 0;
+    let mut bits = 0;
+    let mut words = Vec::new();
+    for &(value, width) in fields {
+        acc = (acc << width) | value as u64;
+        bits += width;
+        while bits >= RADIX_BITS {
+            bits -= RADIX_BITS;
+            words.push(((acc >> bits) & 1023) as u16);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    words
+}
+
+fn encode_share(share: &Share) -> String {
+    let mut words = int_to_words(&[
+        (share.identifier as u32, ID_LENGTH_BITS),
+        (share.extendable as u32, 1),
+        (share.iteration_exponent as u32, ITERATION_EXP_LENGTH_BITS),
+        (share.group_index as u32, 4),
+        ((share.group_threshold - 1) as u32, 4),
+        ((share.group_count - 1) as u32, 4),
+        (share.member_index as u32, 4),
+        ((share.member_threshold - 1) as u32, 4),
+    ]);
+
+    // Share value, left-padded with zero bits to a whole number of words
+    let value_bits = share.value.len() * 8;
+    let padding = (RADIX_BITS - value_bits % RADIX_BITS) % RADIX_BITS;
+    let mut fields = vec![(0, padding)];
+    fields.extend(share.value.iter().map(|&b| (b as u32, 8)));
+    words.extend(int_to_words(&fields));
+
+    let checksum = rs1024_create_checksum(&words, share.extendable);
+    words.extend_from_slice(&checksum);
+
+    words
+        .iter()
+        .map(|&w| wordlist()[w as usize])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn decode_share(mnemonic: &str) -> Result<Share, String> {
+    let words: Vec<u16> = mnemonic
+        .to_lowercase()
+        .split_whitespace()
+        .map(word_index)
+        .collect::<Result<_, _>>()?;
+
+    if words.len() < MIN_MNEMONIC_LENGTH_WORDS {
+        return Err(format!(
+            "Invalid share length: {} words (at least {} required)",
+            words.len(),
+            MIN_MNEMONIC_LENGTH_WORDS
+        ));
+    }
+
+    let padding_bits = ((words.len() - METADATA_LENGTH_WORDS - CHECKSUM_LENGTH_WORDS) * RADIX_BITS) % 16;
+    if padding_bits > 8 {
+        return Err("Invalid share length".to_string());
+    }
+
+    let prefix = (words[0] as u32) << 10 | words[1] as u32;
+    let identifier = (prefix >> 5) as u16;
+    let extendable = (prefix >> 4) & 1 == 1;
+    let iteration_exponent = (prefix & 0xF) as u8;
+
+    if !rs1024_verify_checksum(&words, extendable) {
+        return Err("Invalid share checksum".to_string());
+    }
+
+    let params = (words[2] as u32) << 10 | words[3] as u32;
+    let group_index = ((params >> 16) & 0xF) as u8;
+    let group_threshold = ((params >> 12) & 0xF) as u8 + 1;
+    let group_count = ((params >> 8) & 0xF) as u8 + 1;
+    let member_index = ((params >> 4) & 0xF) as u8;
+    let member_threshold = (params & 0xF) as u8 + 1;
+
+    if group_count < group_threshold {
+        return Err("Invalid share: group threshold exceeds group count".to_string());
+    }
+
+    // Unpack the share value, dropping the leading padding bits
+    let value_words = &words[METADATA_LENGTH_WORDS..words.len() - CHECKSUM_LENGTH_WORDS];
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut value = Vec::new();
+    for (i, &w) in value_words.iter().enumerate() {
+        acc = (acc << RADIX_BITS) | w as u32;
+        bits += RADIX_BITS;
+        if i == 0 {
+            if acc >> (bits - padding_bits) != 0 {
+                return Err("Invalid share padding".to_string());
+            }
+            bits -= padding_bits;
+            acc &= (1 << bits) - 1;
+        }
+        while bits >= 8 {
+            bits -= 8;
+            value.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    if value.len() < MIN_STRENGTH_BYTES || !value.len().is_multiple_of(2) {
+        return Err("Invalid share value length".to_string());
+    }
+
+    Ok(Share {
+        identifier,
+        extendable,
+        iteration_exponent,
+        group_index,
+        group_threshold,
+        group_count,
+        member_index,
+        member_threshold,
+        value,
+    })
+}
+
+/// Split a master secret into `share_count` mnemonic shares, any `threshold`
+/// of which recover it. The passphrase may be empty.
+pub fn split_master_secret(
+    master_secret: &[u8],
+    threshold: u8,
+    share_count: u8,
+    passphrase: &[u8],
+) -> Result<Vec<Zeroizing<String>>, String> {
+    if master_secret.len() < MIN_STRENGTH_BYTES || !master_secret.len().is_multiple_of(2) {
+        return Err("Master secret must be at least 128 bits and a multiple of 16 bits".to_string());
+    }
+
+    let id_bytes = random_bytes(2)?;
+    let identifier = u16::from_be_bytes([id_bytes[0], id_bytes[1]]) & ((1 << ID_LENGTH_BITS) - 1);
+    let extendable = true;
+
+    let encrypted_secret = Zeroizing::new(feistel(
+        master_secret,
+        passphrase,
+        DEFAULT_ITERATION_EXPONENT,
+        identifier,
+        extendable,
+        false,
+    ));
+
+    // Single group: the group share is the encrypted master secret itself
+    let member_shares = split_secret(threshold, share_count, &encrypted_secret)?;
+
+    Ok(member_shares
+        .into_iter()
+        .map(|(member_index, value)| {
+            Zeroizing::new(encode_share(&Share {
+                identifier,
+                extendable,
+                iteration_exponent: DEFAULT_ITERATION_EXPONENT,
+                group_index: 0,
+                group_threshold: 1,
+                group_count: 1,
+                member_index,
+                member_threshold: threshold,
+                value,
+            }))
+        })
+        .collect())
+}
+
+/// Recombine mnemonic shares into the master secret
+pub fn combine_mnemonics(
+    mnemonics: &[Zeroizing<String>],
+    passphrase: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, String> {
+    let shares: Vec<Share> = mnemonics
+        .iter()
+        .filter(|m| !m.trim().is_empty())
+        .map(|m| decode_share(m))
+        .collect::<Result<_, _>>()?;
+
+    let first = shares.first().ok_or("No shares provided")?;
+
+    let consistent = shares.iter().all(|s| {
+        s.identifier == first.identifier
+            && s.extendable == first.extendable
+            && s.iteration_exponent == first.iteration_exponent
+            && s.group_threshold == first.group_threshold
+            && s.group_count == first.group_count
+            && s.value.len() == first.value.len()
+    });
+    if !consistent {
+        return Err("Shares do not belong to the same recovery set".to_string());
+    }
+
+    // Group shares by group index, rejecting duplicates
+    let mut groups: BTreeMap<u8, (u8, IndexedShares)> = BTreeMap::new();
+    for share in &shares {
+        let (threshold, members) = groups
+            .entry(share.group_index)
+            .or_insert((share.member_threshold, Vec::new()));
+        if *threshold != share.member_threshold {
+            return Err("Shares in the same group have different thresholds".to_string());
+        }
+        if members.iter().any(|(index, _)| *index == share.member_index) {
+            return Err("Duplicate share provided".to_string());
+        }
+        members.push((share.member_index, share.value.clone()));
+    }
+
+    let mut group_shares = Vec::new();
+    for (group_index, (threshold, members)) in &groups {
+        if members.len() < *threshold as usize {
+            continue;
+        }
+        group_shares.push((*group_index, recover_secret(*threshold, &members[..*threshold as usize])?));
+    }
+
+    if group_shares.len() < first.group_threshold as usize {
+        let needed = groups
+            .values()
+            .map(|(threshold, members)| threshold.saturating_sub(members.len() as u8))
+            .filter(|&missing| missing > 0)
+            .min()
+            .unwrap_or(1);
+        return Err(format!("Not enough shares: {} more required", needed));
+    }
+    group_shares.truncate(first.group_threshold as usize);
+
+    let encrypted_secret = Zeroizing::new(recover_secret(first.group_threshold, &group_shares)?);
+
+    Ok(Zeroizing::new(feistel(
+        &encrypted_secret,
+        passphrase,
+        first.iteration_exponent,
+        first.identifier,
+        first.extendable,
+        true,
+    )))
+}
+
+/// Check that a single share is well formed (words and checksum)
+pub fn validate_mnemonic(mnemonic: &str) -> bool {
+    decode_share(mnemonic).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_official_vector_without_sharing() {
+        let mnemonic = "duckling enlarge academic academic agency result length solution fridge kidney coal piece deal husband erode duke ajar critical decision keyboard";
+        let secret = combine_mnemonics(&[Zeroizing::new(mnemonic.to_string())], b"TREZOR").unwrap();
+        assert_eq!(hex::encode(secret.as_slice()), "bb54aac4b89dc868ba37d9cc21b2cece");
+    }
+
+    #[test]
+    fn test_split_and_combine_2_of_3() {
+        let secret = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let shares = split_master_secret(&secret, 2, 3, b"").unwrap();
+        assert_eq!(shares.len(), 3);
+
+        for (a, b) in [(0, 1), (0, 2), (1, 2)] {
+            let pair = vec![shares[a].clone(), shares[b].clone()];
+            assert_eq!(*combine_mnemonics(&pair, b"").unwrap(), secret);
+        }
+
+        assert!(combine_mnemonics(&shares[..1], b"").is_err());
+    }
+
+    #[test]
+    fn test_rejects_corrupted_share() {
+        let secret = [7u8; 32];
+        let shares = split_master_secret(&secret, 2, 3, b"").unwrap();
+        let mut words: Vec<&str> = shares[0].split_whitespace().collect();
+        words[5] = if words[5] == "academic" { "acid" } else { "academic" };
+        assert!(!validate_mnemonic(&words.join(" ")));
+    }
+}
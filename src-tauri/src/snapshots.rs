@@ -0,0 +1,291 @@
+//! Automatic local snapshots
+//!
+//! Snapshots use the backup archive format, keyed with a random key kept in
+//! the vault directory (`snapshot.key`). They're meant for rolling back on
+//! this machine: taken on a schedule, before migrations, before
+//! `reset_all_data` and before restoring, and pruned to the newest N. Use a
+//! password-protected export for copies that leave the machine.
+//!
+//! Settings live in `auto_backup.json` in the vault directory rather than the
+//! settings table, so they survive `reset_all_data` and can be read while the
+//! database is locked.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+
+use crate::backup::{self, ArchiveFile};
+use crate::encrypted_db;
+use crate::vaults;
+
+const SETTINGS_FILE: &str = "auto_backup.json";
+const KEY_FILE: &str = "snapshot.key";
+const DEFAULT_DIR: &str = "backups";
+const SNAPSHOT_EXTENSION: &str = "bcsnap";
+const DEFAULT_KEEP: usize = 7;
+
+/// How often the background task checks whether a scheduled snapshot is due
+pub const SCHEDULE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Snapshot reasons, recorded in the file name
+pub const REASON_SCHEDULED: &str = "scheduled";
+pub const REASON_MANUAL: &str = "manual";
+pub const REASON_MIGRATION: &str = "pre-migration";
+pub const REASON_RESET: &str = "pre-reset";
+pub const REASON_RESTORE: &str = "pre-restore";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Schedule {
+    Off,
+    Daily,
+    Weekly,
+}
+
+impl Schedule {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "off" => Ok(Self::Off),
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            _ => Err("Invalid backup schedule".to_string()),
+        }
+    }
+
+    fn interval_secs(&self) -> Option<u64> {
+        match self {
+            Self::Off => None,
+            Self::Daily => Some(24 * 60 * 60),
+            Self::Weekly => Some(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoBackupSettings {
+    pub schedule: Schedule,
+    /// Where snapshots go; None for the vault's own `backups` folder
+    pub directory: Option<String>,
+    /// Snapshots to keep per vault
+    pub keep: usize,
+    /// Unix time of the last snapshot
+    pub last_run: u64,
+}
+
+impl Default for AutoBackupSettings {
+    fn default() -> Self {
+        Self {
+            schedule: Schedule::Daily,
+            directory: None,
+            keep: DEFAULT_KEEP,
+            last_run: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotInfo {
+    pub file_name: String,
+    pub created_at: u64,
+    pub reason: String,
+    pub size: u64,
+}
+
+pub fn load_settings(vault_id: &str) -> AutoBackupSettings {
+    std::fs::read_to_string(vaults::vault_dir(vault_id).join(SETTINGS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_settings(vault_id: &str, settings: &AutoBackupSettings) -> Result<(), String> {
+    if settings.keep == 0 {
+        return Err("Keep at least one backup".to_string());
+    }
+
+    let content = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    let path = vaults::vault_dir(vault_id).join(SETTINGS_FILE);
+    let temp = encrypted_db::with_suffix(&path, "new");
+
+    encrypted_db::write_synced(&temp, content)?;
+    std::fs::rename(&temp, &path).map_err(|e| format!("Failed to save backup settings: {}", e))
+}
+
+fn default_dir(vault_id: &str) -> PathBuf {
+    vaults::vault_dir(vault_id).join(DEFAULT_DIR)
+}
+
+/// Snapshot directories to look in: the configured one, then the default
+fn snapshot_dirs(vault_id: &str, settings: &AutoBackupSettings) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(dir) = &settings.directory {
+        dirs.push(PathBuf::from(dir));
+    }
+
+    let fallback = default_dir(vault_id);
+    if !dirs.contains(&fallback) {
+        dirs.push(fallback);
+    }
+    dirs
+}
+
+/// Read (or create) the vault's snapshot key
+fn snapshot_key(vault_id: &str) -> Result<String, String> {
+    let path = vaults::vault_dir(vault_id).join(KEY_FILE);
+    if let Ok(key) = std::fs::read_to_string(&path) {
+        return Ok(key.trim().to_string());
+    }
+
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("Failed to generate snapshot key: {}", e))?;
+    let key = hex::encode(bytes);
+    encrypted_db::write_synced(&path, &key)?;
+    Ok(key)
+}
+
+/// `<vault id>-<unix time>-<reason>.bcsnap`
+fn parse_file_name(vault_id: &str, file_name: &str) -> Option<(u64, String)> {
+    let rest = file_name
+        .strip_prefix(vault_id)?
+        .strip_prefix('-')?
+        .strip_suffix(SNAPSHOT_EXTENSION)?
+        .strip_suffix('.')?;
+    let (created_at, reason) = rest.split_once('-')?;
+
+    Some((created_at.parse().ok()?, reason.to_string()))
+}
+
+/// Snapshots in one directory, newest first
+fn snapshots_in(vault_id: &str, dir: &Path) -> Vec<(SnapshotInfo, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut snapshots: Vec<(SnapshotInfo, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let (created_at, reason) = parse_file_name(vault_id, &file_name)?;
+            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            Some((SnapshotInfo { file_name, created_at, reason, size }, entry.path()))
+        })
+        .collect();
+
+    snapshots.sort_by_key(|(info, _)| Reverse(info.created_at));
+    snapshots
+}
+
+/// List a vault's snapshots, newest first
+pub fn list(vault_id: &str) -> Vec<SnapshotInfo> {
+    let settings = load_settings(vault_id);
+
+    let mut snapshots: Vec<SnapshotInfo> = snapshot_dirs(vault_id, &settings)
+        .iter()
+        .flat_map(|dir| snapshots_in(vault_id, dir))
+        .map(|(info, _)| info)
+        .collect();
+
+    snapshots.sort_by_key(|info| Reverse(info.created_at));
+    snapshots
+}
+
+/// Take a snapshot of a vault now
+///
+/// Falls back to the vault's own folder if the configured directory can't
+/// be written (e.g. an unplugged drive), so a migration or reset is never
+/// left without one.
+pub fn create(vault_id: &str, reason: &str) -> Result<SnapshotInfo, String> {
+    let mut settings = load_settings(vault_id);
+    let key = snapshot_key(vault_id)?;
+    let vault_dir = vaults::vault_dir(vault_id);
+
+    // Seconds resolution; never reuse a name within the same second
+    let mut created_at = encrypted_db::unix_now();
+    let mut last_error = String::new();
+
+    for dir in snapshot_dirs(vault_id, &settings) {
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            last_error = format!("Failed to create backup directory: {}", e);
+            continue;
+        }
+
+        let mut path = dir.join(format!("{}-{}-{}.{}", vault_id, created_at, reason, SNAPSHOT_EXTENSION));
+        while path.exists() {
+            created_at += 1;
+            path = dir.join(format!("{}-{}-{}.{}", vault_id, created_at, reason, SNAPSHOT_EXTENSION));
+        }
+
+        match backup::export_archive(&vault_dir, &vaults::name_of(vault_id), &path, &key) {
+            Ok(_) => {
+                prune(vault_id, &dir, settings.keep)?;
+
+                settings.last_run = created_at;
+                save_settings(vault_id, &settings)?;
+
+                let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                return Ok(SnapshotInfo {
+                    file_name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                    created_at,
+                    reason: reason.to_string(),
+                    size,
+                });
+            }
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(format!("Failed to create backup: {}", last_error))
+}
+
+/// Delete all but the newest `keep` snapshots in a directory
+fn prune(vault_id: &str, dir: &Path, keep: usize) -> Result<(), String> {
+    for (_, path) in snapshots_in(vault_id, dir).into_iter().skip(keep) {
+        encrypted_db::remove_if_exists(&path)?;
+    }
+    Ok(())
+}
+
+/// Take a scheduled snapshot if one is due
+pub fn run_if_due(vault_id: &str) -> Result<Option<SnapshotInfo>, String> {
+    let settings = load_settings(vault_id);
+    let Some(interval) = settings.schedule.interval_secs() else {
+        return Ok(None);
+    };
+
+    if encrypted_db::unix_now() < settings.last_run.saturating_add(interval) {
+        return Ok(None);
+    }
+
+    create(vault_id, REASON_SCHEDULED).map(Some)
+}
+
+/// Read and verify a snapshot for restoring
+pub fn read(vault_id: &str, file_name: &str) -> Result<Vec<ArchiveFile>, String> {
+    let settings = load_settings(vault_id);
+
+    // Only names from our own listing, never arbitrary paths
+    let path = snapshot_dirs(vault_id, &settings)
+        .iter()
+        .flat_map(|dir| snapshots_in(vault_id, dir))
+        .find(|(info, _)| info.file_name == file_name)
+        .map(|(_, path)| path)
+        .ok_or("Backup not found")?;
+
+    let (_, files) = backup::read_archive(&path, &snapshot_key(vault_id)?)?;
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_name() {
+        assert_eq!(
+            parse_file_name("default", "default-1700000000-pre-reset.bcsnap"),
+            Some((1700000000, "pre-reset".to_string()))
+        );
+        assert_eq!(parse_file_name("default", "other-1700000000-manual.bcsnap"), None);
+        assert_eq!(parse_file_name("default", "default-1700000000-manual.db"), None);
+    }
+}
@@ -0,0 +1,372 @@
+//! Holding period tax profiles
+//!
+//! Some countries don't tax BTC held long enough, and tax the rest only above
+//! a yearly allowance. The rules are kept as JSON in the `tax_profile`
+//! setting rather than written into the report, so a profile can be picked
+//! from the presets or adjusted when the law changes.
+//!
+//! Each lot matched to a disposal is exempt or taxable by how long it was
+//! held; the taxable gains and losses of a year are netted and then the
+//! allowance applies. Matching uses the stored cost basis method and pooling.
+
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::audit::{csv_field, money};
+use crate::cost_basis;
+use crate::dates;
+
+const SETTING_KEY: &str = "tax_profile";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllowanceKind {
+    /// Gains below the allowance are tax-free; from there on all of them are
+    /// taxable (a German Freigrenze)
+    Threshold,
+    /// Only the gains above the allowance are taxable
+    Deduction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Allowance {
+    pub from_year: i64,
+    pub amount: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    /// Reports need values in this currency, if set
+    pub currency: Option<String>,
+    /// Lots held longer than this are exempt; 0 exempts everything, None nothing
+    pub exempt_after_months: Option<i64>,
+    pub allowance_kind: AllowanceKind,
+    /// Yearly allowance for taxable gains, by the year it applies from
+    pub allowances: Vec<Allowance>,
+}
+
+impl Profile {
+    /// Built-in profiles
+    pub fn presets() -> Vec<Profile> {
+        vec![
+            Profile {
+                name: "Germany".to_string(),
+                currency: Some("EUR".to_string()),
+                exempt_after_months: Some(12),
+                allowance_kind: AllowanceKind::Threshold,
+                allowances: vec![
+                    Allowance {
+                        from_year: 2009,
+                        amount: 600.0,
+                    },
+                    Allowance {
+                        from_year: 2024,
+                        amount: 1_000.0,
+                    },
+                ],
+            },
+            Profile {
+                name: "Switzerland (private investor)".to_string(),
+                currency: Some("CHF".to_string()),
+                exempt_after_months: Some(0),
+                allowance_kind: AllowanceKind::Deduction,
+                allowances: Vec::new(),
+            },
+        ]
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Tax profile name cannot be empty".to_string());
+        }
+        if self.exempt_after_months.is_some_and(|months| months < 0) {
+            return Err("Holding period cannot be negative".to_string());
+        }
+        if self.allowances.iter().any(|allowance| allowance.amount < 0.0) {
+            return Err("Allowances cannot be negative".to_string());
+        }
+        Ok(())
+    }
+
+    /// The allowance for a tax year
+    pub fn allowance(&self, year: i64) -> f64 {
+        self.allowances
+            .iter()
+            .filter(|allowance| allowance.from_year <= year)
+            .max_by_key(|allowance| allowance.from_year)
+            .map_or(0.0, |allowance| allowance.amount)
+    }
+
+    pub fn is_exempt(&self, acquired_at: i64, disposed_at: i64) -> bool {
+        match self.exempt_after_months {
+            None => false,
+            Some(0) => true,
+            Some(months) => dates::held_longer_than(acquired_at, disposed_at, months),
+        }
+    }
+
+    /// Taxable amount of a year's net taxable gain after the allowance
+    pub fn apply_allowance(&self, net_gain: f64, year: i64) -> f64 {
+        let allowance = self.allowance(year);
+        if net_gain <= 0.0 {
+            return net_gain;
+        }
+
+        match self.allowance_kind {
+            AllowanceKind::Threshold if net_gain < allowance => 0.0,
+            AllowanceKind::Threshold => net_gain,
+            AllowanceKind::Deduction => (net_gain - allowance).max(0.0),
+        }
+    }
+}
+
+/// A lot matched to a disposal, classified by holding period
+#[derive(Debug, Clone, Serialize)]
+pub struct ClassifiedMatch {
+    pub disposal_id: i64,
+    pub lot_id: Option<i64>,
+    pub acquired_at: Option<i64>,
+    pub disposed_at: i64,
+    pub sats: i64,
+    pub proceeds: f64,
+    pub cost_basis: f64,
+    pub gain: f64,
+    pub exempt: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileReport {
+    pub profile: Profile,
+    pub year: i64,
+    pub currency: String,
+    pub matches: Vec<ClassifiedMatch>,
+    pub exempt_gain: f64,
+    /// Taxable gains net of taxable losses, before the allowance
+    pub taxable_net: f64,
+    pub allowance: f64,
+    /// After the allowance; negative for a loss
+    pub taxable_gain: f64,
+    pub warnings: Vec<String>,
+}
+
+/// Classify one year's disposals from a cost basis report over the whole history
+pub fn classify(profile: &Profile, report: &cost_basis::CostBasisReport, year: i64) -> ProfileReport {
+    let mut matches = Vec::new();
+
+    for disposal in &report.disposals {
+        if dates::civil_date(disposal.disposed_at).0 != year {
+            continue;
+        }
+
+        for lot in &disposal.matches {
+            matches.push(ClassifiedMatch {
+                disposal_id: disposal.id,
+                lot_id: Some(lot.lot_id),
+                acquired_at: Some(lot.acquired_at),
+                disposed_at: disposal.disposed_at,
+                sats: lot.sats,
+                proceeds: lot.proceeds,
+                cost_basis: lot.cost_basis,
+                gain: lot.gain,
+                exempt: profile.is_exempt(lot.acquired_at, disposal.disposed_at),
+            });
+        }
+
+        // Nothing is known about when these were acquired, so they can't be exempt
+        if disposal.unmatched_sats > 0 {
+            let proceeds = disposal.proceeds * disposal.unmatched_sats as f64 / disposal.sats as f64;
+            matches.push(ClassifiedMatch {
+                disposal_id: disposal.id,
+                lot_id: None,
+                acquired_at: None,
+                disposed_at: disposal.disposed_at,
+                sats: disposal.unmatched_sats,
+                proceeds,
+                cost_basis: 0.0,
+                gain: proceeds,
+                exempt: profile.exempt_after_months == Some(0),
+            });
+        }
+    }
+
+    let exempt_gain = matches.iter().filter(|m| m.exempt).map(|m| m.gain).sum();
+    let taxable_net = matches.iter().filter(|m| !m.exempt).map(|m| m.gain).sum();
+
+    ProfileReport {
+        profile: profile.clone(),
+        year,
+        currency: report.currency.clone(),
+        matches,
+        exempt_gain,
+        taxable_net,
+        allowance: profile.allowance(year),
+        taxable_gain: profile.apply_allowance(taxable_net, year),
+        warnings: Vec::new(),
+    }
+}
+
+/// The chosen profile, if any
+pub async fn profile(conn: &mut SqliteConnection) -> Result<Option<Profile>, String> {
+    let Some(value) = cost_basis::setting(conn, SETTING_KEY).await? else {
+        return Ok(None);
+    };
+
+    serde_json::from_str(&value)
+        .map(Some)
+        .map_err(|e| format!("Invalid tax profile: {}", e))
+}
+
+/// Choose a profile; None clears it
+pub async fn set_profile(pool: &SqlitePool, profile: Option<&Profile>) -> Result<(), String> {
+    let Some(profile) = profile else {
+        sqlx::query("DELETE FROM settings WHERE key = ?")
+            .bind(SETTING_KEY)
+            .execute(pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        return Ok(());
+    };
+
+    profile.validate()?;
+    let value = serde_json::to_string(profile).map_err(|e| e.to_string())?;
+    sqlx::query(
+        "INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+    )
+    .bind(SETTING_KEY)
+    .bind(&value)
+    .execute(pool)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Report for a calendar year with the chosen profile
+pub async fn generate(pool: &SqlitePool, year: i64) -> Result<ProfileReport, String> {
+    let profile = {
+        let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+        profile(&mut conn).await?.ok_or("No tax profile chosen")?
+    };
+
+    let report = cost_basis::report(pool, None, None, None, None).await?;
+    if let Some(currency) = profile.currency.as_deref() {
+        if !report.currency.eq_ignore_ascii_case(currency) {
+            return Err(format!(
+                "The {} profile needs values in {}, not {}",
+                profile.name, currency, report.currency
+            ));
+        }
+    }
+
+    let mut classified = classify(&profile, &report, year);
+    classified.warnings = report.warnings;
+    Ok(classified)
+}
+
+/// The classified lots and the year's totals as CSV
+pub fn to_csv(report: &ProfileReport) -> String {
+    let currency = &report.currency;
+    let mut csv = format!(
+        "{},Year {}\n\nTransaction,Lot,Acquired,Disposed,Sats,Proceeds ({}),Cost basis ({}),Gain or loss ({}),Exempt\n",
+        csv_field(&report.profile.name),
+        report.year,
+        currency,
+        currency,
+        currency
+    );
+
+    for m in &report.matches {
+        let row = [
+            m.disposal_id.to_string(),
+            m.lot_id.map(|id| id.to_string()).unwrap_or_default(),
+            m.acquired_at
+                .map(dates::iso_date)
+                .unwrap_or_else(|| "Unknown".to_string()),
+            dates::iso_date(m.disposed_at),
+            m.sats.to_string(),
+            money(m.proceeds),
+            money(m.cost_basis),
+            money(m.gain),
+            if m.exempt { "Yes" } else { "No" }.to_string(),
+        ];
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+
+    csv.push_str(&format!("\nExempt gain or loss,{}\n", money(report.exempt_gain)));
+    csv.push_str(&format!(
+        "Taxable gain or loss before allowance,{}\n",
+        money(report.taxable_net)
+    ));
+    csv.push_str(&format!("Allowance,{}\n", money(report.allowance)));
+    csv.push_str(&format!("Taxable gain or loss,{}\n", money(report.taxable_gain)));
+
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cost_basis::{compute, Method, Options};
+    use crate::test_support::entry;
+
+    fn germany() -> Profile {
+        Profile::presets().remove(0)
+    }
+
+    #[test]
+    fn test_allowance() {
+        let profile = germany();
+        assert_eq!(profile.allowance(2008), 0.0);
+        assert_eq!(profile.allowance(2023), 600.0);
+        assert_eq!(profile.allowance(2024), 1_000.0);
+
+        // A threshold: below it nothing is taxable, at it everything is
+        assert_eq!(profile.apply_allowance(999.0, 2024), 0.0);
+        assert_eq!(profile.apply_allowance(1_000.0, 2024), 1_000.0);
+        assert_eq!(profile.apply_allowance(-50.0, 2024), -50.0);
+
+        let deduction = Profile {
+            allowance_kind: AllowanceKind::Deduction,
+            ..germany()
+        };
+        assert_eq!(deduction.apply_allowance(1_500.0, 2024), 500.0);
+        assert_eq!(deduction.apply_allowance(500.0, 2024), 0.0);
+    }
+
+    #[test]
+    fn test_holding_period_classification() {
+        let entries = [
+            entry(1, "2023-01-10", 100_000, 100.0, "Buy"),
+            entry(2, "2024-03-01", 100_000, 200.0, "Buy"),
+            entry(3, "2024-06-01", -200_000, 2_000.0, "Sell"),
+        ];
+        let report = compute(&entries, "EUR", &Options::new(Method::Fifo));
+        let classified = classify(&germany(), &report, 2024);
+
+        assert_eq!(classified.matches.len(), 2);
+        assert!(classified.matches[0].exempt);
+        assert!(!classified.matches[1].exempt);
+        assert_eq!(classified.exempt_gain, 900.0);
+        assert_eq!(classified.taxable_net, 800.0);
+        assert_eq!(classified.taxable_gain, 0.0);
+
+        let csv = to_csv(&classified);
+        assert!(csv.contains("3,1,2023-01-10,2024-06-01,100000,1000.00,100.00,900.00,Yes\n"));
+        assert!(csv.ends_with("Taxable gain or loss,0.00\n"));
+
+        let swiss = &Profile::presets()[1];
+        assert_eq!(classify(swiss, &report, 2024).taxable_net, 0.0);
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(germany().validate().is_ok());
+        let negative = Profile {
+            exempt_after_months: Some(-1),
+            ..germany()
+        };
+        assert!(negative.validate().is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! Helpers for tests that touch the app data directory
+//!
+//! Vault paths are process-wide, so tests that use them take turns: each one
+//! gets a fresh temporary app directory for as long as it holds an `AppDir`.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, RwLock};
+
+static TURN: Mutex<()> = Mutex::new(());
+
+/// App directory `vaults::root_dir` returns while a test holds it
+static ROOT: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+pub fn root_override() -> Option<PathBuf> {
+    ROOT.read().ok().and_then(|root| root.clone())
+}
+
+/// A temporary app directory, removed when dropped
+pub struct AppDir {
+    path: PathBuf,
+    _turn: MutexGuard<'static, ()>,
+}
+
+impl AppDir {
+    pub fn new() -> Self {
+        // A test that panicked still released its directory
+        let turn = TURN.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join(format!("satstone-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+
+        *ROOT.write().unwrap() = Some(path.clone());
+        crate::vaults::init_active();
+
+        Self { path, _turn: turn }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for AppDir {
+    fn drop(&mut self) {
+        *ROOT.write().unwrap() = None;
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Give the active vault a small encrypted database
+/// Returns the recovery phrase that opens it
+pub fn encrypt_active_vault(passphrase: &str) -> crate::crypto::SecretString {
+    use crate::encrypted_db::EncryptedDb;
+
+    let conn = rusqlite::Connection::open(EncryptedDb::get_db_path()).unwrap();
+    conn.execute_batch("CREATE TABLE notes (body TEXT); INSERT INTO notes VALUES ('kept');")
+        .unwrap();
+    drop(conn);
+
+    let recovery_phrase = crate::crypto::generate_recovery_phrase(12).unwrap();
+    EncryptedDb::encrypt_database(passphrase, &recovery_phrase, "", None).unwrap();
+    recovery_phrase
+}
+
+/// A BTC transaction in wallet 1, `id` seconds into `date` (`YYYY-MM-DD`) so
+/// entries on one day keep their order
+pub fn entry(id: i64, date: &str, amount_sats: i64, fiat_value: f64, category: &str) -> crate::cost_basis::Entry {
+    crate::cost_basis::Entry {
+        id,
+        wallet_id: 1,
+        entity_id: 1,
+        txid: format!("tx{}", id),
+        timestamp: crate::dates::date_to_unix(date).unwrap() + id,
+        amount_sats,
+        fiat_value,
+        category: category.to_string(),
+        fee_sats: 0,
+        fee_value: 0.0,
+    }
+}
+
+/// An in-memory database with no tables yet
+pub async fn blank_pool() -> sqlx::SqlitePool {
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+    use std::str::FromStr;
+
+    let options = SqliteConnectOptions::from_str("sqlite::memory:")
+        .unwrap()
+        .foreign_keys(true);
+
+    // Every in-memory connection is a database of its own, so keep exactly one
+    SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(options)
+        .await
+        .unwrap()
+}
+
+/// An empty in-memory database with the full schema
+pub async fn memory_pool() -> sqlx::SqlitePool {
+    let pool = blank_pool().await;
+    crate::db::create_schema(&pool).await.unwrap();
+    pool
+}
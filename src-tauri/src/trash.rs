@@ -0,0 +1,570 @@
+//! Soft deletion, trash and undo
+//!
+//! Deleting an entity, wallet or transaction (or resetting all data) doesn't
+//! remove rows. It creates a `trash` entry and tags every affected row with its
+//! id in `trash_id`; all reads skip tagged rows. Restoring clears the tags,
+//! undo restores the newest entry, and only emptying the trash deletes for good.
+
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use std::collections::HashMap;
+
+use crate::audit;
+use crate::periods;
+
+pub const OP_DELETE_ENTITY: &str = "delete_entity";
+pub const OP_DELETE_WALLET: &str = "delete_wallet";
+pub const OP_DELETE_TRANSACTION: &str = "delete_transaction";
+pub const OP_RESET: &str = "reset_all_data";
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct TrashEntry {
+    pub id: i64,
+    pub operation: String,
+    pub description: String,
+    pub entities: i64,
+    pub wallets: i64,
+    pub transactions: i64,
+    pub created_at: String,
+}
+
+async fn begin(
+    tx: &mut Transaction<'_, Sqlite>,
+    operation: &str,
+    description: &str,
+    settings: Option<String>,
+) -> Result<i64, String> {
+    let result = sqlx::query("INSERT INTO trash (operation, description, settings) VALUES (?, ?, ?)")
+        .bind(operation)
+        .bind(description)
+        .bind(settings)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| format!("Failed to create trash entry: {}", e))?;
+
+    Ok(result.last_insert_rowid())
+}
+
+/// Trash the live transactions of every wallet in this entry
+async fn trash_wallet_transactions(tx: &mut Transaction<'_, Sqlite>, trash_id: i64) -> Result<(), String> {
+    sqlx::query(
+        "UPDATE transactions SET trash_id = ? WHERE trash_id IS NULL AND wallet_id IN (SELECT id FROM wallets WHERE trash_id = ?)",
+    )
+    .bind(trash_id)
+    .bind(trash_id)
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Move an entity to the trash along with its wallets and their transactions
+pub async fn delete_entity(pool: &SqlitePool, id: i64) -> Result<(), String> {
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    let name = sqlx::query_as::<_, (String,)>("SELECT name FROM entities WHERE id = ? AND trash_id IS NULL")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("Entity not found")?
+        .0;
+
+    periods::ensure_none_closed(
+        &mut tx,
+        "t.trash_id IS NULL AND t.wallet_id IN (SELECT id FROM wallets WHERE entity_id = ?)",
+        Some(id),
+    )
+    .await?;
+
+    let trash_id = begin(&mut tx, OP_DELETE_ENTITY, &name, None).await?;
+
+    sqlx::query("UPDATE entities SET trash_id = ? WHERE id = ?")
+        .bind(trash_id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    sqlx::query("UPDATE wallets SET trash_id = ? WHERE entity_id = ? AND trash_id IS NULL")
+        .bind(trash_id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    trash_wallet_transactions(&mut tx, trash_id).await?;
+
+    audit::record(
+        &mut tx,
+        OP_DELETE_ENTITY,
+        "entities",
+        Some(id),
+        Some(json!({ "name": name })),
+        Some(json!({ "trash_id": trash_id })),
+    )
+    .await?;
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+/// Move a wallet to the trash along with its transactions
+pub async fn delete_wallet(pool: &SqlitePool, id: i64) -> Result<(), String> {
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    let name = sqlx::query_as::<_, (String,)>("SELECT name FROM wallets WHERE id = ? AND trash_id IS NULL")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("Wallet not found")?
+        .0;
+
+    periods::ensure_none_closed(&mut tx, "t.trash_id IS NULL AND t.wallet_id = ?", Some(id)).await?;
+
+    let trash_id = begin(&mut tx, OP_DELETE_WALLET, &name, None).await?;
+
+    sqlx::query("UPDATE wallets SET trash_id = ? WHERE id = ?")
+        .bind(trash_id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    trash_wallet_transactions(&mut tx, trash_id).await?;
+
+    audit::record(
+        &mut tx,
+        OP_DELETE_WALLET,
+        "wallets",
+        Some(id),
+        Some(json!({ "name": name })),
+        Some(json!({ "trash_id": trash_id })),
+    )
+    .await?;
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+/// Move a single transaction to the trash
+pub async fn delete_transaction(pool: &SqlitePool, id: i64, description: &str) -> Result<(), String> {
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    periods::ensure_transaction_open(&mut tx, id).await?;
+
+    let trash_id = begin(&mut tx, OP_DELETE_TRANSACTION, description, None).await?;
+
+    let result = sqlx::query("UPDATE transactions SET trash_id = ? WHERE id = ? AND trash_id IS NULL")
+        .bind(trash_id)
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if result.rows_affected() == 0 {
+        return Err("Transaction not found".to_string());
+    }
+
+    let old_values = audit::transaction_values(&mut tx, id).await?;
+    audit::record(
+        &mut tx,
+        OP_DELETE_TRANSACTION,
+        "transactions",
+        Some(id),
+        Some(old_values),
+        Some(json!({ "trash_id": trash_id })),
+    )
+    .await?;
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+/// Move all wallets, transactions and non-personal entities to the trash
+///
+/// Settings are kept in the trash entry so undo can put them back. The price
+/// cache is dropped for good; it's refetched on demand.
+pub async fn reset_all(pool: &SqlitePool) -> Result<(), String> {
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    periods::ensure_none_closed(&mut tx, "t.trash_id IS NULL", None::<i64>).await?;
+
+    let settings: HashMap<String, String> = sqlx::query_as::<_, (String, String)>("SELECT key, value FROM settings")
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .collect();
+    let settings = serde_json::to_string(&settings).map_err(|e| e.to_string())?;
+
+    let trash_id = begin(&mut tx, OP_RESET, "All data", Some(settings)).await?;
+
+    for statement in [
+        "UPDATE entities SET trash_id = ? WHERE entity_type != 'personal' AND trash_id IS NULL",
+        "UPDATE wallets SET trash_id = ? WHERE trash_id IS NULL",
+        "UPDATE transactions SET trash_id = ? WHERE trash_id IS NULL",
+    ] {
+        sqlx::query(statement)
+            .bind(trash_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    for statement in ["DELETE FROM settings", "DELETE FROM price_cache"] {
+        sqlx::query(statement)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    audit::record(&mut tx, OP_RESET, "trash", Some(trash_id), None, None).await?;
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+/// List trash entries, newest first
+pub async fn list(pool: &SqlitePool) -> Result<Vec<TrashEntry>, String> {
+    sqlx::query_as::<_, TrashEntry>(
+        r#"
+        SELECT t.id, t.operation, t.description,
+               (SELECT COUNT(*) FROM entities WHERE trash_id = t.id) as entities,
+               (SELECT COUNT(*) FROM wallets WHERE trash_id = t.id) as wallets,
+               (SELECT COUNT(*) FROM transactions WHERE trash_id = t.id) as transactions,
+               CAST(t.created_at AS TEXT) as created_at
+        FROM trash t
+        ORDER BY t.id DESC
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Put everything in a trash entry back
+pub async fn restore(pool: &SqlitePool, trash_id: i64) -> Result<(), String> {
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    let (operation, description, settings) = sqlx::query_as::<_, (String, String, Option<String>)>(
+        "SELECT operation, description, settings FROM trash WHERE id = ?",
+    )
+    .bind(trash_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("Trash entry not found")?;
+
+    // Restored rows would land inside a sealed period
+    periods::ensure_none_closed(&mut tx, "t.trash_id = ?", Some(trash_id)).await?;
+
+    // Rows whose parent was deleted separately would come back invisible
+    let (orphaned_wallets,) = sqlx::query_as::<_, (i64,)>(
+        r#"
+        SELECT COUNT(*) FROM wallets w JOIN entities e ON w.entity_id = e.id
+        WHERE w.trash_id = ? AND e.trash_id IS NOT NULL AND e.trash_id != ?
+        "#,
+    )
+    .bind(trash_id)
+    .bind(trash_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+    if orphaned_wallets > 0 {
+        return Err("Restore the owner of these wallets first".to_string());
+    }
+
+    let (orphaned_transactions,) = sqlx::query_as::<_, (i64,)>(
+        r#"
+        SELECT COUNT(*) FROM transactions t JOIN wallets w ON t.wallet_id = w.id
+        WHERE t.trash_id = ? AND w.trash_id IS NOT NULL AND w.trash_id != ?
+        "#,
+    )
+    .bind(trash_id)
+    .bind(trash_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+    if orphaned_transactions > 0 {
+        return Err("Restore the wallet of these transactions first".to_string());
+    }
+
+    for statement in [
+        "UPDATE entities SET trash_id = NULL WHERE trash_id = ?",
+        "UPDATE wallets SET trash_id = NULL WHERE trash_id = ?",
+        "UPDATE transactions SET trash_id = NULL WHERE trash_id = ?",
+        "DELETE FROM trash WHERE id = ?",
+    ] {
+        sqlx::query(statement)
+            .bind(trash_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    if let Some(settings) = settings {
+        let settings: HashMap<String, String> =
+            serde_json::from_str(&settings).map_err(|e| format!("Failed to read saved settings: {}", e))?;
+        for (key, value) in settings {
+            sqlx::query(
+                "INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            )
+            .bind(key)
+            .bind(value)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+    }
+
+    audit::record(
+        &mut tx,
+        "restore_from_trash",
+        "trash",
+        Some(trash_id),
+        Some(json!({ "operation": operation, "description": description })),
+        None,
+    )
+    .await?;
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+/// Restore the most recent deletion
+pub async fn undo_last(pool: &SqlitePool) -> Result<TrashEntry, String> {
+    let entry = list(pool).await?.into_iter().next().ok_or("Nothing to undo")?;
+
+    restore(pool, entry.id).await?;
+    Ok(entry)
+}
+
+/// Permanently delete one trash entry, or all of them
+pub async fn purge(pool: &SqlitePool, trash_id: Option<i64>) -> Result<(), String> {
+    let mut tx = pool.begin().await.map_err(|e| e.to_string())?;
+
+    // Children first; anything already cascaded away is simply gone
+    let statements = match trash_id {
+        Some(_) => [
+            "DELETE FROM transactions WHERE trash_id = ?",
+            "DELETE FROM wallets WHERE trash_id = ?",
+            "DELETE FROM entities WHERE trash_id = ?",
+            "DELETE FROM trash WHERE id = ?",
+        ],
+        None => [
+            "DELETE FROM transactions WHERE trash_id IS NOT NULL",
+            "DELETE FROM wallets WHERE trash_id IS NOT NULL",
+            "DELETE FROM entities WHERE trash_id IS NOT NULL",
+            "DELETE FROM trash",
+        ],
+    };
+
+    for statement in statements {
+        let mut query = sqlx::query(statement);
+        if let Some(id) = trash_id {
+            query = query.bind(id);
+        }
+        query.execute(&mut *tx).await.map_err(|e| e.to_string())?;
+    }
+
+    audit::record(&mut tx, "empty_trash", "trash", trash_id, None, None).await?;
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::memory_pool;
+
+    /// Entity 1 (Personal) with wallet 1, entity 2 (Business) with wallet 2;
+    /// transactions 1 and 2 in wallet 1, transaction 3 in wallet 2
+    async fn books() -> SqlitePool {
+        let pool = memory_pool().await;
+
+        for statement in [
+            "INSERT INTO entities (id, name, entity_type) VALUES (2, 'Business', 'business')",
+            "INSERT INTO wallets (id, name, xpub, entity_id) VALUES (1, 'Savings', 'xpub1', 1), (2, 'Shop', 'xpub2', 2)",
+            "INSERT INTO transactions (id, wallet_id, txid, amount_sats, timestamp) VALUES
+                (1, 1, 'a', 100000, 1704067200), (2, 1, 'b', -20000, 1706745600), (3, 2, 'c', 50000, 1706745600)",
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        pool
+    }
+
+    /// Ids of the rows in `table` that aren't in the trash
+    async fn live(pool: &SqlitePool, table: &str) -> Vec<i64> {
+        sqlx::query_as::<_, (i64,)>(&format!("SELECT id FROM {} WHERE trash_id IS NULL ORDER BY id", table))
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id,)| id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_delete_entity_and_restore() {
+        let pool = books().await;
+
+        delete_entity(&pool, 2).await.unwrap();
+        assert_eq!(live(&pool, "entities").await, vec![1]);
+        assert_eq!(live(&pool, "wallets").await, vec![1]);
+        assert_eq!(live(&pool, "transactions").await, vec![1, 2]);
+
+        let entries = list(&pool).await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, OP_DELETE_ENTITY);
+        assert_eq!(entries[0].description, "Business");
+        assert_eq!(
+            (entries[0].entities, entries[0].wallets, entries[0].transactions),
+            (1, 1, 1)
+        );
+
+        assert_eq!(delete_entity(&pool, 2).await.unwrap_err(), "Entity not found");
+
+        restore(&pool, entries[0].id).await.unwrap();
+        assert_eq!(live(&pool, "entities").await, vec![1, 2]);
+        assert_eq!(live(&pool, "wallets").await, vec![1, 2]);
+        assert_eq!(live(&pool, "transactions").await, vec![1, 2, 3]);
+        assert!(list(&pool).await.unwrap().is_empty());
+
+        let commands: Vec<(String,)> = sqlx::query_as("SELECT command FROM audit_log ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            commands,
+            vec![(OP_DELETE_ENTITY.to_string(),), ("restore_from_trash".to_string(),)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_refused_while_parent_is_trashed() {
+        let pool = books().await;
+
+        delete_wallet(&pool, 2).await.unwrap();
+        delete_entity(&pool, 2).await.unwrap();
+        let entries = list(&pool).await.unwrap();
+        let (entity_entry, wallet_entry) = (entries[0].id, entries[1].id);
+
+        // The wallet went first, so the entity's entry holds no wallets
+        assert_eq!(entries[0].wallets, 0);
+        assert_eq!(
+            restore(&pool, wallet_entry).await.unwrap_err(),
+            "Restore the owner of these wallets first"
+        );
+        assert_eq!(live(&pool, "wallets").await, vec![1]);
+
+        restore(&pool, entity_entry).await.unwrap();
+        restore(&pool, wallet_entry).await.unwrap();
+        assert_eq!(live(&pool, "wallets").await, vec![1, 2]);
+        assert_eq!(live(&pool, "transactions").await, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_restore_refused_while_wallet_is_trashed() {
+        let pool = books().await;
+
+        delete_transaction(&pool, 1, "Deposit").await.unwrap();
+        delete_wallet(&pool, 1).await.unwrap();
+        let entries = list(&pool).await.unwrap();
+
+        assert_eq!(entries[0].transactions, 1);
+        assert_eq!(
+            restore(&pool, entries[1].id).await.unwrap_err(),
+            "Restore the wallet of these transactions first"
+        );
+        assert_eq!(live(&pool, "transactions").await, vec![3]);
+    }
+
+    #[tokio::test]
+    async fn test_undo_last() {
+        let pool = books().await;
+
+        delete_transaction(&pool, 3, "Sale").await.unwrap();
+        delete_wallet(&pool, 1).await.unwrap();
+
+        assert_eq!(undo_last(&pool).await.unwrap().operation, OP_DELETE_WALLET);
+        assert_eq!(live(&pool, "transactions").await, vec![1, 2]);
+
+        assert_eq!(undo_last(&pool).await.unwrap().description, "Sale");
+        assert_eq!(live(&pool, "transactions").await, vec![1, 2, 3]);
+
+        assert_eq!(undo_last(&pool).await.unwrap_err(), "Nothing to undo");
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let pool = books().await;
+
+        delete_wallet(&pool, 2).await.unwrap();
+        delete_transaction(&pool, 1, "Deposit").await.unwrap();
+        let wallet_entry = list(&pool).await.unwrap()[1].id;
+
+        purge(&pool, Some(wallet_entry)).await.unwrap();
+        let (wallets,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM wallets")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(wallets, 1);
+        assert_eq!(list(&pool).await.unwrap().len(), 1);
+
+        purge(&pool, None).await.unwrap();
+        let (transactions,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM transactions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(transactions, 1);
+        assert!(list(&pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reset_all_and_restore_settings() {
+        let pool = books().await;
+        sqlx::query("UPDATE settings SET value = 'USD' WHERE key = 'fiat_currency'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        reset_all(&pool).await.unwrap();
+        assert_eq!(live(&pool, "entities").await, vec![1]);
+        assert!(live(&pool, "wallets").await.is_empty());
+        assert!(live(&pool, "transactions").await.is_empty());
+        let (settings,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM settings")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(settings, 0);
+
+        let entry = undo_last(&pool).await.unwrap();
+        assert_eq!(entry.operation, OP_RESET);
+        assert_eq!(live(&pool, "entities").await, vec![1, 2]);
+        assert_eq!(live(&pool, "transactions").await, vec![1, 2, 3]);
+        let (currency,): (String,) = sqlx::query_as("SELECT value FROM settings WHERE key = 'fiat_currency'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(currency, "USD");
+    }
+
+    #[tokio::test]
+    async fn test_closed_periods_block_deletion() {
+        let pool = books().await;
+        let mut conn = pool.acquire().await.unwrap();
+        periods::close(&mut conn, "2024-01-01", "2024-01-31").await.unwrap();
+        drop(conn);
+
+        assert!(delete_wallet(&pool, 1)
+            .await
+            .unwrap_err()
+            .contains("closed period 2024-01-01 to 2024-01-31"));
+        assert!(delete_transaction(&pool, 1, "Deposit").await.is_err());
+        assert!(reset_all(&pool).await.is_err());
+
+        // Wallet 2 only has a February transaction
+        delete_wallet(&pool, 2).await.unwrap();
+        assert_eq!(live(&pool, "transactions").await, vec![1, 2]);
+    }
+}
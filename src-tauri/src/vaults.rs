@@ -0,0 +1,349 @@
+//! Vaults (profiles): separate databases under one app data directory
+//!
+//! Each vault is a directory holding its own `personal.db`, `satstone.db`
+//! and encryption header. The default vault is the app data directory
+//! itself, so installs from before vaults existed keep their data; others
+//! live in `vaults/<id>/`. Names and the active vault are kept in `vaults.json`.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/// The vault that uses the top-level app directory
+pub const DEFAULT_VAULT_ID: &str = "default";
+
+const DEFAULT_VAULT_NAME: &str = "Personal";
+const REGISTRY_FILE: &str = "vaults.json";
+
+/// Vault the database paths currently point at (None = default)
+static ACTIVE_VAULT: RwLock<Option<String>> = RwLock::new(None);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vault {
+    pub id: String,
+    pub name: String,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VaultInfo {
+    pub id: String,
+    pub name: String,
+    pub created_at: u64,
+    pub active: bool,
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Registry {
+    active: String,
+    vaults: Vec<Vault>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            active: DEFAULT_VAULT_ID.to_string(),
+            vaults: vec![Vault {
+                id: DEFAULT_VAULT_ID.to_string(),
+                name: DEFAULT_VAULT_NAME.to_string(),
+                created_at: 0,
+            }],
+        }
+    }
+}
+
+/// Get the app data directory
+pub fn root_dir() -> PathBuf {
+    #[cfg(test)]
+    if let Some(root) = crate::test_support::root_override() {
+        return root;
+    }
+
+    let app_dir = dirs::data_local_dir()
+        .expect("Failed to get local data directory")
+        .join("com.satstone.bitfinances");
+
+    std::fs::create_dir_all(&app_dir).expect("Failed to create app directory");
+    app_dir
+}
+
+/// Get the directory holding a vault's files
+pub fn vault_dir(id: &str) -> PathBuf {
+    if id == DEFAULT_VAULT_ID {
+        root_dir()
+    } else {
+        root_dir().join("vaults").join(id)
+    }
+}
+
+/// Get the active vault's id
+pub fn active_id() -> String {
+    ACTIVE_VAULT
+        .read()
+        .ok()
+        .and_then(|active| active.clone())
+        .unwrap_or_else(|| DEFAULT_VAULT_ID.to_string())
+}
+
+/// Get a vault's name
+pub fn name_of(id: &str) -> String {
+    load_registry()
+        .vaults
+        .into_iter()
+        .find(|v| v.id == id)
+        .map(|v| v.name)
+        .unwrap_or_else(|| DEFAULT_VAULT_NAME.to_string())
+}
+
+/// Get the active vault's name
+pub fn active_name() -> String {
+    name_of(&active_id())
+}
+
+/// Get the active vault's directory
+pub fn active_dir() -> PathBuf {
+    let dir = vault_dir(&active_id());
+    std::fs::create_dir_all(&dir).expect("Failed to create vault directory");
+    dir
+}
+
+fn load_registry() -> Registry {
+    std::fs::read_to_string(root_dir().join(REGISTRY_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_registry(registry: &Registry) -> Result<(), String> {
+    let path = root_dir().join(REGISTRY_FILE);
+    let temp = path.with_extension("json.new");
+    let content = serde_json::to_string_pretty(registry).map_err(|e| e.to_string())?;
+
+    std::fs::write(&temp, content).map_err(|e| format!("Failed to save vaults: {}", e))?;
+    std::fs::rename(&temp, &path).map_err(|e| format!("Failed to save vaults: {}", e))
+}
+
+/// Point the database paths at the vault that was open last
+pub fn init_active() {
+    let registry = load_registry();
+    let active = if registry.vaults.iter().any(|v| v.id == registry.active) {
+        registry.active
+    } else {
+        DEFAULT_VAULT_ID.to_string()
+    };
+
+    if let Ok(mut current) = ACTIVE_VAULT.write() {
+        *current = Some(active);
+    }
+}
+
+/// List all vaults
+pub fn list() -> Vec<VaultInfo> {
+    let active = active_id();
+
+    load_registry()
+        .vaults
+        .into_iter()
+        .map(|vault| VaultInfo {
+            active: vault.id == active,
+            encrypted: crate::encrypted_db::EncryptedDb::is_sqlcipher_file(
+                &vault_dir(&vault.id).join(crate::encrypted_db::DB_FILE_NAME),
+            ),
+            id: vault.id,
+            name: vault.name,
+            created_at: vault.created_at,
+        })
+        .collect()
+}
+
+fn validate_name(registry: &Registry, name: &str, except_id: Option<&str>) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Vault name cannot be empty".to_string());
+    }
+
+    let taken = registry
+        .vaults
+        .iter()
+        .any(|v| Some(v.id.as_str()) != except_id && v.name.eq_ignore_ascii_case(name));
+    if taken {
+        return Err("A vault with this name already exists".to_string());
+    }
+
+    Ok(name.to_string())
+}
+
+/// Create an empty vault; its database is set up when it's first opened
+pub fn create(name: &str) -> Result<Vault, String> {
+    let mut registry = load_registry();
+    let name = validate_name(&registry, name, None)?;
+
+    let vault = Vault {
+        id: uuid::Uuid::new_v4().to_string(),
+        name,
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+
+    std::fs::create_dir_all(vault_dir(&vault.id))
+        .map_err(|e| format!("Failed to create vault directory: {}", e))?;
+
+    registry.vaults.push(vault.clone());
+    save_registry(&registry)?;
+
+    Ok(vault)
+}
+
+/// Rename a vault
+pub fn rename(id: &str, name: &str) -> Result<(), String> {
+    let mut registry = load_registry();
+    let name = validate_name(&registry, name, Some(id))?;
+
+    let vault = registry
+        .vaults
+        .iter_mut()
+        .find(|v| v.id == id)
+        .ok_or("Vault not found")?;
+    vault.name = name;
+
+    save_registry(&registry)
+}
+
+/// Delete a vault and all of its files
+/// `confirm_name` must repeat the vault's name, so a stray call can't delete it
+pub fn delete(id: &str, confirm_name: &str) -> Result<(), String> {
+    if id == DEFAULT_VAULT_ID {
+        return Err("The default vault cannot be deleted".to_string());
+    }
+    if id == active_id() {
+        return Err("Switch to another vault before deleting this one".to_string());
+    }
+
+    let mut registry = load_registry();
+    let vault = registry.vaults.iter().find(|v| v.id == id).ok_or("Vault not found")?;
+    if !vault.name.eq_ignore_ascii_case(confirm_name.trim()) {
+        return Err("Type the vault's name to confirm deleting it".to_string());
+    }
+    registry.vaults.retain(|v| v.id != id);
+
+    // Forget it first, so a failed removal never leaves a listed vault half-deleted
+    save_registry(&registry)?;
+
+    let dir = vault_dir(id);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).map_err(|e| format!("Failed to delete vault files: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Make a vault the active one (and the one opened on next start)
+pub fn set_active(id: &str) -> Result<(), String> {
+    let mut registry = load_registry();
+    if !registry.vaults.iter().any(|v| v.id == id) {
+        return Err("Vault not found".to_string());
+    }
+
+    registry.active = id.to_string();
+    save_registry(&registry)?;
+
+    let mut current = ACTIVE_VAULT.write().map_err(|_| "Vault state is unavailable".to_string())?;
+    *current = Some(id.to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::AppDir;
+
+    fn names() -> Vec<String> {
+        list().into_iter().map(|v| v.name).collect()
+    }
+
+    #[test]
+    fn test_default_vault_is_the_app_dir() {
+        let dir = AppDir::new();
+
+        assert_eq!(active_id(), DEFAULT_VAULT_ID);
+        assert_eq!(active_dir(), dir.path());
+        assert_eq!(names(), vec![DEFAULT_VAULT_NAME]);
+    }
+
+    #[test]
+    fn test_create_and_rename() {
+        let dir = AppDir::new();
+
+        let vault = create("  Business ").unwrap();
+        assert_eq!(vault.name, "Business");
+        assert_eq!(vault_dir(&vault.id), dir.path().join("vaults").join(&vault.id));
+        assert!(vault_dir(&vault.id).is_dir());
+
+        assert_eq!(create("business").unwrap_err(), "A vault with this name already exists");
+        assert_eq!(create(" ").unwrap_err(), "Vault name cannot be empty");
+
+        rename(&vault.id, "Company").unwrap();
+        // Renaming to its own name in another case is fine
+        rename(&vault.id, "COMPANY").unwrap();
+        assert_eq!(rename(&vault.id, "personal").unwrap_err(), "A vault with this name already exists");
+        assert_eq!(rename("missing", "Other").unwrap_err(), "Vault not found");
+        assert_eq!(names(), vec!["Personal", "COMPANY"]);
+    }
+
+    #[test]
+    fn test_registry_persists() {
+        let _dir = AppDir::new();
+
+        let vault = create("Business").unwrap();
+        set_active(&vault.id).unwrap();
+        assert_eq!(active_id(), vault.id);
+        assert!(list().iter().any(|v| v.id == vault.id && v.active));
+
+        // As on the next start
+        *ACTIVE_VAULT.write().unwrap() = None;
+        init_active();
+        assert_eq!(active_id(), vault.id);
+        assert_eq!(active_name(), "Business");
+        assert_eq!(names(), vec!["Personal", "Business"]);
+
+        assert_eq!(set_active("missing").unwrap_err(), "Vault not found");
+        assert_eq!(active_id(), vault.id);
+    }
+
+    #[test]
+    fn test_delete() {
+        let _dir = AppDir::new();
+
+        let vault = create("Business").unwrap();
+        std::fs::write(vault_dir(&vault.id).join("personal.db"), b"data").unwrap();
+
+        assert_eq!(delete(&vault.id, "Personal").unwrap_err(), "Type the vault's name to confirm deleting it");
+        assert!(vault_dir(&vault.id).exists());
+
+        delete(&vault.id, "business").unwrap();
+        assert!(!vault_dir(&vault.id).exists());
+        assert_eq!(names(), vec!["Personal"]);
+        assert_eq!(delete(&vault.id, "Business").unwrap_err(), "Vault not found");
+    }
+
+    #[test]
+    fn test_delete_refuses_default_and_active() {
+        let _dir = AppDir::new();
+
+        assert_eq!(delete(DEFAULT_VAULT_ID, "Personal").unwrap_err(), "The default vault cannot be deleted");
+
+        let vault = create("Business").unwrap();
+        set_active(&vault.id).unwrap();
+        assert_eq!(
+            delete(&vault.id, "Business").unwrap_err(),
+            "Switch to another vault before deleting this one"
+        );
+        assert!(vault_dir(&vault.id).exists());
+
+        set_active(DEFAULT_VAULT_ID).unwrap();
+        delete(&vault.id, "Business").unwrap();
+    }
+}